@@ -1,12 +1,12 @@
-/// Example: Managing backends at runtime
-///
-/// This example demonstrates how to:
-/// - Initialize the backend pool
-/// - Add backends dynamically
-/// - Remove backends
-/// - List current backends
-
-use flax::core::route::{get_backend_pool, init_backend_pool, Backend};
+//! Example: Managing backends at runtime
+//!
+//! This example demonstrates how to:
+//! - Initialize the backend pool
+//! - Add backends dynamically
+//! - Remove backends
+//! - List current backends
+
+use flax::backend::{Backend, get_backend_pool, init_backend_pool};
 
 fn main() {
     // Initialize with some default backends
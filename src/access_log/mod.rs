@@ -0,0 +1,25 @@
+//! Access logging
+//!
+//! Workers format one line per request into a thread-local buffer and hand
+//! full buffers to a dedicated writer thread over a bounded channel, so a
+//! slow disk never stalls the io_uring loop; if the channel is full the
+//! lines are dropped and counted instead. SIGUSR1 makes the writer reopen
+//! the file for log rotation.
+
+pub mod record;
+pub mod writer;
+
+use serde::Deserialize;
+
+pub use record::RequestLog;
+pub use writer::{enabled, finish, flush, flush_due, record, reopen, start};
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    /// Apache/nginx combined log format, followed by Flax's timing fields
+    #[default]
+    Combined,
+    /// One JSON object per line
+    Json,
+}
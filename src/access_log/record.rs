@@ -0,0 +1,240 @@
+use std::fmt::Write;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use memchr::memchr;
+use serde_json::json;
+
+use crate::protocol::{HttpMetadata, find_header, peek_response_status};
+use crate::trace::{TraceContext, context::hex, otlp};
+use crate::util::address::Address;
+
+use super::writer;
+
+/// Everything logged about one request, filled in as it progresses.
+#[derive(Debug)]
+pub struct RequestLog {
+    pub timestamp: SystemTime,
+    pub started: Instant,
+    pub client: Option<SocketAddr>,
+    pub method: String,
+    pub path: String,
+    pub protocol: String,
+    pub host: Option<String>,
+    pub referer: Option<String>,
+    pub user_agent: Option<String>,
+    pub upstream: Option<Address>,
+    pub request_id: Option<String>,
+    /// Flax's span for the request
+    pub trace: Option<TraceContext>,
+    pub status: Option<u16>,
+    pub response_bytes: u64,
+    /// From the complete request head to an established backend connection
+    pub connect_time: Option<Duration>,
+    /// From the complete request head to the first response bytes
+    pub first_byte_time: Option<Duration>,
+}
+
+impl RequestLog {
+    /// Whether anything consumes finished records: the access log or the span exporter.
+    #[inline]
+    pub fn wanted() -> bool {
+        writer::enabled() || otlp::enabled()
+    }
+
+    pub fn new(
+        client: Option<SocketAddr>,
+        head: &[u8],
+        meta: &HttpMetadata<'_>,
+        upstream: Option<Address>,
+    ) -> Self {
+        let request_line = &head[..memchr(b'\r', head).unwrap_or(head.len())];
+        let protocol = request_line
+            .rsplit(|&b| b == b' ')
+            .next()
+            .unwrap_or_default();
+        let text = |bytes: &[u8]| String::from_utf8_lossy(bytes).into_owned();
+
+        Self {
+            timestamp: SystemTime::now(),
+            started: Instant::now(),
+            client,
+            method: text(meta.method_bytes),
+            path: text(meta.path_bytes),
+            protocol: text(protocol),
+            host: meta.host_header_value.map(text),
+            referer: find_header(head, b"Referer").map(text),
+            user_agent: find_header(head, b"User-Agent").map(text),
+            upstream,
+            request_id: None,
+            trace: None,
+            status: None,
+            response_bytes: 0,
+            connect_time: None,
+            first_byte_time: None,
+        }
+    }
+
+    /// The backend connection is ready (fresh connect or cache hit).
+    pub fn connected(&mut self) {
+        self.connect_time = Some(self.started.elapsed());
+    }
+
+    /// Called with each chunk received from the backend.
+    pub fn response_data(&mut self, data: &[u8]) {
+        if self.first_byte_time.is_none() {
+            self.first_byte_time = Some(self.started.elapsed());
+            self.status = peek_response_status(data);
+        }
+    }
+
+    /// The request is over: write it to the access log and export its span.
+    pub fn finish(&self) {
+        writer::record(self);
+        otlp::export(self);
+    }
+
+    /// Append the record in combined log format plus Flax's own fields.
+    ///
+    /// ```text
+    /// 10.0.0.7 - - [18/Oct/2026:09:12:03 +0000] "GET /a HTTP/1.1" 200 512 "-" "curl/8.5.0"
+    ///     host="example.com" upstream=127.0.0.1:8081 request_id="5f0c..." connect_time=0.000 first_byte_time=0.002 request_time=0.003
+    /// ```
+    pub fn write_combined(&self, out: &mut String) {
+        let client = self
+            .client
+            .map_or_else(|| "-".to_string(), |a| a.ip().to_string());
+        let _ = write!(out, "{client} - - [{}] \"", clf_time(self.timestamp));
+        escape_into(out, &self.method);
+        out.push(' ');
+        escape_into(out, &self.path);
+        out.push(' ');
+        escape_into(out, &self.protocol);
+        out.push_str("\" ");
+        match self.status {
+            Some(status) => {
+                let _ = write!(out, "{status} ");
+            }
+            None => out.push_str("- "),
+        }
+        match self.response_bytes {
+            0 => out.push('-'),
+            n => {
+                let _ = write!(out, "{n}");
+            }
+        }
+        for value in [&self.referer, &self.user_agent] {
+            out.push_str(" \"");
+            escape_into(out, value.as_deref().unwrap_or("-"));
+            out.push('"');
+        }
+        out.push_str(" host=\"");
+        escape_into(out, self.host.as_deref().unwrap_or("-"));
+        out.push('"');
+        match self.upstream {
+            Some(addr) => {
+                let _ = write!(out, " upstream={addr}");
+            }
+            None => out.push_str(" upstream=-"),
+        }
+        out.push_str(" request_id=\"");
+        escape_into(out, self.request_id.as_deref().unwrap_or("-"));
+        out.push('"');
+        for (name, value) in [
+            ("connect_time", self.connect_time),
+            ("first_byte_time", self.first_byte_time),
+            ("request_time", Some(self.started.elapsed())),
+        ] {
+            match value {
+                Some(d) => {
+                    let _ = write!(out, " {name}={:.3}", d.as_secs_f64());
+                }
+                None => {
+                    let _ = write!(out, " {name}=-");
+                }
+            }
+        }
+        out.push('\n');
+    }
+
+    /// Append the record as one JSON object per line.
+    pub fn write_json(&self, out: &mut String) {
+        let seconds = |d: Option<Duration>| d.map(|d| d.as_secs_f64());
+        let value = json!({
+            "time": rfc3339_time(self.timestamp),
+            "client": self.client.map(|a| a.to_string()),
+            "method": self.method,
+            "path": self.path,
+            "protocol": self.protocol,
+            "host": self.host,
+            "status": self.status,
+            "bytes": self.response_bytes,
+            "referer": self.referer,
+            "user_agent": self.user_agent,
+            "upstream": self.upstream.map(|a| a.to_string()),
+            "request_id": self.request_id,
+            "trace_id": self.trace.map(|t| hex(&t.trace_id)),
+            "connect_time": seconds(self.connect_time),
+            "first_byte_time": seconds(self.first_byte_time),
+            "request_time": self.started.elapsed().as_secs_f64(),
+        });
+        let _ = writeln!(out, "{value}");
+    }
+}
+
+/// Copy `value`, escaping quotes, backslashes and non-printable bytes as nginx does.
+fn escape_into(out: &mut String, value: &str) {
+    for b in value.bytes() {
+        match b {
+            0x20..=0x7e if b != b'"' && b != b'\\' => out.push(b as char),
+            _ => {
+                let _ = write!(out, "\\x{b:02X}");
+            }
+        }
+    }
+}
+
+const MONTHS: [&str; 12] = [
+    "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+];
+
+/// UTC date and time of day, split into (year, month 1-12, day, hour, minute, second, millis).
+fn utc_parts(time: SystemTime) -> (i64, u32, u32, u32, u32, u32, u32) {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = since_epoch.as_secs() as i64;
+    let (days, rem) = (secs.div_euclid(86_400), secs.rem_euclid(86_400) as u32);
+
+    // days-to-civil, H. Hinnant
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + (month <= 2) as i64;
+
+    (
+        year,
+        month,
+        day,
+        rem / 3600,
+        rem / 60 % 60,
+        rem % 60,
+        since_epoch.subsec_millis(),
+    )
+}
+
+fn clf_time(time: SystemTime) -> String {
+    let (year, month, day, hour, minute, second, _) = utc_parts(time);
+    format!(
+        "{day:02}/{}/{year}:{hour:02}:{minute:02}:{second:02} +0000",
+        MONTHS[month as usize - 1]
+    )
+}
+
+fn rfc3339_time(time: SystemTime) -> String {
+    let (year, month, day, hour, minute, second, millis) = utc_parts(time);
+    format!("{year}-{month:02}-{day:02}T{hour:02}:{minute:02}:{second:02}.{millis:03}Z")
+}
@@ -0,0 +1,228 @@
+use std::cell::RefCell;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{Receiver, RecvTimeoutError, SyncSender, TrySendError, sync_channel};
+use std::sync::{Mutex, OnceLock};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use tracing::warn;
+
+use crate::metrics::worker_metrics;
+use crate::rate_limited;
+
+use super::LogFormat;
+use super::record::RequestLog;
+
+/// Chunks queued for the writer thread before workers start dropping lines.
+const QUEUE_DEPTH: usize = 1024;
+/// A worker hands its buffer over once it grows past this.
+const FLUSH_THRESHOLD: usize = 16 * 1024;
+/// ... or once its oldest line has waited this long.
+const FLUSH_INTERVAL: Duration = Duration::from_millis(200);
+/// Written-out chunks kept for workers to reuse.
+const MAX_SPARE: usize = 64;
+/// How often the writer thread wakes up to notice a reopen request.
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+struct Sink {
+    tx: SyncSender<String>,
+    format: LogFormat,
+    writer: Mutex<Option<JoinHandle<()>>>,
+    /// Chunks the writer is done with, emptied but keeping their capacity
+    spare: Mutex<Vec<String>>,
+}
+
+static SINK: OnceLock<Sink> = OnceLock::new();
+static REOPEN: AtomicBool = AtomicBool::new(false);
+
+struct WorkerBuffer {
+    sink: &'static Sink,
+    buf: String,
+    /// When the first line still in `buf` was written
+    since: Option<Instant>,
+}
+
+thread_local! {
+    static BUFFER: RefCell<Option<WorkerBuffer>> = const { RefCell::new(None) };
+}
+
+/// Open the log and start the writer thread. `-` logs to stdout.
+pub fn start(path: &Path, format: LogFormat) -> io::Result<()> {
+    let file = open(path)?;
+    let (tx, rx) = sync_channel(QUEUE_DEPTH);
+    let path = path.to_path_buf();
+    let writer = thread::Builder::new()
+        .name("flax-access-log".into())
+        .spawn(move || run_writer(rx, path, file))?;
+    SINK.set(Sink {
+        tx,
+        format,
+        writer: Mutex::new(Some(writer)),
+        spare: Mutex::new(Vec::new()),
+    })
+    .map_err(|_| io::Error::other("access log already started"))
+}
+
+/// Write out everything queued so far and stop the writer thread.
+///
+/// Call once the workers have exited and flushed their buffers.
+pub fn finish() {
+    let Some(sink) = SINK.get() else {
+        return;
+    };
+    let Some(writer) = sink.writer.lock().unwrap().take() else {
+        return;
+    };
+    // an empty chunk tells the writer to stop; workers never send one
+    if sink.tx.send(String::new()).is_ok() {
+        let _ = writer.join();
+    }
+}
+
+/// Whether requests should be recorded at all.
+#[inline]
+pub fn enabled() -> bool {
+    SINK.get().is_some()
+}
+
+/// Ask the writer thread to reopen the log file, e.g. after rotation.
+pub fn reopen() {
+    REOPEN.store(true, Ordering::Relaxed);
+}
+
+/// Format `log` into this thread's buffer.
+pub fn record(log: &RequestLog) {
+    let Some(sink) = SINK.get() else {
+        return;
+    };
+    BUFFER.with_borrow_mut(|slot| {
+        let worker = slot.get_or_insert_with(|| WorkerBuffer {
+            sink,
+            buf: sink.take_spare(),
+            since: None,
+        });
+        if worker.since.is_none() {
+            worker.since = Some(Instant::now());
+        }
+        match sink.format {
+            LogFormat::Combined => log.write_combined(&mut worker.buf),
+            LogFormat::Json => log.write_json(&mut worker.buf),
+        }
+        if worker.buf.len() >= FLUSH_THRESHOLD {
+            worker.flush();
+        }
+    });
+}
+
+/// Hand whatever this thread has buffered to the writer thread. Never blocks.
+///
+/// For a worker about to wait for completions, or shutting down.
+pub fn flush() {
+    BUFFER.with_borrow_mut(|slot| {
+        if let Some(worker) = slot {
+            worker.flush();
+        }
+    });
+}
+
+/// Like [`flush`], but only once the oldest buffered line has waited
+/// `FLUSH_INTERVAL`, so a busy worker hands over full chunks.
+pub fn flush_due() {
+    BUFFER.with_borrow_mut(|slot| {
+        if let Some(worker) = slot
+            && worker
+                .since
+                .is_some_and(|since| since.elapsed() >= FLUSH_INTERVAL)
+        {
+            worker.flush();
+        }
+    });
+}
+
+impl Sink {
+    fn take_spare(&self) -> String {
+        self.spare
+            .lock()
+            .unwrap()
+            .pop()
+            .unwrap_or_else(|| String::with_capacity(FLUSH_THRESHOLD * 2))
+    }
+
+    fn give_back(&self, mut chunk: String) {
+        chunk.clear();
+        let mut spare = self.spare.lock().unwrap();
+        if spare.len() < MAX_SPARE {
+            spare.push(chunk);
+        }
+    }
+}
+
+impl WorkerBuffer {
+    fn flush(&mut self) {
+        if self.buf.is_empty() {
+            return;
+        }
+        self.since = None;
+        let next = self.sink.take_spare();
+        let chunk = std::mem::replace(&mut self.buf, next);
+        match self.sink.tx.try_send(chunk) {
+            Ok(()) => {}
+            Err(TrySendError::Full(chunk) | TrySendError::Disconnected(chunk)) => {
+                // writer can't keep up (or is gone) - drop rather than stall the ring
+                let lines = chunk.bytes().filter(|&b| b == b'\n').count();
+                worker_metrics().access_log_dropped.add(lines as u64);
+                self.sink.give_back(chunk);
+            }
+        }
+    }
+}
+
+enum Target {
+    File(File),
+    Stdout,
+}
+
+fn open(path: &Path) -> io::Result<Target> {
+    if path == Path::new("-") {
+        return Ok(Target::Stdout);
+    }
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .map(Target::File)
+}
+
+fn run_writer(rx: Receiver<String>, path: PathBuf, mut target: Target) {
+    loop {
+        let chunk = match rx.recv_timeout(POLL_INTERVAL) {
+            Ok(chunk) if chunk.is_empty() => return,
+            Ok(chunk) => Some(chunk),
+            Err(RecvTimeoutError::Timeout) => None,
+            Err(RecvTimeoutError::Disconnected) => return,
+        };
+
+        if REOPEN.swap(false, Ordering::Relaxed) {
+            match open(&path) {
+                Ok(reopened) => target = reopened,
+                Err(e) => warn!(path = %path.display(), "access log: cannot reopen: {e}"),
+            }
+        }
+
+        if let Some(chunk) = chunk {
+            let result = match &mut target {
+                Target::File(file) => file.write_all(chunk.as_bytes()),
+                Target::Stdout => io::stdout().lock().write_all(chunk.as_bytes()),
+            };
+            if let Err(e) = result {
+                rate_limited!(warn, "access log: write failed: {e}");
+            }
+            if let Some(sink) = SINK.get() {
+                sink.give_back(chunk);
+            }
+        }
+    }
+}
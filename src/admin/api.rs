@@ -0,0 +1,307 @@
+use std::io;
+
+use serde_json::{Value, json};
+
+use crate::backend::{
+    Backend, BackendPool, BackendState, BackendStatus, Health, MAX_WEIGHT, get_pool, pools,
+};
+use crate::cluster;
+use crate::metrics;
+use crate::tls;
+use crate::util::address::Address;
+
+pub struct Response {
+    pub status: u16,
+    pub content_type: &'static str,
+    pub body: String,
+}
+
+impl Response {
+    fn json(status: u16, body: Value) -> Self {
+        let mut body = body.to_string();
+        body.push('\n');
+        Self {
+            status,
+            content_type: "application/json",
+            body,
+        }
+    }
+
+    fn ok(body: Value) -> Self {
+        Self::json(200, body)
+    }
+
+    pub fn error(status: u16, message: &str) -> Self {
+        Self::json(status, json!({ "error": message }))
+    }
+
+    pub fn to_http(&self) -> Vec<u8> {
+        let mut out = format!(
+            "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n",
+            self.status,
+            reason(self.status),
+            self.content_type,
+            self.body.len()
+        );
+        if self.status == 401 {
+            out.push_str("WWW-Authenticate: Bearer\r\n");
+        }
+        out.push_str("\r\n");
+        out.push_str(&self.body);
+        out.into_bytes()
+    }
+}
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        201 => "Created",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        409 => "Conflict",
+        413 => "Payload Too Large",
+        431 => "Request Header Fields Too Large",
+        _ => "Error",
+    }
+}
+
+/// Route an authenticated admin request.
+///
+/// ```text
+/// GET    /metrics                                 Prometheus text format
+/// GET    /pools
+/// GET    /pools/{pool}
+/// GET    /pools/{pool}/backends
+/// POST   /pools/{pool}/backends                   {"address": "..", "weight": 1}
+/// GET    /pools/{pool}/backends/{address}
+/// DELETE /pools/{pool}/backends/{address}
+/// POST   /pools/{pool}/backends/{address}/drain
+/// POST   /pools/{pool}/backends/{address}/activate
+/// PUT    /pools/{pool}/backends/{address}/weight  {"weight": 3}
+/// GET    /cluster
+/// ```
+///
+/// Unix backends go in the path percent-encoded, e.g. `unix:%2Frun%2Fapp.sock`.
+pub fn handle(method: &str, path: &str, body: &[u8]) -> Response {
+    let path = path.split('?').next().unwrap_or("");
+    let segments: Vec<String> = path
+        .split('/')
+        .filter(|s| !s.is_empty())
+        .map(percent_decode)
+        .collect();
+    let segments: Vec<&str> = segments.iter().map(String::as_str).collect();
+
+    match (method, segments.as_slice()) {
+        ("GET", ["metrics"]) => Response {
+            status: 200,
+            content_type: "text/plain; version=0.0.4",
+            body: metrics::render(),
+        },
+        (_, ["metrics"]) => method_not_allowed(),
+
+        ("GET", ["pools"]) => {
+            let all: Vec<Value> = pools().map(|(name, pool)| pool_json(name, pool)).collect();
+            Response::ok(json!({ "pools": all }))
+        }
+        (_, ["pools"]) => method_not_allowed(),
+
+        (_, ["pools", name, rest @ ..]) => {
+            let Some(pool) = get_pool(name) else {
+                return Response::error(404, "no such pool");
+            };
+            handle_pool(method, name, pool, rest, body)
+        }
+
+        ("POST", ["tls", "reload"]) => match tls::reload() {
+            Ok(()) => Response::ok(json!({ "reloaded": true })),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Response::error(404, &e.to_string()),
+            Err(e) => Response::error(500, &e.to_string()),
+        },
+        (_, ["tls", "reload"]) => method_not_allowed(),
+
+        ("GET", ["cluster"]) => match cluster::local_address() {
+            Some(address) => Response::ok(json!({
+                "address": address.to_string(),
+                "incarnation": cluster::incarnation(),
+                "health": cluster::health_multiplier(),
+                "members": cluster::members().iter().map(ToString::to_string).collect::<Vec<_>>(),
+                "suspects": cluster::suspects().iter().map(ToString::to_string).collect::<Vec<_>>(),
+            })),
+            None => Response::error(404, "not in a cluster"),
+        },
+        (_, ["cluster"]) => method_not_allowed(),
+
+        _ => Response::error(404, "no such endpoint"),
+    }
+}
+
+fn handle_pool(
+    method: &str,
+    name: &str,
+    pool: &BackendPool,
+    rest: &[&str],
+    body: &[u8],
+) -> Response {
+    match (method, rest) {
+        ("GET", []) => Response::ok(pool_json(name, pool)),
+        ("GET", ["backends"]) => Response::ok(json!({ "backends": backends_json(pool) })),
+        ("POST", ["backends"]) => add_backend(pool, body),
+        (_, [] | ["backends"]) => method_not_allowed(),
+
+        (_, ["backends", address, action @ ..]) => {
+            let Ok(address) = address.parse::<Address>() else {
+                return Response::error(400, "invalid backend address");
+            };
+            let Some(status) = pool
+                .status()
+                .into_iter()
+                .find(|s| s.backend.address == address)
+            else {
+                return Response::error(404, "no such backend");
+            };
+            handle_backend(method, pool, status, action, body)
+        }
+
+        _ => Response::error(404, "no such endpoint"),
+    }
+}
+
+fn handle_backend(
+    method: &str,
+    pool: &BackendPool,
+    status: BackendStatus,
+    action: &[&str],
+    body: &[u8],
+) -> Response {
+    let address = status.backend.address;
+    let changed = match (method, action) {
+        ("GET", []) => return Response::ok(backend_json(&status)),
+        ("DELETE", []) => {
+            pool.remove_backend(address);
+            return Response::ok(json!({ "removed": address.to_string() }));
+        }
+        ("POST", ["drain"]) => pool.set_state(address, BackendState::Draining),
+        ("POST", ["activate"]) => pool.set_state(address, BackendState::Active),
+        ("PUT", ["weight"]) => {
+            let weight = match parse_body(body).map(|v| parse_weight(&v)) {
+                Ok(Ok(weight)) => weight,
+                Ok(Err(e)) | Err(e) => return e,
+            };
+            pool.set_weight(address, weight)
+        }
+        (_, [] | ["drain"] | ["activate"] | ["weight"]) => return method_not_allowed(),
+        _ => return Response::error(404, "no such endpoint"),
+    };
+
+    if !changed {
+        // removed concurrently
+        return Response::error(404, "no such backend");
+    }
+    match pool
+        .status()
+        .into_iter()
+        .find(|s| s.backend.address == address)
+    {
+        Some(status) => Response::ok(backend_json(&status)),
+        None => Response::error(404, "no such backend"),
+    }
+}
+
+fn add_backend(pool: &BackendPool, body: &[u8]) -> Response {
+    let value = match parse_body(body) {
+        Ok(value) => value,
+        Err(e) => return e,
+    };
+    let Some(address) = value
+        .get("address")
+        .and_then(Value::as_str)
+        .and_then(|a| a.parse::<Address>().ok())
+    else {
+        return Response::error(400, "body needs an \"address\" like \"10.0.0.5:8080\" or \"unix:/run/app.sock\"");
+    };
+    let weight = if value.get("weight").is_some() {
+        match parse_weight(&value) {
+            Ok(weight) => weight,
+            Err(e) => return e,
+        }
+    } else {
+        1
+    };
+
+    if !pool.add_backend(Backend::with_weight(address, weight)) {
+        return Response::error(409, "backend already in pool");
+    }
+    let status = pool
+        .status()
+        .into_iter()
+        .find(|s| s.backend.address == address);
+    Response::json(201, status.map(|s| backend_json(&s)).unwrap_or(Value::Null))
+}
+
+fn parse_body(body: &[u8]) -> Result<Value, Response> {
+    serde_json::from_slice(body).map_err(|_| Response::error(400, "body must be a JSON object"))
+}
+
+fn parse_weight(value: &Value) -> Result<u32, Response> {
+    value
+        .get("weight")
+        .and_then(Value::as_u64)
+        .filter(|&w| w <= MAX_WEIGHT as u64)
+        .map(|w| w as u32)
+        .ok_or_else(|| {
+            Response::error(
+                400,
+                &format!("\"weight\" must be an integer from 0 to {MAX_WEIGHT}"),
+            )
+        })
+}
+
+fn method_not_allowed() -> Response {
+    Response::error(405, "method not allowed")
+}
+
+fn pool_json(name: &str, pool: &BackendPool) -> Value {
+    json!({ "name": name, "backends": backends_json(pool) })
+}
+
+fn backends_json(pool: &BackendPool) -> Vec<Value> {
+    pool.status().iter().map(backend_json).collect()
+}
+
+fn backend_json(status: &BackendStatus) -> Value {
+    json!({
+        "address": status.backend.address.to_string(),
+        "weight": status.backend.weight,
+        "state": match status.state {
+            BackendState::Active => "active",
+            BackendState::Draining => "draining",
+        },
+        "health": match status.health {
+            Health::Healthy => "healthy",
+            Health::Unhealthy => "unhealthy",
+        },
+        "consecutive_failures": status.consecutive_failures,
+    })
+}
+
+/// Decode %XX escapes, e.g. `%5B::1%5D:8080` for an IPv6 backend.
+fn percent_decode(segment: &str) -> String {
+    let bytes = segment.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%'
+            && let Some(hex) = segment.get(i + 1..i + 3)
+            && let Ok(byte) = u8::from_str_radix(hex, 16)
+        {
+            out.push(byte);
+            i += 3;
+            continue;
+        }
+        out.push(bytes[i]);
+        i += 1;
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
@@ -0,0 +1,11 @@
+//! Admin HTTP API
+//!
+//! A small blocking HTTP/1.1 server on its own thread, bound to a loopback
+//! address or a Unix socket and protected by a static bearer token. It
+//! exposes the backend pools as JSON and lets operators add, remove, drain
+//! and re-weight backends while Flax is running.
+
+pub mod api;
+pub mod server;
+
+pub use server::spawn_admin;
@@ -0,0 +1,193 @@
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, TcpListener};
+use std::os::unix::net::UnixListener;
+use std::path::PathBuf;
+use std::thread;
+use std::time::Duration;
+
+use memchr::memmem;
+use tracing::{info, warn};
+
+use crate::config::AdminSection;
+use crate::core::socket::bind_unix_listener;
+use crate::protocol::{find_header, peek_request_headers};
+
+use super::api::{self, Response};
+
+const MAX_HEAD: usize = 8 * 1024;
+const MAX_BODY: usize = 64 * 1024;
+const IO_TIMEOUT: Duration = Duration::from_secs(5);
+/// Retry interval while another process (e.g. the one being upgraded) holds the endpoint.
+const BIND_RETRY: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone)]
+enum Endpoint {
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+}
+
+impl fmt::Display for Endpoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Endpoint::Tcp(addr) => write!(f, "{addr}"),
+            Endpoint::Unix(path) => write!(f, "{}", path.display()),
+        }
+    }
+}
+
+/// Start the admin API on its own thread.
+///
+/// Binding happens on that thread and is retried while the endpoint is busy,
+/// so a process started with `--upgrade` picks it up once the old one exits.
+pub fn spawn_admin(config: &AdminSection) -> io::Result<()> {
+    let token = match (&config.token, &config.token_file) {
+        (Some(token), _) => token.clone(),
+        (None, Some(path)) => std::fs::read_to_string(path)?.trim().to_string(),
+        (None, None) => String::new(),
+    };
+    if token.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "admin token must not be empty",
+        ));
+    }
+    let endpoint = match (&config.listen, &config.unix) {
+        (_, Some(path)) => Endpoint::Unix(path.clone()),
+        (Some(addr), None) => Endpoint::Tcp(*addr),
+        (None, None) => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "admin needs listen or unix",
+            ));
+        }
+    };
+
+    thread::Builder::new()
+        .name("flax-admin".into())
+        .spawn(move || serve(endpoint, token))?;
+    Ok(())
+}
+
+fn serve(endpoint: Endpoint, token: String) {
+    match bind_with_retry(&endpoint) {
+        Listener::Tcp(listener) => {
+            for stream in listener.incoming().flatten() {
+                let _ = stream.set_read_timeout(Some(IO_TIMEOUT));
+                let _ = stream.set_write_timeout(Some(IO_TIMEOUT));
+                handle_connection(stream, &token);
+            }
+        }
+        Listener::Unix(listener) => {
+            for stream in listener.incoming().flatten() {
+                let _ = stream.set_read_timeout(Some(IO_TIMEOUT));
+                let _ = stream.set_write_timeout(Some(IO_TIMEOUT));
+                handle_connection(stream, &token);
+            }
+        }
+    }
+}
+
+enum Listener {
+    Tcp(TcpListener),
+    Unix(UnixListener),
+}
+
+fn bind_with_retry(endpoint: &Endpoint) -> Listener {
+    let mut logged = false;
+    loop {
+        let bound = match endpoint {
+            Endpoint::Tcp(addr) => TcpListener::bind(addr).map(Listener::Tcp),
+            Endpoint::Unix(path) => bind_unix_listener(path, false).map(Listener::Unix),
+        };
+        match bound {
+            Ok(listener) => {
+                info!(%endpoint, "admin API listening");
+                return listener;
+            }
+            Err(e) => {
+                if !logged {
+                    warn!(%endpoint, "admin API cannot bind ({e}), retrying");
+                    logged = true;
+                }
+                thread::sleep(BIND_RETRY);
+            }
+        }
+    }
+}
+
+fn handle_connection<S: Read + Write>(mut stream: S, token: &str) {
+    let response = match read_request(&mut stream) {
+        Ok(Some(request)) => respond(&request, token),
+        Ok(None) => return,
+        Err(response) => response,
+    };
+    let _ = stream.write_all(&response.to_http());
+}
+
+struct Request {
+    head: Vec<u8>,
+    method: String,
+    path: String,
+    body: Vec<u8>,
+}
+
+fn respond(request: &Request, token: &str) -> Response {
+    let authorized = find_header(&request.head, b"Authorization")
+        .and_then(|v| v.strip_prefix(b"Bearer "))
+        .is_some_and(|presented| constant_time_eq(presented, token.as_bytes()));
+    if !authorized {
+        return Response::error(401, "missing or invalid bearer token");
+    }
+    api::handle(&request.method, &request.path, &request.body)
+}
+
+/// Read one request; `Ok(None)` means the peer went away before sending one.
+fn read_request<S: Read>(stream: &mut S) -> Result<Option<Request>, Response> {
+    let mut buf = Vec::with_capacity(1024);
+    let mut chunk = [0u8; 1024];
+    let head_end = loop {
+        if let Some(pos) = memmem::find(&buf, b"\r\n\r\n") {
+            break pos + 4;
+        }
+        if buf.len() > MAX_HEAD {
+            return Err(Response::error(431, "request head too large"));
+        }
+        match stream.read(&mut chunk) {
+            Ok(0) if buf.is_empty() => return Ok(None),
+            Ok(0) | Err(_) => return Err(Response::error(400, "incomplete request")),
+            Ok(n) => buf.extend_from_slice(&chunk[..n]),
+        }
+    };
+
+    let meta = peek_request_headers(&buf).map_err(|_| Response::error(400, "malformed request"))?;
+    let method = String::from_utf8_lossy(meta.method_bytes).into_owned();
+    let path = String::from_utf8_lossy(meta.path_bytes).into_owned();
+    let body_len = meta.content_length_value.unwrap_or(0);
+    if body_len > MAX_BODY {
+        return Err(Response::error(413, "request body too large"));
+    }
+
+    let mut body = buf.split_off(head_end);
+    while body.len() < body_len {
+        match stream.read(&mut chunk) {
+            Ok(0) | Err(_) => return Err(Response::error(400, "incomplete request body")),
+            Ok(n) => body.extend_from_slice(&chunk[..n]),
+        }
+    }
+    body.truncate(body_len);
+
+    Ok(Some(Request {
+        head: buf,
+        method,
+        path,
+        body,
+    }))
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
@@ -4,12 +4,56 @@ use std::{
     os::fd::RawFd,
 };
 
+use crate::backend::pool::{in_any_pool, removal_epoch};
+use crate::metrics::worker_metrics;
+use crate::tls::TlsSession;
+use crate::util::address::Address;
 use crate::util::fd::close_fd_quiet;
 
 const MAX_CACHED: usize = 200;
 
+/// An idle backend connection, with its TLS session on pools that use TLS.
+pub struct CachedConnection {
+    pub fd: RawFd,
+    /// Must have no I/O in flight
+    pub tls: Option<Box<TlsSession>>,
+    /// Client the connection's PROXY protocol header announced; the header
+    /// is only sent once, so no other client's requests may use it
+    pub proxied_for: Option<SocketAddr>,
+}
+
+impl CachedConnection {
+    /// Whether the backend may still be sending or have closed: an idle
+    /// connection has nothing to read, and a close shows up as EOF or
+    /// close_notify.
+    fn peer_may_have_closed(&self) -> bool {
+        if self.tls.as_ref().is_some_and(|tls| tls.peer_has_closed()) {
+            return true;
+        }
+        let mut byte = 0u8;
+        let n = unsafe {
+            libc::recv(
+                self.fd,
+                (&raw mut byte).cast(),
+                1,
+                libc::MSG_PEEK | libc::MSG_DONTWAIT,
+            )
+        };
+        !(n < 0 && std::io::Error::last_os_error().kind() == std::io::ErrorKind::WouldBlock)
+    }
+
+    fn close(self) {
+        if let Some(mut tls) = self.tls {
+            tls.close(self.fd);
+        }
+        close_fd_quiet(self.fd);
+    }
+}
+
 pub struct BackendConnectionCache {
-    map: HashMap<SocketAddr, VecDeque<RawFd>>,
+    map: HashMap<Address, VecDeque<CachedConnection>>,
+    /// `removal_epoch()` as of the last sweep
+    removals: u64,
 }
 
 // Not thread safe! To be used exclusively by thread.
@@ -17,23 +61,90 @@ impl BackendConnectionCache {
     pub fn new() -> Option<Self> {
         Some(Self {
             map: HashMap::new(),
+            removals: removal_epoch(),
         })
     }
 
-    pub fn borrow_connection(&mut self, addr: &SocketAddr) -> Option<RawFd> {
-        match self.map.get_mut(addr) {
-            Some(deque) => deque.pop_front(),
-            None => None,
+    /// Close the connections to backends that have left every pool since
+    /// the last sweep.
+    pub fn sweep(&mut self) {
+        let removals = removal_epoch();
+        if removals == self.removals {
+            return;
+        }
+        self.removals = removals;
+        self.map.retain(|addr, deque| {
+            if in_any_pool(*addr) {
+                return true;
+            }
+            for conn in deque.drain(..) {
+                conn.close();
+            }
+            false
+        });
+    }
+
+    /// Take an idle connection to `addr`. On pools that send PROXY protocol
+    /// headers `client` is the client of the request, and only a connection
+    /// announced for that client will do; elsewhere it is `None`.
+    pub fn borrow_connection(
+        &mut self,
+        addr: &Address,
+        client: Option<SocketAddr>,
+    ) -> Option<CachedConnection> {
+        self.sweep();
+        let mut conn = None;
+        if let Some(deque) = self.map.get_mut(addr) {
+            while let Some(pos) = deque.iter().position(|conn| conn.proxied_for == client) {
+                let candidate = deque.remove(pos).unwrap();
+                if !candidate.peer_may_have_closed() {
+                    conn = Some(candidate);
+                    break;
+                }
+                // closed by the backend while it sat here
+                candidate.close();
+            }
+        }
+        let metrics = worker_metrics();
+        if conn.is_some() {
+            metrics.cache_hits.inc();
+        } else {
+            metrics.cache_misses.inc();
         }
+        conn
     }
 
-    pub fn return_connection(&mut self, addr: &SocketAddr, fd: RawFd) {
+    /// Keep an idle connection for reuse. A full cache makes room by closing
+    /// its oldest connection to `addr`, which may be one no later client can
+    /// use.
+    /// A connection the backend has closed, or to a backend no pool has any
+    /// more, is closed instead.
+    pub fn return_connection(&mut self, addr: &Address, conn: CachedConnection) {
+        self.sweep();
+        if conn.peer_may_have_closed() || !in_any_pool(*addr) {
+            conn.close();
+            return;
+        }
         let deque = self.map.entry(*addr).or_default();
 
-        if deque.len() < MAX_CACHED {
-            deque.push_back(fd);
-        } else {
-            close_fd_quiet(fd);
-        };
+        if deque.len() >= MAX_CACHED
+            && let Some(oldest) = deque.pop_front()
+        {
+            worker_metrics().cache_evictions.inc();
+            oldest.close();
+        }
+        deque.push_back(conn);
+    }
+
+    /// Close every cached connection, returning how many were closed.
+    pub fn close_all(&mut self) -> usize {
+        let mut closed = 0;
+        for (_, deque) in self.map.drain() {
+            for conn in deque {
+                conn.close();
+                closed += 1;
+            }
+        }
+        closed
     }
 }
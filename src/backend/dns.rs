@@ -0,0 +1,489 @@
+//! Backends named in DNS
+//!
+//! A backend may be given as `host:port`, reached at every A and AAAA record
+//! of the host, or as `srv:_service._proto.name`, whose SRV records supply
+//! ports and weights. The `flax-dns` thread looks every name up at startup and
+//! again as its records expire, or on a fixed interval, so workers never wait
+//! on a lookup. Each change is diffed into the pool as described in
+//! [`managed`](super::managed). A failed lookup keeps the addresses of the last
+//! one that worked.
+
+use std::collections::{BTreeSet, HashMap};
+use std::io::{self, Read, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpStream, UdpSocket};
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+use std::{fmt, fs, thread};
+
+use serde::Deserialize;
+use tracing::{info, warn};
+
+use crate::backend::MAX_WEIGHT;
+use crate::backend::managed::ManagedBackends;
+use crate::metrics::Counter;
+use crate::protocol::dns::{self, NXDOMAIN, Record, RecordData, RecordType, Response};
+use crate::util::address::Address;
+use crate::util::random;
+
+const DNS_PORT: u16 = 53;
+const RESOLV_CONF: &str = "/etc/resolv.conf";
+/// Bounds on how long looked-up records are relied on
+const MIN_TTL: Duration = Duration::from_secs(1);
+const MAX_TTL: Duration = Duration::from_secs(3600);
+/// How soon a failed lookup is tried again
+const RETRY_AFTER: Duration = Duration::from_secs(5);
+/// CNAMEs followed from a name to its records
+const MAX_CNAMES: usize = 8;
+/// Larger than any response sent without EDNS, which queries don't offer
+const MAX_UDP_RESPONSE: usize = 4096;
+
+/// Names looked up, counting each `host:port` or `srv:` backend once
+pub static LOOKUPS: Counter = Counter::new();
+/// Lookups that failed and left their backends as they were
+pub static LOOKUP_FAILURES: Counter = Counter::new();
+
+/// A backend named in DNS
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub enum DnsTarget {
+    /// Every address of `name`, on `port`
+    Host { name: String, port: u16 },
+    /// The targets and ports of an SRV name
+    Srv(String),
+}
+
+impl FromStr for DnsTarget {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        if let Some(name) = s.strip_prefix("srv:") {
+            return dns_name(name)
+                .map(DnsTarget::Srv)
+                .ok_or_else(|| format!("{s:?}: {name:?} is not a DNS name"));
+        }
+        let (name, port) = s
+            .rsplit_once(':')
+            .ok_or_else(|| format!("{s:?} is not an address, host:port or srv:name"))?;
+        let port = port
+            .parse()
+            .map_err(|_| format!("{s:?}: {port:?} is not a port"))?;
+        let name = dns_name(name).ok_or_else(|| format!("{s:?}: {name:?} is not a host name"))?;
+        Ok(DnsTarget::Host { name, port })
+    }
+}
+
+impl TryFrom<String> for DnsTarget {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, String> {
+        s.parse()
+    }
+}
+
+impl fmt::Display for DnsTarget {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DnsTarget::Host { name, port } => write!(f, "{name}:{port}"),
+            DnsTarget::Srv(name) => write!(f, "srv:{name}"),
+        }
+    }
+}
+
+/// `name` lowercase and without its trailing dot, if its labels are letters,
+/// digits, hyphens and underscores (as in `_http._tcp`).
+fn dns_name(name: &str) -> Option<String> {
+    let name = name.strip_suffix('.').unwrap_or(name);
+    let valid = !name.is_empty()
+        && name.len() <= 253
+        && name.split('.').all(|label| {
+            !label.is_empty()
+                && label.len() <= 63
+                && label
+                    .bytes()
+                    .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_')
+        });
+    valid.then(|| name.to_ascii_lowercase())
+}
+
+/// A backend named in DNS, and the pool it belongs to.
+#[derive(Debug, Clone)]
+pub struct DnsBackend {
+    pub pool: String,
+    pub target: DnsTarget,
+    /// Weight of each address of a host; SRV records carry their own
+    pub weight: u32,
+}
+
+#[derive(Debug, Clone)]
+pub struct DnsOptions {
+    /// Servers asked in order until one answers
+    pub nameservers: Vec<SocketAddr>,
+    /// How long each server gets to answer
+    pub timeout: Duration,
+    /// Lookup interval; the records' TTL when `None`
+    pub refresh: Option<Duration>,
+    /// How long vanished backends drain before they are removed
+    pub drain: Duration,
+}
+
+/// A nameserver given as an IP address, with or without a port.
+pub fn parse_nameserver(s: &str) -> Option<SocketAddr> {
+    s.parse()
+        .ok()
+        .or_else(|| Some(SocketAddr::new(s.parse().ok()?, DNS_PORT)))
+}
+
+/// The nameservers of /etc/resolv.conf, or the local one if it names none,
+/// as the C library does.
+pub fn system_nameservers() -> Vec<SocketAddr> {
+    let conf = fs::read_to_string(RESOLV_CONF).unwrap_or_default();
+    let servers: Vec<SocketAddr> = conf
+        .lines()
+        .filter_map(|line| line.strip_prefix("nameserver"))
+        .filter_map(|rest| rest.trim().parse::<IpAddr>().ok())
+        .map(|ip| SocketAddr::new(ip, DNS_PORT))
+        .collect();
+    if servers.is_empty() {
+        return vec![SocketAddr::new(Ipv4Addr::LOCALHOST.into(), DNS_PORT)];
+    }
+    servers
+}
+
+/// Look up every backend once, then start the thread that keeps them
+/// current. Does nothing without DNS backends.
+pub fn start(backends: Vec<DnsBackend>, options: DnsOptions) -> io::Result<()> {
+    if backends.is_empty() {
+        return Ok(());
+    }
+    let mut state = DnsBackends::new(backends, options);
+    // before the workers start, so they begin with every address known
+    state.refresh(Instant::now());
+    thread::Builder::new()
+        .name("flax-dns".to_string())
+        .spawn(move || {
+            loop {
+                thread::sleep(state.next_due().saturating_duration_since(Instant::now()));
+                state.refresh(Instant::now());
+            }
+        })?;
+    Ok(())
+}
+
+struct Target {
+    backend: DnsBackend,
+    /// Addresses and weights of the last lookup that worked
+    last: Vec<(SocketAddr, u32)>,
+    due: Instant,
+}
+
+struct DnsBackends {
+    resolver: Resolver,
+    targets: Vec<Target>,
+    managed: ManagedBackends,
+    refresh: Option<Duration>,
+}
+
+impl DnsBackends {
+    fn new(backends: Vec<DnsBackend>, options: DnsOptions) -> Self {
+        let now = Instant::now();
+        Self {
+            resolver: Resolver {
+                nameservers: options.nameservers,
+                timeout: options.timeout,
+            },
+            targets: backends
+                .into_iter()
+                .map(|backend| Target {
+                    backend,
+                    last: Vec::new(),
+                    due: now,
+                })
+                .collect(),
+            managed: ManagedBackends::new("dns", options.drain),
+            refresh: options.refresh,
+        }
+    }
+
+    /// Look up the names that are due, update their pools and remove the
+    /// backends that finished draining.
+    fn refresh(&mut self, now: Instant) {
+        let mut changed = BTreeSet::new();
+        for target in self.targets.iter_mut().filter(|t| t.due <= now) {
+            let backend = &target.backend;
+            LOOKUPS.inc();
+            match self.resolver.resolve(&backend.target, backend.weight) {
+                Ok((mut addresses, ttl)) => {
+                    target.due = now + self.refresh.unwrap_or(ttl.clamp(MIN_TTL, MAX_TTL));
+                    addresses.sort_unstable();
+                    addresses.dedup();
+                    if addresses == target.last {
+                        continue;
+                    }
+                    let added: Vec<_> = addresses
+                        .iter()
+                        .filter(|a| !target.last.contains(a))
+                        .collect();
+                    let removed: Vec<_> = target
+                        .last
+                        .iter()
+                        .filter(|a| !addresses.contains(a))
+                        .collect();
+                    info!(
+                        pool = backend.pool,
+                        name = %backend.target,
+                        ?added,
+                        ?removed,
+                        "dns backends changed"
+                    );
+                    target.last = addresses;
+                    changed.insert(backend.pool.clone());
+                }
+                Err(e) => {
+                    LOOKUP_FAILURES.inc();
+                    let retry = self.refresh.map_or(RETRY_AFTER, |r| r.min(RETRY_AFTER));
+                    target.due = now + retry;
+                    warn!(
+                        pool = backend.pool,
+                        name = %backend.target,
+                        kept = target.last.len(),
+                        "dns lookup failed, keeping the last addresses: {e}"
+                    );
+                }
+            }
+        }
+        for pool in changed {
+            let mut wanted: HashMap<Address, u32> = HashMap::new();
+            for target in self.targets.iter().filter(|t| t.backend.pool == pool) {
+                for &(addr, weight) in &target.last {
+                    let w = wanted.entry(addr.into()).or_default();
+                    *w = (*w).max(weight);
+                }
+            }
+            self.managed.update(&pool, &wanted, now);
+        }
+        self.managed.remove_drained(now);
+    }
+
+    fn next_due(&self) -> Instant {
+        let lookups = self.targets.iter().map(|t| t.due);
+        lookups
+            .chain(self.managed.next_removal())
+            .min()
+            .unwrap_or_else(|| Instant::now() + MAX_TTL)
+    }
+}
+
+/// Addresses and weights of a backend, and how long they may be relied on
+type Resolved = (Vec<(SocketAddr, u32)>, Duration);
+
+struct Resolver {
+    nameservers: Vec<SocketAddr>,
+    timeout: Duration,
+}
+
+impl Resolver {
+    fn resolve(&self, target: &DnsTarget, weight: u32) -> io::Result<Resolved> {
+        match target {
+            DnsTarget::Host { name, port } => {
+                let (ips, ttl) = self.addresses(name)?;
+                let addresses = ips
+                    .into_iter()
+                    .map(|ip| (SocketAddr::new(ip, *port), weight))
+                    .collect();
+                Ok((addresses, ttl))
+            }
+            DnsTarget::Srv(name) => self.srv(name),
+        }
+    }
+
+    /// The A and AAAA records of `name`; none at all is an error, and so is
+    /// a failure of both queries. Only the query that succeeded counts
+    /// towards the TTL when the other fails.
+    fn addresses(&self, name: &str) -> io::Result<(Vec<IpAddr>, Duration)> {
+        let mut ips = Vec::new();
+        let mut ttl = u32::MAX;
+        let mut failure = None;
+        for rtype in [RecordType::A, RecordType::Aaaa] {
+            let response = match self.query(name, rtype) {
+                Ok(response) => response,
+                Err(e) => {
+                    failure = Some(e);
+                    continue;
+                }
+            };
+            let (records, records_ttl) = records_of(&response.records, name);
+            ips.extend(records.into_iter().filter_map(|data| match data {
+                RecordData::Ip(ip) => Some(*ip),
+                _ => None,
+            }));
+            ttl = ttl.min(records_ttl);
+        }
+        if ips.is_empty() {
+            return Err(failure.unwrap_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("{name} has no addresses"),
+                )
+            }));
+        }
+        Ok((ips, Duration::from_secs(ttl.into())))
+    }
+
+    /// Backends of the SRV records of `name` with the lowest priority, at the
+    /// addresses sent along with them or else looked up.
+    fn srv(&self, name: &str) -> io::Result<Resolved> {
+        let response = self.query(name, RecordType::Srv)?;
+        let (records, mut ttl) = records_of(&response.records, name);
+        let srvs: Vec<(u16, u16, u16, &str)> = records
+            .into_iter()
+            .filter_map(|data| match data {
+                // "." means the service is not offered at this name
+                RecordData::Srv {
+                    priority,
+                    weight,
+                    port,
+                    target,
+                } if !target.is_empty() => Some((*priority, *weight, *port, target.as_str())),
+                _ => None,
+            })
+            .collect();
+        let Some(priority) = srvs.iter().map(|s| s.0).min() else {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("{name} has no SRV records"),
+            ));
+        };
+        let mut backends = Vec::new();
+        for &(_, weight, port, target) in srvs.iter().filter(|s| s.0 == priority) {
+            let (additional, additional_ttl) = records_of(&response.records, target);
+            let mut ips: Vec<IpAddr> = additional
+                .into_iter()
+                .filter_map(|data| match data {
+                    RecordData::Ip(ip) => Some(*ip),
+                    _ => None,
+                })
+                .collect();
+            if ips.is_empty() {
+                let (looked_up, looked_up_ttl) = self.addresses(target)?;
+                ips = looked_up;
+                ttl = ttl.min(looked_up_ttl.as_secs() as u32);
+            } else {
+                ttl = ttl.min(additional_ttl);
+            }
+            // weight 0 is "rarely" rather than "never"
+            let weight = u32::from(weight).clamp(1, MAX_WEIGHT);
+            backends.extend(
+                ips.into_iter()
+                    .map(|ip| (SocketAddr::new(ip, port), weight)),
+            );
+        }
+        Ok((backends, Duration::from_secs(ttl.into())))
+    }
+
+    /// Ask the nameservers in turn until one answers, even if only to say
+    /// that `name` does not exist.
+    fn query(&self, name: &str, rtype: RecordType) -> io::Result<Response> {
+        let id = random::next_u64() as u16;
+        let query = dns::encode_query(id, name, rtype)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, format!("{name}: {e}")))?;
+        let mut last_error = io::Error::new(io::ErrorKind::NotFound, "no nameservers");
+        for &server in &self.nameservers {
+            match self.exchange(server, &query, id) {
+                Ok(response) if response.rcode == 0 || response.rcode == NXDOMAIN => {
+                    return Ok(response);
+                }
+                Ok(response) => {
+                    last_error = io::Error::other(format!(
+                        "{server}: response code {} for {name}",
+                        response.rcode
+                    ));
+                }
+                Err(e) => last_error = io::Error::new(e.kind(), format!("{server}: {e}")),
+            }
+        }
+        Err(last_error)
+    }
+
+    /// Send `query` over UDP, and again over TCP if the answer is truncated.
+    fn exchange(&self, server: SocketAddr, query: &[u8], id: u16) -> io::Result<Response> {
+        let local: IpAddr = match server {
+            SocketAddr::V4(_) => Ipv4Addr::UNSPECIFIED.into(),
+            SocketAddr::V6(_) => Ipv6Addr::UNSPECIFIED.into(),
+        };
+        let socket = UdpSocket::bind((local, 0))?;
+        socket.connect(server)?;
+        socket.send(query)?;
+        let deadline = Instant::now() + self.timeout;
+        let mut buf = [0; MAX_UDP_RESPONSE];
+        let response = loop {
+            let left = deadline.saturating_duration_since(Instant::now());
+            if left.is_zero() {
+                return Err(io::Error::new(io::ErrorKind::TimedOut, "no response"));
+            }
+            socket.set_read_timeout(Some(left))?;
+            let n = match socket.recv(&mut buf) {
+                Ok(n) => n,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => continue,
+                Err(e) => return Err(e),
+            };
+            // anything else is stale or spoofed
+            match dns::parse_response(&buf[..n]) {
+                Ok(response) if response.id == id => break response,
+                _ => continue,
+            }
+        };
+        if response.truncated {
+            return self.exchange_tcp(server, query, id);
+        }
+        Ok(response)
+    }
+
+    fn exchange_tcp(&self, server: SocketAddr, query: &[u8], id: u16) -> io::Result<Response> {
+        let mut stream = TcpStream::connect_timeout(&server, self.timeout)?;
+        stream.set_read_timeout(Some(self.timeout))?;
+        stream.set_write_timeout(Some(self.timeout))?;
+        // messages over TCP are prefixed by their length
+        let mut message = (query.len() as u16).to_be_bytes().to_vec();
+        message.extend_from_slice(query);
+        stream.write_all(&message)?;
+        let mut len = [0; 2];
+        stream.read_exact(&mut len)?;
+        let mut buf = vec![0; u16::from_be_bytes(len).into()];
+        stream.read_exact(&mut buf)?;
+        let response =
+            dns::parse_response(&buf).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        if response.id != id {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "response to another query",
+            ));
+        }
+        Ok(response)
+    }
+}
+
+/// The records of `name` other than CNAMEs, after following its CNAMEs, and
+/// the smallest TTL along the way.
+fn records_of<'a>(records: &'a [Record], name: &str) -> (Vec<&'a RecordData>, u32) {
+    let mut name = name;
+    let mut ttl = u32::MAX;
+    for _ in 0..MAX_CNAMES {
+        let cname = records.iter().find_map(|r| match &r.data {
+            RecordData::Cname(target) if r.name == name => Some((target.as_str(), r.ttl)),
+            _ => None,
+        });
+        let Some((target, cname_ttl)) = cname else {
+            break;
+        };
+        name = target;
+        ttl = ttl.min(cname_ttl);
+    }
+    let mut found = Vec::new();
+    for record in records.iter().filter(|r| r.name == name) {
+        if !matches!(record.data, RecordData::Cname(_)) {
+            ttl = ttl.min(record.ttl);
+            found.push(&record.data);
+        }
+    }
+    (found, ttl)
+}
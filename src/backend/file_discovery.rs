@@ -0,0 +1,140 @@
+//! Backends listed in a watched file
+//!
+//! The file maps pool names to backends, written like those of the config
+//! file, in JSON or, for a `.yaml` or `.yml` file, YAML:
+//!
+//! ```json
+//! { "default": ["10.0.0.5:8080", { "address": "10.0.0.6:8080", "weight": 3 }] }
+//! ```
+//!
+//! It is read at startup and again whenever it is written or replaced, by the
+//! `flax-discovery` thread. Each pool's listing is diffed into the pool as
+//! described in [`managed`](super::managed); a pool the file leaves out has
+//! no backends from it. A file that can't be read, doesn't parse or names an
+//! unknown pool changes nothing, and the last good listing stays in effect.
+
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use std::{fs, io, thread};
+
+use serde::Deserialize;
+use tracing::{error, info, warn};
+
+use crate::backend::managed::ManagedBackends;
+use crate::backend::{MAX_WEIGHT, get_pool, pools};
+use crate::util::address::Address;
+use crate::util::file_watch::FileWatch;
+
+#[derive(Debug, Clone)]
+pub struct FileDiscovery {
+    pub path: PathBuf,
+    /// How long backends gone from the file drain before they are removed
+    pub drain: Duration,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum FileEntry {
+    Address(Address),
+    Weighted { address: Address, weight: u32 },
+}
+
+/// Addresses and weights listed for each pool
+type Listing = HashMap<String, HashMap<Address, u32>>;
+
+/// Apply the file, then start the thread that watches it. Fails if the file
+/// can't be applied, as there is no good listing to fall back on yet.
+pub fn start(options: FileDiscovery) -> io::Result<()> {
+    // watching first, so a change made while reading is not missed
+    let watch = FileWatch::new(&options.path)?;
+    let mut managed = ManagedBackends::new("file", options.drain);
+    let listing = read(&options.path)?;
+    apply(&mut managed, &listing, Instant::now());
+    let path = options.path;
+    thread::Builder::new()
+        .name("flax-discovery".to_string())
+        .spawn(move || {
+            loop {
+                let timeout = managed
+                    .next_removal()
+                    .map(|at| at.saturating_duration_since(Instant::now()));
+                match watch.wait(timeout) {
+                    Ok(false) => {}
+                    Ok(true) => match read(&path) {
+                        Ok(listing) => apply(&mut managed, &listing, Instant::now()),
+                        Err(e) => warn!(
+                            path = %path.display(),
+                            "backend file rejected, keeping the last backends: {e}"
+                        ),
+                    },
+                    Err(e) => {
+                        error!(path = %path.display(), "watching the backend file failed: {e}");
+                        return;
+                    }
+                }
+                managed.remove_drained(Instant::now());
+            }
+        })?;
+    Ok(())
+}
+
+fn apply(managed: &mut ManagedBackends, listing: &Listing, now: Instant) {
+    let none = HashMap::new();
+    for (name, _) in pools() {
+        let wanted = listing.get(name).unwrap_or(&none);
+        let diff = managed.update(name, wanted, now);
+        if !diff.is_empty() {
+            info!(
+                pool = name,
+                added = ?diff.added,
+                draining = ?diff.draining,
+                reactivated = ?diff.reactivated,
+                reweighted = ?diff.reweighted,
+                "backend file applied"
+            );
+        }
+    }
+    managed.remove_drained(now);
+}
+
+/// Read and check the whole file.
+fn read(path: &Path) -> io::Result<Listing> {
+    let text = fs::read_to_string(path)?;
+    let yaml = matches!(
+        path.extension().and_then(|e| e.to_str()),
+        Some("yaml" | "yml")
+    );
+    let parsed: Result<BTreeMap<String, Vec<FileEntry>>, String> = if yaml {
+        serde_yaml::from_str(&text).map_err(|e| e.to_string())
+    } else {
+        serde_json::from_str(&text).map_err(|e| e.to_string())
+    };
+    let parsed = parsed.map_err(|e| invalid(format!("{}: {e}", path.display())))?;
+    let mut listing = Listing::new();
+    for (name, entries) in parsed {
+        if get_pool(&name).is_none() {
+            return Err(invalid(format!("unknown pool {name:?}")));
+        }
+        let backends = listing.entry(name.clone()).or_default();
+        for entry in entries {
+            let (address, weight) = match entry {
+                FileEntry::Address(address) => (address, 1),
+                FileEntry::Weighted { address, weight } => (address, weight),
+            };
+            if weight > MAX_WEIGHT {
+                return Err(invalid(format!(
+                    "pool {name:?}: weight of {address} exceeds {MAX_WEIGHT}"
+                )));
+            }
+            if backends.insert(address, weight).is_some() {
+                return Err(invalid(format!("pool {name:?}: {address} is listed twice")));
+            }
+        }
+    }
+    Ok(listing)
+}
+
+fn invalid(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
@@ -0,0 +1,137 @@
+//! Backends kept in a pool on behalf of a discovery source
+//!
+//! DNS and file discovery each say which addresses a pool should have; this
+//! turns that into changes to the pool. Sources only touch the backends they
+//! added themselves: an address the pool already holds from the config file,
+//! the admin API or another source is left alone. Backends a source no longer
+//! wants drain first and are removed once the drain period is over, unless
+//! they were reactivated through the admin API meanwhile.
+
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use tracing::info;
+
+use crate::backend::{Backend, BackendState, get_pool};
+use crate::util::address::Address;
+
+/// What one update changed in a pool.
+#[derive(Debug, Default)]
+pub struct Diff {
+    pub added: Vec<Address>,
+    /// Wanted again while draining
+    pub reactivated: Vec<Address>,
+    pub reweighted: Vec<Address>,
+    pub draining: Vec<Address>,
+}
+
+impl Diff {
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty()
+            && self.reactivated.is_empty()
+            && self.reweighted.is_empty()
+            && self.draining.is_empty()
+    }
+}
+
+/// Backends a source added to one pool.
+#[derive(Default)]
+struct Owned {
+    /// Weight each was last given
+    weights: HashMap<Address, u32>,
+    /// Those draining, and when they are removed
+    draining: HashMap<Address, Instant>,
+}
+
+pub struct ManagedBackends {
+    /// Named in logs
+    source: &'static str,
+    drain: Duration,
+    pools: HashMap<String, Owned>,
+}
+
+impl ManagedBackends {
+    pub fn new(source: &'static str, drain: Duration) -> Self {
+        Self {
+            source,
+            drain,
+            pools: HashMap::new(),
+        }
+    }
+
+    /// Make the source's backends in pool `name` those in `wanted`, with
+    /// their weights.
+    pub fn update(&mut self, name: &str, wanted: &HashMap<Address, u32>, now: Instant) -> Diff {
+        let mut diff = Diff::default();
+        let Some(pool) = get_pool(name) else {
+            return diff;
+        };
+        let owned = self.pools.entry(name.to_string()).or_default();
+        let present = pool.list_backends();
+        // taken out through the admin API since; added afresh if still wanted
+        owned.weights.retain(|addr, _| present.contains(addr));
+        owned.draining.retain(|addr, _| present.contains(addr));
+        for (&addr, &weight) in wanted {
+            match owned.weights.get(&addr) {
+                // configured, added through the admin API or by another source
+                None if present.contains(&addr) => {}
+                None => {
+                    pool.add_backend(Backend::with_weight(addr, weight));
+                    owned.weights.insert(addr, weight);
+                    diff.added.push(addr);
+                }
+                Some(&last) => {
+                    if owned.draining.remove(&addr).is_some() {
+                        pool.set_state(addr, BackendState::Active);
+                        diff.reactivated.push(addr);
+                    }
+                    if last != weight {
+                        pool.set_weight(addr, weight);
+                        owned.weights.insert(addr, weight);
+                        diff.reweighted.push(addr);
+                    }
+                }
+            }
+        }
+        for &addr in owned.weights.keys() {
+            if !wanted.contains_key(&addr) && !owned.draining.contains_key(&addr) {
+                pool.set_state(addr, BackendState::Draining);
+                owned.draining.insert(addr, now + self.drain);
+                diff.draining.push(addr);
+            }
+        }
+        diff
+    }
+
+    /// Remove the backends whose drain period is over.
+    pub fn remove_drained(&mut self, now: Instant) {
+        for (name, owned) in &mut self.pools {
+            let Some(pool) = get_pool(name) else {
+                continue;
+            };
+            let status = pool.status();
+            owned.draining.retain(|&addr, &mut until| {
+                if until > now {
+                    return true;
+                }
+                let draining = status
+                    .iter()
+                    .any(|s| s.backend.address == addr && s.state == BackendState::Draining);
+                if draining {
+                    pool.remove_backend(addr);
+                    info!(pool = name, backend = %addr, source = self.source, "drained backend removed");
+                }
+                owned.weights.remove(&addr);
+                false
+            });
+        }
+    }
+
+    /// When the next draining backend is due for removal.
+    pub fn next_removal(&self) -> Option<Instant> {
+        self.pools
+            .values()
+            .flat_map(|o| o.draining.values().copied())
+            .min()
+    }
+}
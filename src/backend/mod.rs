@@ -1,6 +1,13 @@
 pub mod connection_cache;
+pub mod dns;
+pub mod file_discovery;
+pub mod managed;
 pub mod pool;
 
-pub use pool::{Backend, BackendPool, get_backend_pool, init_backend_pool, select_backend};
+pub use pool::{
+    Backend, BackendPool, BackendState, BackendStatus, DEFAULT_POOL, Health, MAX_WEIGHT,
+    get_backend_pool, get_pool, in_any_pool, init_backend_pool, init_backend_pools, pools,
+    removal_epoch, select_backend,
+};
 
-pub use connection_cache::BackendConnectionCache;
+pub use connection_cache::{BackendConnectionCache, CachedConnection};
@@ -1,84 +1,419 @@
-use std::net::SocketAddr;
-use std::sync::atomic::{AtomicUsize, Ordering};
+use std::collections::HashMap;
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::sync::atomic::{AtomicU32, AtomicU64, AtomicUsize, Ordering};
 use std::sync::{OnceLock, RwLock};
+use std::time::{Duration, Instant};
+
+use crate::protocol::ProxyVersion;
+use crate::tls::UpstreamTls;
+use crate::util::address::Address;
+
+/// Name of the pool used when none is configured explicitly.
+pub const DEFAULT_POOL: &str = "default";
+/// Upper bound for backend weights; keeps the selection schedule small.
+pub const MAX_WEIGHT: u32 = 100;
+
+/// Consecutive connect failures before a backend is taken out of rotation.
+const FAILURE_THRESHOLD: u32 = 3;
+/// How long an unhealthy backend is skipped before it is tried again.
+const RETRY_AFTER: Duration = Duration::from_secs(10);
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct Backend {
-    pub address: SocketAddr,
+    pub address: Address,
+    pub weight: u32,
 }
 
 impl Backend {
-    pub fn new(address: SocketAddr) -> Self {
-        Self { address }
+    pub fn new(address: Address) -> Self {
+        Self { address, weight: 1 }
+    }
+
+    pub fn with_weight(address: Address, weight: u32) -> Self {
+        Self { address, weight }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BackendState {
+    /// Receives new requests
+    Active,
+    /// Finishes in-flight requests but gets no new ones
+    Draining,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Health {
+    Healthy,
+    /// Failed too many connects in a row; retried after a cooldown
+    Unhealthy,
+}
+
+/// Point-in-time view of one backend.
+#[derive(Debug, Clone)]
+pub struct BackendStatus {
+    pub backend: Backend,
+    pub state: BackendState,
+    pub health: Health,
+    pub consecutive_failures: u32,
+    /// Times picked by `select` since it was added
+    pub selected_total: u64,
+    /// Failed connects since it was added
+    pub failures_total: u64,
+}
+
+#[derive(Debug)]
+struct Entry {
+    backend: Backend,
+    state: BackendState,
+    failures: AtomicU32,
+    selected_total: AtomicU64,
+    failures_total: AtomicU64,
+    /// Milliseconds since pool creation after which an unhealthy entry is retried
+    retry_at_ms: AtomicU64,
+}
+
+impl Entry {
+    fn new(backend: Backend) -> Self {
+        Self {
+            backend,
+            state: BackendState::Active,
+            failures: AtomicU32::new(0),
+            selected_total: AtomicU64::new(0),
+            failures_total: AtomicU64::new(0),
+            retry_at_ms: AtomicU64::new(0),
+        }
+    }
+
+    fn health(&self, now_ms: u64) -> Health {
+        if self.failures.load(Ordering::Relaxed) < FAILURE_THRESHOLD
+            || now_ms >= self.retry_at_ms.load(Ordering::Relaxed)
+        {
+            Health::Healthy
+        } else {
+            Health::Unhealthy
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct Members {
+    entries: Vec<Entry>,
+    /// Indices into `entries`, interleaved by weight (smooth weighted round-robin)
+    schedule: Vec<usize>,
+}
+
+impl Members {
+    fn position(&self, address: Address) -> Option<usize> {
+        self.entries.iter().position(|e| e.backend.address == address)
+    }
+
+    fn rebuild_schedule(&mut self) {
+        let eligible: Vec<(usize, i64)> = self
+            .entries
+            .iter()
+            .enumerate()
+            .filter(|(_, e)| e.state == BackendState::Active && e.backend.weight > 0)
+            .map(|(i, e)| (i, e.backend.weight.min(MAX_WEIGHT) as i64))
+            .collect();
+        let total: i64 = eligible.iter().map(|&(_, w)| w).sum();
+
+        let mut current = vec![0i64; eligible.len()];
+        let mut schedule = Vec::with_capacity(total as usize);
+        for _ in 0..total {
+            let mut best = 0;
+            for (slot, &(_, weight)) in eligible.iter().enumerate() {
+                current[slot] += weight;
+                if current[slot] > current[best] {
+                    best = slot;
+                }
+            }
+            current[best] -= total;
+            schedule.push(eligible[best].0);
+        }
+        self.schedule = schedule;
     }
 }
 
 #[derive(Debug)]
 pub struct BackendPool {
-    backends: RwLock<Vec<Backend>>,
+    members: RwLock<Members>,
     counter: AtomicUsize,
+    epoch: Instant,
+    /// Set when the backends are reached over TLS
+    tls: Option<UpstreamTls>,
+    /// Set when backend connections start with a PROXY protocol header
+    proxy_protocol: Option<ProxyVersion>,
 }
 
 impl BackendPool {
     pub fn new(backends: Vec<Backend>) -> Self {
+        let mut members = Members {
+            entries: backends.into_iter().map(Entry::new).collect(),
+            schedule: Vec::new(),
+        };
+        members.rebuild_schedule();
         Self {
-            backends: RwLock::new(backends),
+            members: RwLock::new(members),
             counter: AtomicUsize::new(0),
+            epoch: Instant::now(),
+            tls: None,
+            proxy_protocol: None,
         }
     }
 
-    pub fn select(&self) -> Option<SocketAddr> {
-        let backends = self.backends.read().unwrap();
-        if backends.is_empty() {
+    pub fn with_tls(mut self, tls: UpstreamTls) -> Self {
+        self.tls = Some(tls);
+        self
+    }
+
+    pub fn tls(&self) -> Option<&UpstreamTls> {
+        self.tls.as_ref()
+    }
+
+    pub fn with_proxy_protocol(mut self, version: ProxyVersion) -> Self {
+        self.proxy_protocol = Some(version);
+        self
+    }
+
+    pub fn proxy_protocol(&self) -> Option<ProxyVersion> {
+        self.proxy_protocol
+    }
+
+    fn now_ms(&self) -> u64 {
+        self.epoch.elapsed().as_millis() as u64
+    }
+
+    /// Pick the next backend by weight, skipping unhealthy ones.
+    ///
+    /// If every active backend is unhealthy one is returned anyway, so a pool
+    /// recovers on its own instead of refusing all traffic.
+    pub fn select(&self) -> Option<Address> {
+        let members = self.members.read().unwrap();
+        let len = members.schedule.len();
+        if len == 0 {
             return None;
         }
-        let idx = self.counter.fetch_add(1, Ordering::Relaxed) % backends.len();
-        Some(backends[idx].address)
+        let now = self.now_ms();
+        let start = self.counter.fetch_add(1, Ordering::Relaxed);
+        for i in 0..len {
+            let entry = &members.entries[members.schedule[(start + i) % len]];
+            if entry.health(now) == Health::Healthy {
+                entry.selected_total.fetch_add(1, Ordering::Relaxed);
+                return Some(entry.backend.address);
+            }
+        }
+        let entry = &members.entries[members.schedule[start % len]];
+        entry.selected_total.fetch_add(1, Ordering::Relaxed);
+        Some(entry.backend.address)
     }
 
-    pub fn add_backend(&self, backend: Backend) {
-        let mut backends = self.backends.write().unwrap();
-        backends.push(backend);
+    /// Pick the backend `key` maps to, by weighted rendezvous hashing over the
+    /// backends in rotation and skipping unhealthy ones as `select` does.
+    ///
+    /// A key keeps its backend for as long as that backend stays in rotation;
+    /// when backends come or go, only the keys mapping to them move.
+    pub fn select_by_hash(&self, key: u64) -> Option<Address> {
+        let members = self.members.read().unwrap();
+        let now = self.now_ms();
+        let best = |healthy_only: bool| {
+            members
+                .entries
+                .iter()
+                .filter(|e| e.state == BackendState::Active && e.backend.weight > 0)
+                .filter(|e| !healthy_only || e.health(now) == Health::Healthy)
+                .max_by(|a, b| {
+                    rendezvous_score(key, &a.backend).total_cmp(&rendezvous_score(key, &b.backend))
+                })
+        };
+        let entry = best(true).or_else(|| best(false))?;
+        entry.selected_total.fetch_add(1, Ordering::Relaxed);
+        Some(entry.backend.address)
     }
 
-    pub fn remove_backend(&self, address: SocketAddr) -> bool {
-        let mut backends = self.backends.write().unwrap();
-        if let Some(pos) = backends.iter().position(|b| b.address == address) {
-            backends.remove(pos);
+    /// Add a backend; returns false if the address is already in the pool.
+    pub fn add_backend(&self, backend: Backend) -> bool {
+        let mut members = self.members.write().unwrap();
+        if members.position(backend.address).is_some() {
+            return false;
+        }
+        members.entries.push(Entry::new(backend));
+        members.rebuild_schedule();
+        true
+    }
+
+    pub fn remove_backend(&self, address: Address) -> bool {
+        let mut members = self.members.write().unwrap();
+        if let Some(pos) = members.position(address) {
+            members.entries.remove(pos);
+            members.rebuild_schedule();
+            REMOVALS.fetch_add(1, Ordering::Relaxed);
             true
         } else {
             false
         }
     }
 
-    pub fn list_backends(&self) -> Vec<SocketAddr> {
-        let backends = self.backends.read().unwrap();
-        backends.iter().map(|b| b.address).collect()
+    pub fn set_weight(&self, address: Address, weight: u32) -> bool {
+        let mut members = self.members.write().unwrap();
+        let Some(pos) = members.position(address) else {
+            return false;
+        };
+        members.entries[pos].backend.weight = weight;
+        members.rebuild_schedule();
+        true
+    }
+
+    pub fn set_state(&self, address: Address, state: BackendState) -> bool {
+        let mut members = self.members.write().unwrap();
+        let Some(pos) = members.position(address) else {
+            return false;
+        };
+        members.entries[pos].state = state;
+        members.rebuild_schedule();
+        true
+    }
+
+    /// Record a failed connect; enough of them in a row mark the backend unhealthy.
+    pub fn report_failure(&self, address: Address) {
+        let members = self.members.read().unwrap();
+        if let Some(pos) = members.position(address) {
+            let entry = &members.entries[pos];
+            entry.failures_total.fetch_add(1, Ordering::Relaxed);
+            let failures = entry.failures.fetch_add(1, Ordering::Relaxed) + 1;
+            if failures >= FAILURE_THRESHOLD {
+                let retry_at = self.now_ms() + RETRY_AFTER.as_millis() as u64;
+                entry.retry_at_ms.store(retry_at, Ordering::Relaxed);
+            }
+        }
+    }
+
+    pub fn report_success(&self, address: Address) {
+        let members = self.members.read().unwrap();
+        if let Some(pos) = members.position(address) {
+            members.entries[pos].failures.store(0, Ordering::Relaxed);
+        }
+    }
+
+    pub fn list_backends(&self) -> Vec<Address> {
+        let members = self.members.read().unwrap();
+        members.entries.iter().map(|e| e.backend.address).collect()
+    }
+
+    pub fn status(&self) -> Vec<BackendStatus> {
+        let members = self.members.read().unwrap();
+        let now = self.now_ms();
+        members
+            .entries
+            .iter()
+            .map(|e| BackendStatus {
+                backend: e.backend,
+                state: e.state,
+                health: e.health(now),
+                consecutive_failures: e.failures.load(Ordering::Relaxed),
+                selected_total: e.selected_total.load(Ordering::Relaxed),
+                failures_total: e.failures_total.load(Ordering::Relaxed),
+            })
+            .collect()
+    }
+
+    pub fn contains(&self, address: Address) -> bool {
+        self.members.read().unwrap().position(address).is_some()
     }
 
     pub fn count(&self) -> usize {
-        self.backends.read().unwrap().len()
+        self.members.read().unwrap().entries.len()
     }
 
     pub fn clear(&self) {
-        let mut backends = self.backends.write().unwrap();
-        backends.clear();
+        let mut members = self.members.write().unwrap();
+        members.entries.clear();
+        members.schedule.clear();
+        REMOVALS.fetch_add(1, Ordering::Relaxed);
     }
 }
 
-static POOL: OnceLock<BackendPool> = OnceLock::new();
+/// Score of `backend` for `key`; the highest wins. The log of a uniform draw
+/// makes a backend's share of keys proportional to its weight.
+fn rendezvous_score(key: u64, backend: &Backend) -> f64 {
+    let mut hasher = DefaultHasher::new();
+    (key, backend.address).hash(&mut hasher);
+    // uniform in (0, 1)
+    let unit = ((hasher.finish() >> 11) as f64 + 0.5) / (1u64 << 53) as f64;
+    -(backend.weight.min(MAX_WEIGHT) as f64) / unit.ln()
+}
+
+static POOLS: OnceLock<Vec<(String, BackendPool)>> = OnceLock::new();
+
+/// Bumped whenever backends leave a pool, so that the workers know to close
+/// the connections they cached to them
+static REMOVALS: AtomicU64 = AtomicU64::new(0);
 
+/// Initialize the named backend pools, with TLS for those named in `tls` and
+/// PROXY protocol headers for those named in `proxy_protocol`. Pools are
+/// fixed for the life of the process; their members can change at runtime.
+pub fn init_backend_pools(
+    pools: Vec<(String, Vec<Backend>)>,
+    mut tls: HashMap<String, UpstreamTls>,
+    proxy_protocol: HashMap<String, ProxyVersion>,
+) {
+    let pools = pools
+        .into_iter()
+        .map(|(name, backends)| {
+            let pool = BackendPool::new(backends);
+            let pool = match tls.remove(&name) {
+                Some(tls) => pool.with_tls(tls),
+                None => pool,
+            };
+            let pool = match proxy_protocol.get(&name) {
+                Some(&version) => pool.with_proxy_protocol(version),
+                None => pool,
+            };
+            (name, pool)
+        })
+        .collect();
+    POOLS.set(pools).expect("Backend pools already initialized");
+}
+
+/// Initialize a single pool named `default`.
 pub fn init_backend_pool(backends: Vec<Backend>) {
-    POOL.set(BackendPool::new(backends))
-        .expect("Backend pool already initialized");
+    init_backend_pools(
+        vec![(DEFAULT_POOL.to_string(), backends)],
+        HashMap::new(),
+        HashMap::new(),
+    );
+}
+
+fn all_pools() -> &'static [(String, BackendPool)] {
+    POOLS
+        .get()
+        .expect("Backend pools not initialized - call init_backend_pools first")
+}
+
+pub fn get_pool(name: &str) -> Option<&'static BackendPool> {
+    all_pools().iter().find(|(n, _)| n == name).map(|(_, p)| p)
+}
+
+pub fn pools() -> impl Iterator<Item = (&'static str, &'static BackendPool)> {
+    all_pools().iter().map(|(n, p)| (n.as_str(), p))
+}
+
+/// Changes whenever backends have left a pool.
+pub fn removal_epoch() -> u64 {
+    REMOVALS.load(Ordering::Relaxed)
+}
+
+/// Whether any pool has a backend at `address`.
+pub fn in_any_pool(address: Address) -> bool {
+    POOLS
+        .get()
+        .is_some_and(|pools| pools.iter().any(|(_, pool)| pool.contains(address)))
 }
 
 pub fn get_backend_pool() -> &'static BackendPool {
-    POOL.get().expect("Backend pool not initialized - call init_backend_pool first")
+    get_pool(DEFAULT_POOL).expect("no default backend pool configured")
 }
 
-pub fn select_backend() -> SocketAddr {
-    get_backend_pool()
-        .select()
-        .unwrap_or_else(|| "127.0.0.1:8081".parse().unwrap())
+pub fn select_backend() -> Option<Address> {
+    get_backend_pool().select()
 }
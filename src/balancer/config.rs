@@ -1,4 +1,93 @@
+use std::io;
+use std::os::fd::RawFd;
+use std::sync::Arc;
+use std::time::Duration;
+
+use rustls::ServerConfig;
+use serde::Deserialize;
+
+use crate::backend::DEFAULT_POOL;
+use crate::balancer::router::RouteKey;
 use crate::core::constants;
+use crate::tls::SniHostMismatch;
+use crate::tls::client_auth::ClientMatcher;
+use crate::trace::TraceOptions;
+use crate::util::cidr::Cidr;
+
+/// How the worker's io_uring instance submits work to the kernel.
+///
+/// SQPOLL and DEFER_TASKRUN are mutually exclusive: deferred task running
+/// needs the submitting thread to reap completions itself, while SQPOLL hands
+/// submission to a kernel thread.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RingMode {
+    /// Single issuer with deferred task running (the default).
+    DeferTaskrun,
+    /// Kernel-side submission queue polling.
+    Sqpoll(SqpollConfig),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SqpollConfig {
+    /// Milliseconds the poll thread spins without work before it goes to sleep
+    pub idle_ms: u32,
+    /// CPU the poll thread is pinned to
+    pub cpu: Option<u32>,
+    /// Share one poll thread across all workers instead of one per ring
+    pub shared: bool,
+    /// Ring whose poll thread and async workqueue are shared (IORING_SETUP_ATTACH_WQ)
+    pub attach_wq_fd: Option<RawFd>,
+}
+
+/// What the connections of a listener carry.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ListenerMode {
+    /// HTTP/1.x requests, each parsed, routed and forwarded on its own
+    #[default]
+    Http,
+    /// Opaque byte streams relayed to a backend picked at accept, with
+    /// half-closes passed on in both directions
+    Tcp,
+    /// Datagrams relayed per client flow to a backend picked for the flow's
+    /// first one, replies going back to the client they answer
+    Udp,
+}
+
+/// How a UDP listener keeps its client flows.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UdpOptions {
+    /// A flow without datagrams either way for this long is closed
+    pub idle_timeout: Duration,
+    /// A flow is closed this long after it started even while in use, so
+    /// long-lived senders get balanced again
+    pub session_timeout: Option<Duration>,
+    /// How the backend of a new flow is picked
+    pub affinity: FlowAffinity,
+}
+
+impl Default for UdpOptions {
+    fn default() -> Self {
+        Self {
+            idle_timeout: constants::UDP_IDLE_TIMEOUT,
+            session_timeout: None,
+            affinity: FlowAffinity::None,
+        }
+    }
+}
+
+/// Which backend a client's new flow goes to.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FlowAffinity {
+    /// The next one by weight, like a new TCP connection
+    #[default]
+    None,
+    /// The one the client's IP address hashes to, for all its ports
+    ClientIp,
+    /// The one the client's address and port hash to
+    ClientAddress,
+}
 
 #[derive(Debug, Clone)]
 pub struct WorkerConfig {
@@ -12,7 +101,37 @@ pub struct WorkerConfig {
     pub header_buffer_capacity: usize,
     /// Initial capacity for connection pool
     pub pool_capacity: usize,
-    pub sqpoll_cpu: u32,
+    /// Submission mode of the worker ring
+    pub ring_mode: RingMode,
+    /// How long in-flight requests may run after shutdown is requested
+    pub drain_timeout: Duration,
+    /// Whether the listener speaks HTTP or relays TCP
+    pub mode: ListenerMode,
+    /// Backend pool requests are routed to
+    pub pool: String,
+    /// Virtual host names and the pools their requests go to instead
+    pub hosts: Vec<(String, String)>,
+    /// Name virtual hosts are looked up by
+    pub route_by: RouteKey,
+    /// Policy for requests whose `Host` differs from the connection's SNI
+    pub sni_host_mismatch: SniHostMismatch,
+    /// Client certificates and the pools their requests go to, in order
+    pub client_routes: Vec<(ClientMatcher, String)>,
+    /// The TLS listener asks for client certificates; their identity is
+    /// forwarded to backends
+    pub client_auth: bool,
+    /// Client certificates allowed to send requests; any when empty
+    pub client_allow: ClientMatcher,
+    /// Networks whose connections start with a PROXY protocol header
+    pub trusted_proxies: Vec<Cidr>,
+    /// Flow handling of UDP listeners
+    pub udp: UdpOptions,
+    /// Index used to label this worker's metrics
+    pub worker_id: usize,
+    /// Request ID and trace context handling
+    pub trace: TraceOptions,
+    /// Terminate TLS on accepted connections
+    pub tls: Option<Arc<ServerConfig>>,
 }
 
 impl Default for WorkerConfig {
@@ -23,20 +142,77 @@ impl Default for WorkerConfig {
             io_buffer_capacity: constants::IO_BUFFER_CAPACITY,
             header_buffer_capacity: constants::HEADER_BUFFER_CAPACITY,
             pool_capacity: 4096,
-            sqpoll_cpu: 0,
+            ring_mode: RingMode::DeferTaskrun,
+            drain_timeout: constants::DRAIN_TIMEOUT,
+            mode: ListenerMode::Http,
+            pool: DEFAULT_POOL.to_string(),
+            hosts: Vec::new(),
+            route_by: RouteKey::Host,
+            sni_host_mismatch: SniHostMismatch::Allow,
+            client_routes: Vec::new(),
+            client_auth: false,
+            client_allow: ClientMatcher::default(),
+            trusted_proxies: Vec::new(),
+            udp: UdpOptions::default(),
+            worker_id: 0,
+            trace: TraceOptions::default(),
+            tls: None,
         }
     }
 }
 
 impl WorkerConfig {
-    pub fn get(ring_size: u32, pool_capacity: usize, sqpoll_cpu: u32) -> Self {
+    pub fn get(ring_size: u32, pool_capacity: usize, ring_mode: RingMode) -> Self {
         Self {
             initial_accepts: constants::INITIAL_ACCEPTS_PER_WORKER,
             ring_size,
             io_buffer_capacity: constants::IO_BUFFER_CAPACITY,
             header_buffer_capacity: constants::HEADER_BUFFER_CAPACITY,
             pool_capacity,
-            sqpoll_cpu,
+            ring_mode,
+            drain_timeout: constants::DRAIN_TIMEOUT,
+            mode: ListenerMode::Http,
+            pool: DEFAULT_POOL.to_string(),
+            hosts: Vec::new(),
+            route_by: RouteKey::Host,
+            sni_host_mismatch: SniHostMismatch::Allow,
+            client_routes: Vec::new(),
+            client_auth: false,
+            client_allow: ClientMatcher::default(),
+            trusted_proxies: Vec::new(),
+            udp: UdpOptions::default(),
+            worker_id: 0,
+            trace: TraceOptions::default(),
+            tls: None,
         }
     }
+
+    /// Reject configurations the kernel would refuse or silently misbehave with.
+    pub fn validate(&self) -> io::Result<()> {
+        if self.ring_size == 0 {
+            return Err(invalid("ring_size must be greater than zero"));
+        }
+        if self.initial_accepts == 0 || self.initial_accepts > self.ring_size as usize {
+            return Err(invalid("initial_accepts must be between 1 and ring_size"));
+        }
+
+        if let RingMode::Sqpoll(sq) = self.ring_mode {
+            if sq.idle_ms == 0 {
+                return Err(invalid("sqpoll idle_ms must be greater than zero"));
+            }
+            if let Some(cpu) = sq.cpu {
+                let online = unsafe { libc::sysconf(libc::_SC_NPROCESSORS_ONLN) };
+                if online > 0 && cpu as i64 >= online {
+                    return Err(invalid(&format!(
+                        "sqpoll cpu {cpu} does not exist ({online} cpus online)"
+                    )));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, msg.to_string())
 }
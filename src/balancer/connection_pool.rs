@@ -1,17 +1,45 @@
-use std::collections::VecDeque;
 use std::os::fd::RawFd;
 
 use crate::core::connection_pair::ConnectionPair;
+use crate::core::stream_pump::{Direction, Operation};
+use crate::metrics::worker_metrics;
 use crate::protocol::HttpBuf;
-use crate::util::fd::close_fd_quiet;
+use crate::tls::{TlsSession, ktls};
+use crate::util::fd::shutdown_and_close;
+
+/// Bits of a pair id holding the slot index; the generation sits above them.
+const SLOT_BITS: u32 = 32;
+const SLOT_MASK: usize = (1 << SLOT_BITS) - 1;
+/// Generations wrap within the 56 bits user_data has for the id.
+const GENERATION_MASK: u32 = (1 << 24) - 1;
+
+/// Which connection of a pair a TLS session belongs to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Side {
+    Client,
+    Backend,
+}
 
 /// Connection pool using slab allocation with a freelist
 ///
 /// This manages a pool of ConnectionPair objects, reusing slots
 /// to avoid allocations in the hot path.
+///
+/// Pair ids combine the slot index with a per-slot generation that changes
+/// whenever the slot is released. Completions still in flight for a pair
+/// that has been torn down carry the old id, so they no longer match and
+/// can't be mistaken for events of the slot's next occupant.
+///
+/// Closing a socket does not stop a recv or send the ring already holds on
+/// it, so a torn-down TLS session with one outstanding is kept in
+/// `lingering` until the completion arrives and its buffers are unused.
 pub struct ConnectionPool {
     pairs: Vec<Option<ConnectionPair>>,
+    generations: Vec<u32>,
     freelist: Vec<usize>,
+    lingering: Vec<(usize, Side, Box<TlsSession>)>,
+    /// Occupied slots, kept up to date so draining needn't scan `pairs`
+    occupied: usize,
     io_buffer_capacity: usize,
     header_buffer_capacity: usize,
 }
@@ -24,24 +52,29 @@ impl ConnectionPool {
     ) -> Self {
         Self {
             pairs: Vec::with_capacity(initial_capacity),
+            generations: Vec::with_capacity(initial_capacity),
             freelist: Vec::new(),
+            lingering: Vec::new(),
+            occupied: 0,
             io_buffer_capacity,
             header_buffer_capacity,
         }
     }
 
     pub fn alloc(&mut self) -> usize {
-        if let Some(id) = self.freelist.pop() {
-            return id;
-        }
-        let id = self.pairs.len();
-        self.pairs.push(None);
-        id
+        let slot = self.freelist.pop().unwrap_or_else(|| {
+            self.pairs.push(None);
+            self.generations.push(0);
+            self.pairs.len() - 1
+        });
+        ((self.generations[slot] as usize) << SLOT_BITS) | slot
     }
 
     pub fn ensure_slot(&mut self, id: usize, client_fd: RawFd) {
-        if id >= self.pairs.len() {
-            self.pairs.resize_with(id + 1, || None);
+        let slot = id & SLOT_MASK;
+        if slot >= self.pairs.len() {
+            self.pairs.resize_with(slot + 1, || None);
+            self.generations.resize(slot + 1, 0);
         }
         let mut p = ConnectionPair::new_with_client(
             id,
@@ -50,31 +83,106 @@ impl ConnectionPool {
             self.header_buffer_capacity,
         );
         p.header_buffer = HttpBuf::with_capacity(self.header_buffer_capacity);
-        self.pairs[id] = Some(p);
+        if self.pairs[slot].replace(p).is_none() {
+            self.occupied += 1;
+        }
     }
 
     pub fn get_mut(&mut self, id: usize) -> Option<&mut ConnectionPair> {
-        self.pairs.get_mut(id).and_then(|p| p.as_mut())
+        self.pairs
+            .get_mut(id & SLOT_MASK)
+            .and_then(|p| p.as_mut())
+            .filter(|p| p.id == id)
+    }
+
+    /// Remove the pair with this id, if it is still the slot's occupant.
+    fn take(&mut self, id: usize) -> Option<ConnectionPair> {
+        let slot = id & SLOT_MASK;
+        let entry = self.pairs.get_mut(slot)?;
+        if entry.as_ref().is_none_or(|p| p.id != id) {
+            return None;
+        }
+        let pair = entry.take();
+        self.occupied -= 1;
+        self.generations[slot] = (self.generations[slot] + 1) & GENERATION_MASK;
+        self.freelist.push(slot);
+        pair
     }
 
     pub fn teardown(&mut self, id: usize) {
-        if let Some(p) = self.pairs.get_mut(id).and_then(|p| p.take()) {
+        if let Some(mut p) = self.take(id) {
+            if let Some(log) = &p.access {
+                // request cut short by an error or the client going away
+                log.finish();
+            }
             if p.client_fd >= 0 {
-                close_fd_quiet(p.client_fd);
+                self.close_client(&mut p);
             }
             if p.backend_fd >= 0 {
-                close_fd_quiet(p.backend_fd);
+                self.close_backend(&mut p);
             }
-            self.freelist.push(id);
         }
     }
 
+    /// Release the slot of a pair whose backend connection was already
+    /// handed back to the cache, closing only the client side.
     pub fn recycle_slot_only(&mut self, id: usize) {
-        if let Some(slot) = self.pairs.get_mut(id)
-            && let Some(p) = slot.take()
+        if let Some(mut p) = self.take(id)
             && p.client_fd >= 0
         {
-            close_fd_quiet(p.client_fd);
+            self.close_client(&mut p);
+        }
+    }
+
+    fn close_client(&mut self, pair: &mut ConnectionPair) {
+        if let Some(mut tls) = pair.tls.take() {
+            tls.close(pair.client_fd);
+            if tls.io_in_flight() {
+                self.lingering.push((pair.id, Side::Client, tls));
+            }
+        } else if pair.ktls.is_some() {
+            ktls::close_notify(pair.client_fd);
+        }
+        shutdown_and_close(pair.client_fd);
+        worker_metrics().connections_closed.inc();
+    }
+
+    fn close_backend(&mut self, pair: &mut ConnectionPair) {
+        if let Some(mut tls) = pair.backend_tls.take() {
+            tls.close(pair.backend_fd);
+            if tls.io_in_flight() {
+                self.lingering.push((pair.id, Side::Backend, tls));
+            }
+        }
+        shutdown_and_close(pair.backend_fd);
+    }
+
+    /// Account for a completion that arrived after its pair was torn down.
+    pub fn completed_after_teardown(&mut self, id: usize, op: Operation) {
+        let (side, recv) = match op {
+            Operation::RecvHeaders | Operation::Recv(Direction::ClientToBackend) => {
+                (Side::Client, true)
+            }
+            Operation::Send(Direction::BackendToClient) => (Side::Client, false),
+            Operation::Recv(Direction::BackendToClient) => (Side::Backend, true),
+            Operation::Send(Direction::ClientToBackend) => (Side::Backend, false),
+            _ => return,
+        };
+        let Some(index) = self
+            .lingering
+            .iter()
+            .position(|(i, s, _)| *i == id && *s == side)
+        else {
+            return;
+        };
+        let tls = &mut self.lingering[index].2;
+        if recv {
+            tls.recv_in_flight = false;
+        } else {
+            tls.send_in_flight = false;
+        }
+        if !tls.io_in_flight() {
+            self.lingering.swap_remove(index);
         }
     }
 
@@ -82,6 +190,11 @@ impl ConnectionPool {
         &mut self.pairs
     }
 
+    /// Number of occupied slots, including slots waiting on an accept.
+    pub fn active_count(&self) -> usize {
+        self.occupied
+    }
+
     pub fn len(&self) -> usize {
         self.pairs.len()
     }
@@ -90,3 +203,32 @@ impl ConnectionPool {
         self.pairs.is_empty()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn active_count() {
+        let mut pool = ConnectionPool::new(4, 64, 64);
+        let ids: Vec<usize> = (0..3)
+            .map(|_| {
+                let id = pool.alloc();
+                pool.ensure_slot(id, -1);
+                id
+            })
+            .collect();
+        assert_eq!(pool.active_count(), 3);
+
+        pool.teardown(ids[1]);
+        // a stale id no longer names the slot's occupant
+        pool.teardown(ids[1]);
+        pool.recycle_slot_only(ids[0]);
+        assert_eq!(pool.active_count(), 1);
+
+        let reused = pool.alloc();
+        pool.ensure_slot(reused, -1);
+        assert_ne!(reused, ids[1]);
+        assert_eq!(pool.active_count(), 2);
+    }
+}
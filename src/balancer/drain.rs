@@ -0,0 +1,118 @@
+use std::fmt;
+use std::time::{Duration, Instant};
+
+use io_uring::{IoUring, types};
+
+use crate::backend::BackendConnectionCache;
+
+use super::connection_pool::ConnectionPool;
+use super::uring_ops::{post_cancel_accept, post_drain_deadline};
+
+/// What a worker did while shutting down.
+#[derive(Debug, Default, Clone)]
+pub struct WorkerSummary {
+    /// Connections mid-request when draining started
+    pub in_flight: usize,
+    /// Connected clients that had not sent anything yet
+    pub idle_closed: usize,
+    /// Pooled backend connections closed
+    pub cached_backends_closed: usize,
+    /// Connections still open at the drain deadline
+    pub forced_closed: usize,
+    pub drain_time: Duration,
+}
+
+impl fmt::Display for WorkerSummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "drained in {:?}: {} in flight, {} idle closed, {} cached backends closed, {} forced at deadline",
+            self.drain_time,
+            self.in_flight,
+            self.idle_closed,
+            self.cached_backends_closed,
+            self.forced_closed
+        )
+    }
+}
+
+pub struct DrainState {
+    started: Instant,
+    /// Read by the kernel when the deadline SQE is submitted.
+    _deadline: Box<types::Timespec>,
+    pub deadline_expired: bool,
+    pub summary: WorkerSummary,
+}
+
+impl DrainState {
+    pub fn finish(mut self) -> WorkerSummary {
+        self.summary.drain_time = self.started.elapsed();
+        self.summary
+    }
+}
+
+/// Stop accepting and start draining the worker.
+///
+/// Pending accepts are cancelled, clients that connected but never sent a
+/// byte are shut down, pooled backend fds are closed, and a deadline timer
+/// is armed. Requests already in progress are left to finish.
+pub fn begin_drain(
+    ring: &mut IoUring,
+    pool: &mut ConnectionPool,
+    cache: &mut BackendConnectionCache,
+    timeout: Duration,
+) -> DrainState {
+    let mut summary = WorkerSummary::default();
+
+    for pair in pool.pairs_mut().iter().flatten() {
+        if pair.client_fd < 0 {
+            post_cancel_accept(ring, pair.id);
+        } else if pair.backend_fd < 0
+            && pair.header_buffer.window().is_empty()
+            && pair.pump_backend_to_client.is_idle()
+        {
+            // wakes the outstanding header recv with EOF, which tears the pair down
+            unsafe { libc::shutdown(pair.client_fd, libc::SHUT_RDWR) };
+            summary.idle_closed += 1;
+        } else {
+            summary.in_flight += 1;
+        }
+    }
+
+    summary.cached_backends_closed = cache.close_all();
+
+    let deadline = Box::new(
+        types::Timespec::new()
+            .sec(timeout.as_secs())
+            .nsec(timeout.subsec_nanos()),
+    );
+    post_drain_deadline(ring, &deadline);
+
+    DrainState {
+        started: Instant::now(),
+        _deadline: deadline,
+        deadline_expired: false,
+        summary,
+    }
+}
+
+/// Tear down every remaining connection, returning how many were open.
+pub fn force_close(pool: &mut ConnectionPool) -> usize {
+    let ids: Vec<usize> = pool
+        .pairs_mut()
+        .iter()
+        .flatten()
+        .map(|pair| pair.id)
+        .collect();
+
+    let mut closed = 0;
+    for id in ids {
+        if let Some(pair) = pool.get_mut(id)
+            && pair.client_fd >= 0
+        {
+            closed += 1;
+        }
+        pool.teardown(id);
+    }
+    closed
+}
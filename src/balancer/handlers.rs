@@ -1,55 +1,246 @@
+use std::net::SocketAddr;
 use std::os::fd::RawFd;
+use std::time::Instant;
 
 use io_uring::{IoUring, opcode};
+use rustls::HandshakeKind;
+use tracing::{Span, debug, debug_span, field};
 
-use crate::backend::{BackendConnectionCache, select_backend};
-use crate::balancer::config::WorkerConfig;
+use crate::access_log::RequestLog;
+use crate::backend::{BackendConnectionCache, BackendPool, CachedConnection};
+use crate::balancer::config::{ListenerMode, WorkerConfig};
 use crate::core::connection_pair::ConnectionPair;
 use crate::core::stream_pump::{Direction, Operation, StreamPump};
+use crate::core::socket::{local_addr, peer_addr};
 use crate::core::user_data::pack_user_data;
-use crate::protocol::peek_request_headers;
+use crate::metrics::worker_metrics;
+use crate::protocol::{
+    HeaderEdits, HelloError, HttpMetadata, ParseError, ProxiedConnection, ProxyError, ProxyVersion,
+    error_response, peek_client_hello, peek_proxy_header, peek_request_headers,
+    peek_response_length, peek_response_status,
+};
+use crate::rate_limited;
+use crate::tls::client_auth::{self, ClientIdentity};
+use crate::tls::{RecvOutcome, TlsSession, ktls};
+use crate::trace::{self, TraceContext};
+use crate::util::address::Address;
 use crate::util::fd::close_fd_quiet;
 
 use super::connection_pool::ConnectionPool;
+use super::router::Router;
 use super::uring_ops::{
-    post_accept, post_connect_backend, post_recv_headers, post_recv_pump, post_send_pump,
+    post_accept, post_cancel, post_connect_backend, post_recv_headers, post_recv_pump,
+    post_send_pump, post_tls_recv, post_tls_send, push_sqe,
 };
 
+/// Tags of the operations TLS sessions post: ciphertext for the client goes
+/// out as the backend-to-client send, ciphertext for the backend as the
+/// client-to-backend send, and the backend's arrives as its recv.
+const TO_CLIENT: Operation = Operation::Send(Direction::BackendToClient);
+const TO_BACKEND: Operation = Operation::Send(Direction::ClientToBackend);
+const FROM_BACKEND: Operation = Operation::Recv(Direction::BackendToClient);
+
+/// Set up the connection accepted into slot `id` and keep the accept
+/// pipeline full. Returns whether a client was accepted; in TCP mode nothing
+/// but a trusted proxy's PROXY protocol header is read from it until
+/// `start_passthrough` has connected it.
 pub fn handle_accept(
     ring: &mut IoUring,
     pool: &mut ConnectionPool,
     id: usize,
     res: i32,
     listen_fd: RawFd,
-    _config: &WorkerConfig,
-) {
+    config: &WorkerConfig,
+    draining: bool,
+) -> bool {
     if res < 0 {
-        // accept failed, re-arm on same slot
-        post_accept(ring, listen_fd, id);
-        return;
+        if draining {
+            // accept cancelled by the drain - release the slot
+            pool.teardown(id);
+        } else {
+            // accept failed, re-arm on same slot
+            worker_metrics().accept_errors.inc();
+            let err = std::io::Error::from_raw_os_error(-res);
+            rate_limited!(warn, "accept failed: {err}");
+            post_accept(ring, listen_fd, id);
+        }
+        return false;
     }
 
     // accept succeeded - store client FD and start reading headers
+    worker_metrics().accepts.inc();
     if let Some(pair) = pool.get_mut(id) {
         pair.client_fd = res;
-        pair.header_buffer.start = 0;
-        pair.header_buffer.end = 0;
+        pair.span = debug_span!("conn", pair = id, client = field::Empty);
+        if RequestLog::wanted() || !pair.span.is_disabled() || !config.trusted_proxies.is_empty() {
+            pair.client_address = peer_addr(res);
+        }
+        pair.awaiting_proxy_header = pair.client_address.is_some_and(|peer| {
+            let trusted = &config.trusted_proxies;
+            trusted.iter().any(|network| network.contains(peer.ip()))
+        });
+        if let Some(client) = pair.client_address.filter(|_| !pair.awaiting_proxy_header) {
+            // a proxy's client is recorded once its header names it
+            pair.span.record("client", field::display(client));
+        }
+        if let Some(tls_config) = &config.tls {
+            match TlsSession::new(tls_config.clone()) {
+                Ok(session) => pair.tls = Some(Box::new(session)),
+                Err(e) => {
+                    rate_limited!(warn, "tls session setup failed: {e}");
+                    pool.teardown(id);
+                    return false;
+                }
+            }
+        }
+        if config.mode == ListenerMode::Http || pair.awaiting_proxy_header {
+            pair.header_buffer.start = 0;
+            pair.header_buffer.end = 0;
+            post_recv_headers(ring, pair);
+        }
+    }
+
+    if !draining {
+        // keep accept pipeline full - allocate new slot; when draining this
+        // raced with the cancel, so serve the client but stop accepting
+        let nid = pool.alloc();
+        pool.ensure_slot(nid, -1);
+        post_accept(ring, listen_fd, nid);
+    }
+    true
+}
+
+/// Relay a connection accepted in TCP mode: pick a backend and start
+/// connecting to it. With virtual hosts the pick waits for the ClientHello,
+/// which `handle_client_hello` reads first. Both directions start streaming
+/// once the connect completes.
+///
+/// A connection from a trusted proxy comes back here once
+/// `handle_proxy_header` has read its header; whatever followed the header
+/// is still in the header buffer.
+pub fn start_passthrough(
+    ring: &mut IoUring,
+    pool: &mut ConnectionPool,
+    router: &Router,
+    id: usize,
+) {
+    let Some(pair) = pool.get_mut(id) else {
+        return;
+    };
+    pair.passthrough = true;
+    if pair.awaiting_proxy_header {
+        // handle_accept already posted the recv for it
+        return;
+    }
+    if !router.has_hosts() {
+        let data_len = hand_over_header_buffer(pair);
+        pair.pump_client_to_backend.bytes_ready_to_send = data_len;
+        pair.pump_client_to_backend.bytes_already_sent = 0;
+        connect_passthrough(ring, pool, router.route(None, None, None), id);
+    } else if pair.header_buffer.window().is_empty() {
         post_recv_headers(ring, pair);
+    } else {
+        route_by_client_hello(ring, pool, router, id);
     }
+}
 
-    // keep accept pipeline full - allocate new slot
-    let nid = pool.alloc();
-    pool.ensure_slot(nid, -1);
-    post_accept(ring, listen_fd, nid);
+/// Route a passthrough connection by the server name of the ClientHello
+/// buffered so far, or read more of it. Connections that don't start with
+/// one, or with one too large for the header buffer, go to the default
+/// pool. What was read is relayed ahead of the rest of the stream.
+pub fn handle_client_hello(
+    ring: &mut IoUring,
+    pool: &mut ConnectionPool,
+    router: &Router,
+    id: usize,
+    res: i32,
+) {
+    if res <= 0 {
+        // gone before saying anything useful
+        pool.teardown(id);
+        return;
+    }
+    let Some(pair) = pool.get_mut(id) else {
+        return;
+    };
+    worker_metrics().received(Direction::ClientToBackend, res as usize);
+    pair.header_buffer.wrote(res as usize);
+    route_by_client_hello(ring, pool, router, id);
+}
+
+/// Route a passthrough connection by the ClientHello in its header buffer,
+/// or read more of it.
+fn route_by_client_hello(
+    ring: &mut IoUring,
+    pool: &mut ConnectionPool,
+    router: &Router,
+    id: usize,
+) {
+    let Some(pair) = pool.get_mut(id) else {
+        return;
+    };
+    let sni = match peek_client_hello(pair.header_buffer.window()) {
+        Err(HelloError::Incomplete) if !pair.header_buffer.spare_mut().is_empty() => {
+            post_recv_headers(ring, pair);
+            return;
+        }
+        Ok(hello) => {
+            debug!(sni = hello.server_name, alpn = ?hello.alpn, "client hello");
+            hello.server_name
+        }
+        Err(e) => {
+            rate_limited!(debug, error = ?e, "no client hello to route by");
+            None
+        }
+    };
+    pair.server_name = sni;
+    let backends = router.route(None, pair.server_name.as_deref(), None);
+    let data_len = hand_over_header_buffer(pair);
+    pair.pump_client_to_backend.bytes_ready_to_send = data_len;
+    pair.pump_client_to_backend.bytes_already_sent = 0;
+    connect_passthrough(ring, pool, backends, id);
+}
+
+/// Connect a passthrough connection to a backend of `backends`.
+fn connect_passthrough(
+    ring: &mut IoUring,
+    pool: &mut ConnectionPool,
+    backends: &'static BackendPool,
+    id: usize,
+) {
+    let Some(pair) = pool.get_mut(id) else {
+        return;
+    };
+    let Some(backend_addr) = backends.select() else {
+        worker_metrics().no_backend.inc();
+        rate_limited!(warn, "no backend available");
+        pool.teardown(id);
+        return;
+    };
+    pair.backend_address = Some(backend_addr);
+    pair.backend_pool = Some(backends);
+    if let Err(e) = post_connect_backend(ring, pair, backend_addr, backends.tls()) {
+        rate_limited!(warn, "backend connect failed: {e}");
+        backends.report_failure(backend_addr);
+        pool.teardown(id);
+    }
 }
 
 pub fn handle_recv_headers(
     ring: &mut IoUring,
     pool: &mut ConnectionPool,
     cache: &mut BackendConnectionCache,
+    router: &Router,
     id: usize,
     res: i32,
+    config: &WorkerConfig,
 ) {
+    let Some(res) = pool
+        .get_mut(id)
+        .and_then(|pair| decrypt_client_recv(ring, pair, Operation::RecvHeaders, res))
+    else {
+        return;
+    };
     if res <= 0 {
         if let Some(pair) = pool.get_mut(id) {
             pair.had_error = true;
@@ -57,81 +248,314 @@ pub fn handle_recv_headers(
         pool.teardown(id);
         return;
     }
+    worker_metrics().received(Direction::ClientToBackend, res as usize);
 
-    let mut teardown = false;
-    {
-        let Some(pair) = pool.get_mut(id) else {
-            return;
-        };
-        pair.header_buffer.wrote(res as usize);
-        let win = pair.header_buffer.window();
-        match peek_request_headers(win) {
-            Err("Incomplete message") => {
-                // need more data
-                post_recv_headers(ring, pair);
+    let Some(pair) = pool.get_mut(id) else {
+        return;
+    };
+    pair.header_buffer.wrote(res as usize);
+    process_request_head(ring, pool, cache, router, id, config);
+}
+
+/// Route and forward the request whose head is in the header buffer, or
+/// read more of it. A later request on a kept-alive connection goes through
+/// here like the first, and on to the backend already serving it.
+fn process_request_head(
+    ring: &mut IoUring,
+    pool: &mut ConnectionPool,
+    cache: &mut BackendConnectionCache,
+    router: &Router,
+    id: usize,
+    config: &WorkerConfig,
+) {
+    let metrics = worker_metrics();
+    let Some(pair) = pool.get_mut(id) else {
+        return;
+    };
+    let continuing = pair.backend_fd >= 0;
+    let win = pair.header_buffer.window();
+    match peek_request_headers(win).and_then(refuse_proxy_header) {
+        Err(ParseError::Incomplete) => {
+            // need more data
+            if !offload_tls(pair) {
+                pool.teardown(id);
                 return;
             }
-            Err(_) => {
-                // malformed request - drop connection
-                pair.had_error = true;
-                teardown = true;
+            post_recv_headers(ring, pair);
+        }
+        Err(e) => {
+            // malformed request - answer 400 and close
+            metrics.parse_error(e);
+            rate_limited!(debug, error = %e, "malformed request");
+            pair.request_id = trace::request_id_for_error(win, &config.trace);
+            refuse(ring, pool, id, 400);
+        }
+        Ok(meta) => {
+            let mut edits = HeaderEdits::default();
+            let (request_id, trace_context) = trace::identify(win, &config.trace, &mut edits);
+            pair.request_id = request_id;
+
+            let client = pair.client_cert.as_deref();
+            if config.client_auth {
+                forward_client_identity(client, &mut edits);
+            }
+            if !config.client_allow.is_empty()
+                && !client.is_some_and(|client| config.client_allow.matches(client))
+            {
+                metrics.client_cert_denied.inc();
+                rate_limited!(
+                    debug,
+                    subject = client.map(|client| client.subject.as_str()),
+                    "client certificate not allowed"
+                );
+                pair.access = request_log(
+                    pair.client_address,
+                    pair.request_id.as_deref(),
+                    win,
+                    &meta,
+                    None,
+                    trace_context,
+                );
+                refuse(ring, pool, id, 403);
+                return;
+            }
+
+            let sni = pair.tls.as_ref().and_then(|tls| tls.server_name());
+            let backends = router.route(meta.host_header_value, sni, client);
+            if config.sni_host_mismatch.rejects(meta.host_header_value, sni) {
+                // Host names another site than the one the TLS session was set up for
+                metrics.misdirected.inc();
+                rate_limited!(
+                    debug,
+                    sni,
+                    host = %String::from_utf8_lossy(meta.host_header_value.unwrap_or_default()),
+                    "misdirected request"
+                );
+                pair.access = request_log(
+                    pair.client_address,
+                    pair.request_id.as_deref(),
+                    win,
+                    &meta,
+                    None,
+                    trace_context,
+                );
+                refuse(ring, pool, id, 421);
+                return;
+            }
+
+            if continuing && !pair.backend_pool.is_some_and(|last| std::ptr::eq(last, backends)) {
+                // the backend serving the connection can't serve this one;
+                // the client asks again on a new connection
+                rate_limited!(debug, "request routed to another pool on a kept-alive connection");
+                pool.teardown(id);
+                return;
             }
-            Ok(meta) => {
-                // headers complete - route to backend
-                let backend_addr = select_backend();
-
-                // persist request metadata
-                pair.request_content_length = meta.content_length_value;
-                pair.request_transfer_encoding_chunked = meta.transfer_encoding_is_chunked;
-                pair.backend_address = Some(backend_addr);
-
-                // swap buffers to avoid copy (zero-copy if data is at front)
-                let (header_buf, start, end) = pair.header_buffer.drain();
-                let pump = &mut pair.pump_client_to_backend;
-                let old_pump_buf = std::mem::replace(&mut pump.buffer, header_buf);
-                pair.header_buffer.replace_buffer(old_pump_buf);
-
-                // move data to front if needed (zero-copy when start==0)
-                let data_len = end - start;
-                if start != 0 && data_len > 0 {
-                    pump.buffer.copy_within(start..end, 0);
+            let selected = if continuing {
+                // the backend of the last request serves this one too
+                pair.backend_address.zip(pair.backend_pool)
+            } else {
+                backends.select().map(|backend_addr| (backend_addr, backends))
+            };
+            match selected {
+                None => {
+                    // no backend to route to - answer 503 and close
+                    metrics.no_backend.inc();
+                    rate_limited!(warn, "no backend available");
+                    pair.access = request_log(
+                        pair.client_address,
+                        pair.request_id.as_deref(),
+                        win,
+                        &meta,
+                        None,
+                        trace_context,
+                    );
+                    refuse(ring, pool, id, 503);
                 }
-                pump.bytes_ready_to_send = data_len;
-                pump.bytes_already_sent = 0;
-
-                if let Some(backend_fd) = cache.borrow_connection(&backend_addr) {
-                    pair.attach_backend_socket(backend_fd);
-                    pair.start_streaming();
-
-                    if pair.pump_client_to_backend.bytes_ready_to_send > 0 {
-                        post_send_pump(
-                            ring,
-                            id,
-                            &mut pair.pump_client_to_backend,
-                            Operation::Send(Direction::ClientToBackend),
+                Some((backend_addr, backends)) => {
+                    // headers complete - persist request metadata
+                    pair.head_request = meta.method_bytes == b"HEAD";
+                    // after a response with no known end, none of the
+                    // connection's later ones has one either
+                    pair.awaiting_response_head = !continuing || pair.response_remaining == Some(0);
+                    pair.response_remaining = None;
+                    pair.backend_address = Some(backend_addr);
+                    pair.backend_pool = Some(backends);
+                    pair.request_started = Some(Instant::now());
+                    pair.request_span = debug_span!(
+                        parent: &pair.span,
+                        "request",
+                        method = %String::from_utf8_lossy(meta.method_bytes),
+                        path = %String::from_utf8_lossy(meta.path_bytes),
+                        backend = %backend_addr,
+                        request_id = pair.request_id.as_deref(),
+                    );
+                    pair.access = request_log(
+                        pair.client_address,
+                        pair.request_id.as_deref(),
+                        win,
+                        &meta,
+                        Some(backend_addr),
+                        trace_context,
+                    );
+                    let head_end = meta.header_block_end_index;
+                    let body = if meta.transfer_encoding_is_chunked {
+                        // where a chunked body ends isn't followed, so the
+                        // backend is asked not to read a request after it
+                        edits.remove("Connection");
+                        edits.add("Connection", "close".to_string());
+                        None
+                    } else {
+                        Some(meta.content_length_value.unwrap_or(0))
+                    };
+
+                    let data_len = hand_over_header_buffer(pair);
+                    let pump = &mut pair.pump_client_to_backend;
+                    pump.bytes_ready_to_send = edits.apply(&mut pump.buffer, data_len, head_end);
+                    pump.bytes_already_sent = 0;
+                    pump.remaining_request_body_bytes = body;
+                    let body_start = pump.bytes_ready_to_send - (data_len - head_end);
+                    hold_next_request(pair, body_start);
+
+                    if continuing {
+                        if let Some(log) = pair.access.as_mut() {
+                            log.connected();
+                        }
+                        send_to_backend(ring, pair);
+                        return;
+                    }
+                    let proxied_for = backends
+                        .proxy_protocol()
+                        .and_then(|_| pair.resolve_client_address());
+                    if let Some(conn) = cache.borrow_connection(&backend_addr, proxied_for) {
+                        if let Some(log) = pair.access.as_mut() {
+                            log.connected();
+                        }
+                        pair.attach_backend_socket(conn.fd);
+                        pair.backend_tls = conn.tls;
+                        pair.start_streaming();
+                        send_to_backend(ring, pair);
+                        recv_from_backend(ring, pair);
+                    } else if let Err(e) =
+                        post_connect_backend(ring, pair, backend_addr, backends.tls())
+                    {
+                        rate_limited!(
+                            warn,
+                            parent: &pair.request_span,
+                            "backend connect failed: {e}"
                         );
+                        backends.report_failure(backend_addr);
+                        send_error_response(ring, pair, 502);
                     }
-
-                    post_recv_pump(
-                        ring,
-                        id,
-                        &mut pair.pump_backend_to_client,
-                        Operation::Recv(Direction::BackendToClient),
-                    );
-                } else if post_connect_backend(ring, pair, backend_addr).is_err() {
-                    pair.had_error = true;
-                    teardown = true;
                 }
             }
         }
     }
+}
+
+/// Refuse the request whose head is in the header buffer with `status`. A
+/// later request on a kept-alive connection can't be answered, with the
+/// backend of the last one still attached, so its connection just ends.
+fn refuse(ring: &mut IoUring, pool: &mut ConnectionPool, id: usize, status: u16) {
+    let Some(pair) = pool.get_mut(id) else {
+        return;
+    };
+    if pair.backend_fd < 0 {
+        send_error_response(ring, pair, status);
+        return;
+    }
+    if let Some(log) = pair.access.as_mut() {
+        log.status = Some(status);
+    }
+    pool.teardown(id);
+}
 
-    if teardown {
+/// A PROXY protocol v1 header parses as a request line; from a peer that is
+/// not a trusted proxy it is refused rather than passed on as a request.
+fn refuse_proxy_header(meta: HttpMetadata<'_>) -> Result<HttpMetadata<'_>, ParseError> {
+    if meta.method_bytes == b"PROXY" {
+        return Err(ParseError::InvalidMethod);
+    }
+    Ok(meta)
+}
+
+/// Read the PROXY protocol header a trusted proxy starts its connections
+/// with, and take the addresses it names as the client's. What follows the
+/// header is the client's own: a request head, a ClientHello or TLS
+/// handshake, handled as if it had only just arrived.
+pub fn handle_proxy_header(
+    ring: &mut IoUring,
+    pool: &mut ConnectionPool,
+    cache: &mut BackendConnectionCache,
+    router: &Router,
+    id: usize,
+    res: i32,
+    config: &WorkerConfig,
+) {
+    if res <= 0 {
         pool.teardown(id);
+        return;
+    }
+    let Some(pair) = pool.get_mut(id) else {
+        return;
+    };
+    worker_metrics().received(Direction::ClientToBackend, res as usize);
+    pair.header_buffer.wrote(res as usize);
+
+    let header = match peek_proxy_header(pair.header_buffer.window()) {
+        Err(ProxyError::Incomplete) if !pair.header_buffer.spare_mut().is_empty() => {
+            post_recv_headers(ring, pair);
+            return;
+        }
+        Ok(header) => header,
+        Err(e) => {
+            worker_metrics().proxy_header_errors.inc();
+            rate_limited!(
+                warn,
+                proxy = pair.client_address.map(field::display),
+                error = ?e,
+                "bad proxy protocol header"
+            );
+            pool.teardown(id);
+            return;
+        }
+    };
+    let proxy = pair.client_address;
+    if let Some((source, destination)) = header.addresses {
+        pair.client_address = Some(source);
+        pair.local_address = Some(destination);
+    }
+    if let Some(client) = pair.client_address {
+        pair.span.record("client", field::display(client));
+    }
+    debug!(proxy = proxy.map(field::display), "proxy protocol header");
+    pair.awaiting_proxy_header = false;
+    let end = pair.header_buffer.start + header.len;
+    pair.header_buffer.consume_to(end);
+
+    if config.mode == ListenerMode::Tcp {
+        start_passthrough(ring, pool, router, id);
+    } else if let Some(tls) = pair.tls.as_mut() {
+        // the rest is the start of the TLS handshake
+        if !tls.prefill(pair.header_buffer.window()) {
+            pool.teardown(id);
+            return;
+        }
+        pair.header_buffer.start = 0;
+        pair.header_buffer.end = 0;
+        post_recv_headers(ring, pair);
+    } else if pair.header_buffer.window().is_empty() {
+        post_recv_headers(ring, pair);
+    } else {
+        process_request_head(ring, pool, cache, router, id, config);
     }
 }
 
-pub fn handle_connect_backend(ring: &mut IoUring, pool: &mut ConnectionPool, id: usize, _res: i32) {
+pub fn handle_connect_backend(
+    ring: &mut IoUring,
+    pool: &mut ConnectionPool,
+    id: usize,
+    _res: i32,
+) {
     let Some(pair) = pool.get_mut(id) else {
         return;
     };
@@ -155,45 +579,75 @@ pub fn handle_connect_backend(ring: &mut IoUring, pool: &mut ConnectionPool, id:
             .build()
             .user_data(pack_user_data(id, Operation::ConnectBackend));
         unsafe {
-            ring.submission().push(&sqe).expect("SQ full (nop retry)");
+            push_sqe(ring, &sqe, "nop retry");
         }
         return;
     }
 
     if err_code != 0 {
-        pair.had_error = true;
-        pool.teardown(id);
+        let err = std::io::Error::from_raw_os_error(err_code);
+        rate_limited!(warn, "backend connect failed: {err}");
+        if let (Some(addr), Some(backends)) = (pair.backend_address, pair.backend_pool) {
+            backends.report_failure(addr);
+        }
+        if pair.passthrough {
+            // nothing to answer with - the client just sees the connection close
+            pool.teardown(id);
+        } else {
+            send_error_response(ring, pair, 502);
+        }
         return;
     }
 
+    if let (Some(addr), Some(backends)) = (pair.backend_address, pair.backend_pool) {
+        backends.report_success(addr);
+    }
+    if let Some(log) = pair.access.as_mut() {
+        log.connected();
+    }
+
     // connection established - start bidirectional streaming
     pair.start_streaming();
-
-    // client → backend: send buffered request
-    if pair.pump_client_to_backend.bytes_ready_to_send > 0 {
-        post_send_pump(
-            ring,
-            id,
-            &mut pair.pump_client_to_backend,
-            Operation::Send(Direction::ClientToBackend),
-        );
+    if let Some(version) = pair.backend_pool.and_then(|backends| backends.proxy_protocol()) {
+        announce_client(pair, version);
     }
 
+    // client → backend: send buffered request (behind the handshake on TLS)
+    send_to_backend(ring, pair);
+
     // backend → client: start receiving response
-    post_recv_pump(
-        ring,
-        id,
-        &mut pair.pump_backend_to_client,
-        Operation::Recv(Direction::BackendToClient),
-    );
+    recv_from_backend(ring, pair);
+
+    if pair.passthrough {
+        debug!(backend = ?pair.backend_address, "relaying connection");
+        if pair.pump_client_to_backend.bytes_ready_to_send == 0 {
+            // otherwise the send of the peeked bytes posts it once complete
+            recv_from_client(ring, pair);
+        }
+    }
 }
 
 pub fn handle_recv_client_to_backend(
     ring: &mut IoUring,
     pool: &mut ConnectionPool,
+    cache: &mut BackendConnectionCache,
+    router: &Router,
     id: usize,
     res: i32,
+    config: &WorkerConfig,
 ) {
+    let Some(res) = pool.get_mut(id).and_then(|pair| {
+        decrypt_client_recv(ring, pair, Operation::Recv(Direction::ClientToBackend), res)
+    }) else {
+        return;
+    };
+    if res == 0 && pool.get_mut(id).is_some_and(|pair| pair.passthrough) {
+        end_of_stream(pool, id, Direction::ClientToBackend);
+        return;
+    }
+    if res == 0 && pool.get_mut(id).is_some_and(|pair| park_backend(ring, pair)) {
+        return;
+    }
     if res <= 0 {
         if let Some(pair) = pool.get_mut(id) {
             pair.had_error = true;
@@ -207,18 +661,109 @@ pub fn handle_recv_client_to_backend(
         return;
     };
 
+    worker_metrics().received(Direction::ClientToBackend, res as usize);
     let pump = &mut pair.pump_client_to_backend;
+    let start = pump.bytes_ready_to_send;
     pump.recv_in_flight = false;
     pump.bytes_ready_to_send += res as usize;
-    post_send_pump(ring, id, pump, Operation::Send(Direction::ClientToBackend));
+    hold_next_request(pair, start);
+    if next_request_due(pair) {
+        next_request(ring, pool, cache, router, id, config);
+    } else {
+        send_to_backend(ring, pair);
+    }
+}
+
+/// Pass over the body of the request being relayed in the client's bytes
+/// from `from` on. Whatever follows the body is the start of the client's
+/// next request, which moves to the header buffer to be read before any of
+/// it goes to the backend.
+fn hold_next_request(pair: &mut ConnectionPair, from: usize) {
+    let pump = &mut pair.pump_client_to_backend;
+    let Some(body) = pump.remaining_request_body_bytes.as_mut() else {
+        return;
+    };
+    let end = pump.bytes_ready_to_send;
+    let next = from + (*body).min(end - from);
+    *body -= next - from;
+    if next < end {
+        pair.header_buffer.extend_from_slice(&pump.buffer[next..end]);
+        pump.bytes_ready_to_send = next;
+        pair.next_request_pending = true;
+    }
+}
+
+/// Whether the held head of the client's next request can be read: all of
+/// the last request went to the backend, and its response ended or has no
+/// end that can be told.
+fn next_request_due(pair: &ConnectionPair) -> bool {
+    let pump = &pair.pump_client_to_backend;
+    pair.next_request_pending
+        && !pump.send_in_flight
+        && pump.bytes_already_sent >= pump.bytes_ready_to_send
+        && (pair.response_remaining == Some(0)
+            || pair.response_remaining.is_none() && !pair.awaiting_response_head)
+}
+
+/// Finish the last request of a kept-alive connection and read the next.
+fn next_request(
+    ring: &mut IoUring,
+    pool: &mut ConnectionPool,
+    cache: &mut BackendConnectionCache,
+    router: &Router,
+    id: usize,
+    config: &WorkerConfig,
+) {
+    let Some(pair) = pool.get_mut(id) else {
+        return;
+    };
+    pair.next_request_pending = false;
+    if let Some(log) = pair.access.take() {
+        log.finish();
+    }
+    pair.request_span = Span::none();
+    process_request_head(ring, pool, cache, router, id, config);
+}
+
+/// The client left once the response to its last request was complete, and
+/// the backend kept the connection open. Cancel the recv waiting on the
+/// backend, so that the connection can be cached when it completes. Returns
+/// false if the connection can't be kept.
+fn park_backend(ring: &mut IoUring, pair: &mut ConnectionPair) -> bool {
+    let to_backend = &pair.pump_client_to_backend;
+    let to_client = &pair.pump_backend_to_client;
+    if pair.response_remaining != Some(0)
+        || pair.backend_fd < 0
+        || to_backend.send_in_flight
+        || to_backend.bytes_already_sent < to_backend.bytes_ready_to_send
+        || !to_client.recv_in_flight
+        || to_client.send_in_flight
+        || to_client.bytes_ready_to_send > 0
+    {
+        return false;
+    }
+    pair.pump_client_to_backend.recv_in_flight = false;
+    pair.pump_client_to_backend.reset_buffer();
+    pair.parking_backend = true;
+    post_cancel(ring, pair.id, FROM_BACKEND);
+    true
 }
 
 pub fn handle_send_client_to_backend(
     ring: &mut IoUring,
     pool: &mut ConnectionPool,
+    cache: &mut BackendConnectionCache,
+    router: &Router,
     id: usize,
     res: i32,
+    config: &WorkerConfig,
 ) {
+    let Some(res) = pool
+        .get_mut(id)
+        .and_then(|pair| backend_send_complete(ring, pair, res))
+    else {
+        return;
+    };
     if res < 0 {
         if let Some(pair) = pool.get_mut(id) {
             pair.had_error = true;
@@ -231,17 +776,26 @@ pub fn handle_send_client_to_backend(
         return;
     };
 
+    worker_metrics().sent(Direction::ClientToBackend, res as usize);
     let pump = &mut pair.pump_client_to_backend;
     pump.send_in_flight = false;
     pump.bytes_already_sent += res as usize;
 
     if pump.bytes_already_sent < pump.bytes_ready_to_send {
         // partial send - continue sending
-        post_send_pump(ring, id, pump, Operation::Send(Direction::ClientToBackend));
+        send_to_backend(ring, pair);
     } else {
         // all data sent - reset and receive more
         pump.reset_buffer();
-        post_recv_pump(ring, id, pump, Operation::Recv(Direction::ClientToBackend));
+        if !offload_tls(pair) {
+            pool.teardown(id);
+            return;
+        }
+        if next_request_due(pair) {
+            next_request(ring, pool, cache, router, id, config);
+        } else if !pair.next_request_pending {
+            recv_from_client(ring, pair);
+        }
     }
 }
 
@@ -249,9 +803,34 @@ pub fn handle_recv_backend_to_client(
     ring: &mut IoUring,
     pool: &mut ConnectionPool,
     cache: &mut BackendConnectionCache,
+    router: &Router,
     id: usize,
     res: i32,
+    config: &WorkerConfig,
 ) {
+    if let Some(pair) = pool.get_mut(id).filter(|pair| pair.parking_backend) {
+        pair.parking_backend = false;
+        // anything but the cancellation means the backend wasn't idle after all
+        let cancelled = res == -libc::ECANCELED;
+        if cancelled {
+            pair.pump_backend_to_client.recv_in_flight = false;
+            if let Some(tls) = pair.backend_tls.as_mut() {
+                tls.recv_in_flight = false;
+            }
+        }
+        if cancelled && finish_request(pair, cache) {
+            pool.recycle_slot_only(id);
+        } else {
+            pool.teardown(id);
+        }
+        return;
+    }
+    let Some(res) = pool
+        .get_mut(id)
+        .and_then(|pair| decrypt_backend_recv(ring, pair, res))
+    else {
+        return;
+    };
     if res < 0 {
         if let Some(pair) = pool.get_mut(id) {
             pair.had_error = true;
@@ -259,6 +838,10 @@ pub fn handle_recv_backend_to_client(
         pool.teardown(id);
         return;
     }
+    if res == 0 && pool.get_mut(id).is_some_and(|pair| pair.passthrough) {
+        end_of_stream(pool, id, Direction::BackendToClient);
+        return;
+    }
     let reuse_backend = {
         let Some(pair) = pool.get_mut(id) else {
             return;
@@ -266,12 +849,40 @@ pub fn handle_recv_backend_to_client(
 
         if res == 0 {
             pair.pump_backend_to_client.recv_in_flight = false;
+            // the backend closed; there's nothing left to reuse
+            pair.response_remaining = None;
             Some(finish_request(pair, cache))
         } else {
+            let metrics = worker_metrics();
+            metrics.received(Direction::BackendToClient, res as usize);
+            if let Some(started) = pair.request_started.take() {
+                // first response bytes from the backend
+                metrics.request_latency.observe(started.elapsed());
+            }
             let pump = &mut pair.pump_backend_to_client;
+            if let Some(log) = pair.access.as_mut() {
+                let start = pump.bytes_ready_to_send;
+                log.response_data(&pump.buffer[start..start + res as usize]);
+            }
             pump.recv_in_flight = false;
             pump.bytes_ready_to_send += res as usize;
-            post_send_pump(ring, id, pump, Operation::Send(Direction::BackendToClient));
+            // a head split across recvs leaves the response to end at EOF
+            pair.response_remaining = if std::mem::take(&mut pair.awaiting_response_head) {
+                let received = pump.bytes_ready_to_send;
+                if peek_response_status(&pump.buffer[..received]) == Some(101) {
+                    // switched protocols: the client's bytes are no longer requests
+                    pair.pump_client_to_backend.remaining_request_body_bytes = None;
+                }
+                peek_response_length(&pump.buffer[..received], pair.head_request)
+                    .and_then(|len| len.checked_sub(received))
+            } else {
+                pair.response_remaining
+                    .and_then(|remaining| remaining.checked_sub(res as usize))
+            };
+            send_to_client(ring, pair);
+            if next_request_due(pair) {
+                next_request(ring, pool, cache, router, id, config);
+            }
             return;
         }
     };
@@ -289,6 +900,12 @@ pub fn handle_send_backend_to_client(
     id: usize,
     res: i32,
 ) {
+    let Some(res) = pool
+        .get_mut(id)
+        .and_then(|pair| client_send_complete(ring, pair, res))
+    else {
+        return;
+    };
     if res < 0 {
         if let Some(pair) = pool.get_mut(id) {
             pair.had_error = true;
@@ -301,44 +918,497 @@ pub fn handle_send_backend_to_client(
         return;
     };
 
+    worker_metrics().sent(Direction::BackendToClient, res as usize);
+    if let Some(log) = pair.access.as_mut() {
+        log.response_bytes += res as u64;
+    }
     let pump = &mut pair.pump_backend_to_client;
     pump.send_in_flight = false;
     pump.bytes_already_sent += res as usize;
 
     if pump.bytes_already_sent < pump.bytes_ready_to_send {
         // partial send - continue sending
-        post_send_pump(ring, id, pump, Operation::Send(Direction::BackendToClient));
+        send_to_client(ring, pair);
+    } else if pair.close_after_response {
+        // our own error response is out - nothing more to relay
+        pool.teardown(id);
     } else {
         // all data sent - reset and receive more
         pump.reset_buffer();
-        post_recv_pump(ring, id, pump, Operation::Recv(Direction::BackendToClient));
+        if pair.response_remaining == Some(0)
+            && let Some(log) = pair.access.take()
+        {
+            // the response is out: log it now rather than when the client
+            // sends its next request or leaves
+            log.finish();
+        }
+        recv_from_backend(ring, pair);
+    }
+}
+
+/// One direction of a passthrough connection reached end of stream. Its
+/// buffer is already relayed, so the EOF is passed on by shutting down the
+/// write side of the other socket; the pair goes once both directions have
+/// ended.
+fn end_of_stream(pool: &mut ConnectionPool, id: usize, direction: Direction) {
+    let Some(pair) = pool.get_mut(id) else {
+        return;
+    };
+    let (pump, write_fd) = match direction {
+        Direction::ClientToBackend => {
+            if let Some(tls) = pair.backend_tls.as_mut() {
+                tls.close(pair.backend_fd);
+            }
+            (&mut pair.pump_client_to_backend, pair.backend_fd)
+        }
+        Direction::BackendToClient => (&mut pair.pump_backend_to_client, pair.client_fd),
+    };
+    pump.recv_in_flight = false;
+    pump.finished = true;
+    unsafe { libc::shutdown(write_fd, libc::SHUT_WR) };
+    debug!(?direction, "end of stream");
+
+    if pair.pump_client_to_backend.finished && pair.pump_backend_to_client.finished {
+        pool.teardown(id);
+    }
+}
+
+/// Start a new backend connection with the PROXY protocol header naming
+/// the pair's client: in front of the buffered request, or of the handshake
+/// on TLS backends.
+fn announce_client(pair: &mut ConnectionPair, version: ProxyVersion) {
+    let addresses = pair
+        .resolve_client_address()
+        .zip(pair.local_address.or_else(|| local_addr(pair.client_fd)));
+    let header = version.header(&ProxiedConnection {
+        addresses,
+        authority: pair.server_name.as_deref(),
+        alpn: pair.alpn.as_deref(),
+    });
+    if let Some(tls) = pair.backend_tls.as_mut() {
+        tls.send_first(&header);
+        return;
+    }
+    let pump = &mut pair.pump_client_to_backend;
+    let len = pump.bytes_ready_to_send;
+    if pump.buffer.len() < len + header.len() {
+        pump.buffer.resize(len + header.len(), 0);
+    }
+    pump.buffer.copy_within(..len, header.len());
+    pump.buffer[..header.len()].copy_from_slice(&header);
+    pump.bytes_ready_to_send += header.len();
+}
+
+/// Move what the header buffer holds to the front of the client-to-backend
+/// pump's buffer, swapping the two buffers rather than copying (a copy
+/// within the buffer only happens when the data doesn't start at its front).
+/// Returns how many bytes were moved.
+fn hand_over_header_buffer(pair: &mut ConnectionPair) -> usize {
+    let (header_buf, start, end) = pair.header_buffer.drain();
+    let pump = &mut pair.pump_client_to_backend;
+    let old_pump_buf = std::mem::replace(&mut pump.buffer, header_buf);
+    pair.header_buffer.replace_buffer(old_pump_buf);
+
+    let data_len = end - start;
+    if start != 0 && data_len > 0 {
+        pump.buffer.copy_within(start..end, 0);
+    }
+    data_len
+}
+
+/// Access log record for a request, if requests are being logged or traced.
+fn request_log(
+    client: Option<SocketAddr>,
+    request_id: Option<&str>,
+    head: &[u8],
+    meta: &HttpMetadata,
+    upstream: Option<Address>,
+    trace: Option<TraceContext>,
+) -> Option<Box<RequestLog>> {
+    if !RequestLog::wanted() {
+        return None;
+    }
+    let mut log = RequestLog::new(client, head, meta, upstream);
+    log.request_id = request_id.map(str::to_string);
+    log.trace = trace;
+    Some(Box::new(log))
+}
+
+/// Answer the client with a generated error response and close the connection
+/// once it has been sent. Any backend connection of the request is dropped;
+/// its TLS session, if any, must not have I/O in flight.
+fn send_error_response(ring: &mut IoUring, pair: &mut ConnectionPair, status: u16) {
+    worker_metrics().error_responses.inc();
+    if pair.backend_fd >= 0 {
+        if let Some(mut tls) = pair.backend_tls.take() {
+            tls.close(pair.backend_fd);
+        }
+        close_fd_quiet(pair.backend_fd);
+        pair.backend_fd = -1;
+    }
+    if let Some(log) = pair.access.as_mut() {
+        log.status = Some(status);
+    }
+    pair.had_error = true;
+    pair.close_after_response = true;
+
+    let response = error_response(status, pair.request_id.as_deref());
+    let pump = &mut pair.pump_backend_to_client;
+    if pump.buffer.len() < response.len() {
+        pump.buffer.resize(response.len(), 0);
+    }
+    pump.buffer[..response.len()].copy_from_slice(response.as_bytes());
+    pump.read_fd = -1;
+    pump.write_fd = pair.client_fd;
+    pump.bytes_ready_to_send = response.len();
+    pump.bytes_already_sent = 0;
+    send_to_client(ring, pair);
+}
+
+/// Post the next recv from the client into the client-to-backend pump.
+fn recv_from_client(ring: &mut IoUring, pair: &mut ConnectionPair) {
+    let pump = &mut pair.pump_client_to_backend;
+    match pair.tls.as_mut() {
+        None => post_recv_pump(
+            ring,
+            pair.id,
+            pump,
+            pair.ktls.as_deref_mut(),
+            Operation::Recv(Direction::ClientToBackend),
+        ),
+        Some(tls) => {
+            if pump.recv_in_flight {
+                return;
+            }
+            pump.recv_in_flight = true;
+            let tag = Operation::Recv(Direction::ClientToBackend);
+            post_tls_recv(ring, pair.id, tls, pair.client_fd, tag);
+        }
+    }
+}
+
+/// Post the next recv from the backend into the backend-to-client pump.
+fn recv_from_backend(ring: &mut IoUring, pair: &mut ConnectionPair) {
+    let pump = &mut pair.pump_backend_to_client;
+    match pair.backend_tls.as_mut() {
+        None => post_recv_pump(ring, pair.id, pump, None, FROM_BACKEND),
+        Some(tls) => {
+            if pump.recv_in_flight {
+                return;
+            }
+            pump.recv_in_flight = true;
+            post_tls_recv(ring, pair.id, tls, pair.backend_fd, FROM_BACKEND);
+        }
+    }
+}
+
+/// Send what the client-to-backend pump holds, encrypting it first for TLS
+/// backends. A fresh TLS session sends its ClientHello here, and keeps the
+/// request until the handshake is done.
+fn send_to_backend(ring: &mut IoUring, pair: &mut ConnectionPair) {
+    let pump = &mut pair.pump_client_to_backend;
+    match pair.backend_tls.as_mut() {
+        None => post_send_pump(ring, pair.id, pump, TO_BACKEND),
+        Some(tls) => {
+            if !pump.send_in_flight && pump.bytes_already_sent < pump.bytes_ready_to_send {
+                tls.encrypt(&pump.buffer[pump.bytes_already_sent..pump.bytes_ready_to_send]);
+                pump.send_in_flight = true;
+            } else {
+                tls.queue_output();
+            }
+            post_tls_send(ring, pair.id, tls, pair.backend_fd, TO_BACKEND);
+        }
+    }
+}
+
+/// Move a TLS connection to kernel TLS once its session is ready to let go.
+/// Returns false if the connection was lost in the attempt.
+fn offload_tls(pair: &mut ConnectionPair) -> bool {
+    let Some(tls) = pair.tls.as_mut().filter(|tls| tls.ready_for_offload()) else {
+        return true;
+    };
+    if let Err(e) = ktls::enable_ulp(pair.client_fd) {
+        // the socket is untouched - carry on in userspace
+        rate_limited!(debug, "kernel tls unavailable for connection: {e}");
+        tls.decline_offload();
+        return true;
+    }
+    let tls = pair.tls.take().unwrap();
+    match tls.offload(pair.client_fd) {
+        Ok(()) => {
+            pair.ktls = Some(ktls::RecordRecv::new());
+            worker_metrics().ktls_offloaded.inc();
+            debug!("tls offloaded to the kernel");
+            true
+        }
+        Err(e) => {
+            worker_metrics().tls_errors.inc();
+            rate_limited!(warn, "kernel tls offload failed: {e}");
+            false
+        }
+    }
+}
+
+/// Send what the backend-to-client pump holds, encrypting it first on TLS
+/// connections.
+fn send_to_client(ring: &mut IoUring, pair: &mut ConnectionPair) {
+    let pump = &mut pair.pump_backend_to_client;
+    match pair.tls.as_mut() {
+        None => post_send_pump(
+            ring,
+            pair.id,
+            pump,
+            Operation::Send(Direction::BackendToClient),
+        ),
+        Some(tls) => {
+            if pump.send_in_flight || pump.bytes_already_sent >= pump.bytes_ready_to_send {
+                return;
+            }
+            tls.encrypt(&pump.buffer[pump.bytes_already_sent..pump.bytes_ready_to_send]);
+            pump.send_in_flight = true;
+            post_tls_send(ring, pair.id, tls, pair.client_fd, TO_CLIENT);
+        }
+    }
+}
+
+/// Turn a completed client recv (`RecvHeaders` or client-to-backend `Recv`)
+/// into its plaintext result. Plain connections pass `res` through; on TLS
+/// connections the ciphertext is decrypted into the buffer a plain recv would
+/// have filled. `None` means nothing is ready yet and another recv is posted.
+fn decrypt_client_recv(
+    ring: &mut IoUring,
+    pair: &mut ConnectionPair,
+    op: Operation,
+    res: i32,
+) -> Option<i32> {
+    if let Some(record) = &pair.ktls {
+        return Some(record.complete(res));
+    }
+    let Some(tls) = pair.tls.as_mut() else {
+        return Some(res);
+    };
+    let dst = match op {
+        Operation::RecvHeaders => pair.header_buffer.spare_mut(),
+        _ => {
+            let pump = &mut pair.pump_client_to_backend;
+            &mut pump.buffer[pump.bytes_ready_to_send..]
+        }
+    };
+    let outcome = tls.complete_recv(res, dst);
+
+    if tls.take_handshake_done() {
+        let metrics = worker_metrics();
+        metrics.tls_handshakes.inc();
+        let resumed = tls.conn.handshake_kind() == Some(HandshakeKind::Resumed);
+        if resumed {
+            metrics.tls_resumed.inc();
+        }
+        debug!(
+            version = ?tls.conn.protocol_version(),
+            cipher = ?tls.conn.negotiated_cipher_suite().map(|s| s.suite()),
+            alpn = ?tls.conn.alpn_protocol().map(String::from_utf8_lossy),
+            sni = tls.server_name(),
+            resumed,
+            "tls handshake complete"
+        );
+        pair.client_cert = tls
+            .conn
+            .peer_certificates()
+            .and_then(|certs| certs.first())
+            .and_then(ClientIdentity::from_certificate)
+            .map(Box::new);
+        pair.server_name = tls.server_name().map(str::to_string);
+        pair.alpn = tls.conn.alpn_protocol().map(<[u8]>::to_vec);
     }
+
+    match outcome {
+        RecvOutcome::Plaintext(n) => {
+            tls.queue_output();
+            post_tls_send(ring, pair.id, tls, pair.client_fd, TO_CLIENT);
+            Some(n as i32)
+        }
+        RecvOutcome::NeedMore => {
+            tls.queue_output();
+            post_tls_send(ring, pair.id, tls, pair.client_fd, TO_CLIENT);
+            post_tls_recv(ring, pair.id, tls, pair.client_fd, op);
+            None
+        }
+        RecvOutcome::Closed => Some(0),
+        RecvOutcome::Failed(e) => {
+            // any alert rustls queued goes out with the close at teardown
+            worker_metrics().tls_errors.inc();
+            if let Some(reason) = client_auth::rejection(&e) {
+                worker_metrics().client_cert_rejected.inc();
+                rate_limited!(
+                    warn,
+                    client = pair
+                        .client_address
+                        .or_else(|| peer_addr(pair.client_fd))
+                        .map(field::display),
+                    "client certificate rejected: {reason}"
+                );
+            } else {
+                rate_limited!(debug, handshaking = tls.conn.is_handshaking(), "tls: {e}");
+            }
+            Some(-libc::EPROTO)
+        }
+    }
+}
+
+/// Replace whatever `X-Client-Cert-*` headers the client sent with the
+/// identity of its verified certificate, if it presented one.
+fn forward_client_identity(client: Option<&ClientIdentity>, edits: &mut HeaderEdits) {
+    edits.remove(client_auth::SUBJECT_HEADER);
+    edits.remove(client_auth::SAN_HEADER);
+    let Some(client) = client else {
+        return;
+    };
+    edits.add(client_auth::SUBJECT_HEADER, client.subject.clone());
+    if let Some(san) = client.san() {
+        edits.add(client_auth::SAN_HEADER, san);
+    }
+}
+
+/// Account for a completed client send. On TLS connections this is the
+/// ciphertext queue draining; the pump only hears about it, in plaintext
+/// bytes, once everything encrypted for it is on the wire.
+fn client_send_complete(ring: &mut IoUring, pair: &mut ConnectionPair, res: i32) -> Option<i32> {
+    let Some(tls) = pair.tls.as_mut() else {
+        return Some(res);
+    };
+    if res < 0 {
+        tls.send_in_flight = false;
+        return Some(res);
+    }
+    let reported = tls.complete_send(res as usize);
+    post_tls_send(ring, pair.id, tls, pair.client_fd, TO_CLIENT);
+    reported.map(|n| n as i32)
+}
+
+/// Turn a completed backend recv into its plaintext result, the way
+/// `decrypt_client_recv` does for the client. A handshake that fails before
+/// anything was relayed is answered with 502 here, which yields `None`;
+/// passthrough connections, which have no one to answer, just fail.
+fn decrypt_backend_recv(ring: &mut IoUring, pair: &mut ConnectionPair, res: i32) -> Option<i32> {
+    let Some(tls) = pair.backend_tls.as_mut() else {
+        return Some(res);
+    };
+    let pump = &mut pair.pump_backend_to_client;
+    let outcome = tls.complete_recv(res, &mut pump.buffer[pump.bytes_ready_to_send..]);
+
+    if tls.take_handshake_done() {
+        let metrics = worker_metrics();
+        metrics.backend_tls_handshakes.inc();
+        let resumed = tls.conn.handshake_kind() == Some(HandshakeKind::Resumed);
+        if resumed {
+            metrics.backend_tls_resumed.inc();
+        }
+        debug!(
+            version = ?tls.conn.protocol_version(),
+            cipher = ?tls.conn.negotiated_cipher_suite().map(|s| s.suite()),
+            alpn = ?tls.conn.alpn_protocol().map(String::from_utf8_lossy),
+            resumed,
+            "backend tls handshake complete"
+        );
+    }
+
+    match outcome {
+        RecvOutcome::Plaintext(n) => {
+            tls.queue_output();
+            post_tls_send(ring, pair.id, tls, pair.backend_fd, TO_BACKEND);
+            Some(n as i32)
+        }
+        RecvOutcome::NeedMore => {
+            tls.queue_output();
+            post_tls_send(ring, pair.id, tls, pair.backend_fd, TO_BACKEND);
+            post_tls_recv(ring, pair.id, tls, pair.backend_fd, FROM_BACKEND);
+            None
+        }
+        RecvOutcome::Closed => Some(0),
+        RecvOutcome::Failed(e) => {
+            if let Some(errno) = e.raw_os_error() {
+                return Some(-errno);
+            }
+            worker_metrics().backend_tls_errors.inc();
+            let handshaking = tls.conn.is_handshaking();
+            rate_limited!(warn, handshaking, "backend tls: {e}");
+            if !handshaking || tls.io_in_flight() || pair.passthrough {
+                return Some(-libc::EPROTO);
+            }
+            // no response bytes yet - the client gets an answer
+            if let (Some(addr), Some(backends)) = (pair.backend_address, pair.backend_pool) {
+                backends.report_failure(addr);
+            }
+            send_error_response(ring, pair, 502);
+            None
+        }
+    }
+}
+
+/// Account for a completed backend send, the way `client_send_complete` does
+/// for the client.
+fn backend_send_complete(ring: &mut IoUring, pair: &mut ConnectionPair, res: i32) -> Option<i32> {
+    let Some(tls) = pair.backend_tls.as_mut() else {
+        return Some(res);
+    };
+    if res < 0 {
+        tls.send_in_flight = false;
+        return Some(res);
+    }
+    let reported = tls.complete_send(res as usize);
+    post_tls_send(ring, pair.id, tls, pair.backend_fd, TO_BACKEND);
+    reported.map(|n| n as i32)
 }
 
+/// Wrap up the request once the backend is done. Returns whether the backend
+/// connection went back to the cache; if not, the teardown that follows
+/// closes it.
+///
+/// Only a connection whose last response ended where its head said, with
+/// the backend keeping it open, is cached; after EOF there is nothing left
+/// to reuse. Neither is one whose request body didn't all arrive, or that a
+/// next request is waiting to go out on.
 pub fn finish_request(pair: &mut ConnectionPair, cache: &mut BackendConnectionCache) -> bool {
     let pumps_idle = pair.pump_client_to_backend.is_idle() && pair.pump_backend_to_client.is_idle();
-    let healthy_backend = !pair.had_error && pair.backend_fd >= 0;
+    let healthy_backend = !pair.had_error
+        && pair.backend_fd >= 0
+        && pair.response_remaining == Some(0)
+        && pair.pump_client_to_backend.remaining_request_body_bytes == Some(0)
+        && !pair.next_request_pending
+        && pair.backend_tls.as_ref().is_none_or(|tls| !tls.io_in_flight());
 
     let mut reused = false;
 
-    if pumps_idle && healthy_backend {
-        if let Some(addr) = pair.backend_address {
-            cache.return_connection(&addr, pair.backend_fd);
-            reused = true;
-        } else {
-            close_fd_quiet(pair.backend_fd);
-        }
-        pair.backend_fd = -1;
-    } else if pair.backend_fd >= 0 {
-        close_fd_quiet(pair.backend_fd);
+    if let Some(log) = pair.access.take() {
+        log.finish();
+    }
+    debug!(parent: &pair.request_span, reused = pumps_idle && healthy_backend, "request finished");
+    pair.request_span = Span::none();
+
+    if pumps_idle && healthy_backend && let Some(addr) = pair.backend_address {
+        let conn = CachedConnection {
+            fd: pair.backend_fd,
+            tls: pair.backend_tls.take(),
+            proxied_for: pair
+                .backend_pool
+                .and_then(|backends| backends.proxy_protocol())
+                .and(pair.client_address),
+        };
+        cache.return_connection(&addr, conn);
         pair.backend_fd = -1;
+        reused = true;
     }
 
     pair.backend_address = None;
+    pair.backend_pool = None;
     pair.backend_sockaddr_storage = None;
     pair.backend_sockaddr_len = 0;
-    pair.request_content_length = None;
-    pair.request_transfer_encoding_chunked = false;
+    pair.next_request_pending = false;
+    pair.head_request = false;
+    pair.awaiting_response_head = false;
+    pair.response_remaining = None;
+    pair.request_started = None;
+    pair.request_id = None;
     pair.had_error = false;
 
     reset_pump_after_finish(&mut pair.pump_client_to_backend);
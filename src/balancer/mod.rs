@@ -3,14 +3,25 @@
 //! This module provides the core load balancing functionality including:
 //! - Worker event loop powered by io_uring
 //! - Connection pool management
-//! - io_uring operation helpers
+//! - Routing requests to backend pools by virtual host
+//! - UDP flows relayed to backends datagram by datagram
+//! - Graceful shutdown and connection draining
+//! - io_uring setup and operation helpers
 
 pub mod config;
 pub mod connection_pool;
+pub mod drain;
 pub mod handlers;
+pub mod ring;
+pub mod router;
+pub mod shutdown;
+pub mod udp;
 pub mod uring_ops;
 pub mod worker;
 
-pub use config::WorkerConfig;
+pub use config::{RingMode, SqpollConfig, WorkerConfig};
 pub use connection_pool::ConnectionPool;
+pub use drain::WorkerSummary;
+pub use ring::prepare_ring_mode;
+pub use shutdown::ShutdownSignal;
 pub use worker::run_worker;
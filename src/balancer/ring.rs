@@ -0,0 +1,74 @@
+//! io_uring instance setup
+//!
+//! Builds worker rings according to the configured `RingMode` and checks at
+//! startup that the running kernel accepts that mode.
+
+use std::io;
+use std::os::fd::AsRawFd;
+
+use io_uring::IoUring;
+
+use crate::balancer::config::{RingMode, WorkerConfig};
+
+/// Entries for the probe ring; it never carries any I/O.
+const PROBE_RING_ENTRIES: u32 = 2;
+
+/// Build an io_uring instance for a worker.
+pub fn build_ring(ring_size: u32, mode: RingMode) -> io::Result<IoUring> {
+    let mut builder = IoUring::builder();
+    builder.setup_single_issuer();
+
+    match mode {
+        RingMode::DeferTaskrun => {
+            builder.setup_defer_taskrun();
+        }
+        RingMode::Sqpoll(sq) => {
+            builder.setup_sqpoll(sq.idle_ms);
+            if let Some(cpu) = sq.cpu {
+                builder.setup_sqpoll_cpu(cpu);
+            }
+            if let Some(fd) = sq.attach_wq_fd {
+                builder.setup_attach_wq(fd);
+            }
+        }
+    }
+
+    builder.build(ring_size)
+}
+
+/// Validate the ring mode against the running kernel before any worker starts.
+///
+/// For shared SQPOLL this returns the ring that owns the poll thread, and the
+/// config is updated so workers attach to it. The caller must keep that ring
+/// alive for as long as workers run.
+pub fn prepare_ring_mode(config: &mut WorkerConfig) -> io::Result<Option<IoUring>> {
+    let probe = build_ring(PROBE_RING_ENTRIES, config.ring_mode)
+        .map_err(|e| describe(e, config.ring_mode))?;
+
+    match &mut config.ring_mode {
+        RingMode::Sqpoll(sq) if sq.shared => {
+            sq.attach_wq_fd = Some(probe.as_raw_fd());
+            Ok(Some(probe))
+        }
+        _ => Ok(None),
+    }
+}
+
+fn describe(err: io::Error, mode: RingMode) -> io::Error {
+    let hint = match (err.raw_os_error(), mode) {
+        (Some(libc::EPERM), RingMode::Sqpoll(_)) => {
+            "SQPOLL needs CAP_SYS_NICE on kernels older than 5.11"
+        }
+        (Some(libc::EINVAL), RingMode::Sqpoll(sq)) if sq.cpu.is_some() => {
+            "kernel rejected SQPOLL setup; check that sqpoll cpu is online and allowed"
+        }
+        (Some(libc::EINVAL), RingMode::Sqpoll(_)) => {
+            "kernel does not support SQPOLL with SINGLE_ISSUER"
+        }
+        (Some(libc::EINVAL), RingMode::DeferTaskrun) => {
+            "DEFER_TASKRUN needs Linux 6.1 or newer; configure sqpoll instead"
+        }
+        _ => "io_uring setup failed",
+    };
+    io::Error::new(err.kind(), format!("{hint} ({mode:?}): {err}"))
+}
@@ -0,0 +1,89 @@
+use std::io;
+
+use serde::Deserialize;
+
+use crate::backend::{BackendPool, get_pool};
+use crate::tls::client_auth::{ClientIdentity, ClientMatcher};
+use crate::util::hostname::{HostMap, normalize};
+
+use super::config::WorkerConfig;
+
+/// Which name of a request selects its virtual host.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RouteKey {
+    /// The `Host` header, or the SNI name if the request has none
+    #[default]
+    Host,
+    /// The SNI name, or `Host` on plaintext connections and handshakes without SNI
+    Sni,
+}
+
+/// Picks the backend pool of a request: the pool of the first client route
+/// its client certificate matches, else the virtual host's pool when its
+/// name matches one, the worker's pool otherwise.
+pub struct Router {
+    default: &'static BackendPool,
+    hosts: HostMap<&'static BackendPool>,
+    route_by: RouteKey,
+    clients: Vec<(ClientMatcher, &'static BackendPool)>,
+}
+
+impl Router {
+    pub fn new(config: &WorkerConfig) -> io::Result<Self> {
+        let pool = |name: &str| {
+            get_pool(name).ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("unknown backend pool {name:?}"),
+                )
+            })
+        };
+        let mut hosts = HostMap::default();
+        for (name, pool_name) in &config.hosts {
+            hosts.insert(name, pool(pool_name)?);
+        }
+        let clients = config
+            .client_routes
+            .iter()
+            .map(|(matcher, pool_name)| Ok((matcher.clone(), pool(pool_name)?)))
+            .collect::<io::Result<_>>()?;
+        Ok(Self {
+            default: pool(&config.pool)?,
+            hosts,
+            route_by: config.route_by,
+            clients,
+        })
+    }
+
+    /// Whether a name can select another pool than the default one.
+    pub fn has_hosts(&self) -> bool {
+        !self.hosts.is_empty()
+    }
+
+    pub fn route(
+        &self,
+        host: Option<&[u8]>,
+        sni: Option<&str>,
+        client: Option<&ClientIdentity>,
+    ) -> &'static BackendPool {
+        if let Some(client) = client
+            && let Some((_, pool)) = self.clients.iter().find(|(m, _)| m.matches(client))
+        {
+            return pool;
+        }
+        if self.hosts.is_empty() {
+            return self.default;
+        }
+        let name = match self.route_by {
+            RouteKey::Host => host.and_then(normalize).or_else(|| sni.and_then(sni_name)),
+            RouteKey::Sni => sni.and_then(sni_name).or_else(|| host.and_then(normalize)),
+        };
+        name.and_then(|name| self.hosts.get(&name).copied())
+            .unwrap_or(self.default)
+    }
+}
+
+fn sni_name(sni: &str) -> Option<String> {
+    normalize(sni.as_bytes())
+}
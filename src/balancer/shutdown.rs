@@ -0,0 +1,64 @@
+use std::io;
+use std::os::fd::RawFd;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use crate::util::fd::close_fd_quiet;
+
+/// Process-wide shutdown request shared by all workers.
+///
+/// Each worker registers an eventfd and keeps a read on it in its ring, so
+/// triggering wakes workers that are blocked in `submit_and_wait`.
+#[derive(Debug, Default)]
+pub struct ShutdownSignal {
+    requested: AtomicBool,
+    wakers: Mutex<Vec<RawFd>>,
+}
+
+impl ShutdownSignal {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn is_requested(&self) -> bool {
+        self.requested.load(Ordering::Acquire)
+    }
+
+    /// Ask every worker to stop accepting and drain. Safe to call repeatedly.
+    pub fn trigger(&self) {
+        self.requested.store(true, Ordering::Release);
+        let wakers = self.wakers.lock().unwrap();
+        for &fd in wakers.iter() {
+            wake(fd);
+        }
+    }
+
+    /// Create an eventfd for a worker. If shutdown was already requested, the
+    /// eventfd is readable right away.
+    pub fn register_waker(&self) -> io::Result<RawFd> {
+        let fd = unsafe { libc::eventfd(0, libc::EFD_CLOEXEC) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        self.wakers.lock().unwrap().push(fd);
+        if self.is_requested() {
+            wake(fd);
+        }
+        Ok(fd)
+    }
+
+    pub fn unregister_waker(&self, fd: RawFd) {
+        let mut wakers = self.wakers.lock().unwrap();
+        if let Some(pos) = wakers.iter().position(|&w| w == fd) {
+            wakers.swap_remove(pos);
+            close_fd_quiet(fd);
+        }
+    }
+}
+
+fn wake(fd: RawFd) {
+    let one: u64 = 1;
+    unsafe {
+        libc::write(fd, &one as *const u64 as *const libc::c_void, 8);
+    }
+}
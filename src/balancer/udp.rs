@@ -0,0 +1,767 @@
+//! UDP load balancing
+//!
+//! A UDP listener has no connections to follow, so it keeps flows instead:
+//! the datagrams from one client address form a flow, relayed to a backend
+//! picked for its first datagram over a socket connected to that backend.
+//! Replies arriving on that socket go back out through the listener to the
+//! client. A flow is closed once idle for `idle_timeout`, or
+//! `session_timeout` after it started; the client's next datagram opens a
+//! new one.
+//!
+//! Every datagram moves through a RecvMsg or SendMsg on a boxed message
+//! header, which stays put while the kernel holds it. A client datagram
+//! changes hands without being copied: its buffer is queued on the flow and
+//! the listener receives into a spare one.
+
+use std::collections::{HashMap, VecDeque};
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::io;
+use std::mem;
+use std::net::SocketAddr;
+use std::os::fd::RawFd;
+use std::ptr;
+use std::time::{Duration, Instant};
+
+use io_uring::{IoUring, types};
+use socket2::{SockAddr, SockAddrStorage};
+use tracing::debug;
+
+use crate::backend::{BackendPool, get_pool};
+use crate::core::socket::make_backend_udp_socket;
+use crate::core::stream_pump::{Direction, Operation};
+use crate::core::user_data::{CONTROL_ID, unpack_user_data};
+use crate::metrics::{register_worker, worker_metrics};
+use crate::rate_limited;
+use crate::util::address::Address;
+use crate::util::fd::close_fd_quiet;
+
+use super::config::{FlowAffinity, UdpOptions, WorkerConfig};
+use super::drain::WorkerSummary;
+use super::ring::build_ring;
+use super::shutdown::ShutdownSignal;
+use super::uring_ops::{
+    post_cancel, post_drain_deadline, post_recv_msg, post_send_msg, post_shutdown_watch,
+    post_sweep_timer,
+};
+
+/// Largest UDP payload, so no datagram is ever truncated
+const MAX_DATAGRAM: usize = 65_535;
+/// Client datagrams a flow holds while its backend socket is busy; more are
+/// dropped, as a congested network would
+const MAX_QUEUED: usize = 32;
+/// Datagram buffers kept for reuse instead of being freed
+const SPARE_DATAGRAMS: usize = 64;
+/// How often flows are checked for expiry
+const SWEEP_INTERVAL: Duration = Duration::from_secs(1);
+/// While draining, how long a flow waits for the reply to its last datagram
+const DRAIN_IDLE: Duration = Duration::from_secs(1);
+
+/// Bits of a flow id holding the slot index; the generation sits above them.
+const SLOT_BITS: u32 = 32;
+const SLOT_MASK: usize = (1 << SLOT_BITS) - 1;
+const GENERATION_MASK: u32 = (1 << 24) - 1;
+
+/// One datagram and the message header the kernel reads it from or writes
+/// it into.
+struct Datagram {
+    buf: Box<[u8]>,
+    len: usize,
+    addr: libc::sockaddr_storage,
+    iov: libc::iovec,
+    msg: libc::msghdr,
+}
+
+impl Datagram {
+    fn new(buf: Box<[u8]>) -> Box<Self> {
+        Box::new(Self {
+            buf,
+            len: 0,
+            // SAFETY: all zeros is valid for these C structs.
+            addr: unsafe { mem::zeroed() },
+            iov: unsafe { mem::zeroed() },
+            msg: unsafe { mem::zeroed() },
+        })
+    }
+
+    /// Header for receiving into the whole buffer, with room for the
+    /// sender's address.
+    fn recv_header(&mut self) -> *mut libc::msghdr {
+        self.iov = libc::iovec {
+            iov_base: self.buf.as_mut_ptr().cast(),
+            iov_len: self.buf.len(),
+        };
+        self.msg = unsafe { mem::zeroed() };
+        self.msg.msg_name = (&raw mut self.addr).cast();
+        self.msg.msg_namelen = mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+        self.msg.msg_iov = &raw mut self.iov;
+        self.msg.msg_iovlen = 1;
+        &raw mut self.msg
+    }
+
+    /// Header for sending the datagram to `to`, or to the peer the socket is
+    /// connected to.
+    fn send_header(&mut self, to: Option<SocketAddr>) -> *const libc::msghdr {
+        self.iov = libc::iovec {
+            iov_base: self.buf.as_mut_ptr().cast(),
+            iov_len: self.len,
+        };
+        self.msg = unsafe { mem::zeroed() };
+        if let Some(to) = to {
+            let to = SockAddr::from(to);
+            // SAFETY: a socket address always fits in sockaddr_storage.
+            unsafe {
+                ptr::copy_nonoverlapping(
+                    to.as_ptr().cast::<u8>(),
+                    (&raw mut self.addr).cast::<u8>(),
+                    to.len() as usize,
+                );
+            }
+            self.msg.msg_name = (&raw mut self.addr).cast();
+            self.msg.msg_namelen = to.len();
+        }
+        self.msg.msg_iov = &raw mut self.iov;
+        self.msg.msg_iovlen = 1;
+        &raw const self.msg
+    }
+
+    /// Sender of a received datagram.
+    fn source(&self) -> Option<SocketAddr> {
+        let mut storage = SockAddrStorage::zeroed();
+        // SAFETY: the kernel wrote an address of `msg_namelen` bytes.
+        let addr = unsafe {
+            *storage.view_as::<libc::sockaddr_storage>() = self.addr;
+            SockAddr::new(storage, self.msg.msg_namelen)
+        };
+        addr.as_socket()
+    }
+}
+
+/// What a flow's reply buffer is doing.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Reply {
+    /// Waiting for a datagram from the backend
+    Receiving,
+    /// Relaying one to the client
+    Sending,
+    Idle,
+}
+
+/// The datagrams of one client, relayed to one backend.
+struct Flow {
+    id: usize,
+    client: SocketAddr,
+    backend: Address,
+    /// Socket connected to the backend
+    fd: RawFd,
+    /// Client datagrams not yet sent to the backend; the front one is in
+    /// flight while `sending`
+    outgoing: VecDeque<Box<Datagram>>,
+    sending: bool,
+    /// Buffer replies are received into and relayed from
+    reply: Box<Datagram>,
+    reply_state: Reply,
+    /// A datagram went to the backend and nothing came back since
+    awaiting_reply: bool,
+    /// The backend answered at least once
+    answered: bool,
+    started: Instant,
+    last_active: Instant,
+    /// Closed, waiting for its outstanding operations to complete
+    closing: bool,
+}
+
+impl Flow {
+    /// Nothing is on its way to the backend or expected back from it.
+    fn settled(&self) -> bool {
+        self.outgoing.is_empty() && !self.awaiting_reply
+    }
+
+    fn in_flight(&self) -> bool {
+        self.sending || self.reply_state != Reply::Idle
+    }
+}
+
+/// Flows of one worker, by id and by client address.
+///
+/// Ids carry a per-slot generation like the pair ids of `ConnectionPool`,
+/// so completions for a released flow never reach the slot's next one.
+#[derive(Default)]
+struct FlowTable {
+    slots: Vec<Option<Flow>>,
+    generations: Vec<u32>,
+    freelist: Vec<usize>,
+    by_client: HashMap<SocketAddr, usize>,
+}
+
+impl FlowTable {
+    fn insert(&mut self, build: impl FnOnce(usize) -> Flow) -> usize {
+        let slot = self.freelist.pop().unwrap_or_else(|| {
+            self.slots.push(None);
+            self.generations.push(0);
+            self.slots.len() - 1
+        });
+        let id = ((self.generations[slot] as usize) << SLOT_BITS) | slot;
+        let flow = build(id);
+        self.by_client.insert(flow.client, id);
+        self.slots[slot] = Some(flow);
+        id
+    }
+
+    fn get(&self, id: usize) -> Option<&Flow> {
+        self.slots
+            .get(id & SLOT_MASK)
+            .and_then(|f| f.as_ref())
+            .filter(|f| f.id == id)
+    }
+
+    fn get_mut(&mut self, id: usize) -> Option<&mut Flow> {
+        self.slots
+            .get_mut(id & SLOT_MASK)
+            .and_then(|f| f.as_mut())
+            .filter(|f| f.id == id)
+    }
+
+    fn find(&self, client: SocketAddr) -> Option<usize> {
+        self.by_client.get(&client).copied()
+    }
+
+    /// Stop routing the client's datagrams to this flow.
+    fn unlink(&mut self, client: SocketAddr, id: usize) {
+        if self.by_client.get(&client) == Some(&id) {
+            self.by_client.remove(&client);
+        }
+    }
+
+    fn remove(&mut self, id: usize) -> Option<Flow> {
+        let slot = id & SLOT_MASK;
+        let entry = self.slots.get_mut(slot)?;
+        if entry.as_ref().is_none_or(|f| f.id != id) {
+            return None;
+        }
+        let flow = entry.take();
+        self.generations[slot] = (self.generations[slot] + 1) & GENERATION_MASK;
+        self.freelist.push(slot);
+        flow
+    }
+
+    fn ids(&self) -> Vec<usize> {
+        self.slots.iter().flatten().map(|f| f.id).collect()
+    }
+
+    /// Flows still accepting datagrams
+    fn open_count(&self) -> usize {
+        self.by_client.len()
+    }
+
+    fn is_empty(&self) -> bool {
+        self.slots.iter().all(Option::is_none)
+    }
+}
+
+struct UdpRelay {
+    ring: IoUring,
+    listen_fd: RawFd,
+    pool: &'static BackendPool,
+    options: UdpOptions,
+    max_flows: usize,
+    flows: FlowTable,
+    /// Buffers of the listener's receives, by slot; `None` once one has
+    /// completed for good
+    receives: Vec<Option<Box<Datagram>>>,
+    spare: Vec<Box<[u8]>>,
+    draining: bool,
+    /// Read by the kernel when the sweep timer is submitted
+    sweep: Box<types::Timespec>,
+}
+
+/// Run a worker event loop for a UDP listener
+///
+/// `listen_fd` is the worker's SO_REUSEPORT UDP socket. Returns once a
+/// requested drain has let the open flows finish or hit its deadline.
+pub fn run_udp_worker(
+    listen_fd: RawFd,
+    config: WorkerConfig,
+    shutdown: &ShutdownSignal,
+) -> io::Result<WorkerSummary> {
+    register_worker(config.worker_id);
+    let ring = build_ring(config.ring_size, config.ring_mode)?;
+    let pool = get_pool(&config.pool).ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("unknown pool {:?}", config.pool),
+        )
+    })?;
+    let mut relay = UdpRelay {
+        ring,
+        listen_fd,
+        pool,
+        options: config.udp,
+        max_flows: config.pool_capacity,
+        flows: FlowTable::default(),
+        receives: Vec::new(),
+        spare: Vec::new(),
+        draining: false,
+        sweep: Box::new(
+            types::Timespec::new()
+                .sec(SWEEP_INTERVAL.as_secs())
+                .nsec(SWEEP_INTERVAL.subsec_nanos()),
+        ),
+    };
+    for slot in 0..config.initial_accepts {
+        relay.receives.push(None);
+        let datagram = relay.spare_datagram();
+        relay.post_receive(slot, datagram);
+    }
+    post_sweep_timer(&mut relay.ring, &relay.sweep);
+
+    let shutdown_fd = shutdown.register_waker()?;
+    let mut shutdown_buf = [0u8; 8];
+    post_shutdown_watch(&mut relay.ring, shutdown_fd, &mut shutdown_buf);
+    let mut summary = WorkerSummary::default();
+    let mut drain_started: Option<(Instant, Box<types::Timespec>)> = None;
+
+    let mut events: Vec<(u64, i32)> = Vec::with_capacity(512);
+    loop {
+        relay.ring.submit_and_wait(1)?;
+        events.clear();
+        events.extend(
+            relay
+                .ring
+                .completion()
+                .map(|cqe| (cqe.user_data(), cqe.result())),
+        );
+
+        for &(tag, res) in &events {
+            let (id, op) = unpack_user_data(tag);
+            if id == CONTROL_ID {
+                match op {
+                    Operation::Shutdown if drain_started.is_none() => {
+                        if shutdown.is_requested() {
+                            let (in_flight, idle_closed) = relay.begin_drain();
+                            summary.in_flight = in_flight;
+                            summary.idle_closed = idle_closed;
+                            let deadline = Box::new(
+                                types::Timespec::new()
+                                    .sec(config.drain_timeout.as_secs())
+                                    .nsec(config.drain_timeout.subsec_nanos()),
+                            );
+                            post_drain_deadline(&mut relay.ring, &deadline);
+                            drain_started = Some((Instant::now(), deadline));
+                        } else {
+                            post_shutdown_watch(&mut relay.ring, shutdown_fd, &mut shutdown_buf);
+                        }
+                    }
+                    Operation::DrainDeadline => {
+                        summary.forced_closed = relay.close_all();
+                    }
+                    Operation::Timeout(_) => {
+                        relay.expire_flows();
+                        post_sweep_timer(&mut relay.ring, &relay.sweep);
+                    }
+                    _ => {}
+                }
+                continue;
+            }
+
+            match op {
+                Operation::Recv(Direction::ClientToBackend) => relay.client_datagram(id, res),
+                Operation::Send(Direction::ClientToBackend) => relay.backend_sent(id, res),
+                Operation::Recv(Direction::BackendToClient) => relay.backend_reply(id, res),
+                Operation::Send(Direction::BackendToClient) => relay.reply_sent(id, res),
+                _ => {}
+            }
+        }
+
+        if relay.draining && relay.flows.is_empty() && relay.receives.iter().all(Option::is_none) {
+            break;
+        }
+    }
+
+    shutdown.unregister_waker(shutdown_fd);
+    if let Some((started, _)) = drain_started {
+        summary.drain_time = started.elapsed();
+    }
+    Ok(summary)
+}
+
+impl UdpRelay {
+    fn post_receive(&mut self, slot: usize, mut datagram: Box<Datagram>) {
+        let msg = datagram.recv_header();
+        post_recv_msg(
+            &mut self.ring,
+            self.listen_fd,
+            msg,
+            slot,
+            Operation::Recv(Direction::ClientToBackend),
+        );
+        self.receives[slot] = Some(datagram);
+    }
+
+    fn spare_datagram(&mut self) -> Box<Datagram> {
+        let buf = self
+            .spare
+            .pop()
+            .unwrap_or_else(|| vec![0; MAX_DATAGRAM].into_boxed_slice());
+        Datagram::new(buf)
+    }
+
+    fn recycle(&mut self, datagram: Box<Datagram>) {
+        if self.spare.len() < SPARE_DATAGRAMS {
+            self.spare.push(datagram.buf);
+        }
+    }
+
+    /// A datagram from a client arrived on listener receive `slot`.
+    fn client_datagram(&mut self, slot: usize, res: i32) {
+        let Some(mut datagram) = self.receives.get_mut(slot).and_then(Option::take) else {
+            return;
+        };
+        if res < 0 {
+            if res != -libc::ECANCELED && !self.draining {
+                rate_limited!(
+                    warn,
+                    "udp receive failed: {}",
+                    io::Error::from_raw_os_error(-res)
+                );
+                self.post_receive(slot, datagram);
+            }
+            return;
+        }
+        datagram.len = res as usize;
+        let metrics = worker_metrics();
+        metrics.received(Direction::ClientToBackend, datagram.len);
+
+        let Some(client) = datagram.source() else {
+            self.post_receive(slot, datagram);
+            return;
+        };
+        let Some(id) = self.flows.find(client).or_else(|| self.open_flow(client)) else {
+            metrics.udp_dropped.inc();
+            self.post_receive(slot, datagram);
+            return;
+        };
+        let flow = self.flows.get_mut(id).expect("flow just looked up");
+        flow.last_active = Instant::now();
+        if flow.outgoing.len() >= MAX_QUEUED {
+            metrics.udp_dropped.inc();
+            self.post_receive(slot, datagram);
+            return;
+        }
+        flow.outgoing.push_back(datagram);
+        self.forward(id);
+        let fresh = self.spare_datagram();
+        self.post_receive(slot, fresh);
+    }
+
+    /// Start a flow for `client` with a backend picked for it.
+    fn open_flow(&mut self, client: SocketAddr) -> Option<usize> {
+        if self.flows.open_count() >= self.max_flows {
+            rate_limited!(warn, max = self.max_flows, "udp flow limit reached");
+            return None;
+        }
+        let backend = match flow_key(client, self.options.affinity) {
+            Some(key) => self.pool.select_by_hash(key),
+            None => self.pool.select(),
+        };
+        let Some(backend) = backend else {
+            worker_metrics().no_backend.inc();
+            rate_limited!(warn, "no backend for udp flow");
+            return None;
+        };
+        let fd = backend
+            .inet()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::Unsupported, "not an IP address"))
+            .and_then(make_backend_udp_socket);
+        let fd = match fd {
+            Ok(fd) => fd,
+            Err(e) => {
+                rate_limited!(warn, %backend, "udp backend socket: {e}");
+                self.pool.report_failure(backend);
+                return None;
+            }
+        };
+        let reply = self.spare_datagram();
+        let now = Instant::now();
+        let id = self.flows.insert(|id| Flow {
+            id,
+            client,
+            backend,
+            fd,
+            outgoing: VecDeque::new(),
+            sending: false,
+            reply,
+            reply_state: Reply::Idle,
+            awaiting_reply: false,
+            answered: false,
+            started: now,
+            last_active: now,
+            closing: false,
+        });
+        worker_metrics().udp_flows_opened.inc();
+        debug!(%client, %backend, "udp flow opened");
+        self.receive_reply(id);
+        Some(id)
+    }
+
+    /// Send the flow's next queued datagram, unless one is in flight.
+    fn forward(&mut self, id: usize) {
+        let Some(flow) = self.flows.get_mut(id) else {
+            return;
+        };
+        if flow.sending || flow.closing {
+            return;
+        }
+        let Some(datagram) = flow.outgoing.front_mut() else {
+            return;
+        };
+        let msg = datagram.send_header(None);
+        flow.sending = true;
+        let fd = flow.fd;
+        post_send_msg(
+            &mut self.ring,
+            fd,
+            msg,
+            id,
+            Operation::Send(Direction::ClientToBackend),
+        );
+    }
+
+    fn receive_reply(&mut self, id: usize) {
+        let Some(flow) = self.flows.get_mut(id) else {
+            return;
+        };
+        let msg = flow.reply.recv_header();
+        flow.reply_state = Reply::Receiving;
+        let fd = flow.fd;
+        post_recv_msg(
+            &mut self.ring,
+            fd,
+            msg,
+            id,
+            Operation::Recv(Direction::BackendToClient),
+        );
+    }
+
+    fn backend_sent(&mut self, id: usize, res: i32) {
+        let Some(flow) = self.flows.get_mut(id) else {
+            return;
+        };
+        flow.sending = false;
+        let sent = flow.outgoing.pop_front();
+        if res >= 0 {
+            worker_metrics().sent(Direction::ClientToBackend, res as usize);
+            flow.awaiting_reply = true;
+        }
+        if let Some(datagram) = sent {
+            self.recycle(datagram);
+        }
+        if res < 0 {
+            self.backend_failed(id, res);
+        } else {
+            self.forward(id);
+        }
+        self.release_if_done(id);
+    }
+
+    fn backend_reply(&mut self, id: usize, res: i32) {
+        let Some(flow) = self.flows.get_mut(id) else {
+            return;
+        };
+        flow.reply_state = Reply::Idle;
+        if flow.closing {
+            self.release_if_done(id);
+            return;
+        }
+        if res < 0 {
+            self.backend_failed(id, res);
+            self.release_if_done(id);
+            return;
+        }
+        worker_metrics().received(Direction::BackendToClient, res as usize);
+        flow.reply.len = res as usize;
+        flow.awaiting_reply = false;
+        flow.last_active = Instant::now();
+        if !flow.answered {
+            flow.answered = true;
+            self.pool.report_success(flow.backend);
+        }
+        let msg = flow.reply.send_header(Some(flow.client));
+        flow.reply_state = Reply::Sending;
+        post_send_msg(
+            &mut self.ring,
+            self.listen_fd,
+            msg,
+            id,
+            Operation::Send(Direction::BackendToClient),
+        );
+    }
+
+    fn reply_sent(&mut self, id: usize, res: i32) {
+        let Some(flow) = self.flows.get_mut(id) else {
+            return;
+        };
+        flow.reply_state = Reply::Idle;
+        if res < 0 {
+            rate_limited!(
+                debug,
+                client = %flow.client,
+                "udp reply not sent: {}",
+                io::Error::from_raw_os_error(-res)
+            );
+        } else {
+            worker_metrics().sent(Direction::BackendToClient, res as usize);
+        }
+        if flow.closing {
+            self.release_if_done(id);
+        } else if self.draining && flow.settled() {
+            self.close_flow(id, "drained");
+        } else {
+            self.receive_reply(id);
+        }
+    }
+
+    /// The backend socket of a flow failed; a refusal (an ICMP port
+    /// unreachable for an earlier datagram) counts against the backend.
+    fn backend_failed(&mut self, id: usize, res: i32) {
+        let Some(flow) = self.flows.get(id) else {
+            return;
+        };
+        if res == -libc::ECONNREFUSED {
+            self.pool.report_failure(flow.backend);
+        }
+        rate_limited!(
+            debug,
+            backend = %flow.backend,
+            "udp backend failed: {}",
+            io::Error::from_raw_os_error(-res)
+        );
+        self.close_flow(id, "backend failed");
+    }
+
+    /// Stop relaying for a flow; it is released once nothing is in flight.
+    fn close_flow(&mut self, id: usize, reason: &str) {
+        let Some(flow) = self.flows.get_mut(id) else {
+            return;
+        };
+        if flow.closing {
+            return;
+        }
+        flow.closing = true;
+        let (client, backend, receiving) = (
+            flow.client,
+            flow.backend,
+            flow.reply_state == Reply::Receiving,
+        );
+        self.flows.unlink(client, id);
+        worker_metrics().udp_flows_closed.inc();
+        debug!(%client, %backend, reason, "udp flow closed");
+        if receiving {
+            post_cancel(
+                &mut self.ring,
+                id,
+                Operation::Recv(Direction::BackendToClient),
+            );
+        }
+        self.release_if_done(id);
+    }
+
+    fn release_if_done(&mut self, id: usize) {
+        if !self
+            .flows
+            .get_mut(id)
+            .is_some_and(|f| f.closing && !f.in_flight())
+        {
+            return;
+        }
+        let Some(flow) = self.flows.remove(id) else {
+            return;
+        };
+        close_fd_quiet(flow.fd);
+        self.recycle(flow.reply);
+        for datagram in flow.outgoing {
+            self.recycle(datagram);
+        }
+    }
+
+    /// Close the flows that went idle or outlived their session.
+    fn expire_flows(&mut self) {
+        let now = Instant::now();
+        let idle_limit = if self.draining {
+            self.options.idle_timeout.min(DRAIN_IDLE)
+        } else {
+            self.options.idle_timeout
+        };
+        for id in self.flows.ids() {
+            let Some(flow) = self.flows.get_mut(id) else {
+                continue;
+            };
+            if flow.closing {
+                continue;
+            }
+            let reason = if now.duration_since(flow.last_active) >= idle_limit {
+                "idle"
+            } else if self
+                .options
+                .session_timeout
+                .is_some_and(|limit| now.duration_since(flow.started) >= limit)
+                && flow.outgoing.is_empty()
+            {
+                "session timeout"
+            } else {
+                continue;
+            };
+            self.close_flow(id, reason);
+        }
+    }
+
+    /// Stop receiving from clients and close the flows with nothing left to
+    /// relay. Returns how many flows are still waiting on their backend and
+    /// how many were closed.
+    fn begin_drain(&mut self) -> (usize, usize) {
+        self.draining = true;
+        for slot in 0..self.receives.len() {
+            if self.receives[slot].is_some() {
+                post_cancel(
+                    &mut self.ring,
+                    slot,
+                    Operation::Recv(Direction::ClientToBackend),
+                );
+            }
+        }
+        let (mut in_flight, mut closed) = (0, 0);
+        for id in self.flows.ids() {
+            let Some(flow) = self.flows.get_mut(id) else {
+                continue;
+            };
+            if flow.settled() {
+                self.close_flow(id, "drained");
+                closed += 1;
+            } else {
+                in_flight += 1;
+            }
+        }
+        (in_flight, closed)
+    }
+
+    /// Close every flow, returning how many were still open.
+    fn close_all(&mut self) -> usize {
+        let open = self.flows.open_count();
+        for id in self.flows.ids() {
+            self.close_flow(id, "drain deadline");
+        }
+        open
+    }
+}
+
+/// What a client's flows are hashed by, if they have affinity.
+fn flow_key(client: SocketAddr, affinity: FlowAffinity) -> Option<u64> {
+    let mut hasher = DefaultHasher::new();
+    // IPv4 clients of a dual-stack listener arrive IPv4-mapped
+    let ip = client.ip().to_canonical();
+    match affinity {
+        FlowAffinity::None => return None,
+        FlowAffinity::ClientIp => ip.hash(&mut hasher),
+        FlowAffinity::ClientAddress => (ip, client.port()).hash(&mut hasher),
+    }
+    Some(hasher.finish())
+}
@@ -1,18 +1,45 @@
-use std::net::SocketAddr;
+//! io_uring SQE submission helpers
+//!
+//! These functions submit various operations to the io_uring submission queue.
+//! They handle the low-level details of creating SQEs with proper user_data tagging.
+
 use std::os::fd::RawFd;
 use std::{io, ptr};
 
-use io_uring::{opcode, types, IoUring};
+use io_uring::{opcode, squeue, types, IoUring};
 
 use crate::core::connection_pair::ConnectionPair;
 use crate::core::socket::make_backend_socket;
-use crate::core::stream_pump::{Operation, StreamPump};
-use crate::core::user_data::pack_user_data;
+use crate::core::stream_pump::{Direction, Operation, StreamPump};
+use crate::core::user_data::{CONTROL_ID, pack_user_data};
+use crate::metrics::worker_metrics;
+use crate::tls::ktls::RecordRecv;
+use crate::tls::{TlsSession, UpstreamTls};
+use crate::util::address::Address;
 
-/// io_uring SQE submission helpers
+/// Push an SQE, flushing the queue to the kernel first if it is full.
 ///
-/// These functions submit various operations to the io_uring submission queue.
-/// They handle the low-level details of creating SQEs with proper user_data tagging.
+/// # Safety
+/// Same contract as `SubmissionQueue::push`: buffers referenced by the
+/// entry must stay valid until it completes.
+pub unsafe fn push_sqe(ring: &mut IoUring, sqe: &squeue::Entry, what: &str) {
+    if unsafe { ring.submission().push(sqe) }.is_ok() {
+        return;
+    }
+    worker_metrics().sq_full.inc();
+    ring.submit().expect("io_uring submit failed");
+    if ring.params().is_setup_sqpoll() {
+        // the poll thread frees slots asynchronously
+        ring.submitter()
+            .squeue_wait()
+            .expect("io_uring sq wait failed");
+    }
+    unsafe {
+        ring.submission()
+            .push(sqe)
+            .unwrap_or_else(|_| panic!("SQ full ({what})"));
+    }
+}
 
 /// Post an accept operation for a new client connection
 pub fn post_accept(ring: &mut IoUring, listen_fd: RawFd, pair_id: usize) {
@@ -20,34 +47,49 @@ pub fn post_accept(ring: &mut IoUring, listen_fd: RawFd, pair_id: usize) {
         .build()
         .user_data(pack_user_data(pair_id, Operation::Accept));
     unsafe {
-        ring.submission()
-            .push(&sqe)
-            .expect("SQ full (accept)");
+        push_sqe(ring, &sqe, "accept");
     }
 }
 
 /// Post a recv operation to read HTTP headers from the client
+///
+/// A PROXY protocol header comes ahead of the TLS handshake, so it is read
+/// with a plain recv on TLS connections too.
 pub fn post_recv_headers(ring: &mut IoUring, pair: &mut ConnectionPair) {
+    if let Some(tls) = pair.tls.as_mut().filter(|_| !pair.awaiting_proxy_header) {
+        post_tls_recv(ring, pair.id, tls, pair.client_fd, Operation::RecvHeaders);
+        return;
+    }
     let (ptr, len) = pair.header_buffer.write_ptr_len();
+    if let Some(record) = pair.ktls.as_mut() {
+        let msg = record.prepare(ptr, len);
+        post_recv_msg(ring, pair.client_fd, msg, pair.id, Operation::RecvHeaders);
+        return;
+    }
     let sqe = opcode::Recv::new(types::Fd(pair.client_fd), ptr, len as u32)
         .build()
         .user_data(pack_user_data(pair.id, Operation::RecvHeaders));
     unsafe {
-        ring.submission()
-            .push(&sqe)
-            .expect("SQ full (recv headers)");
+        push_sqe(ring, &sqe, "recv headers");
     }
 }
 
 /// Post a connect operation to establish backend connection
 ///
 /// Uses blocking connect (instant for localhost), then switches to non-blocking for io_uring.
+/// With `tls` the pair gets a fresh client session, which resumes an earlier
+/// one with this backend where the session cache has it.
 pub fn post_connect_backend(
     ring: &mut IoUring,
     pair: &mut ConnectionPair,
-    backend_addr: SocketAddr,
+    backend_addr: Address,
+    tls: Option<&UpstreamTls>,
 ) -> io::Result<()> {
-    let (backend_fd, storage, slen) = make_backend_socket(backend_addr)?;
+    if let Some(tls) = tls {
+        let session = tls.session(backend_addr).map_err(io::Error::other)?;
+        pair.backend_tls = Some(Box::new(session));
+    }
+    let (backend_fd, storage, slen) = make_backend_socket(&backend_addr)?;
     pair.attach_backend_socket(backend_fd);
 
     // Store sockaddr first so we can get a stable pointer
@@ -78,9 +120,7 @@ pub fn post_connect_backend(
         .build()
         .user_data(pack_user_data(pair.id, Operation::ConnectBackend));
     unsafe {
-        ring.submission()
-            .push(&sqe)
-            .expect("SQ full (nop)");
+        push_sqe(ring, &sqe, "nop");
     }
 
     Ok(())
@@ -88,12 +128,14 @@ pub fn post_connect_backend(
 
 /// Post a recv operation on a stream pump
 ///
-/// This receives data from the source FD into the pump's buffer.
+/// This receives data from the source FD into the pump's buffer, through
+/// `record` when the source is a kernel TLS socket.
 /// Only posts if there's free space and no recv is already in flight.
 pub fn post_recv_pump(
     ring: &mut IoUring,
     pair_id: usize,
     pump: &mut StreamPump,
+    record: Option<&mut RecordRecv>,
     tag: Operation,
 ) {
     if pump.recv_in_flight {
@@ -104,15 +146,17 @@ pub fn post_recv_pump(
         return;
     }
     let ptr = unsafe { pump.buffer.as_mut_ptr().add(pump.bytes_ready_to_send) };
-    let len = free as u32;
-    let sqe = opcode::Recv::new(types::Fd(pump.read_fd), ptr, len)
+    pump.recv_in_flight = true;
+    if let Some(record) = record {
+        let msg = record.prepare(ptr, free);
+        post_recv_msg(ring, pump.read_fd, msg, pair_id, tag);
+        return;
+    }
+    let sqe = opcode::Recv::new(types::Fd(pump.read_fd), ptr, free as u32)
         .build()
         .user_data(pack_user_data(pair_id, tag));
-    pump.recv_in_flight = true;
     unsafe {
-        ring.submission()
-            .push(&sqe)
-            .expect("SQ full (recv pump)");
+        push_sqe(ring, &sqe, "recv pump");
     }
 }
 
@@ -140,8 +184,122 @@ pub fn post_send_pump(
         .user_data(pack_user_data(pair_id, tag));
     pump.send_in_flight = true;
     unsafe {
-        ring.submission()
-            .push(&sqe)
-            .expect("SQ full (send pump)");
+        push_sqe(ring, &sqe, "send pump");
+    }
+}
+
+/// Post a recv on a TLS connection
+///
+/// Ciphertext lands in the session's receive buffer. When the session still
+/// holds decrypted bytes a Nop is posted instead, so the completion hands
+/// those out without waiting for the peer.
+pub fn post_tls_recv(
+    ring: &mut IoUring,
+    pair_id: usize,
+    tls: &mut TlsSession,
+    fd: RawFd,
+    tag: Operation,
+) {
+    let sqe = match tls.recv_target() {
+        Some((ptr, len)) => opcode::Recv::new(types::Fd(fd), ptr, len as u32).build(),
+        None => opcode::Nop::new().build(),
+    };
+    unsafe {
+        push_sqe(ring, &sqe.user_data(pack_user_data(pair_id, tag)), "tls recv");
+    }
+}
+
+/// Send queued ciphertext to the peer, unless a send is outstanding
+pub fn post_tls_send(
+    ring: &mut IoUring,
+    pair_id: usize,
+    tls: &mut TlsSession,
+    fd: RawFd,
+    tag: Operation,
+) {
+    let Some((ptr, len)) = tls.send_target() else {
+        return;
+    };
+    let sqe = opcode::Send::new(types::Fd(fd), ptr, len as u32)
+        .build()
+        .user_data(pack_user_data(pair_id, tag));
+    unsafe {
+        push_sqe(ring, &sqe, "tls send");
+    }
+}
+
+/// Post a read on the worker's shutdown eventfd
+///
+/// `buf` receives the eventfd counter and must outlive the operation.
+pub fn post_shutdown_watch(ring: &mut IoUring, event_fd: RawFd, buf: &mut [u8; 8]) {
+    let sqe = opcode::Read::new(types::Fd(event_fd), buf.as_mut_ptr(), buf.len() as u32)
+        .build()
+        .user_data(pack_user_data(CONTROL_ID, Operation::Shutdown));
+    unsafe {
+        push_sqe(ring, &sqe, "shutdown watch");
+    }
+}
+
+/// Cancel the outstanding accept on a pool slot
+pub fn post_cancel_accept(ring: &mut IoUring, pair_id: usize) {
+    post_cancel(ring, pair_id, Operation::Accept);
+}
+
+/// Cancel the outstanding operation `op` of `id`
+pub fn post_cancel(ring: &mut IoUring, id: usize, op: Operation) {
+    let sqe = opcode::AsyncCancel::new(pack_user_data(id, op))
+        .build()
+        .user_data(pack_user_data(CONTROL_ID, Operation::Cancel));
+    unsafe {
+        push_sqe(ring, &sqe, "cancel");
+    }
+}
+
+/// Post a recvmsg of one datagram
+///
+/// The header, and the buffer and address it points to, must stay put
+/// until the operation completes.
+pub fn post_recv_msg(ring: &mut IoUring, fd: RawFd, msg: *mut libc::msghdr, id: usize, tag: Operation) {
+    let sqe = opcode::RecvMsg::new(types::Fd(fd), msg)
+        .build()
+        .user_data(pack_user_data(id, tag));
+    unsafe {
+        push_sqe(ring, &sqe, "recvmsg");
+    }
+}
+
+/// Post a sendmsg of one datagram, under the same contract as `post_recv_msg`
+pub fn post_send_msg(ring: &mut IoUring, fd: RawFd, msg: *const libc::msghdr, id: usize, tag: Operation) {
+    let sqe = opcode::SendMsg::new(types::Fd(fd), msg)
+        .build()
+        .user_data(pack_user_data(id, tag));
+    unsafe {
+        push_sqe(ring, &sqe, "sendmsg");
+    }
+}
+
+/// Arm the timer of a worker's periodic housekeeping
+///
+/// As with the drain deadline, `timespec` must stay alive until the next
+/// submit.
+pub fn post_sweep_timer(ring: &mut IoUring, timespec: &types::Timespec) {
+    let sqe = opcode::Timeout::new(timespec)
+        .build()
+        .user_data(pack_user_data(CONTROL_ID, Operation::Timeout(Direction::ClientToBackend)));
+    unsafe {
+        push_sqe(ring, &sqe, "sweep timer");
+    }
+}
+
+/// Arm the drain deadline timer
+///
+/// The kernel reads `timespec` when the SQE is submitted, so it must stay
+/// alive until the next submit.
+pub fn post_drain_deadline(ring: &mut IoUring, timespec: &types::Timespec) {
+    let sqe = opcode::Timeout::new(timespec)
+        .build()
+        .user_data(pack_user_data(CONTROL_ID, Operation::DrainDeadline));
+    unsafe {
+        push_sqe(ring, &sqe, "drain deadline");
     }
 }
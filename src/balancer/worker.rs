@@ -1,40 +1,56 @@
 use std::io;
 use std::os::fd::RawFd;
 
-use io_uring::IoUring;
-
 use crate::{
+    access_log,
     backend::BackendConnectionCache,
-    balancer::config::WorkerConfig,
+    balancer::config::{ListenerMode, WorkerConfig},
     core::{
         stream_pump::{Direction, Operation},
-        user_data::unpack_user_data,
+        user_data::{CONTROL_ID, unpack_user_data},
     },
+    metrics::register_worker,
 };
 
 use super::{
     connection_pool::ConnectionPool,
+    drain::{DrainState, WorkerSummary, begin_drain, force_close},
     handlers::{
-        handle_accept, handle_connect_backend, handle_recv_backend_to_client,
+        handle_accept, handle_client_hello, handle_connect_backend, handle_proxy_header, handle_recv_backend_to_client,
         handle_recv_client_to_backend, handle_recv_headers, handle_send_backend_to_client,
-        handle_send_client_to_backend,
+        handle_send_client_to_backend, start_passthrough,
     },
-    uring_ops::post_accept,
+    ring::build_ring,
+    router::Router,
+    shutdown::ShutdownSignal,
+    udp::run_udp_worker,
+    uring_ops::{post_accept, post_shutdown_watch},
 };
 
 /// Run a worker event loop
 ///
 /// This is the main io_uring reactor that processes incoming connections,
 /// parses HTTP headers, routes to backends, and streams data bidirectionally.
+/// In TCP mode connections skip the parsing and go straight to a backend;
+/// UDP listeners run the flow relay of `udp::run_udp_worker` instead.
 ///
 /// # Arguments
 /// * `listen_fd` - File descriptor for the listening socket (SO_REUSEPORT)
 /// * `config` - Worker configuration
-pub fn run_worker(listen_fd: RawFd, config: WorkerConfig) -> io::Result<()> {
-    let mut ring = IoUring::builder()
-        .setup_single_issuer()
-        .setup_defer_taskrun()
-        .build(config.ring_size)?;
+/// * `shutdown` - Shared signal that switches the worker into draining
+///
+/// Returns once a requested drain has completed or hit its deadline.
+pub fn run_worker(
+    listen_fd: RawFd,
+    config: WorkerConfig,
+    shutdown: &ShutdownSignal,
+) -> io::Result<WorkerSummary> {
+    if config.mode == ListenerMode::Udp {
+        return run_udp_worker(listen_fd, config, shutdown);
+    }
+    register_worker(config.worker_id);
+    let mut ring = build_ring(config.ring_size, config.ring_mode)?;
+    let router = Router::new(&config)?;
 
     let mut pool = ConnectionPool::new(
         config.pool_capacity,
@@ -44,7 +60,7 @@ pub fn run_worker(listen_fd: RawFd, config: WorkerConfig) -> io::Result<()> {
     let mut backend_connection_cache = {
         let cache = BackendConnectionCache::new();
         if cache.is_none() {
-            eprintln!("Failed to instantiate backend connection cache.");
+            tracing::error!("failed to instantiate backend connection cache");
         };
         cache.unwrap()
     };
@@ -55,14 +71,18 @@ pub fn run_worker(listen_fd: RawFd, config: WorkerConfig) -> io::Result<()> {
         post_accept(&mut ring, listen_fd, id);
     }
 
+    let shutdown_fd = shutdown.register_waker()?;
+    let mut shutdown_buf = [0u8; 8];
+    post_shutdown_watch(&mut ring, shutdown_fd, &mut shutdown_buf);
+    let mut drain: Option<DrainState> = None;
+
     let mut events: [(u64, i32); 512] = [(0, 0); 512];
-    let mut event_count_batch: usize = 0;
 
     loop {
-        event_count_batch = 0;
+        let mut event_count_batch: usize = 0;
         {
-            let mut cq = ring.completion();
-            while let Some(cqe) = cq.next() {
+            let cq = ring.completion();
+            for cqe in cq {
                 if event_count_batch < 512 {
                     events[event_count_batch] = (cqe.user_data(), cqe.result());
                     event_count_batch += 1;
@@ -88,6 +108,8 @@ pub fn run_worker(listen_fd: RawFd, config: WorkerConfig) -> io::Result<()> {
 
         // if still no events nor pending work, block until at least one arrives
         if event_count_batch == 0 {
+            // don't sit on access log lines while idle
+            access_log::flush();
             ring.submit_and_wait(1)?;
 
             let cq = ring.completion();
@@ -99,43 +121,114 @@ pub fn run_worker(listen_fd: RawFd, config: WorkerConfig) -> io::Result<()> {
             }
         }
 
-        for i in 0..event_count_batch {
-            let (tag, res) = events[i];
+        for &(tag, res) in &events[..event_count_batch] {
             let (id, op) = unpack_user_data(tag);
 
-            let Some(_pair) = pool.get_mut(id) else {
+            if id == CONTROL_ID {
+                match op {
+                    Operation::Shutdown if drain.is_none() => {
+                        if shutdown.is_requested() {
+                            drain = Some(begin_drain(
+                                &mut ring,
+                                &mut pool,
+                                &mut backend_connection_cache,
+                                config.drain_timeout,
+                            ));
+                        } else {
+                            post_shutdown_watch(&mut ring, shutdown_fd, &mut shutdown_buf);
+                        }
+                    }
+                    Operation::DrainDeadline => {
+                        if let Some(d) = drain.as_mut() {
+                            d.summary.forced_closed = force_close(&mut pool);
+                            d.deadline_expired = true;
+                        }
+                    }
+                    _ => {}
+                }
+                continue;
+            }
+
+            let Some(pair) = pool.get_mut(id) else {
+                pool.completed_after_teardown(id, op);
                 continue;
             };
+            let span = pair.current_span();
+            let _entered = span.enter();
+            let awaiting_proxy_header = pair.awaiting_proxy_header;
 
             match op {
                 Operation::Accept => {
-                    handle_accept(&mut ring, &mut pool, id, res, listen_fd, &config)
+                    let accepted = handle_accept(
+                        &mut ring,
+                        &mut pool,
+                        id,
+                        res,
+                        listen_fd,
+                        &config,
+                        drain.is_some(),
+                    );
+                    if accepted && config.mode == ListenerMode::Tcp {
+                        start_passthrough(&mut ring, &mut pool, &router, id);
+                    }
                 }
 
-                Operation::RecvHeaders => handle_recv_headers(
+                Operation::RecvHeaders if awaiting_proxy_header => handle_proxy_header(
                     &mut ring,
                     &mut pool,
                     &mut backend_connection_cache,
+                    &router,
                     id,
                     res,
+                    &config,
                 ),
 
-                Operation::ConnectBackend => handle_connect_backend(&mut ring, &mut pool, id, res),
-
-                Operation::Recv(Direction::ClientToBackend) => {
-                    handle_recv_client_to_backend(&mut ring, &mut pool, id, res)
+                Operation::RecvHeaders if config.mode == ListenerMode::Tcp => {
+                    handle_client_hello(&mut ring, &mut pool, &router, id, res)
                 }
 
-                Operation::Send(Direction::ClientToBackend) => {
-                    handle_send_client_to_backend(&mut ring, &mut pool, id, res)
+                Operation::RecvHeaders => handle_recv_headers(
+                    &mut ring,
+                    &mut pool,
+                    &mut backend_connection_cache,
+                    &router,
+                    id,
+                    res,
+                    &config,
+                ),
+
+                Operation::ConnectBackend => {
+                    handle_connect_backend(&mut ring, &mut pool, id, res)
                 }
 
+                Operation::Recv(Direction::ClientToBackend) => handle_recv_client_to_backend(
+                    &mut ring,
+                    &mut pool,
+                    &mut backend_connection_cache,
+                    &router,
+                    id,
+                    res,
+                    &config,
+                ),
+
+                Operation::Send(Direction::ClientToBackend) => handle_send_client_to_backend(
+                    &mut ring,
+                    &mut pool,
+                    &mut backend_connection_cache,
+                    &router,
+                    id,
+                    res,
+                    &config,
+                ),
+
                 Operation::Recv(Direction::BackendToClient) => handle_recv_backend_to_client(
                     &mut ring,
                     &mut pool,
                     &mut backend_connection_cache,
+                    &router,
                     id,
                     res,
+                    &config,
                 ),
 
                 Operation::Send(Direction::BackendToClient) => {
@@ -145,7 +238,23 @@ pub fn run_worker(listen_fd: RawFd, config: WorkerConfig) -> io::Result<()> {
                 Operation::Timeout(_) => {
                     pool.teardown(id);
                 }
+
+                Operation::Shutdown | Operation::Cancel | Operation::DrainDeadline => {}
             }
         }
+
+        access_log::flush_due();
+        backend_connection_cache.sweep();
+
+        if let Some(d) = &drain
+            && (d.deadline_expired || pool.active_count() == 0)
+        {
+            break;
+        }
     }
+
+    backend_connection_cache.close_all();
+    access_log::flush();
+    shutdown.unregister_waker(shutdown_fd);
+    Ok(drain.map(DrainState::finish).unwrap_or_default())
 }
@@ -0,0 +1,50 @@
+//! Membership updates waiting to be piggybacked
+//!
+//! Every ping, ping-req and ack carries as many updates as fit, those sent
+//! the fewest times first. An update is dropped once it has gone out λ·log(N)
+//! times, by which point it has most likely reached every member, and a newer
+//! update about the same member replaces it.
+
+use std::net::SocketAddr;
+
+use super::message::Update;
+
+struct Pending {
+    update: Update,
+    transmits: u32,
+}
+
+#[derive(Default)]
+pub struct Gossip {
+    pending: Vec<Pending>,
+}
+
+impl Gossip {
+    pub fn push(&mut self, update: Update) {
+        self.pending.retain(|p| p.update.member != update.member);
+        self.pending.push(Pending {
+            update,
+            transmits: 0,
+        });
+    }
+
+    /// Updates fitting in `room` bytes, counted as sent; those sent `limit`
+    /// times are forgotten. `skip` is left out, for updates the message
+    /// carries already.
+    pub fn take(&mut self, room: usize, limit: u32, skip: Option<SocketAddr>) -> Vec<Update> {
+        self.pending.sort_by_key(|p| p.transmits);
+        let mut room = room;
+        let mut updates = Vec::new();
+        for pending in &mut self.pending {
+            let len = pending.update.encoded_len();
+            if Some(pending.update.member) == skip || len > room {
+                continue;
+            }
+            room -= len;
+            pending.transmits += 1;
+            updates.push(pending.update);
+        }
+        self.pending.retain(|p| p.transmits < limit);
+        updates
+    }
+}
@@ -0,0 +1,178 @@
+//! The other members, in the shuffled order they are probed in
+//!
+//! Probes go round the list one member per protocol period, and the list is
+//! shuffled before each round rather than a target being drawn at random
+//! each period, so probes from different members are spread across the
+//! cluster and no member waits more than N periods for its next one. A
+//! member joining mid-round goes in at a random place among those still to
+//! be probed, and one leaving is just taken out, so churn never restarts a
+//! round that members already probed would be first in again.
+
+use std::net::SocketAddr;
+
+use crate::util::random;
+
+#[derive(Debug, Default)]
+pub struct Members {
+    list: Vec<SocketAddr>,
+    /// Position of the next member to probe
+    next: usize,
+}
+
+impl Members {
+    pub fn len(&self) -> usize {
+        self.list.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.list.is_empty()
+    }
+
+    pub fn contains(&self, addr: SocketAddr) -> bool {
+        self.list.contains(&addr)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = SocketAddr> + '_ {
+        self.list.iter().copied()
+    }
+
+    /// Returns false if `addr` is already a member.
+    pub fn add(&mut self, addr: SocketAddr) -> bool {
+        if self.contains(addr) {
+            return false;
+        }
+        // anywhere from the next member to probe to the end of the round
+        let ahead = (self.list.len() - self.next + 1) as u64;
+        let pos = self.next + (random::next_u64() % ahead) as usize;
+        self.list.insert(pos, addr);
+        true
+    }
+
+    /// Returns false if `addr` was not a member.
+    pub fn remove(&mut self, addr: SocketAddr) -> bool {
+        let Some(pos) = self.list.iter().position(|&a| a == addr) else {
+            return false;
+        };
+        self.list.remove(pos);
+        if pos < self.next {
+            self.next -= 1;
+        }
+        true
+    }
+
+    /// The member to probe this period; `None` while there are no others.
+    pub fn next_target(&mut self) -> Option<SocketAddr> {
+        if self.list.is_empty() {
+            return None;
+        }
+        if self.next >= self.list.len() {
+            shuffle(&mut self.list);
+            self.next = 0;
+        }
+        let addr = self.list[self.next];
+        self.next += 1;
+        Some(addr)
+    }
+
+    /// Up to `k` members picked at random, other than `exclude`.
+    pub fn random(&self, k: usize, exclude: SocketAddr) -> Vec<SocketAddr> {
+        let mut others: Vec<SocketAddr> = self.iter().filter(|&a| a != exclude).collect();
+        let k = k.min(others.len());
+        // the first k steps of a Fisher-Yates shuffle
+        for i in 0..k {
+            let j = i + (random::next_u64() % (others.len() - i) as u64) as usize;
+            others.swap(i, j);
+        }
+        others.truncate(k);
+        others
+    }
+}
+
+fn shuffle(list: &mut [SocketAddr]) {
+    for i in (1..list.len()).rev() {
+        let j = (random::next_u64() % (i as u64 + 1)) as usize;
+        list.swap(i, j);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(port: u16) -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], port))
+    }
+
+    fn members(ports: &[u16]) -> Members {
+        let mut members = Members::default();
+        for &port in ports {
+            members.add(addr(port));
+        }
+        members
+    }
+
+    /// The targets of the next `n` periods.
+    fn probes(members: &mut Members, n: usize) -> Vec<SocketAddr> {
+        (0..n).filter_map(|_| members.next_target()).collect()
+    }
+
+    fn sorted(mut list: Vec<SocketAddr>) -> Vec<SocketAddr> {
+        list.sort_unstable();
+        list
+    }
+
+    #[test]
+    fn every_member_once_per_round() {
+        let mut members = members(&[1, 2, 3, 4, 5]);
+        for _ in 0..10 {
+            let round = probes(&mut members, 5);
+            assert_eq!(sorted(round), (1..=5).map(addr).collect::<Vec<_>>());
+        }
+    }
+
+    #[test]
+    fn joiner_goes_among_those_still_to_probe() {
+        for _ in 0..50 {
+            let mut members = members(&[1, 2, 3, 4]);
+            let mut round = probes(&mut members, 2);
+            members.add(addr(9));
+            round.extend(probes(&mut members, 3));
+            assert_eq!(sorted(round), [1, 2, 3, 4, 9].map(addr));
+        }
+    }
+
+    #[test]
+    fn leaving_keeps_the_round() {
+        for _ in 0..50 {
+            let mut members = members(&[1, 2, 3, 4, 5]);
+            let mut round = probes(&mut members, 2);
+            // one already probed and one not
+            let probed = round[0];
+            let waiting = (1..=5).map(addr).find(|a| !round.contains(a)).unwrap();
+            members.remove(probed);
+            members.remove(waiting);
+            round.extend(probes(&mut members, 2));
+            let expected: Vec<SocketAddr> = (1..=5).map(addr).filter(|&a| a != waiting).collect();
+            assert_eq!(sorted(round), expected);
+        }
+    }
+
+    #[test]
+    fn add_and_remove_report_membership() {
+        let mut members = members(&[1]);
+        assert!(!members.add(addr(1)));
+        assert!(members.remove(addr(1)));
+        assert!(!members.remove(addr(1)));
+        assert_eq!(members.next_target(), None);
+    }
+
+    #[test]
+    fn random_leaves_out_the_excluded() {
+        let members = members(&[1, 2, 3]);
+        for _ in 0..20 {
+            let picked = members.random(5, addr(2));
+            assert_eq!(sorted(picked), [addr(1), addr(3)]);
+        }
+        assert_eq!(members.random(1, addr(2)).len(), 1);
+    }
+}
@@ -0,0 +1,334 @@
+//! Messages between cluster members, one per UDP datagram
+//!
+//! ```text
+//! version u8 | kind u8 | seq u32 | incarnation u32 | target (ping-req only)
+//!     | count u8 | count * (status u8 | incarnation u32 | member
+//!     | suspecting member (suspect only))
+//! ```
+//!
+//! Addresses are a family byte (4 or 6), the IP address and a port, all
+//! integers big-endian. `seq` ties an ack to the probe it answers, and
+//! `incarnation` is the sender's own. The updates after it are the gossip
+//! piggybacked on the message.
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+/// Messages are kept within this many bytes, under the usual MTU
+pub const MAX_LEN: usize = 1400;
+
+const VERSION: u8 = 3;
+const KIND_PING: u8 = 1;
+const KIND_ACK: u8 = 2;
+const KIND_PING_REQ: u8 = 3;
+const KIND_JOIN: u8 = 4;
+
+const STATUS_ALIVE: u8 = 1;
+const STATUS_JOINED: u8 = 2;
+const STATUS_SUSPECT: u8 = 3;
+const STATUS_DEAD: u8 = 4;
+const STATUS_LEFT: u8 = 5;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kind {
+    /// Are you alive?
+    Ping,
+    /// Answer to a ping, a ping-req relayed back to whoever asked, or the
+    /// members sent to one joining
+    Ack,
+    /// Ping `target` on my behalf and relay its ack
+    PingReq { target: SocketAddr },
+    /// Let me in and tell me who the members are
+    Join,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Status {
+    /// Still alive, at a new incarnation if it refuted a suspicion
+    Alive,
+    /// Heard from for the first time
+    Joined,
+    /// Missed a probe of the member `by`; dead unless it refutes this in
+    /// time
+    Suspect { by: SocketAddr },
+    /// Confirmed dead after being suspected
+    Dead,
+    /// Left the cluster on shutdown
+    Left,
+}
+
+/// What one member believes about another.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Update {
+    pub member: SocketAddr,
+    pub status: Status,
+    pub incarnation: u32,
+}
+
+impl Update {
+    /// Bytes taken in a message.
+    pub fn encoded_len(&self) -> usize {
+        let by = match self.status {
+            Status::Suspect { by } => addr_len(by),
+            _ => 0,
+        };
+        5 + addr_len(self.member) + by
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Message {
+    pub kind: Kind,
+    pub seq: u32,
+    pub incarnation: u32,
+    pub updates: Vec<Update>,
+}
+
+impl Message {
+    /// Bytes taken without the updates.
+    pub fn header_len(&self) -> usize {
+        let target = match self.kind {
+            Kind::PingReq { target } => addr_len(target),
+            _ => 0,
+        };
+        11 + target
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(MAX_LEN);
+        let kind = match self.kind {
+            Kind::Ping => KIND_PING,
+            Kind::Ack => KIND_ACK,
+            Kind::PingReq { .. } => KIND_PING_REQ,
+            Kind::Join => KIND_JOIN,
+        };
+        out.extend_from_slice(&[VERSION, kind]);
+        out.extend_from_slice(&self.seq.to_be_bytes());
+        out.extend_from_slice(&self.incarnation.to_be_bytes());
+        if let Kind::PingReq { target } = self.kind {
+            put_addr(&mut out, target);
+        }
+        out.push(self.updates.len() as u8);
+        for update in &self.updates {
+            out.push(match update.status {
+                Status::Alive => STATUS_ALIVE,
+                Status::Joined => STATUS_JOINED,
+                Status::Suspect { .. } => STATUS_SUSPECT,
+                Status::Dead => STATUS_DEAD,
+                Status::Left => STATUS_LEFT,
+            });
+            out.extend_from_slice(&update.incarnation.to_be_bytes());
+            put_addr(&mut out, update.member);
+            if let Status::Suspect { by } = update.status {
+                put_addr(&mut out, by);
+            }
+        }
+        out
+    }
+
+    /// `None` for anything that isn't a whole message of this version.
+    pub fn decode(buf: &[u8]) -> Option<Self> {
+        let mut r = Reader(buf);
+        if r.u8()? != VERSION {
+            return None;
+        }
+        let kind = r.u8()?;
+        let seq = r.u32()?;
+        let incarnation = r.u32()?;
+        let kind = match kind {
+            KIND_PING => Kind::Ping,
+            KIND_ACK => Kind::Ack,
+            KIND_PING_REQ => Kind::PingReq { target: r.addr()? },
+            KIND_JOIN => Kind::Join,
+            _ => return None,
+        };
+        let count = r.u8()?;
+        let mut updates = Vec::with_capacity(count as usize);
+        for _ in 0..count {
+            let status = r.u8()?;
+            let incarnation = r.u32()?;
+            let member = r.addr()?;
+            let status = match status {
+                STATUS_ALIVE => Status::Alive,
+                STATUS_JOINED => Status::Joined,
+                STATUS_SUSPECT => Status::Suspect { by: r.addr()? },
+                STATUS_DEAD => Status::Dead,
+                STATUS_LEFT => Status::Left,
+                _ => return None,
+            };
+            updates.push(Update {
+                member,
+                status,
+                incarnation,
+            });
+        }
+        r.0.is_empty().then_some(Message {
+            kind,
+            seq,
+            incarnation,
+            updates,
+        })
+    }
+}
+
+fn addr_len(addr: SocketAddr) -> usize {
+    match addr {
+        SocketAddr::V4(_) => 7,
+        SocketAddr::V6(_) => 19,
+    }
+}
+
+fn put_addr(out: &mut Vec<u8>, addr: SocketAddr) {
+    match addr.ip() {
+        IpAddr::V4(ip) => {
+            out.push(4);
+            out.extend_from_slice(&ip.octets());
+        }
+        IpAddr::V6(ip) => {
+            out.push(6);
+            out.extend_from_slice(&ip.octets());
+        }
+    }
+    out.extend_from_slice(&addr.port().to_be_bytes());
+}
+
+struct Reader<'a>(&'a [u8]);
+
+impl Reader<'_> {
+    fn array<const N: usize>(&mut self) -> Option<[u8; N]> {
+        let (head, rest) = self.0.split_first_chunk::<N>()?;
+        self.0 = rest;
+        Some(*head)
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        Some(self.array::<1>()?[0])
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        Some(u32::from_be_bytes(self.array()?))
+    }
+
+    fn addr(&mut self) -> Option<SocketAddr> {
+        let ip: IpAddr = match self.u8()? {
+            4 => Ipv4Addr::from(self.array::<4>()?).into(),
+            6 => Ipv6Addr::from(self.array::<16>()?).into(),
+            _ => return None,
+        };
+        Some(SocketAddr::new(ip, u16::from_be_bytes(self.array()?)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn v4(port: u16) -> SocketAddr {
+        SocketAddr::from(([10, 0, 0, 1], port))
+    }
+
+    fn v6(port: u16) -> SocketAddr {
+        SocketAddr::from((Ipv6Addr::LOCALHOST, port))
+    }
+
+    fn every_kind() -> Vec<Message> {
+        let updates = vec![
+            Update {
+                member: v4(1),
+                status: Status::Alive,
+                incarnation: 7,
+            },
+            Update {
+                member: v6(2),
+                status: Status::Suspect { by: v4(3) },
+                incarnation: u32::MAX,
+            },
+            Update {
+                member: v4(4),
+                status: Status::Left,
+                incarnation: 0,
+            },
+        ];
+        [
+            Kind::Ping,
+            Kind::Ack,
+            Kind::PingReq { target: v6(5) },
+            Kind::Join,
+        ]
+        .into_iter()
+        .map(|kind| Message {
+            kind,
+            seq: 0xdead_beef,
+            incarnation: 3,
+            updates: updates.clone(),
+        })
+        .collect()
+    }
+
+    #[test]
+    fn round_trip() {
+        for message in every_kind() {
+            let bytes = message.encode();
+            let len = message.header_len()
+                + message
+                    .updates
+                    .iter()
+                    .map(Update::encoded_len)
+                    .sum::<usize>();
+            assert_eq!(bytes.len(), len);
+            assert_eq!(Message::decode(&bytes), Some(message));
+        }
+    }
+
+    #[test]
+    fn truncated() {
+        for message in every_kind() {
+            let bytes = message.encode();
+            for len in 0..bytes.len() {
+                assert_eq!(Message::decode(&bytes[..len]), None, "{len} bytes");
+            }
+        }
+    }
+
+    #[test]
+    fn trailing_bytes() {
+        let mut bytes = every_kind()[0].encode();
+        bytes.push(0);
+        assert_eq!(Message::decode(&bytes), None);
+    }
+
+    #[test]
+    fn hostile() {
+        let ping = every_kind()[0].encode();
+        let cases: &[(&str, usize, u8)] = &[
+            ("version", 0, VERSION + 1),
+            ("kind", 1, 0),
+            ("kind", 1, 9),
+            // more updates than the datagram holds
+            ("count", 10, 200),
+            ("status", 11, 0),
+            ("status", 11, 6),
+            ("address family", 16, 5),
+            ("address family claiming v6", 16, 6),
+        ];
+        for &(field, at, value) in cases {
+            let mut bytes = ping.clone();
+            bytes[at] = value;
+            assert_eq!(Message::decode(&bytes), None, "{field} {value}");
+        }
+    }
+
+    #[test]
+    fn empty_updates() {
+        let message = Message {
+            kind: Kind::Ack,
+            seq: 1,
+            incarnation: 0,
+            updates: Vec::new(),
+        };
+        assert_eq!(
+            message.encode(),
+            [VERSION, KIND_ACK, 0, 0, 0, 1, 0, 0, 0, 0, 0]
+        );
+        assert_eq!(Message::decode(&message.encode()), Some(message));
+    }
+}
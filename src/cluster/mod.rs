@@ -0,0 +1,236 @@
+//! Membership of a Flax cluster
+//!
+//! Flax instances find out which of them are alive with SWIM (see
+//! documentation/gossip.md). Each protocol period T' a member pings the next
+//! one in its shuffled list; without an ack in time it asks `k` others to
+//! ping that member on its behalf, and a member none of them reached by the
+//! end of the period is suspected. Every member is pinged at least once every
+//! N periods, so a failure is suspected within T' * N. Joins, suspicions,
+//! refutations, failures and departures spread by gossip piggybacked on the
+//! probes. A new member joins by asking the seeds it is configured with for
+//! the members they know.
+//!
+//! Probes go over UDP from the `flax-cluster` thread, away from the workers.
+//! The thread binds the socket itself, retrying while the process being
+//! upgraded still holds it; that one leaves as soon as the handoff
+//! completes, and lets go of the socket.
+
+pub mod gossip;
+pub mod members;
+pub mod message;
+pub mod suspicion;
+pub mod swim;
+
+use std::net::{SocketAddr, UdpSocket};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, Instant};
+use std::{io, thread};
+
+use tracing::{info, warn};
+
+use crate::metrics::Counter;
+
+use message::Message;
+use swim::{Outgoing, Swim, SwimOptions};
+
+/// Larger than any message
+const MAX_MESSAGE: usize = 1500;
+const BIND_RETRY: Duration = Duration::from_secs(1);
+
+/// Direct pings sent, one per protocol period
+pub static PROBES: Counter = Counter::new();
+/// Ping-reqs sent for pings that went unanswered
+pub static INDIRECT_PROBES: Counter = Counter::new();
+/// Members suspected, by this member or through gossip
+pub static SUSPICIONS: Counter = Counter::new();
+/// Members declared failed
+pub static MEMBER_FAILURES: Counter = Counter::new();
+/// Suspicions of this member it refuted
+pub static REFUTATIONS: Counter = Counter::new();
+
+static CLUSTER: OnceLock<Cluster> = OnceLock::new();
+
+struct Cluster {
+    address: SocketAddr,
+    /// Once bound, until this member leaves
+    socket: Mutex<Option<Arc<UdpSocket>>>,
+    swim: Mutex<Swim>,
+}
+
+#[derive(Debug, Clone)]
+pub struct ClusterOptions {
+    /// Address probes are exchanged on, which other members know this one by
+    pub bind: SocketAddr,
+    /// Members to join through
+    pub seeds: Vec<SocketAddr>,
+    pub swim: SwimOptions,
+}
+
+/// Join through the seeds once the cluster socket is bound.
+pub fn start(options: ClusterOptions) -> io::Result<()> {
+    let seeds = options.seeds.clone();
+    let swim = Swim::new(options.bind, options.seeds, options.swim, Instant::now());
+    let cluster = Cluster {
+        address: options.bind,
+        socket: Mutex::new(None),
+        swim: Mutex::new(swim),
+    };
+    if CLUSTER.set(cluster).is_err() {
+        return Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            "cluster already started",
+        ));
+    }
+    info!(address = %options.bind, ?seeds, "joining cluster");
+    thread::Builder::new()
+        .name("flax-cluster".to_string())
+        .spawn(run)?;
+    Ok(())
+}
+
+/// Tell the other members this one is leaving, and close the cluster socket
+/// once the thread is done with it; nothing when not in a cluster or
+/// already gone.
+pub fn leave() {
+    let Some(cluster) = CLUSTER.get() else {
+        return;
+    };
+    let mut socket = cluster.socket.lock().unwrap();
+    let mut out: Outgoing = Vec::new();
+    {
+        let mut swim = cluster.swim.lock().unwrap();
+        if swim.has_left() {
+            return;
+        }
+        swim.leave(&mut out);
+    }
+    info!(address = %cluster.address, "leaving cluster");
+    if let Some(socket) = socket.take() {
+        send(&socket, &mut out);
+    }
+}
+
+fn run() {
+    let cluster = CLUSTER.get().expect("cluster started");
+    let socket = Arc::new(bind_with_retry(cluster.address));
+    {
+        let mut slot = cluster.socket.lock().unwrap();
+        if cluster.swim.lock().unwrap().has_left() {
+            return;
+        }
+        *slot = Some(socket.clone());
+    }
+    let mut buf = [0u8; MAX_MESSAGE];
+    let mut out: Outgoing = Vec::new();
+    loop {
+        let deadline = {
+            let swim = cluster.swim.lock().unwrap();
+            if swim.has_left() {
+                // dropping the socket frees the address for a new process
+                return;
+            }
+            swim.next_deadline()
+        };
+        // a zero timeout would mean none at all
+        let wait = deadline
+            .saturating_duration_since(Instant::now())
+            .max(Duration::from_millis(1));
+        if let Err(e) = socket.set_read_timeout(Some(wait)) {
+            warn!("cluster socket: {e}");
+            return;
+        }
+        let received = match socket.recv_from(&mut buf) {
+            Ok((n, from)) => Message::decode(&buf[..n]).map(|m| (from, m)),
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    io::ErrorKind::WouldBlock
+                        | io::ErrorKind::TimedOut
+                        | io::ErrorKind::Interrupted
+                ) =>
+            {
+                None
+            }
+            Err(e) => {
+                warn!("cluster socket: {e}");
+                None
+            }
+        };
+        {
+            let mut swim = cluster.swim.lock().unwrap();
+            let now = Instant::now();
+            if let Some((from, message)) = received {
+                swim.receive(from, message, now, &mut out);
+            }
+            swim.tick(now, &mut out);
+        }
+        send(&socket, &mut out);
+    }
+}
+
+fn bind_with_retry(address: SocketAddr) -> UdpSocket {
+    let mut logged = false;
+    loop {
+        match UdpSocket::bind(address) {
+            Ok(socket) => return socket,
+            Err(e) => {
+                if !logged {
+                    warn!(%address, "cluster cannot bind ({e}), retrying");
+                    logged = true;
+                }
+                thread::sleep(BIND_RETRY);
+            }
+        }
+    }
+}
+
+fn send(socket: &UdpSocket, out: &mut Outgoing) {
+    for (to, message) in out.drain(..) {
+        // a member that can't be reached is for the probes to find out
+        let _ = socket.send_to(&message.encode(), to);
+    }
+}
+
+/// Address of this member; `None` when not in a cluster.
+pub fn local_address() -> Option<SocketAddr> {
+    CLUSTER.get().map(|c| c.address)
+}
+
+/// This member's incarnation; `None` when not in a cluster.
+pub fn incarnation() -> Option<u32> {
+    CLUSTER.get().map(|c| c.swim.lock().unwrap().incarnation())
+}
+
+/// What this member stretches its periods and ping timeouts by, 1 when
+/// healthy; `None` when not in a cluster.
+pub fn health_multiplier() -> Option<u32> {
+    CLUSTER
+        .get()
+        .map(|c| c.swim.lock().unwrap().health_multiplier())
+}
+
+/// The other members believed alive, suspected ones included; empty when
+/// not in a cluster.
+pub fn members() -> Vec<SocketAddr> {
+    let Some(cluster) = CLUSTER.get() else {
+        return Vec::new();
+    };
+    let mut members: Vec<SocketAddr> = cluster.swim.lock().unwrap().members().iter().collect();
+    members.sort_unstable();
+    members
+}
+
+/// The members currently suspected.
+pub fn suspects() -> Vec<SocketAddr> {
+    let Some(cluster) = CLUSTER.get() else {
+        return Vec::new();
+    };
+    let swim = cluster.swim.lock().unwrap();
+    let mut suspects: Vec<SocketAddr> = swim
+        .members()
+        .iter()
+        .filter(|&m| swim.is_suspect(m))
+        .collect();
+    suspects.sort_unstable();
+    suspects
+}
@@ -0,0 +1,112 @@
+//! How long a suspected member has to refute it (Lifeguard)
+//!
+//! A suspicion starts out lasting the maximum timeout and shrinks towards
+//! the minimum as other members independently suspect the same member, on a
+//! log scale so the first confirmations count the most. `k` confirmations
+//! bring it down to the minimum; with too few members to expect any, it
+//! lasts the minimum from the start.
+
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+pub struct Suspicion {
+    since: Instant,
+    /// Whoever suspected the member first, then each confirmation
+    suspectors: Vec<SocketAddr>,
+    min: Duration,
+    max: Duration,
+    k: usize,
+}
+
+impl Suspicion {
+    pub fn new(by: SocketAddr, now: Instant, min: Duration, max: Duration, k: usize) -> Self {
+        Self {
+            since: now,
+            suspectors: vec![by],
+            min,
+            max,
+            k,
+        }
+    }
+
+    /// The member that suspected it first.
+    pub fn by(&self) -> SocketAddr {
+        self.suspectors[0]
+    }
+
+    /// Count `by` as confirming the suspicion; false if it already had, or
+    /// enough have to bring the timeout down to the minimum.
+    pub fn confirm(&mut self, by: SocketAddr) -> bool {
+        if self.suspectors.len() > self.k || self.suspectors.contains(&by) {
+            return false;
+        }
+        self.suspectors.push(by);
+        true
+    }
+
+    /// When the member is declared dead unless it refutes this.
+    pub fn deadline(&self) -> Instant {
+        self.since + self.timeout()
+    }
+
+    fn timeout(&self) -> Duration {
+        if self.k == 0 {
+            return self.min;
+        }
+        let confirmations = (self.suspectors.len() - 1) as f64;
+        let fraction = (confirmations + 1.0).ln() / (self.k as f64 + 1.0).ln();
+        let shrunk = (self.max - self.min).mul_f64(fraction.min(1.0));
+        self.max - shrunk
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MIN: Duration = Duration::from_secs(1);
+    const MAX: Duration = Duration::from_secs(6);
+
+    fn addr(port: u16) -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], port))
+    }
+
+    #[test]
+    fn shrinks_with_confirmations() {
+        let now = Instant::now();
+        let mut suspicion = Suspicion::new(addr(1), now, MIN, MAX, 3);
+        assert_eq!(suspicion.deadline(), now + MAX);
+        let mut last = MAX;
+        for by in 2..=4 {
+            assert!(suspicion.confirm(addr(by)));
+            let timeout = suspicion.deadline() - now;
+            assert!(timeout < last, "{timeout:?} after {} confirmations", by - 1);
+            last = timeout;
+        }
+        assert_eq!(suspicion.deadline(), now + MIN);
+        // log scale: the first confirmation takes off the most
+        let mut one = Suspicion::new(addr(1), now, MIN, MAX, 3);
+        one.confirm(addr(2));
+        assert_eq!(one.deadline() - now, MAX - (MAX - MIN) / 2);
+    }
+
+    #[test]
+    fn confirmations_counted_once_and_up_to_k() {
+        let now = Instant::now();
+        let mut suspicion = Suspicion::new(addr(1), now, MIN, MAX, 2);
+        assert!(!suspicion.confirm(addr(1)));
+        assert!(suspicion.confirm(addr(2)));
+        assert!(!suspicion.confirm(addr(2)));
+        assert!(suspicion.confirm(addr(3)));
+        assert!(!suspicion.confirm(addr(4)));
+        assert_eq!(suspicion.deadline(), now + MIN);
+        assert_eq!(suspicion.by(), addr(1));
+    }
+
+    #[test]
+    fn least_timeout_without_anyone_to_confirm() {
+        let now = Instant::now();
+        let suspicion = Suspicion::new(addr(1), now, MIN, MAX, 0);
+        assert_eq!(suspicion.deadline(), now + MIN);
+    }
+}
@@ -0,0 +1,892 @@
+//! SWIM failure detection and dissemination, without the I/O
+//!
+//! Time and datagrams are passed in and messages to send handed back, so the
+//! thread in [`super`] only moves bytes. Each protocol period starts with a
+//! ping to the next member. If no ack arrives within the ping timeout, `k`
+//! other members get a ping-req asking them to ping it too and relay its
+//! ack. A member not heard from by either route when the period ends is
+//! suspected, and confirmed dead unless it refutes the suspicion in time.
+//!
+//! What members learn travels as updates piggybacked on the probes (see
+//! [`super::gossip`]). Each member numbers its incarnations: an update about
+//! a member overrides what is known of it at a lower incarnation, suspicion
+//! also one at the same incarnation, and a member told it is suspected
+//! refutes that as alive at a higher one.
+//!
+//! Lifeguard's refinements keep a slow member from being taken for a dead
+//! one. A member that misses acks or has to refute suspicion of itself is
+//! likely slow itself, so its local health multiplier goes up, stretching
+//! its periods and ping timeouts until its probes succeed again. Suspicions
+//! last longer at first and shrink as other members confirm them (see
+//! [`super::suspicion`]). And every message to a suspected member tells it
+//! so, letting it refute at its next probe rather than whenever the gossip
+//! reaches it.
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+use tracing::{info, warn};
+
+use super::gossip::Gossip;
+use super::members::Members;
+use super::message::{Kind, MAX_LEN, Message, Status, Update};
+use super::suspicion::Suspicion;
+use super::{INDIRECT_PROBES, MEMBER_FAILURES, PROBES, REFUTATIONS, SUSPICIONS};
+
+#[derive(Debug, Clone, Copy)]
+pub struct SwimOptions {
+    /// Protocol period T'
+    pub period: Duration,
+    /// How long a direct ping waits for its ack
+    pub ping_timeout: Duration,
+    /// Members asked to ping an unresponsive one
+    pub indirect: usize,
+    /// Suspicion lasts at least this many periods times log(N)
+    pub suspicion_multiplier: u32,
+    /// Unconfirmed suspicion lasts this many times the least it can
+    pub suspicion_max_multiplier: u32,
+    /// Most the periods and ping timeouts are stretched by when this member
+    /// is missing acks
+    pub max_health_multiplier: u32,
+    /// Updates are piggybacked this many times log(N) (λ)
+    pub retransmit_multiplier: u32,
+}
+
+/// Messages to send, and where
+pub type Outgoing = Vec<(SocketAddr, Message)>;
+
+/// Confirmations that bring a suspicion down to its least timeout
+const CONFIRMATIONS: usize = 3;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    Alive,
+    Suspect,
+    Dead,
+    Left,
+}
+
+impl State {
+    fn is_gone(self) -> bool {
+        matches!(self, State::Dead | State::Left)
+    }
+}
+
+/// What is known of a member. Dead and departed ones are kept so that stale
+/// updates about them are told apart from their return.
+#[derive(Debug, Clone, Copy)]
+struct Record {
+    state: State,
+    incarnation: u32,
+}
+
+/// This period's probe.
+struct Probe {
+    target: SocketAddr,
+    seq: u32,
+    started: Instant,
+    indirect_sent: bool,
+    acked: bool,
+}
+
+/// A ping sent for another member's ping-req, whose ack goes back to it.
+struct Relay {
+    requester: SocketAddr,
+    seq: u32,
+    expires: Instant,
+}
+
+pub struct Swim {
+    address: SocketAddr,
+    incarnation: u32,
+    options: SwimOptions,
+    /// Asked to let this member in while it knows of no others
+    seeds: Vec<SocketAddr>,
+    /// Alive and suspected members, in probe order
+    members: Members,
+    records: HashMap<SocketAddr, Record>,
+    /// Of the members in the suspect state
+    suspicions: HashMap<SocketAddr, Suspicion>,
+    gossip: Gossip,
+    /// Local health score: one less than what periods and ping timeouts
+    /// are stretched by
+    health: u32,
+    next_seq: u32,
+    probe: Option<Probe>,
+    next_period: Instant,
+    /// Keyed by the seq of the relayed ping
+    relays: HashMap<u32, Relay>,
+    left: bool,
+}
+
+impl Swim {
+    pub fn new(
+        address: SocketAddr,
+        seeds: Vec<SocketAddr>,
+        options: SwimOptions,
+        now: Instant,
+    ) -> Self {
+        Self {
+            address,
+            incarnation: 0,
+            options,
+            seeds,
+            members: Members::default(),
+            records: HashMap::new(),
+            suspicions: HashMap::new(),
+            gossip: Gossip::default(),
+            health: 0,
+            next_seq: 0,
+            probe: None,
+            next_period: now,
+            relays: HashMap::new(),
+            left: false,
+        }
+    }
+
+    pub fn members(&self) -> &Members {
+        &self.members
+    }
+
+    pub fn incarnation(&self) -> u32 {
+        self.incarnation
+    }
+
+    pub fn is_suspect(&self, member: SocketAddr) -> bool {
+        self.suspicions.contains_key(&member)
+    }
+
+    pub fn has_left(&self) -> bool {
+        self.left
+    }
+
+    /// What periods and ping timeouts are stretched by, 1 when healthy.
+    pub fn health_multiplier(&self) -> u32 {
+        self.health + 1
+    }
+
+    /// When `tick` next has something to do.
+    pub fn next_deadline(&self) -> Instant {
+        let mut deadline = self.next_period;
+        if let Some(p) = &self.probe
+            && !p.acked
+            && !p.indirect_sent
+        {
+            deadline = deadline.min(p.started + self.ping_timeout());
+        }
+        for suspicion in self.suspicions.values() {
+            deadline = deadline.min(suspicion.deadline());
+        }
+        deadline
+    }
+
+    /// Send the ping-reqs, confirm the suspicions and start the periods that
+    /// are due.
+    pub fn tick(&mut self, now: Instant, out: &mut Outgoing) {
+        if self.left {
+            return;
+        }
+        let ping_timeout = self.ping_timeout();
+        if let Some(probe) = &mut self.probe
+            && !probe.acked
+            && !probe.indirect_sent
+            && now >= probe.started + ping_timeout
+        {
+            probe.indirect_sent = true;
+            let target = probe.target;
+            let seq = probe.seq;
+            for via in self.members.random(self.options.indirect, target) {
+                INDIRECT_PROBES.inc();
+                self.send(via, Kind::PingReq { target }, seq, out);
+            }
+        }
+        let expired: Vec<Update> = self
+            .suspicions
+            .iter()
+            .filter(|(_, suspicion)| now >= suspicion.deadline())
+            .map(|(&member, _)| Update {
+                member,
+                status: Status::Dead,
+                incarnation: self.records[&member].incarnation,
+            })
+            .collect();
+        for update in expired {
+            self.apply(update, now);
+        }
+        if now < self.next_period {
+            return;
+        }
+        if let Some(probe) = self.probe.take() {
+            if probe.acked {
+                self.health = self.health.saturating_sub(1);
+            } else {
+                self.degrade_health();
+                self.suspect(probe.target, now);
+            }
+        }
+        self.relays.retain(|_, relay| relay.expires > now);
+        // a period overrun by a stall is not made up for
+        let period = self.options.period * self.health_multiplier();
+        self.next_period = (self.next_period + period).max(now);
+        if self.members.is_empty() {
+            // alone, whether just started or cut off from the rest
+            for seed in self.seeds.clone() {
+                let seq = self.seq();
+                self.send(seed, Kind::Join, seq, out);
+            }
+            return;
+        }
+        if let Some(target) = self.members.next_target() {
+            let seq = self.seq();
+            PROBES.inc();
+            self.probe = Some(Probe {
+                target,
+                seq,
+                started: now,
+                indirect_sent: false,
+                acked: false,
+            });
+            self.send(target, Kind::Ping, seq, out);
+        }
+    }
+
+    /// Handle a message from `from`, which vouches for itself as alive at
+    /// the incarnation it carries.
+    pub fn receive(
+        &mut self,
+        from: SocketAddr,
+        message: Message,
+        now: Instant,
+        out: &mut Outgoing,
+    ) {
+        if self.left {
+            return;
+        }
+        let status = if self.records.contains_key(&from) {
+            Status::Alive
+        } else {
+            Status::Joined
+        };
+        self.apply(
+            Update {
+                member: from,
+                status,
+                incarnation: message.incarnation,
+            },
+            now,
+        );
+        for update in message.updates {
+            self.apply(update, now);
+        }
+        match message.kind {
+            Kind::Ping => self.send(from, Kind::Ack, message.seq, out),
+            Kind::PingReq { target } => {
+                let seq = self.seq();
+                self.relays.insert(
+                    seq,
+                    Relay {
+                        requester: from,
+                        seq: message.seq,
+                        expires: now + self.options.period,
+                    },
+                );
+                self.send(target, Kind::Ping, seq, out);
+            }
+            Kind::Ack => {
+                if let Some(probe) = &mut self.probe
+                    && probe.seq == message.seq
+                {
+                    probe.acked = true;
+                } else if let Some(relay) = self.relays.remove(&message.seq) {
+                    self.send(relay.requester, Kind::Ack, relay.seq, out);
+                }
+            }
+            Kind::Join => self.send_members(from, message.seq, out),
+        }
+    }
+
+    /// Tell some members this one is leaving, and stop taking part.
+    pub fn leave(&mut self, out: &mut Outgoing) {
+        let update = Update {
+            member: self.address,
+            status: Status::Left,
+            incarnation: self.incarnation,
+        };
+        for member in self
+            .members
+            .random(self.retransmit_limit() as usize, self.address)
+        {
+            let seq = self.seq();
+            out.push((
+                member,
+                Message {
+                    kind: Kind::Ping,
+                    seq,
+                    incarnation: self.incarnation,
+                    updates: vec![update],
+                },
+            ));
+        }
+        self.left = true;
+    }
+
+    /// Take `update` in if it is news, and pass it on.
+    fn apply(&mut self, update: Update, now: Instant) {
+        let member = update.member;
+        if member == self.address {
+            self.refute(update);
+            return;
+        }
+        let record = self.records.get(&member).copied();
+        if let (Some(r), Status::Suspect { by }) = (record, update.status)
+            && r.state == State::Suspect
+            && r.incarnation == update.incarnation
+        {
+            // another member suspects it too
+            if let Some(suspicion) = self.suspicions.get_mut(&member)
+                && suspicion.confirm(by)
+            {
+                self.gossip.push(update);
+            }
+            return;
+        }
+        let newer = match (record, update.status) {
+            (None, Status::Alive | Status::Joined) => true,
+            // nothing to suspect or bury in a member never heard of
+            (None, _) => false,
+            (Some(r), Status::Alive | Status::Joined) => update.incarnation > r.incarnation,
+            (Some(r), Status::Suspect { .. }) => match r.state {
+                State::Alive => update.incarnation >= r.incarnation,
+                State::Suspect => update.incarnation > r.incarnation,
+                State::Dead | State::Left => false,
+            },
+            (Some(r), Status::Dead | Status::Left) => {
+                !r.state.is_gone() && update.incarnation >= r.incarnation
+            }
+        };
+        if !newer {
+            return;
+        }
+        let state = match update.status {
+            Status::Alive | Status::Joined => State::Alive,
+            Status::Suspect { .. } => State::Suspect,
+            Status::Dead => State::Dead,
+            Status::Left => State::Left,
+        };
+        let was = record.map(|r| r.state);
+        self.suspicions.remove(&member);
+        self.records.insert(
+            member,
+            Record {
+                state,
+                incarnation: update.incarnation,
+            },
+        );
+        match state {
+            State::Alive if was.is_none_or(State::is_gone) => {
+                self.members.add(member);
+                info!(%member, members = self.members.len(), "cluster member joined");
+            }
+            State::Alive => {
+                if was == Some(State::Suspect) {
+                    info!(%member, incarnation = update.incarnation, "cluster member refuted suspicion");
+                }
+            }
+            State::Suspect => {
+                if let Status::Suspect { by } = update.status {
+                    let suspicion = self.new_suspicion(by, now);
+                    self.suspicions.insert(member, suspicion);
+                }
+                SUSPICIONS.inc();
+                warn!(%member, incarnation = update.incarnation, "cluster member suspected");
+            }
+            State::Dead => {
+                self.members.remove(member);
+                MEMBER_FAILURES.inc();
+                warn!(%member, members = self.members.len(), "cluster member failed");
+            }
+            State::Left => {
+                self.members.remove(member);
+                info!(%member, members = self.members.len(), "cluster member left");
+            }
+        }
+        self.gossip.push(update);
+    }
+
+    /// Suspect a member that missed its probe, or confirm someone else's
+    /// suspicion of it.
+    fn suspect(&mut self, member: SocketAddr, now: Instant) {
+        if let Some(record) = self.records.get(&member)
+            && !record.state.is_gone()
+        {
+            let incarnation = record.incarnation;
+            self.apply(
+                Update {
+                    member,
+                    status: Status::Suspect { by: self.address },
+                    incarnation,
+                },
+                now,
+            );
+        }
+    }
+
+    /// Answer news of this member's suspicion or death with a higher
+    /// incarnation.
+    fn refute(&mut self, update: Update) {
+        if update.status == Status::Alive
+            || update.status == Status::Joined
+            || update.incarnation < self.incarnation
+        {
+            return;
+        }
+        self.incarnation = update.incarnation + 1;
+        REFUTATIONS.inc();
+        self.degrade_health();
+        info!(incarnation = self.incarnation, "refuting cluster suspicion");
+        self.gossip.push(Update {
+            member: self.address,
+            status: Status::Alive,
+            incarnation: self.incarnation,
+        });
+    }
+
+    /// Queue a message with as much gossip as fits. A member believed
+    /// suspected, dead or gone is told so first, to refute it.
+    fn send(&mut self, to: SocketAddr, kind: Kind, seq: u32, out: &mut Outgoing) {
+        let mut message = Message {
+            kind,
+            seq,
+            incarnation: self.incarnation,
+            updates: Vec::new(),
+        };
+        let news = self.news_for(to);
+        message.updates.extend(news);
+        let room = MAX_LEN - message.header_len() - news.map_or(0, |update| update.encoded_len());
+        let limit = self.retransmit_limit();
+        let gossip = self
+            .gossip
+            .take(room, limit, news.map(|update| update.member));
+        message.updates.extend(gossip);
+        out.push((to, message));
+    }
+
+    /// Answer a join with every member known alive or suspected, in as many
+    /// acks as it takes.
+    fn send_members(&mut self, to: SocketAddr, seq: u32, out: &mut Outgoing) {
+        let mut updates: Vec<Update> = self.news_for(to).into_iter().collect();
+        updates.extend(
+            self.records
+                .iter()
+                .filter(|&(&member, record)| member != to && !record.state.is_gone())
+                .map(|(&member, record)| Update {
+                    member,
+                    status: match self.suspicions.get(&member) {
+                        Some(suspicion) => Status::Suspect { by: suspicion.by() },
+                        None => Status::Alive,
+                    },
+                    incarnation: record.incarnation,
+                }),
+        );
+        let mut ack = Message {
+            kind: Kind::Ack,
+            seq,
+            incarnation: self.incarnation,
+            updates: Vec::new(),
+        };
+        let mut room = MAX_LEN - ack.header_len();
+        for update in updates {
+            if update.encoded_len() > room {
+                let full = std::mem::take(&mut ack.updates);
+                out.push((
+                    to,
+                    Message {
+                        updates: full,
+                        ..ack.clone()
+                    },
+                ));
+                room = MAX_LEN - ack.header_len();
+            }
+            room -= update.encoded_len();
+            ack.updates.push(update);
+        }
+        out.push((to, ack));
+    }
+
+    /// What `member` needs telling if it is believed suspected, dead or
+    /// gone.
+    fn news_for(&self, member: SocketAddr) -> Option<Update> {
+        let record = self.records.get(&member)?;
+        let status = match record.state {
+            State::Alive => return None,
+            State::Suspect => Status::Suspect {
+                by: self.suspicions[&member].by(),
+            },
+            State::Dead => Status::Dead,
+            State::Left => Status::Left,
+        };
+        Some(Update {
+            member,
+            status,
+            incarnation: record.incarnation,
+        })
+    }
+
+    /// ⌈log10(N + 1)⌉ for the N members including this one, at least 1.
+    fn log_n(&self) -> u32 {
+        let n = self.members.len() + 2;
+        ((n as f64).log10().ceil() as u32).max(1)
+    }
+
+    fn ping_timeout(&self) -> Duration {
+        self.options.ping_timeout * self.health_multiplier()
+    }
+
+    /// Missed an ack or had to refute suspicion: likely slow itself.
+    fn degrade_health(&mut self) {
+        self.health = (self.health + 1).min(self.options.max_health_multiplier - 1);
+    }
+
+    /// A suspicion raised by `by`, timed for the current cluster size. The
+    /// member suspected and the one suspecting it first can't confirm it.
+    fn new_suspicion(&self, by: SocketAddr, now: Instant) -> Suspicion {
+        let min = self.options.period * self.options.suspicion_multiplier * self.log_n();
+        let max = min * self.options.suspicion_max_multiplier;
+        let k = CONFIRMATIONS.min(self.members.len().saturating_sub(1));
+        Suspicion::new(by, now, min, max, k)
+    }
+
+    fn retransmit_limit(&self) -> u32 {
+        self.options.retransmit_multiplier * self.log_n()
+    }
+
+    fn seq(&mut self) -> u32 {
+        self.next_seq = self.next_seq.wrapping_add(1);
+        self.next_seq
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PERIOD: Duration = Duration::from_secs(1);
+
+    fn addr(port: u16) -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], port))
+    }
+
+    fn options() -> SwimOptions {
+        SwimOptions {
+            period: PERIOD,
+            ping_timeout: Duration::from_millis(200),
+            indirect: 2,
+            suspicion_multiplier: 4,
+            suspicion_max_multiplier: 6,
+            max_health_multiplier: 8,
+            retransmit_multiplier: 3,
+        }
+    }
+
+    /// Member 1, having heard from the members on `ports` at incarnation 0.
+    fn swim(ports: &[u16], now: Instant) -> Swim {
+        let mut swim = Swim::new(addr(1), Vec::new(), options(), now);
+        for &port in ports {
+            swim.receive(
+                addr(port),
+                message(Kind::Ping, 0, Vec::new()),
+                now,
+                &mut Vec::new(),
+            );
+        }
+        swim
+    }
+
+    fn message(kind: Kind, incarnation: u32, updates: Vec<Update>) -> Message {
+        Message {
+            kind,
+            seq: 1,
+            incarnation,
+            updates,
+        }
+    }
+
+    fn update(port: u16, status: Status, incarnation: u32) -> Update {
+        Update {
+            member: addr(port),
+            status,
+            incarnation,
+        }
+    }
+
+    /// Pass `update` on to member 1 in a ping from member `from`.
+    fn gossip(swim: &mut Swim, from: u16, update: Update, now: Instant) {
+        let ping = message(Kind::Ping, 0, vec![update]);
+        swim.receive(addr(from), ping, now, &mut Vec::new());
+    }
+
+    fn suspect(by: u16) -> Status {
+        Status::Suspect { by: addr(by) }
+    }
+
+    #[test]
+    fn incarnation_ordering() {
+        let now = Instant::now();
+        let cases = [
+            // status, incarnation, suspected after, member after
+            (suspect(3), 0, true, true),
+            (Status::Alive, 0, false, true),
+            (Status::Alive, 1, false, true),
+            (Status::Dead, 0, false, false),
+            (Status::Left, 0, false, false),
+        ];
+        for (status, incarnation, suspected, member) in cases {
+            let mut swim = swim(&[2, 3], now);
+            gossip(&mut swim, 3, update(2, status, incarnation), now);
+            assert_eq!(
+                swim.is_suspect(addr(2)),
+                suspected,
+                "{status:?} {incarnation}"
+            );
+            assert_eq!(
+                swim.members().contains(addr(2)),
+                member,
+                "{status:?} {incarnation}"
+            );
+        }
+    }
+
+    #[test]
+    fn suspicion_needs_a_higher_incarnation_to_clear() {
+        let now = Instant::now();
+        let mut swim = swim(&[2, 3], now);
+        gossip(&mut swim, 3, update(2, suspect(3), 0), now);
+        gossip(&mut swim, 3, update(2, Status::Alive, 0), now);
+        assert!(swim.is_suspect(addr(2)));
+        gossip(&mut swim, 3, update(2, Status::Alive, 1), now);
+        assert!(!swim.is_suspect(addr(2)));
+        // older news of suspicion doesn't bring it back
+        gossip(&mut swim, 3, update(2, suspect(3), 0), now);
+        assert!(!swim.is_suspect(addr(2)));
+    }
+
+    #[test]
+    fn dead_until_it_returns_at_a_higher_incarnation() {
+        let now = Instant::now();
+        let mut swim = swim(&[2, 3], now);
+        gossip(&mut swim, 3, update(2, Status::Dead, 0), now);
+        gossip(&mut swim, 3, update(2, Status::Alive, 0), now);
+        gossip(&mut swim, 3, update(2, suspect(3), 5), now);
+        assert!(!swim.members().contains(addr(2)));
+        gossip(&mut swim, 3, update(2, Status::Alive, 1), now);
+        assert!(swim.members().contains(addr(2)));
+    }
+
+    #[test]
+    fn unknown_members_are_not_buried() {
+        let now = Instant::now();
+        let mut swim = swim(&[3], now);
+        for status in [suspect(3), Status::Dead, Status::Left] {
+            gossip(&mut swim, 3, update(2, status, 0), now);
+            assert!(!swim.members().contains(addr(2)));
+        }
+        gossip(&mut swim, 3, update(2, Status::Joined, 0), now);
+        assert!(swim.members().contains(addr(2)));
+    }
+
+    #[test]
+    fn refutation() {
+        let now = Instant::now();
+        let mut swim = swim(&[2, 3], now);
+        gossip(&mut swim, 3, update(1, Status::Alive, 4), now);
+        assert_eq!(swim.incarnation(), 0);
+        gossip(&mut swim, 3, update(1, suspect(3), 0), now);
+        assert_eq!(swim.incarnation(), 1);
+        // stale news of the suspicion already refuted
+        gossip(&mut swim, 3, update(1, suspect(2), 0), now);
+        assert_eq!(swim.incarnation(), 1);
+        gossip(&mut swim, 2, update(1, Status::Dead, 6), now);
+        assert_eq!(swim.incarnation(), 7);
+
+        let mut out = Vec::new();
+        swim.send(addr(2), Kind::Ping, 1, &mut out);
+        let (_, message) = &out[0];
+        assert_eq!(message.incarnation, 7);
+        assert!(message.updates.contains(&update(1, Status::Alive, 7)));
+    }
+
+    #[test]
+    fn missed_probe_is_suspected_then_dead() {
+        let start = Instant::now();
+        let mut swim = swim(&[2], start);
+        let mut out = Vec::new();
+        swim.tick(start, &mut out);
+        assert!(matches!(out[..], [(to, Message { kind: Kind::Ping, .. })] if to == addr(2)));
+        swim.tick(start + PERIOD, &mut out);
+        assert!(swim.is_suspect(addr(2)));
+        // alone with it, nobody can confirm the suspicion: it lasts the least
+        let least = PERIOD * options().suspicion_multiplier * swim.log_n();
+        swim.tick(start + PERIOD + least - Duration::from_millis(1), &mut out);
+        assert!(swim.members().contains(addr(2)));
+        swim.tick(start + PERIOD + least, &mut out);
+        assert!(!swim.members().contains(addr(2)));
+    }
+
+    #[test]
+    fn only_the_probe_ack_counts() {
+        let start = Instant::now();
+        for (offset, suspected) in [(0, false), (1, true)] {
+            let mut swim = swim(&[2], start);
+            let mut out = Vec::new();
+            swim.tick(start, &mut out);
+            let (_, ping) = out.pop().unwrap();
+            let ack = Message {
+                seq: ping.seq + offset,
+                ..message(Kind::Ack, 0, Vec::new())
+            };
+            swim.receive(addr(2), ack, start, &mut out);
+            swim.tick(start + PERIOD, &mut out);
+            assert_eq!(swim.is_suspect(addr(2)), suspected, "ack {offset} off");
+        }
+    }
+
+    #[test]
+    fn updates_retransmitted_a_limited_number_of_times() {
+        let now = Instant::now();
+        let mut swim = swim(&[2, 3, 4], now);
+        // the joins are gossip too; let them run out first
+        for _ in 0..100 {
+            swim.send(addr(2), Kind::Ping, 1, &mut Vec::new());
+        }
+        let mut out = Vec::new();
+        // the ack to the ping carrying it is its first transmission
+        let ping = message(Kind::Ping, 0, vec![update(4, Status::Alive, 1)]);
+        swim.receive(addr(3), ping, now, &mut out);
+        for _ in 0..100 {
+            swim.send(addr(2), Kind::Ping, 1, &mut out);
+        }
+        let sent = out
+            .iter()
+            .filter(|(_, m)| m.updates.contains(&update(4, Status::Alive, 1)))
+            .count();
+        assert_eq!(sent, swim.retransmit_limit() as usize);
+        assert_eq!(sent, 3);
+    }
+
+    #[test]
+    fn gossip_fits_in_a_message() {
+        let now = Instant::now();
+        let ports: Vec<u16> = (2..300).collect();
+        let mut swim = swim(&ports, now);
+        let mut out = Vec::new();
+        swim.send(addr(2), Kind::Ping, 1, &mut out);
+        let (_, message) = &out[0];
+        assert!(message.encode().len() <= MAX_LEN);
+        assert!(!message.updates.is_empty());
+    }
+
+    #[test]
+    fn join_answered_with_every_member() {
+        let now = Instant::now();
+        let ports: Vec<u16> = (2..300).collect();
+        let mut swim = swim(&ports, now);
+        let mut out = Vec::new();
+        swim.receive(addr(400), message(Kind::Join, 0, Vec::new()), now, &mut out);
+        assert!(out.len() > 1, "{} members in one ack", ports.len());
+        let mut told: Vec<SocketAddr> = out
+            .iter()
+            .inspect(|(to, m)| {
+                assert_eq!(*to, addr(400));
+                assert!(m.encode().len() <= MAX_LEN);
+            })
+            .flat_map(|(_, m)| m.updates.iter().map(|u| u.member))
+            .collect();
+        told.sort_unstable();
+        assert_eq!(told, ports.iter().map(|&p| addr(p)).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn missed_acks_stretch_periods_until_probes_succeed() {
+        let start = Instant::now();
+        let mut swim = swim(&[2, 3, 4, 5], start);
+        let mut out = Vec::new();
+        let mut now = start;
+        swim.tick(now, &mut out);
+        for multiplier in 2..=4 {
+            now = swim.next_period;
+            swim.tick(now, &mut out);
+            assert_eq!(swim.health_multiplier(), multiplier);
+            assert_eq!(swim.next_period - now, PERIOD * multiplier);
+            assert_eq!(swim.ping_timeout(), options().ping_timeout * multiplier);
+        }
+        for multiplier in (1..=3).rev() {
+            let seq = swim.probe.as_ref().unwrap().seq;
+            let ack = Message {
+                seq,
+                ..message(Kind::Ack, 0, Vec::new())
+            };
+            swim.receive(swim.probe.as_ref().unwrap().target, ack, now, &mut out);
+            now = swim.next_period;
+            swim.tick(now, &mut out);
+            assert_eq!(swim.health_multiplier(), multiplier);
+        }
+    }
+
+    #[test]
+    fn health_multiplier_is_capped() {
+        let start = Instant::now();
+        let mut swim = swim(&[2], start);
+        let mut out = Vec::new();
+        for i in 0..20 {
+            // refuting a suspicion of itself counts against it too
+            gossip(&mut swim, 2, update(1, suspect(2), i), start);
+        }
+        assert_eq!(swim.health_multiplier(), options().max_health_multiplier);
+        swim.tick(start, &mut out);
+        assert_eq!(
+            swim.next_period - start,
+            PERIOD * options().max_health_multiplier
+        );
+    }
+
+    #[test]
+    fn suspected_member_is_told_first() {
+        let now = Instant::now();
+        let ports: Vec<u16> = (2..300).collect();
+        let mut swim = swim(&ports, now);
+        gossip(&mut swim, 3, update(2, suspect(3), 0), now);
+        let mut out = Vec::new();
+        swim.send(addr(2), Kind::Ping, 1, &mut out);
+        let (_, message) = &out[0];
+        assert_eq!(message.updates[0], update(2, suspect(3), 0));
+        assert_eq!(
+            message
+                .updates
+                .iter()
+                .filter(|u| u.member == addr(2))
+                .count(),
+            1
+        );
+        assert!(message.encode().len() <= MAX_LEN);
+    }
+
+    #[test]
+    fn confirmations_bring_death_closer() {
+        let now = Instant::now();
+        let mut swim = swim(&[2, 3, 4, 5, 6], now);
+        gossip(&mut swim, 3, update(2, suspect(3), 0), now);
+        let mut deadline = swim.suspicions[&addr(2)].deadline();
+        // the first suspector confirming counts for nothing
+        gossip(&mut swim, 3, update(2, suspect(3), 0), now);
+        assert_eq!(swim.suspicions[&addr(2)].deadline(), deadline);
+        for by in 4..=6 {
+            gossip(&mut swim, by, update(2, suspect(by), 0), now);
+            let shrunk = swim.suspicions[&addr(2)].deadline();
+            assert!(shrunk < deadline, "confirmed by {by}");
+            deadline = shrunk;
+        }
+        let least = PERIOD * options().suspicion_multiplier * swim.log_n();
+        assert_eq!(deadline, now + least);
+    }
+}
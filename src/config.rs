@@ -0,0 +1,933 @@
+//! Process configuration
+//!
+//! Flax reads an optional TOML file given as its first argument. Every field
+//! has a default, so running without a file behaves like the built-in setup:
+//! listen on 0.0.0.0:3000 and balance across three local backends.
+
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use std::{fs, io};
+
+use rustls::pki_types::ServerName;
+use serde::Deserialize;
+
+use crate::access_log::LogFormat;
+use crate::backend::dns::{self, DnsBackend, DnsOptions, DnsTarget};
+use crate::backend::file_discovery::FileDiscovery;
+use crate::backend::{Backend, DEFAULT_POOL, MAX_WEIGHT};
+use crate::balancer::config::{
+    FlowAffinity, ListenerMode, RingMode, SqpollConfig, UdpOptions, WorkerConfig,
+};
+use crate::balancer::router::RouteKey;
+use crate::cluster::ClusterOptions;
+use crate::cluster::swim::SwimOptions;
+use crate::protocol::ProxyVersion;
+use crate::tls::client_auth::ClientMatcher;
+use crate::tls::{SniHostMismatch, TlsVersion};
+use crate::trace::{TraceOptions, otlp};
+use crate::util::address::Address;
+use crate::util::cidr::Cidr;
+use crate::util::hostname;
+use crate::util::logging::LogOutput;
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FlaxConfig {
+    /// Address every worker binds its SO_REUSEPORT listener to, or a Unix
+    /// socket (`unix:/run/flax.sock`, `unix:@flax`) the workers share
+    pub listen: Address,
+    /// `http` routes each request; `tcp` relays whole connections untouched;
+    /// `udp` relays datagrams per client flow
+    pub mode: ListenerMode,
+    /// Number of worker threads; 0 means one per available core
+    pub workers: usize,
+    /// Backends of the `default` pool
+    pub backends: Vec<BackendEntry>,
+    /// Additional named backend pools
+    pub pools: BTreeMap<String, PoolSection>,
+    /// Pool the listener routes requests to
+    pub pool: String,
+    /// Virtual hosts: pools for requests to particular host names
+    pub hosts: BTreeMap<String, HostSection>,
+    /// Name a request's virtual host is looked up by
+    pub route_by: RouteKey,
+    /// Pools for requests from particular client certificates, checked in
+    /// order before virtual hosts
+    pub client_routes: Vec<ClientRouteSection>,
+    /// Unix socket used to hand listeners to a new process (`flax --upgrade`)
+    pub upgrade_socket: Option<PathBuf>,
+    /// Balancers in front of Flax, whose connections start with a PROXY
+    /// protocol header naming the client
+    pub trusted_proxies: Vec<Cidr>,
+    pub worker: WorkerSection,
+    /// Client flows of a UDP listener
+    pub udp: UdpSection,
+    /// Lookups of backends named in DNS
+    pub dns: DnsSection,
+    /// Backends listed in a file that is watched for changes; none when absent
+    pub discovery: Option<DiscoverySection>,
+    /// Membership of a Flax cluster; none when absent
+    pub cluster: Option<ClusterSection>,
+    /// Admin API; disabled when absent
+    pub admin: Option<AdminSection>,
+    /// Access log; disabled when absent
+    pub access_log: Option<AccessLogSection>,
+    /// Diagnostic logging
+    pub log: LogSection,
+    /// Request IDs and distributed tracing
+    pub trace: TraceSection,
+    /// Terminate TLS on the listener; plaintext when absent
+    pub tls: Option<TlsSection>,
+    /// TLS toward the backends of the `default` pool; plaintext when absent
+    pub backend_tls: Option<UpstreamTlsSection>,
+    /// PROXY protocol header starting connections to the `default` pool's
+    /// backends; none when absent
+    pub backend_proxy_protocol: Option<ProxyVersion>,
+}
+
+/// A backend, either as a bare address or as `{ address = "...", weight = 3 }`;
+/// `unix:/run/app.sock` and `unix:@app` name Unix sockets, `app.internal:8080`
+/// every address of a host and `srv:_http._tcp.app.internal` the targets of
+/// SRV records
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+pub enum BackendEntry {
+    Address(BackendTarget),
+    Weighted { address: BackendTarget, weight: u32 },
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(try_from = "String")]
+pub enum BackendTarget {
+    Address(Address),
+    Dns(DnsTarget),
+}
+
+impl TryFrom<String> for BackendTarget {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, String> {
+        match s.parse() {
+            Ok(address) => Ok(BackendTarget::Address(address)),
+            Err(e) if s.starts_with("unix:") => Err(e),
+            Err(_) => s.parse().map(BackendTarget::Dns),
+        }
+    }
+}
+
+impl BackendEntry {
+    fn target(&self) -> (&BackendTarget, Option<u32>) {
+        match self {
+            BackendEntry::Address(target) => (target, None),
+            BackendEntry::Weighted { address, weight } => (address, Some(*weight)),
+        }
+    }
+
+    /// The backend at a fixed address; `None` for one named in DNS.
+    pub fn backend(&self) -> Option<Backend> {
+        match self.target() {
+            (BackendTarget::Address(address), weight) => {
+                Some(Backend::with_weight(*address, weight.unwrap_or(1)))
+            }
+            (BackendTarget::Dns(_), _) => None,
+        }
+    }
+
+    /// The backend named in DNS, as a member of `pool`.
+    pub fn dns_backend(&self, pool: &str) -> Option<DnsBackend> {
+        match self.target() {
+            (BackendTarget::Dns(target), weight) => Some(DnsBackend {
+                pool: pool.to_string(),
+                target: target.clone(),
+                weight: weight.unwrap_or(1),
+            }),
+            (BackendTarget::Address(_), _) => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PoolSection {
+    pub backends: Vec<BackendEntry>,
+    /// TLS toward this pool's backends; plaintext when absent
+    pub tls: Option<UpstreamTlsSection>,
+    /// PROXY protocol header starting connections to this pool's backends
+    pub proxy_protocol: Option<ProxyVersion>,
+}
+
+/// A virtual host, keyed by an exact name or a `*.example.com` wildcard
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct HostSection {
+    pub pool: String,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AdminSection {
+    /// Loopback address to serve the admin API on
+    pub listen: Option<SocketAddr>,
+    /// Unix socket to serve the admin API on, instead of `listen`
+    pub unix: Option<PathBuf>,
+    /// Bearer token required on every request
+    pub token: Option<String>,
+    /// File holding the token, as an alternative to `token`
+    pub token_file: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AccessLogSection {
+    /// File to append to; `-` writes to stdout
+    pub path: PathBuf,
+    #[serde(default)]
+    pub format: LogFormat,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LogSection {
+    /// `tracing` filter directives, e.g. `info,flax::balancer=debug`;
+    /// `RUST_LOG` takes precedence
+    pub filter: String,
+    pub format: LogOutput,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TlsSection {
+    /// PEM certificate chain, leaf first, served when no other matches the SNI
+    pub cert: PathBuf,
+    /// PEM private key (PKCS#8, PKCS#1 or SEC1)
+    pub key: PathBuf,
+    /// Certificates selected by SNI
+    #[serde(default)]
+    pub certificates: Vec<CertificateSection>,
+    #[serde(default)]
+    pub sni_host_mismatch: SniHostMismatch,
+    #[serde(default)]
+    pub min_version: TlsVersion,
+    /// Protocols offered through ALPN, in order of preference
+    #[serde(default = "default_alpn")]
+    pub alpn: Vec<String>,
+    /// Issue TLS 1.3 tickets (and TLS 1.2 stateless tickets) for resumption
+    #[serde(default = "default_true")]
+    pub session_tickets: bool,
+    /// Sessions kept in memory for ID-based resumption
+    #[serde(default = "default_session_cache")]
+    pub session_cache: usize,
+    /// Hand established connections to kernel TLS where the kernel supports it
+    #[serde(default)]
+    pub ktls: bool,
+    /// Ask clients for certificates; none are requested when absent
+    pub client_auth: Option<ClientAuthSection>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ClientAuthSection {
+    /// PEM bundle of the CAs client certificates must chain to
+    pub ca: PathBuf,
+    /// PEM certificate revocation lists of the CAs that issue client certificates
+    #[serde(default)]
+    pub crl: Vec<PathBuf>,
+    /// Refuse handshakes without a certificate; otherwise one is verified
+    /// only if the client sends it
+    #[serde(default = "default_true")]
+    pub required: bool,
+    /// Subjects allowed to send requests, as in `X-Client-Cert-Subject`
+    #[serde(default)]
+    pub allow_subjects: Vec<String>,
+    /// SAN DNS names allowed to send requests, `*.example.com` allowed.
+    /// Any verified client may when neither list is set
+    #[serde(default)]
+    pub allow_names: Vec<String>,
+}
+
+/// Routes requests by the client certificate of their connection
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ClientRouteSection {
+    #[serde(default)]
+    pub subjects: Vec<String>,
+    #[serde(default)]
+    pub names: Vec<String>,
+    pub pool: String,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CertificateSection {
+    /// Server names this certificate is served for, `*.example.com` allowed
+    pub names: Vec<String>,
+    pub cert: PathBuf,
+    pub key: PathBuf,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct UpstreamTlsSection {
+    /// PEM bundle of the CAs backend certificates must chain to
+    pub ca: PathBuf,
+    /// Name sent as SNI and checked against the certificate instead of the
+    /// backend's IP address
+    pub server_name: Option<String>,
+    /// Client certificate chain and key, for backends that require them
+    pub cert: Option<PathBuf>,
+    pub key: Option<PathBuf>,
+    /// Protocols offered through ALPN, in order of preference
+    #[serde(default = "default_alpn")]
+    pub alpn: Vec<String>,
+}
+
+fn default_alpn() -> Vec<String> {
+    vec!["http/1.1".to_string()]
+}
+
+fn default_true() -> bool {
+    true
+}
+
+fn default_session_cache() -> usize {
+    1024
+}
+
+fn default_drain_ms() -> u64 {
+    10_000
+}
+
+fn default_probe_interval_ms() -> u64 {
+    1000
+}
+
+fn default_probe_timeout_ms() -> u64 {
+    300
+}
+
+fn default_indirect_probes() -> usize {
+    3
+}
+
+fn default_suspicion_multiplier() -> u32 {
+    4
+}
+
+fn default_retransmit_multiplier() -> u32 {
+    3
+}
+
+fn default_suspicion_max_multiplier() -> u32 {
+    6
+}
+
+fn default_max_health_multiplier() -> u32 {
+    8
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TraceSection {
+    /// Add `X-Request-Id` to requests that arrive without one
+    pub request_id: bool,
+    /// Forward W3C `traceparent` with Flax's span as the parent
+    pub propagate: bool,
+    /// Export spans as OTLP/JSON, e.g. `http://127.0.0.1:4318/v1/traces`
+    pub otlp_endpoint: Option<String>,
+    /// `service.name` of exported spans
+    pub service_name: String,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct WorkerSection {
+    pub ring_size: u32,
+    pub pool_capacity: usize,
+    /// Grace period for in-flight requests after SIGTERM/SIGINT
+    pub drain_timeout_ms: u64,
+    /// Presence of this table switches the rings to SQPOLL mode
+    pub sqpoll: Option<SqpollSection>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct UdpSection {
+    /// A flow without datagrams either way for this long is closed
+    pub idle_timeout_ms: u64,
+    /// A flow is closed this long after it started even while in use;
+    /// unlimited when absent
+    pub session_timeout_ms: Option<u64>,
+    /// How the backend of a client's new flow is picked
+    pub affinity: FlowAffinity,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DnsSection {
+    /// Servers names are looked up with, as `10.0.0.2` or `10.0.0.2:5353`,
+    /// asked in order; those of /etc/resolv.conf when empty
+    pub nameservers: Vec<String>,
+    /// How long each server gets to answer
+    pub timeout_ms: u64,
+    /// Look names up again at this interval instead of as their records
+    /// expire
+    pub refresh_ms: Option<u64>,
+    /// How long backends gone from DNS drain before they are removed
+    pub drain_ms: u64,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DiscoverySection {
+    /// JSON file, or YAML if it ends in `.yaml` or `.yml`, mapping pool names
+    /// to backends
+    pub path: PathBuf,
+    /// How long backends gone from the file drain before they are removed
+    #[serde(default = "default_drain_ms")]
+    pub drain_ms: u64,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ClusterSection {
+    /// Address probes are exchanged on, which other members know this one by
+    pub bind: SocketAddr,
+    /// Members to join through; none for the first member
+    #[serde(default)]
+    pub seeds: Vec<SocketAddr>,
+    /// Protocol period: one member is probed per interval
+    #[serde(default = "default_probe_interval_ms")]
+    pub probe_interval_ms: u64,
+    /// How long a direct ping waits for its ack before others are asked
+    #[serde(default = "default_probe_timeout_ms")]
+    pub probe_timeout_ms: u64,
+    /// Members asked to ping an unresponsive one on this one's behalf
+    #[serde(default = "default_indirect_probes")]
+    pub indirect_probes: usize,
+    /// Suspected members are declared failed after at least this many
+    /// intervals times log(N) unless they refute it
+    #[serde(default = "default_suspicion_multiplier")]
+    pub suspicion_multiplier: u32,
+    /// A suspicion no other member confirms lasts this many times longer
+    #[serde(default = "default_suspicion_max_multiplier")]
+    pub suspicion_max_multiplier: u32,
+    /// Most the intervals and probe timeouts of a member missing acks are
+    /// stretched by
+    #[serde(default = "default_max_health_multiplier")]
+    pub max_health_multiplier: u32,
+    /// Each membership update is piggybacked this many times log(N)
+    #[serde(default = "default_retransmit_multiplier")]
+    pub retransmit_multiplier: u32,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SqpollSection {
+    pub idle_ms: u32,
+    pub cpu: Option<u32>,
+    pub shared: bool,
+}
+
+impl Default for FlaxConfig {
+    fn default() -> Self {
+        Self {
+            listen: "0.0.0.0:3000".parse().unwrap(),
+            mode: ListenerMode::default(),
+            workers: 0,
+            backends: ["127.0.0.1:8081", "127.0.0.1:8082", "127.0.0.1:8083"]
+                .iter()
+                .map(|a| BackendEntry::Address(BackendTarget::Address(a.parse().unwrap())))
+                .collect(),
+            pools: BTreeMap::new(),
+            pool: DEFAULT_POOL.to_string(),
+            hosts: BTreeMap::new(),
+            route_by: RouteKey::default(),
+            client_routes: Vec::new(),
+            upgrade_socket: None,
+            trusted_proxies: Vec::new(),
+            worker: WorkerSection::default(),
+            udp: UdpSection::default(),
+            dns: DnsSection::default(),
+            discovery: None,
+            cluster: None,
+            admin: None,
+            access_log: None,
+            log: LogSection::default(),
+            trace: TraceSection::default(),
+            tls: None,
+            backend_tls: None,
+            backend_proxy_protocol: None,
+        }
+    }
+}
+
+impl Default for LogSection {
+    fn default() -> Self {
+        Self {
+            filter: "info".to_string(),
+            format: LogOutput::Text,
+        }
+    }
+}
+
+impl Default for TraceSection {
+    fn default() -> Self {
+        let defaults = TraceOptions::default();
+        Self {
+            request_id: defaults.request_id,
+            propagate: defaults.propagate,
+            otlp_endpoint: None,
+            service_name: "flax".to_string(),
+        }
+    }
+}
+
+impl Default for WorkerSection {
+    fn default() -> Self {
+        let defaults = WorkerConfig::default();
+        Self {
+            ring_size: defaults.ring_size,
+            pool_capacity: defaults.pool_capacity,
+            drain_timeout_ms: defaults.drain_timeout.as_millis() as u64,
+            sqpoll: None,
+        }
+    }
+}
+
+impl Default for UdpSection {
+    fn default() -> Self {
+        let defaults = UdpOptions::default();
+        Self {
+            idle_timeout_ms: defaults.idle_timeout.as_millis() as u64,
+            session_timeout_ms: None,
+            affinity: defaults.affinity,
+        }
+    }
+}
+
+impl Default for DnsSection {
+    fn default() -> Self {
+        Self {
+            nameservers: Vec::new(),
+            timeout_ms: 2000,
+            refresh_ms: None,
+            drain_ms: default_drain_ms(),
+        }
+    }
+}
+
+impl Default for SqpollSection {
+    fn default() -> Self {
+        Self {
+            idle_ms: 1000,
+            cpu: None,
+            shared: false,
+        }
+    }
+}
+
+impl FlaxConfig {
+    /// Load from `path`, or fall back to defaults when no path is given.
+    pub fn load(path: Option<&Path>) -> io::Result<Self> {
+        let Some(path) = path else {
+            return Ok(Self::default());
+        };
+        let text = fs::read_to_string(path)?;
+        let config: Self = toml::from_str(&text).map_err(|e| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{}: {}", path.display(), e),
+            )
+        })?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn validate(&self) -> io::Result<()> {
+        if self.pools.contains_key(DEFAULT_POOL) {
+            return Err(invalid(format!(
+                "pool {DEFAULT_POOL:?} is defined by the top-level backends list"
+            )));
+        }
+        let known_pool = |name: &str| name == DEFAULT_POOL || self.pools.contains_key(name);
+        if !known_pool(&self.pool) {
+            return Err(invalid(format!("unknown pool {:?}", self.pool)));
+        }
+        for (name, host) in &self.hosts {
+            if !hostname::valid_pattern(name) {
+                return Err(invalid(format!("host {name:?} is not a host name")));
+            }
+            if !known_pool(&host.pool) {
+                return Err(invalid(format!("host {name:?}: unknown pool {:?}", host.pool)));
+            }
+        }
+        for route in &self.client_routes {
+            if !known_pool(&route.pool) {
+                return Err(invalid(format!("client route: unknown pool {:?}", route.pool)));
+            }
+            if route.subjects.is_empty() && route.names.is_empty() {
+                return Err(invalid(format!(
+                    "client route to {:?}: no subjects or names",
+                    route.pool
+                )));
+            }
+            check_client_names("client route", &route.names)?;
+        }
+        if self.mode == ListenerMode::Tcp {
+            // TLS passes through untouched; only its ClientHello is looked at
+            let http_only = [
+                ("tls", self.tls.is_some()),
+                ("client_routes", !self.client_routes.is_empty()),
+            ];
+            if let Some((key, _)) = http_only.iter().find(|(_, set)| *set) {
+                return Err(invalid(format!("{key} needs mode \"http\"")));
+            }
+        }
+        if self.mode == ListenerMode::Udp {
+            // datagrams carry no names, and are relayed as they arrive
+            let stream_only = [
+                ("tls", self.tls.is_some()),
+                ("client_routes", !self.client_routes.is_empty()),
+                ("hosts", !self.hosts.is_empty()),
+                ("trusted_proxies", !self.trusted_proxies.is_empty()),
+                ("upgrade_socket", self.upgrade_socket.is_some()),
+            ];
+            if let Some((key, _)) = stream_only.iter().find(|(_, set)| *set) {
+                return Err(invalid(format!("{key} can't be used with mode \"udp\"")));
+            }
+            let pool = self.pool.as_str();
+            if self.upstream_tls().iter().any(|(name, _)| *name == pool) {
+                return Err(invalid(format!("pool {pool:?}: tls can't be used with mode \"udp\"")));
+            }
+            if self.proxy_protocol().iter().any(|(name, _)| *name == pool) {
+                return Err(invalid(format!(
+                    "pool {pool:?}: proxy_protocol can't be used with mode \"udp\""
+                )));
+            }
+            if self.listen.inet().is_none() {
+                return Err(invalid("a unix listen address can't be used with mode \"udp\"".into()));
+            }
+            let unix_backend = self
+                .backend_pools()
+                .into_iter()
+                .filter(|(name, _)| name == pool)
+                .flat_map(|(_, backends)| backends)
+                .find(|b| b.address.inet().is_none());
+            if let Some(b) = unix_backend {
+                return Err(invalid(format!(
+                    "pool {pool:?}: unix backend {} can't be used with mode \"udp\"",
+                    b.address
+                )));
+            }
+            if self.udp.idle_timeout_ms == 0 || self.udp.session_timeout_ms == Some(0) {
+                return Err(invalid("udp timeouts must be greater than zero".into()));
+            }
+        }
+        let client_auth = self.tls.as_ref().and_then(|tls| tls.client_auth.as_ref());
+        if !self.client_routes.is_empty() && client_auth.is_none() {
+            return Err(invalid("client_routes need [tls.client_auth]".into()));
+        }
+        if let Some(auth) = client_auth {
+            check_client_names("tls client_auth", &auth.allow_names)?;
+        }
+        for (name, backends) in self.backend_pools() {
+            if let Some(b) = backends.iter().find(|b| b.weight > MAX_WEIGHT) {
+                return Err(invalid(format!(
+                    "pool {name:?}: weight of {} exceeds {MAX_WEIGHT}",
+                    b.address
+                )));
+            }
+        }
+        for (name, entries) in self.backend_entries() {
+            for entry in entries {
+                match entry.target() {
+                    (BackendTarget::Dns(target @ DnsTarget::Srv(_)), Some(_)) => {
+                        return Err(invalid(format!(
+                            "pool {name:?}: {target} takes its weights from its SRV records"
+                        )));
+                    }
+                    (BackendTarget::Dns(target), Some(weight)) if weight > MAX_WEIGHT => {
+                        return Err(invalid(format!(
+                            "pool {name:?}: weight of {target} exceeds {MAX_WEIGHT}"
+                        )));
+                    }
+                    _ => {}
+                }
+            }
+        }
+        self.dns_options()?;
+        let pools = self.backend_pools();
+        for (name, tls) in self.upstream_tls() {
+            if tls.cert.is_some() != tls.key.is_some() {
+                return Err(invalid(format!("pool {name:?}: tls needs both cert and key")));
+            }
+            let unix_backend = pools
+                .iter()
+                .filter(|(pool, _)| pool == name)
+                .flat_map(|(_, backends)| backends)
+                .find(|b| b.address.inet().is_none());
+            if let (None, Some(b)) = (&tls.server_name, unix_backend) {
+                return Err(invalid(format!(
+                    "pool {name:?}: tls to unix backend {} needs server_name",
+                    b.address
+                )));
+            }
+            let dns_backend = self
+                .dns_backends()
+                .into_iter()
+                .find(|b| b.pool == name);
+            if let (None, Some(b)) = (&tls.server_name, dns_backend) {
+                return Err(invalid(format!(
+                    "pool {name:?}: tls to dns backend {} needs server_name",
+                    b.target
+                )));
+            }
+            if let Some(server_name) = &tls.server_name
+                && ServerName::try_from(server_name.as_str()).is_err()
+            {
+                return Err(invalid(format!(
+                    "pool {name:?}: tls server_name {server_name:?} is not a host name or address"
+                )));
+            }
+            if let Some(p) = tls.alpn.iter().find(|p| !p.starts_with("http/1.")) {
+                return Err(invalid(format!(
+                    "pool {name:?}: tls alpn {p:?}: only HTTP/1.x is proxied"
+                )));
+            }
+        }
+        if let Some(admin) = &self.admin {
+            match (admin.listen, &admin.unix) {
+                (Some(addr), None) if !addr.ip().is_loopback() => {
+                    return Err(invalid(format!(
+                        "admin listen address {addr} is not a loopback address"
+                    )));
+                }
+                (Some(_), None) | (None, Some(_)) => {}
+                _ => return Err(invalid("admin needs exactly one of listen or unix".into())),
+            }
+            if admin.token.is_none() == admin.token_file.is_none() {
+                return Err(invalid("admin needs exactly one of token or token_file".into()));
+            }
+        }
+        if let Some(cluster) = &self.cluster {
+            if cluster.bind.ip().is_unspecified() {
+                return Err(invalid(format!(
+                    "cluster bind {}: members need a specific address to reach this one at",
+                    cluster.bind
+                )));
+            }
+            if cluster.seeds.contains(&cluster.bind) {
+                return Err(invalid("cluster seeds can't include bind".into()));
+            }
+            if !(1..cluster.probe_interval_ms).contains(&cluster.probe_timeout_ms) {
+                return Err(invalid(
+                    "cluster probe_timeout_ms must be at least 1 and below probe_interval_ms".into(),
+                ));
+            }
+            let multipliers = [
+                cluster.suspicion_multiplier,
+                cluster.suspicion_max_multiplier,
+                cluster.retransmit_multiplier,
+                cluster.max_health_multiplier,
+            ];
+            if multipliers.contains(&0) {
+                return Err(invalid("cluster multipliers must be at least 1".into()));
+            }
+        }
+        self.otlp_endpoint()?;
+        if let Some(tls) = &self.tls {
+            if let Some(p) = tls.alpn.iter().find(|p| !p.starts_with("http/1.")) {
+                return Err(invalid(format!(
+                    "tls alpn {p:?}: only HTTP/1.x is proxied"
+                )));
+            }
+            for certificate in &tls.certificates {
+                if certificate.names.is_empty() {
+                    return Err(invalid(format!(
+                        "tls certificate {}: no names",
+                        certificate.cert.display()
+                    )));
+                }
+                if let Some(name) = certificate.names.iter().find(|n| !hostname::valid_pattern(n)) {
+                    return Err(invalid(format!(
+                        "tls certificate {}: {name:?} is not a host name",
+                        certificate.cert.display()
+                    )));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Collector spans are exported to, if configured.
+    pub fn otlp_endpoint(&self) -> io::Result<Option<otlp::Endpoint>> {
+        self.trace
+            .otlp_endpoint
+            .as_deref()
+            .map(otlp::Endpoint::parse)
+            .transpose()
+    }
+
+    /// The `default` pool followed by the named pools, with the backends at
+    /// fixed addresses.
+    pub fn backend_pools(&self) -> Vec<(String, Vec<Backend>)> {
+        self.backend_entries()
+            .map(|(name, entries)| {
+                let backends = entries.iter().filter_map(BackendEntry::backend).collect();
+                (name.to_string(), backends)
+            })
+            .collect()
+    }
+
+    /// Backends named in DNS, in every pool.
+    pub fn dns_backends(&self) -> Vec<DnsBackend> {
+        self.backend_entries()
+            .flat_map(|(name, entries)| entries.iter().filter_map(|e| e.dns_backend(name)))
+            .collect()
+    }
+
+    fn backend_entries(&self) -> impl Iterator<Item = (&str, &[BackendEntry])> {
+        let default = (DEFAULT_POOL, self.backends.as_slice());
+        let named = self
+            .pools
+            .iter()
+            .map(|(name, pool)| (name.as_str(), pool.backends.as_slice()));
+        std::iter::once(default).chain(named)
+    }
+
+    /// The watched backend file, if any.
+    pub fn discovery(&self) -> Option<FileDiscovery> {
+        self.discovery.as_ref().map(|section| FileDiscovery {
+            path: section.path.clone(),
+            drain: Duration::from_millis(section.drain_ms),
+        })
+    }
+
+    /// Cluster membership, if configured.
+    pub fn cluster(&self) -> Option<ClusterOptions> {
+        self.cluster.as_ref().map(|section| ClusterOptions {
+            bind: section.bind,
+            seeds: section.seeds.clone(),
+            swim: SwimOptions {
+                period: Duration::from_millis(section.probe_interval_ms),
+                ping_timeout: Duration::from_millis(section.probe_timeout_ms),
+                indirect: section.indirect_probes,
+                suspicion_multiplier: section.suspicion_multiplier,
+                suspicion_max_multiplier: section.suspicion_max_multiplier,
+                max_health_multiplier: section.max_health_multiplier,
+                retransmit_multiplier: section.retransmit_multiplier,
+            },
+        })
+    }
+
+    /// How backends named in DNS are looked up.
+    pub fn dns_options(&self) -> io::Result<DnsOptions> {
+        let dns = &self.dns;
+        let nameservers = match dns.nameservers.as_slice() {
+            [] => dns::system_nameservers(),
+            servers => servers
+                .iter()
+                .map(|s| {
+                    dns::parse_nameserver(s).ok_or_else(|| {
+                        invalid(format!("dns nameserver {s:?} is not an address"))
+                    })
+                })
+                .collect::<io::Result<_>>()?,
+        };
+        if dns.timeout_ms == 0 || dns.refresh_ms == Some(0) {
+            return Err(invalid("dns timeout_ms and refresh_ms must be greater than zero".into()));
+        }
+        Ok(DnsOptions {
+            nameservers,
+            timeout: Duration::from_millis(dns.timeout_ms),
+            refresh: dns.refresh_ms.map(Duration::from_millis),
+            drain: Duration::from_millis(dns.drain_ms),
+        })
+    }
+
+    /// TLS settings of the pools whose backends are reached over TLS.
+    pub fn upstream_tls(&self) -> Vec<(&str, &UpstreamTlsSection)> {
+        let default = self.backend_tls.iter().map(|tls| (DEFAULT_POOL, tls));
+        let named = self
+            .pools
+            .iter()
+            .filter_map(|(name, pool)| Some((name.as_str(), pool.tls.as_ref()?)));
+        default.chain(named).collect()
+    }
+
+    /// PROXY protocol versions of the pools that send headers.
+    pub fn proxy_protocol(&self) -> Vec<(&str, ProxyVersion)> {
+        let default = self
+            .backend_proxy_protocol
+            .map(|version| (DEFAULT_POOL, version));
+        let named = self
+            .pools
+            .iter()
+            .filter_map(|(name, pool)| Some((name.as_str(), pool.proxy_protocol?)));
+        default.into_iter().chain(named).collect()
+    }
+
+    pub fn worker_config(&self) -> WorkerConfig {
+        let ring_mode = match &self.worker.sqpoll {
+            None => RingMode::DeferTaskrun,
+            Some(sq) => RingMode::Sqpoll(SqpollConfig {
+                idle_ms: sq.idle_ms,
+                cpu: sq.cpu,
+                shared: sq.shared,
+                attach_wq_fd: None,
+            }),
+        };
+        let mut config =
+            WorkerConfig::get(self.worker.ring_size, self.worker.pool_capacity, ring_mode);
+        config.drain_timeout = Duration::from_millis(self.worker.drain_timeout_ms);
+        config.mode = self.mode;
+        config.pool = self.pool.clone();
+        config.hosts = self
+            .hosts
+            .iter()
+            .map(|(name, host)| (name.clone(), host.pool.clone()))
+            .collect();
+        config.route_by = self.route_by;
+        config.trusted_proxies = self.trusted_proxies.clone();
+        config.udp = UdpOptions {
+            idle_timeout: Duration::from_millis(self.udp.idle_timeout_ms),
+            session_timeout: self.udp.session_timeout_ms.map(Duration::from_millis),
+            affinity: self.udp.affinity,
+        };
+        config.client_routes = self
+            .client_routes
+            .iter()
+            .map(|route| {
+                let matcher = ClientMatcher::new(&route.subjects, &route.names);
+                (matcher, route.pool.clone())
+            })
+            .collect();
+        if let Some(auth) = self.tls.as_ref().and_then(|tls| tls.client_auth.as_ref()) {
+            config.client_auth = true;
+            config.client_allow = ClientMatcher::new(&auth.allow_subjects, &auth.allow_names);
+        }
+        config.sni_host_mismatch = self
+            .tls
+            .as_ref()
+            .map(|tls| tls.sni_host_mismatch)
+            .unwrap_or_default();
+        config.trace = TraceOptions {
+            request_id: self.trace.request_id,
+            propagate: self.trace.propagate,
+        };
+        config
+    }
+}
+
+fn check_client_names(what: &str, names: &[String]) -> io::Result<()> {
+    match names.iter().find(|name| !hostname::valid_pattern(name)) {
+        Some(name) => Err(invalid(format!("{what}: {name:?} is not a host name"))),
+        None => Ok(()),
+    }
+}
+
+fn invalid(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, msg)
+}
@@ -0,0 +1,90 @@
+//! systemd socket activation (`LISTEN_FDS` / `LISTEN_PID`)
+//!
+//! Passed sockets start at fd 3. Only listening TCP and Unix stream sockets
+//! are used; the environment is cleared afterwards so child processes don't
+//! inherit it.
+
+use std::io;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+
+use tracing::{info, warn};
+
+use crate::core::socket::{getsockopt_int, socket_address};
+use crate::util::address::Address;
+
+const SD_LISTEN_FDS_START: RawFd = 3;
+
+/// Take the listening stream sockets passed by the service manager, if any.
+///
+/// Must be called before any other thread is spawned.
+pub fn inherited_listeners() -> io::Result<Vec<OwnedFd>> {
+    let pid = std::env::var("LISTEN_PID").ok();
+    let count = std::env::var("LISTEN_FDS").ok();
+    // SAFETY: single-threaded at this point in startup.
+    unsafe {
+        std::env::remove_var("LISTEN_PID");
+        std::env::remove_var("LISTEN_FDS");
+        std::env::remove_var("LISTEN_FDNAMES");
+    }
+
+    let (Some(pid), Some(count)) = (pid, count) else {
+        return Ok(Vec::new());
+    };
+    if pid.parse::<u32>().ok() != Some(std::process::id()) {
+        // meant for a parent or sibling process
+        return Ok(Vec::new());
+    }
+    let count: RawFd = count
+        .parse()
+        .map_err(|_| invalid(format!("LISTEN_FDS is not a number: {count:?}")))?;
+
+    let mut listeners = Vec::new();
+    for fd in SD_LISTEN_FDS_START..SD_LISTEN_FDS_START + count {
+        unsafe {
+            if libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) < 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+        let owned = unsafe { OwnedFd::from_raw_fd(fd) };
+        if !is_stream_listener(fd)? {
+            warn!(fd, "socket activation: ignoring fd, not a listening TCP or Unix socket");
+            continue;
+        }
+        listeners.push(owned);
+    }
+    Ok(listeners)
+}
+
+/// Pick the inherited listener bound to `addr`.
+///
+/// A single inherited socket is used even if it doesn't match, since the
+/// unit file is then the source of truth for the address.
+pub fn take_listener(listeners: &mut Vec<OwnedFd>, addr: &Address) -> Option<OwnedFd> {
+    if let Some(pos) = listeners
+        .iter()
+        .position(|l| socket_address(l.as_raw_fd()).as_ref() == Some(addr))
+    {
+        return Some(listeners.swap_remove(pos));
+    }
+    if listeners.len() == 1 {
+        let listener = listeners.pop()?;
+        if let Some(local) = socket_address(listener.as_raw_fd()) {
+            info!(%local, configured = %addr, "socket activation: using inherited listener");
+        }
+        return Some(listener);
+    }
+    None
+}
+
+fn is_stream_listener(fd: RawFd) -> io::Result<bool> {
+    let sock_type = getsockopt_int(fd, libc::SO_TYPE)?;
+    let listening = getsockopt_int(fd, libc::SO_ACCEPTCONN)?;
+    let domain = getsockopt_int(fd, libc::SO_DOMAIN)?;
+    Ok(sock_type == libc::SOCK_STREAM
+        && listening != 0
+        && [libc::AF_INET, libc::AF_INET6, libc::AF_UNIX].contains(&domain))
+}
+
+fn invalid(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, msg)
+}
@@ -1,28 +1,88 @@
 use std::net::SocketAddr;
 use std::os::fd::RawFd;
+use std::time::Instant;
 
 use libc::sockaddr_storage;
+use tracing::Span;
 
+use crate::access_log::RequestLog;
+use crate::backend::BackendPool;
+use crate::core::socket::peer_addr;
 use crate::core::stream_pump::StreamPump;
 use crate::protocol::HttpBuf;
+use crate::tls::TlsSession;
+use crate::tls::client_auth::ClientIdentity;
+use crate::tls::ktls::RecordRecv;
+use crate::util::address::Address;
 
 pub struct ConnectionPair {
     pub id: usize,
     pub client_fd: RawFd,
     pub backend_fd: RawFd,
+    /// Peer address, only looked up when requests are logged or traced, or
+    /// the client's as a trusted proxy's PROXY protocol header named it
+    pub client_address: Option<SocketAddr>,
+    /// Address the client connected to, when a PROXY protocol header named
+    /// one other than the listener's
+    pub local_address: Option<SocketAddr>,
+    /// The connection comes from a trusted proxy and starts with a PROXY
+    /// protocol header, which is read before anything else
+    pub awaiting_proxy_header: bool,
 
-    pub backend_address: Option<SocketAddr>,
+    pub backend_address: Option<Address>,
+    /// Pool `backend_address` was chosen from
+    pub backend_pool: Option<&'static BackendPool>,
     pub backend_sockaddr_storage: Option<Box<sockaddr_storage>>,
     pub backend_sockaddr_len: libc::socklen_t,
+    /// Set when `backend_pool` reaches its backends over TLS
+    pub backend_tls: Option<Box<TlsSession>>,
 
     pub header_buffer: HttpBuf,
+    /// Set on connections accepted by a TLS listener
+    pub tls: Option<Box<TlsSession>>,
+    /// Set once the kernel took over TLS from `tls`; the pumps see
+    /// plaintext, read through this to notice the peer's alerts
+    pub ktls: Option<Box<RecordRecv>>,
+    /// Identity from the client's verified certificate
+    pub client_cert: Option<Box<ClientIdentity>>,
+    /// SNI from the client's TLS handshake, or from the ClientHello of a
+    /// passthrough connection
+    pub server_name: Option<String>,
+    /// Protocol negotiated with the client through ALPN
+    pub alpn: Option<Vec<u8>>,
 
     pub pump_client_to_backend: StreamPump,
     pub pump_backend_to_client: StreamPump,
 
-    pub request_content_length: Option<usize>,
-    pub request_transfer_encoding_chunked: bool,
+    /// The client sent the head of its next request before the last one was
+    /// done; it waits in `header_buffer` and nothing of it reaches the
+    /// backend until it has been read like the first
+    pub next_request_pending: bool,
+    /// The request is a HEAD, so the response has no body whatever its head says
+    pub head_request: bool,
+    /// No bytes of the response have arrived yet
+    pub awaiting_response_head: bool,
+    /// Response bytes the backend has yet to send, when its head told the
+    /// length; the backend connection can serve another request after them
+    pub response_remaining: Option<usize>,
+    /// The client left after a complete response; the recv on the backend
+    /// is being cancelled so its connection can go back to the cache
+    pub parking_backend: bool,
+    /// When the current request head was complete; cleared at the first response bytes
+    pub request_started: Option<Instant>,
+    /// `X-Request-Id` of the request in progress, also sent on error responses
+    pub request_id: Option<String>,
+    /// Access log record of the request in progress
+    pub access: Option<Box<RequestLog>>,
+    /// Lives as long as the client connection
+    pub span: Span,
+    /// Child of `span` for the request in progress
+    pub request_span: Span,
     pub had_error: bool,
+    /// Flax is answering the client itself; close once the response is sent
+    pub close_after_response: bool,
+    /// Relays bytes both ways without parsing them (`mode = "tcp"`)
+    pub passthrough: bool,
 }
 
 impl ConnectionPair {
@@ -35,19 +95,39 @@ impl ConnectionPair {
         Self {
             id,
             client_fd,
+            client_address: None,
+            local_address: None,
+            awaiting_proxy_header: false,
             backend_address: None,
+            backend_pool: None,
             backend_fd: -1,
             backend_sockaddr_storage: None,
             backend_sockaddr_len: 0,
+            backend_tls: None,
 
             header_buffer: HttpBuf::with_capacity(header_buffer_capacity),
+            tls: None,
+            ktls: None,
+            client_cert: None,
+            server_name: None,
+            alpn: None,
 
             pump_client_to_backend: StreamPump::new(client_fd, -1, io_buffer_capacity),
             pump_backend_to_client: StreamPump::new(-1, client_fd, io_buffer_capacity),
 
-            request_content_length: None,
-            request_transfer_encoding_chunked: false,
+            next_request_pending: false,
+            head_request: false,
+            awaiting_response_head: false,
+            response_remaining: None,
+            parking_backend: false,
+            request_started: None,
+            request_id: None,
+            access: None,
+            span: Span::none(),
+            request_span: Span::none(),
             had_error: false,
+            close_after_response: false,
+            passthrough: false,
         }
     }
 
@@ -58,12 +138,29 @@ impl ConnectionPair {
         self.pump_backend_to_client.read_fd = backend_fd;
     }
 
+    /// Peer address, looked up now if it wasn't at accept.
+    pub fn resolve_client_address(&mut self) -> Option<SocketAddr> {
+        if self.client_address.is_none() {
+            self.client_address = peer_addr(self.client_fd);
+        }
+        self.client_address
+    }
+
     /// Store sockaddr backing memory and length so the pointer stays valid during Connect.
     pub fn set_backend_sockaddr(&mut self, storage: Box<sockaddr_storage>, len: libc::socklen_t) {
         self.backend_sockaddr_storage = Some(storage);
         self.backend_sockaddr_len = len;
     }
 
+    /// Span to enter while handling a completion for this pair.
+    pub fn current_span(&self) -> Span {
+        if self.request_span.is_none() {
+            self.span.clone()
+        } else {
+            self.request_span.clone()
+        }
+    }
+
     /// Called once Connect completes successfully to arm both directions.
     pub fn start_streaming(&mut self) {
         // Now that connect is done, both fds are valid in both pumps.
@@ -1,3 +1,5 @@
+use std::time::Duration;
+
 pub const READ_BUF: usize = 4096;
 
 // operation tags for low 8 bits of user_data
@@ -8,4 +10,6 @@ pub const OP_SEND: u8 = 4;
 
 pub const INITIAL_ACCEPTS_PER_WORKER: usize = 8;
 pub const IO_BUFFER_CAPACITY: usize = 32 * 1024;
-pub const HEADER_BUFFER_CAPACITY: usize = 8 * 1024;
\ No newline at end of file
+pub const HEADER_BUFFER_CAPACITY: usize = 8 * 1024;
+pub const DRAIN_TIMEOUT: Duration = Duration::from_secs(30);
+pub const UDP_IDLE_TIMEOUT: Duration = Duration::from_secs(30);
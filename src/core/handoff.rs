@@ -0,0 +1,229 @@
+//! Listening socket handoff for zero-downtime upgrades
+//!
+//! The running process serves a Unix socket. A new process started with
+//! `--upgrade` connects to it and receives the listener fds via SCM_RIGHTS.
+//! Both processes then hold the *same* sockets, so connections already
+//! queued in the accept backlog are picked up by the new process instead of
+//! being reset. Once the new process is accepting it replies with READY and
+//! the old one drains.
+//!
+//! Wire format, old -> new: `FLAX` magic + u32 fd count (native endian),
+//! with the fds attached as SCM_RIGHTS. New -> old: a single READY byte.
+
+use std::io::{self, Read, Write};
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::Path;
+use std::time::Duration;
+use std::{mem, ptr};
+
+use tracing::{info, warn};
+
+use crate::core::socket::{bind_unix_listener, socket_address};
+use crate::util::address::Address;
+
+const MAGIC: &[u8; 4] = b"FLAX";
+const READY: u8 = b'R';
+/// Kernel limit on fds per SCM_RIGHTS message (SCM_MAX_FD).
+const MAX_FDS: usize = 253;
+/// How long the old process waits for the new one to start accepting.
+const READY_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Listener fds received from the previous process.
+pub struct Takeover {
+    stream: UnixStream,
+    fds: Vec<OwnedFd>,
+}
+
+impl Takeover {
+    /// Connect to the running process and receive its listeners.
+    pub fn connect(path: &Path) -> io::Result<Self> {
+        let stream = UnixStream::connect(path)?;
+        let fds = recv_fds(&stream)?;
+        Ok(Self { stream, fds })
+    }
+
+    /// Take the inherited listeners bound to `addr`; any others are closed.
+    pub fn listeners_for(&mut self, addr: &Address) -> Vec<OwnedFd> {
+        let mut out = Vec::new();
+        for fd in self.fds.drain(..) {
+            match socket_address(fd.as_raw_fd()) {
+                Some(local) if local == *addr => out.push(fd),
+                Some(local) => warn!(%local, "handoff: dropping inherited listener"),
+                None => warn!("handoff: dropping inherited fd without an address"),
+            }
+        }
+        out
+    }
+
+    /// Tell the previous process we are accepting so it can start draining.
+    pub fn complete(mut self) -> io::Result<()> {
+        self.stream.write_all(&[READY])
+    }
+}
+
+/// Serve listener handoff requests on `server` until one completes.
+///
+/// `on_complete` runs once a new process has confirmed it is accepting on
+/// the handed-over sockets. Failed attempts are logged and the socket keeps
+/// serving, so a broken new binary leaves this process untouched.
+pub fn serve_handoff(
+    server: UnixListener,
+    listener_fds: Vec<RawFd>,
+    on_complete: impl FnOnce() + Send + 'static,
+) {
+    std::thread::spawn(move || {
+        for conn in server.incoming() {
+            let mut conn = match conn {
+                Ok(conn) => conn,
+                Err(e) => {
+                    warn!("handoff: accept failed: {e}");
+                    continue;
+                }
+            };
+            match hand_over(&mut conn, &listener_fds) {
+                Ok(()) => {
+                    info!("handoff: new process is accepting, draining");
+                    on_complete();
+                    return;
+                }
+                Err(e) => warn!("handoff: aborted: {e}"),
+            }
+        }
+    });
+}
+
+fn hand_over(conn: &mut UnixStream, fds: &[RawFd]) -> io::Result<()> {
+    send_fds(conn, fds)?;
+    conn.set_read_timeout(Some(READY_TIMEOUT))?;
+    let mut reply = [0u8; 1];
+    conn.read_exact(&mut reply)?;
+    if reply[0] != READY {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "unexpected handoff reply",
+        ));
+    }
+    Ok(())
+}
+
+/// Bind the handoff socket.
+///
+/// A stale socket file is replaced, but a live one is only replaced when
+/// `takeover` is set, i.e. after this process has taken over from its owner.
+pub fn bind_handoff_socket(path: &Path, takeover: bool) -> io::Result<UnixListener> {
+    bind_unix_listener(path, takeover).map_err(|e| {
+        if e.kind() != io::ErrorKind::AddrInUse {
+            return e;
+        }
+        io::Error::new(
+            io::ErrorKind::AddrInUse,
+            format!(
+                "{} is served by a running instance; start with --upgrade to take over",
+                path.display()
+            ),
+        )
+    })
+}
+
+fn send_fds(stream: &UnixStream, fds: &[RawFd]) -> io::Result<()> {
+    if fds.is_empty() || fds.len() > MAX_FDS {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("cannot hand over {} fds", fds.len()),
+        ));
+    }
+
+    let mut header = [0u8; 8];
+    header[..4].copy_from_slice(MAGIC);
+    header[4..].copy_from_slice(&(fds.len() as u32).to_ne_bytes());
+    let mut iov = libc::iovec {
+        iov_base: header.as_mut_ptr() as *mut libc::c_void,
+        iov_len: header.len(),
+    };
+
+    let fds_len = mem::size_of_val(fds) as u32;
+    let mut cmsg_buf = vec![0u8; unsafe { libc::CMSG_SPACE(fds_len) } as usize];
+
+    unsafe {
+        let mut msg: libc::msghdr = mem::zeroed();
+        msg.msg_iov = &mut iov;
+        msg.msg_iovlen = 1;
+        msg.msg_control = cmsg_buf.as_mut_ptr() as *mut libc::c_void;
+        msg.msg_controllen = cmsg_buf.len();
+
+        let cmsg = libc::CMSG_FIRSTHDR(&msg);
+        (*cmsg).cmsg_level = libc::SOL_SOCKET;
+        (*cmsg).cmsg_type = libc::SCM_RIGHTS;
+        (*cmsg).cmsg_len = libc::CMSG_LEN(fds_len) as usize;
+        ptr::copy_nonoverlapping(
+            fds.as_ptr() as *const u8,
+            libc::CMSG_DATA(cmsg),
+            fds_len as usize,
+        );
+
+        if libc::sendmsg(stream.as_raw_fd(), &msg, 0) < 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+fn recv_fds(stream: &UnixStream) -> io::Result<Vec<OwnedFd>> {
+    let mut header = [0u8; 8];
+    let mut iov = libc::iovec {
+        iov_base: header.as_mut_ptr() as *mut libc::c_void,
+        iov_len: header.len(),
+    };
+    let max_len = (MAX_FDS * mem::size_of::<RawFd>()) as u32;
+    let mut cmsg_buf = vec![0u8; unsafe { libc::CMSG_SPACE(max_len) } as usize];
+
+    let mut fds = Vec::new();
+    unsafe {
+        let mut msg: libc::msghdr = mem::zeroed();
+        msg.msg_iov = &mut iov;
+        msg.msg_iovlen = 1;
+        msg.msg_control = cmsg_buf.as_mut_ptr() as *mut libc::c_void;
+        msg.msg_controllen = cmsg_buf.len();
+
+        let n = libc::recvmsg(stream.as_raw_fd(), &mut msg, libc::MSG_CMSG_CLOEXEC);
+        if n < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        // take ownership of whatever arrived before validating, so nothing leaks
+        let mut cmsg = libc::CMSG_FIRSTHDR(&msg);
+        while !cmsg.is_null() {
+            if (*cmsg).cmsg_level == libc::SOL_SOCKET && (*cmsg).cmsg_type == libc::SCM_RIGHTS {
+                let data = libc::CMSG_DATA(cmsg) as *const RawFd;
+                let payload = (*cmsg).cmsg_len as usize - libc::CMSG_LEN(0) as usize;
+                for i in 0..payload / mem::size_of::<RawFd>() {
+                    fds.push(OwnedFd::from_raw_fd(ptr::read_unaligned(data.add(i))));
+                }
+            }
+            cmsg = libc::CMSG_NXTHDR(&msg, cmsg);
+        }
+
+        if n as usize != header.len() || &header[..4] != MAGIC {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "not a Flax handoff socket",
+            ));
+        }
+        if msg.msg_flags & libc::MSG_CTRUNC != 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "handoff fds truncated",
+            ));
+        }
+    }
+
+    let expected = u32::from_ne_bytes(header[4..].try_into().unwrap()) as usize;
+    if fds.len() != expected {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("expected {expected} fds, received {}", fds.len()),
+        ));
+    }
+    Ok(fds)
+}
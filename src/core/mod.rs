@@ -1,5 +1,7 @@
+pub mod activation;
 pub mod connection_pair;
 pub mod constants;
+pub mod handoff;
 pub mod socket;
 pub mod stream_pump;
 pub mod user_data;
\ No newline at end of file
@@ -1,32 +1,26 @@
+//! Socket utility functions for load balancer
+//!
+//! This module provides low-level socket operations including:
+//! - Backend connection socket creation, TCP or Unix
+//! - SO_REUSEPORT listener setup for multi-core workers, TCP and UDP
+//! - Spreading a Unix or inherited listener across workers
+//! - Unix listener binding that cleans up stale socket files
+
 use libc::{sockaddr_in6, sockaddr_storage};
-use socket2::{Domain, Protocol, Socket, Type};
+use socket2::{Domain, Protocol, SockRef, Socket, Type};
 use std::io;
-use std::net::{SocketAddr, TcpListener};
-use std::os::fd::{IntoRawFd, RawFd};
+use std::net::{SocketAddr, TcpListener, UdpSocket};
+use std::os::fd::{AsRawFd, BorrowedFd, IntoRawFd, OwnedFd, RawFd};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::Path;
 
-/// Socket utility functions for load balancer
-///
-/// This module provides low-level socket operations including:
-/// - Backend connection socket creation
-/// - SO_REUSEPORT listener setup for multi-core workers
+use crate::util::address::Address;
 
 pub fn make_backend_socket(
-    addr: SocketAddr,
+    addr: &Address,
 ) -> io::Result<(RawFd, Box<sockaddr_storage>, libc::socklen_t)> {
-    let (domain, (storage, len)) = match addr {
-        SocketAddr::V4(_) => {
-            let sock_addr: socket2::SockAddr = addr.into();
-            let mut ss: sockaddr_storage = unsafe { std::mem::zeroed() };
-            unsafe {
-                std::ptr::copy_nonoverlapping(
-                    sock_addr.as_ptr() as *const u8,
-                    &mut ss as *mut _ as *mut u8,
-                    sock_addr.len() as usize,
-                );
-            }
-            (Domain::IPV4, (Box::new(ss), sock_addr.len()))
-        }
-        SocketAddr::V6(a6) => {
+    let (storage, len) = match *addr {
+        Address::Inet(SocketAddr::V6(a6)) => {
             let mut st: sockaddr_in6 = unsafe { std::mem::zeroed() };
             st.sin6_family = libc::AF_INET6 as u16;
             st.sin6_port = a6.port().to_be();
@@ -39,16 +33,29 @@ pub fn make_backend_socket(
                 std::ptr::write(&mut ss as *mut _ as *mut sockaddr_in6, st);
             }
             (
-                Domain::IPV6,
-                (
-                    Box::new(ss),
-                    std::mem::size_of::<sockaddr_in6>() as libc::socklen_t,
-                ),
+                Box::new(ss),
+                std::mem::size_of::<sockaddr_in6>() as libc::socklen_t,
             )
         }
+        _ => {
+            let sock_addr = addr.sockaddr();
+            let mut ss: sockaddr_storage = unsafe { std::mem::zeroed() };
+            unsafe {
+                std::ptr::copy_nonoverlapping(
+                    sock_addr.as_ptr() as *const u8,
+                    &mut ss as *mut _ as *mut u8,
+                    sock_addr.len() as usize,
+                );
+            }
+            (Box::new(ss), sock_addr.len())
+        }
     };
 
-    let sock = Socket::new(domain, Type::STREAM, Some(Protocol::TCP))?;
+    let protocol = match addr {
+        Address::Inet(_) => Some(Protocol::TCP),
+        Address::Unix(_) => None,
+    };
+    let sock = Socket::new(addr.domain(), Type::STREAM, protocol)?;
     sock.set_nonblocking(true)?; // MUST be non-blocking for io_uring
     let fd = sock.into_raw_fd();
 
@@ -75,3 +82,128 @@ pub fn make_reuseport_listener(addr: SocketAddr) -> io::Result<TcpListener> {
     sock.listen(1024)?;
     Ok(sock.into())
 }
+
+/// Create a SO_REUSEPORT UDP socket
+///
+/// The kernel spreads datagrams over the workers' sockets by a hash of the
+/// sender's address, so all of a client's datagrams reach the same worker.
+pub fn make_reuseport_udp_socket(addr: SocketAddr) -> io::Result<UdpSocket> {
+    let sock = Socket::new(Domain::for_address(addr), Type::DGRAM, Some(Protocol::UDP))?;
+    sock.set_reuse_address(true)?;
+    sock.set_reuse_port(true)?;
+    sock.bind(&addr.into())?;
+    Ok(sock.into())
+}
+
+/// Create a UDP socket connected to a backend, carrying one client flow's
+/// datagrams there and its replies back
+pub fn make_backend_udp_socket(addr: SocketAddr) -> io::Result<RawFd> {
+    let sock = Socket::new(Domain::for_address(addr), Type::DGRAM, Some(Protocol::UDP))?;
+    sock.set_nonblocking(true)?;
+    sock.connect(&addr.into())?;
+    Ok(sock.into_raw_fd())
+}
+
+/// Bind one listener per worker at `addr`.
+///
+/// A TCP address gets a SO_REUSEPORT socket per worker. A Unix socket can't
+/// share its address, so all workers accept from duplicates of one; a stale
+/// socket file is replaced, one another process still accepts on is not.
+pub fn bind_listeners(addr: &Address, workers: usize) -> io::Result<Vec<OwnedFd>> {
+    let unix = match addr {
+        Address::Inet(addr) => {
+            return (0..workers)
+                .map(|_| make_reuseport_listener(*addr).map(OwnedFd::from))
+                .collect();
+        }
+        Address::Unix(unix) => unix,
+    };
+    let listener = match unix.path() {
+        Some(path) => OwnedFd::from(bind_unix_listener(path, false)?),
+        None => {
+            let sock = Socket::new(Domain::UNIX, Type::STREAM, None)?;
+            sock.bind(&unix.sockaddr())?;
+            sock.listen(1024)?;
+            OwnedFd::from(sock)
+        }
+    };
+    fan_out_listener(listener, workers)
+}
+
+/// Spread one listener across `workers` sockets.
+///
+/// If the socket already has SO_REUSEPORT (e.g. `ReusePort=yes` in a systemd
+/// socket unit), extra workers bind their own sockets into the same reuseport
+/// group. Otherwise all workers accept from duplicates of the one socket.
+pub fn fan_out_listener(listener: OwnedFd, workers: usize) -> io::Result<Vec<OwnedFd>> {
+    let reuseport = getsockopt_int(listener.as_raw_fd(), libc::SO_REUSEPORT)? != 0;
+    let addr = socket_address(listener.as_raw_fd()).and_then(|addr| addr.inet());
+
+    let mut listeners = Vec::with_capacity(workers);
+    for _ in 1..workers {
+        let extra = match addr {
+            Some(addr) if reuseport => make_reuseport_listener(addr)?.into(),
+            _ => listener.try_clone()?,
+        };
+        listeners.push(extra);
+    }
+    listeners.insert(0, listener);
+    Ok(listeners)
+}
+
+/// Bind a Unix listener at `path`.
+///
+/// A stale socket file left by a dead process is replaced. A socket some
+/// live process still accepts on is only replaced if `replace_live` is set;
+/// otherwise this fails with `AddrInUse`.
+pub fn bind_unix_listener(path: &Path, replace_live: bool) -> io::Result<UnixListener> {
+    match UnixListener::bind(path) {
+        Err(e) if e.kind() == io::ErrorKind::AddrInUse => {
+            if !replace_live && UnixStream::connect(path).is_ok() {
+                return Err(e);
+            }
+            std::fs::remove_file(path)?;
+            UnixListener::bind(path)
+        }
+        other => other,
+    }
+}
+
+pub(crate) fn getsockopt_int(fd: RawFd, opt: libc::c_int) -> io::Result<libc::c_int> {
+    let mut value: libc::c_int = 0;
+    let mut len = std::mem::size_of::<libc::c_int>() as libc::socklen_t;
+    let rc = unsafe {
+        libc::getsockopt(
+            fd,
+            libc::SOL_SOCKET,
+            opt,
+            &mut value as *mut _ as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    if rc < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(value)
+}
+
+/// Remote address of a connected socket.
+pub fn peer_addr(fd: RawFd) -> Option<SocketAddr> {
+    // SAFETY: the caller owns `fd` for the duration of the call.
+    let fd = unsafe { BorrowedFd::borrow_raw(fd) };
+    SockRef::from(&fd).peer_addr().ok()?.as_socket()
+}
+
+/// Address a socket is bound to, IP or Unix.
+pub fn socket_address(fd: RawFd) -> Option<Address> {
+    // SAFETY: the caller owns `fd` for the duration of the call.
+    let fd = unsafe { BorrowedFd::borrow_raw(fd) };
+    Address::from_sockaddr(&SockRef::from(&fd).local_addr().ok()?)
+}
+
+/// Local address of a connected socket.
+pub fn local_addr(fd: RawFd) -> Option<SocketAddr> {
+    // SAFETY: the caller owns `fd` for the duration of the call.
+    let fd = unsafe { BorrowedFd::borrow_raw(fd) };
+    SockRef::from(&fd).local_addr().ok()?.as_socket()
+}
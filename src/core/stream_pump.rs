@@ -10,6 +10,9 @@ pub enum OpCode {
     Send = 4,
     Timeout = 5,
     RecvHeaders = 6,
+    Shutdown = 7,
+    Cancel = 8,
+    DrainDeadline = 9,
 }
 
 impl OpCode {
@@ -23,6 +26,9 @@ impl OpCode {
             4 => Send,
             5 => Timeout,
             6 => RecvHeaders,
+            7 => Shutdown,
+            8 => Cancel,
+            9 => DrainDeadline,
             _ => return None,
         })
     }
@@ -43,6 +49,12 @@ pub enum Operation {
     Send(Direction),
     Timeout(Direction),
     RecvHeaders,
+    /// Worker-level: the shutdown eventfd became readable.
+    Shutdown,
+    /// Worker-level: completion of an AsyncCancel.
+    Cancel,
+    /// Worker-level: the drain deadline expired.
+    DrainDeadline,
 }
 
 /// Single-direction forwarding state.
@@ -62,7 +74,14 @@ pub struct StreamPump {
     /// True if a Send SQE is outstanding.
     pub send_in_flight: bool,
 
+    /// Client-to-backend only: body bytes of the current request still to
+    /// come. `None` when its body can't be framed, or the connection has
+    /// left HTTP, and the client's bytes are relayed as they come.
     pub remaining_request_body_bytes: Option<usize>,
+
+    /// Passthrough only: the read side reached end of stream and the write
+    /// side was shut down after it.
+    pub finished: bool,
 }
 
 impl StreamPump {
@@ -76,6 +95,7 @@ impl StreamPump {
             recv_in_flight: false,
             send_in_flight: false,
             remaining_request_body_bytes: None,
+            finished: false,
         }
     }
 
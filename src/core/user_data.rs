@@ -20,6 +20,9 @@ const OPCODE_MASK: u64 = (1 << OPCODE_BITS) - 1;               // 0x7F
 const DIR_MASK:    u64 = (1 << DIR_BITS) - 1;                  // 0x01
 const ID_MASK:     u64 = (1 << ID_BITS) - 1;                   // 0x00FF_FFFF_FFFF_FFFF
 
+/// Pair id used for worker-level operations that don't belong to a connection.
+pub const CONTROL_ID: usize = ID_MASK as usize;
+
 const OPCODE_SHIFT: u64 = 0;
 const DIR_SHIFT:    u64 = OPCODE_SHIFT + OPCODE_BITS;          // 7
 const ID_SHIFT:     u64 = DIR_SHIFT + DIR_BITS;                // 8
@@ -36,6 +39,9 @@ pub fn pack_user_data(pair_id: usize, op: Operation) -> u64 {
         Operation::Timeout(Direction::ClientToBackend) => (OpCode::Timeout, Direction::ClientToBackend as u8),
         Operation::Timeout(Direction::BackendToClient) => (OpCode::Timeout, Direction::BackendToClient as u8),
         Operation::RecvHeaders             => (OpCode::RecvHeaders, 0),
+        Operation::Shutdown                => (OpCode::Shutdown,    0),
+        Operation::Cancel                  => (OpCode::Cancel,      0),
+        Operation::DrainDeadline           => (OpCode::DrainDeadline, 0),
     };
 
     let id = pair_id as u64;
@@ -59,6 +65,9 @@ pub fn unpack_user_data(tag: u64) -> (usize, Operation) {
         Some(OpCode::Send)        => Operation::Send(if dir == 0 { Direction::ClientToBackend } else { Direction::BackendToClient }),
         Some(OpCode::Timeout)     => Operation::Timeout(if dir == 0 { Direction::ClientToBackend } else { Direction::BackendToClient }),
         Some(OpCode::RecvHeaders) => Operation::RecvHeaders,
+        Some(OpCode::Shutdown)    => Operation::Shutdown,
+        Some(OpCode::Cancel)      => Operation::Cancel,
+        Some(OpCode::DrainDeadline) => Operation::DrainDeadline,
         None => {
             // TODO: Handle as error maybe?
             Operation::Accept
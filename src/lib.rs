@@ -1,5 +1,12 @@
+pub mod access_log;
+pub mod admin;
 pub mod backend;
 pub mod balancer;
+pub mod cluster;
+pub mod config;
 pub mod core;
+pub mod metrics;
 pub mod protocol;
+pub mod tls;
+pub mod trace;
 pub mod util;
@@ -1,57 +1,270 @@
-mod backend;
-mod balancer;
-mod core;
-mod protocol;
-mod util;
-
-use backend::{Backend, init_backend_pool};
-use balancer::{WorkerConfig, run_worker};
-use core::socket::make_reuseport_listener;
+use flax::access_log;
+use flax::admin::spawn_admin;
+use flax::backend::{dns, file_discovery, init_backend_pools, pools};
+use flax::balancer::config::ListenerMode;
+use flax::balancer::{RingMode, ShutdownSignal, prepare_ring_mode, run_worker};
+use flax::cluster;
+use flax::config::FlaxConfig;
+use flax::core::activation::{inherited_listeners, take_listener};
+use flax::core::handoff::{Takeover, bind_handoff_socket, serve_handoff};
+use flax::core::socket::{
+    bind_listeners, fan_out_listener, make_reuseport_udp_socket, socket_address,
+};
+use flax::tls;
+use flax::trace::otlp;
+use flax::util::address::Address;
+use flax::util::logging;
+use flax::util::signals::{block_signals, wait_signal};
 
 use core_affinity::CoreId;
-use std::net::SocketAddr;
-use std::os::fd::AsRawFd;
+use std::net::UdpSocket;
+use std::os::fd::{AsRawFd, OwnedFd, RawFd};
+use std::path::PathBuf;
+use std::sync::Arc;
 use std::{io, thread};
+use tracing::{error, info, warn};
 
 fn main() -> io::Result<()> {
-    let listen_addr: SocketAddr = "0.0.0.0:3000".parse().unwrap();
+    let mut upgrade = false;
+    let mut config_path = None;
+    for arg in std::env::args_os().skip(1) {
+        if arg == "--upgrade" {
+            upgrade = true;
+        } else {
+            config_path = Some(PathBuf::from(arg));
+        }
+    }
+    let config = FlaxConfig::load(config_path.as_deref())?;
+    logging::init(&config.log.filter, config.log.format)?;
+    let mut activated = inherited_listeners()?;
 
-    init_backend_pool(vec![
-        Backend::new("127.0.0.1:8081".parse().unwrap()),
-        Backend::new("127.0.0.1:8082".parse().unwrap()),
-        Backend::new("127.0.0.1:8083".parse().unwrap()),
-    ]);
+    let upstream_tls = config
+        .upstream_tls()
+        .into_iter()
+        .map(|(pool, section)| Ok((pool.to_string(), tls::UpstreamTls::new(section)?)))
+        .collect::<io::Result<_>>()?;
+    let proxy_protocol = config
+        .proxy_protocol()
+        .into_iter()
+        .map(|(pool, version)| (pool.to_string(), version))
+        .collect();
+    init_backend_pools(config.backend_pools(), upstream_tls, proxy_protocol);
 
     let cores: Vec<CoreId> = core_affinity::get_core_ids().expect("get_core_ids failed");
-    let workers = std::thread::available_parallelism()
-        .map(|n| n.get())
-        .unwrap_or(cores.len())
-        .min(cores.len());
+    let workers = match config.workers {
+        0 => std::thread::available_parallelism()
+            .map(|n| n.get())
+            .unwrap_or(cores.len())
+            .min(cores.len()),
+        n => n,
+    };
+
+    // On upgrade, reuse the previous process's sockets (and their accept
+    // queues) and run one worker per inherited listener so none is orphaned.
+    let mut takeover = if upgrade {
+        let path = config.upgrade_socket.as_deref().ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "--upgrade requires upgrade_socket in the config",
+            )
+        })?;
+        Some(Takeover::connect(path)?)
+    } else {
+        None
+    };
+    // Bound up front so a second instance fails before it starts accepting.
+    let handoff_server = match (&config.upgrade_socket, upgrade) {
+        (Some(path), false) => Some(bind_handoff_socket(path, false)?),
+        _ => None,
+    };
+    // UDP sockets are always bound here: handoff and socket activation carry
+    // stream listeners only
+    let udp_sockets: Vec<UdpSocket> = match config.listen {
+        Address::Inet(addr) if config.mode == ListenerMode::Udp => (0..workers)
+            .map(|_| make_reuseport_udp_socket(addr))
+            .collect::<io::Result<_>>()?,
+        _ => Vec::new(),
+    };
+    let listeners: Vec<OwnedFd> = match takeover.as_mut() {
+        _ if config.mode == ListenerMode::Udp => Vec::new(),
+        Some(t) => t.listeners_for(&config.listen),
+        None => match take_listener(&mut activated, &config.listen) {
+            Some(listener) => fan_out_listener(listener, workers)?,
+            None => bind_listeners(&config.listen, workers)?,
+        },
+    };
+    let listen_fds: Vec<RawFd> = listeners
+        .iter()
+        .map(AsRawFd::as_raw_fd)
+        .chain(udp_sockets.iter().map(AsRawFd::as_raw_fd))
+        .collect();
+    if listen_fds.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("no inherited listener is bound to {}", config.listen),
+        ));
+    }
+    for unused in activated.drain(..) {
+        if let Some(addr) = socket_address(unused.as_raw_fd()) {
+            warn!(%addr, "socket activation: no listener configured for this address, closing it");
+        }
+    }
+    let workers = listen_fds.len();
+    let listen_addr = match udp_sockets.first() {
+        Some(socket) => Address::Inet(socket.local_addr()?),
+        None => socket_address(listeners[0].as_raw_fd()).unwrap_or(config.listen),
+    };
+
+    let mut worker_config = config.worker_config();
+    worker_config.validate()?;
+    if let Some(section) = &config.tls {
+        worker_config.tls = Some(tls::server_config(section)?);
+    }
+    // In shared SQPOLL mode this ring owns the poll thread the workers attach to.
+    let _sqpoll_owner = prepare_ring_mode(&mut worker_config)?;
 
-    eprintln!("Starting Flax load balancer");
-    eprintln!("  Listen address: {}", listen_addr);
-    eprintln!("  Workers: {}", workers);
-    eprintln!("  Backends: 127.0.0.1:8081, 127.0.0.1:8082, 127.0.0.1:8083");
+    info!(
+        listen = %listen_addr,
+        mode = ?worker_config.mode,
+        workers,
+        ring_mode = ?worker_config.ring_mode,
+        upgrade = takeover.is_some(),
+        tls = worker_config.tls.is_some(),
+        trusted_proxies = worker_config.trusted_proxies.len(),
+        "starting Flax load balancer"
+    );
+    for (name, pool) in pools() {
+        info!(
+            pool = name,
+            backends = ?pool.list_backends(),
+            tls = pool.tls().is_some(),
+            proxy_protocol = ?pool.proxy_protocol(),
+            "backend pool"
+        );
+    }
+    if worker_config.mode == ListenerMode::Udp {
+        let udp = worker_config.udp;
+        info!(
+            idle_timeout = ?udp.idle_timeout,
+            session_timeout = ?udp.session_timeout,
+            affinity = ?udp.affinity,
+            "udp flows"
+        );
+    }
+    for (name, pool) in &worker_config.hosts {
+        info!(host = name, pool, route_by = ?worker_config.route_by, "virtual host");
+    }
+
+    if let RingMode::Sqpoll(sq) = worker_config.ring_mode
+        && let Some(cpu) = sq.cpu
+        && (0..workers).any(|i| cores[i % cores.len()].id == cpu as usize)
+    {
+        warn!(cpu, "sqpoll cpu is shared with a worker thread");
+    }
+
+    // Blocked before any thread is spawned so only the signal thread sees them.
+    let signals = block_signals(&[libc::SIGTERM, libc::SIGINT, libc::SIGUSR1, libc::SIGHUP])?;
+    if let Some(log) = &config.access_log {
+        access_log::start(&log.path, log.format)?;
+    }
+    if let Some(endpoint) = config.otlp_endpoint()? {
+        info!(collector = %endpoint.authority, path = %endpoint.path, "exporting spans");
+        otlp::start(endpoint, &config.trace.service_name)?;
+    }
+    dns::start(config.dns_backends(), config.dns_options()?)?;
+    if let Some(discovery) = config.discovery() {
+        file_discovery::start(discovery)?;
+    }
+    if let Some(options) = config.cluster() {
+        cluster::start(options)?;
+    }
+    let shutdown = Arc::new(ShutdownSignal::new());
+    spawn_signal_thread(signals, shutdown.clone());
 
     let mut handles = Vec::with_capacity(workers);
 
-    for i in 0..workers {
-        let listener = make_reuseport_listener(listen_addr)?;
-        let core = cores[i];
-        let config = WorkerConfig::default();
+    for (i, &listen_fd) in listen_fds.iter().enumerate() {
+        let core = cores[i % cores.len()];
+        let mut config = worker_config.clone();
+        config.worker_id = i;
+        let shutdown = shutdown.clone();
+
+        let h = thread::Builder::new()
+            .name(format!("flax-worker-{i}"))
+            .spawn(move || {
+                core_affinity::set_for_current(core);
+                info!(worker = i, core = core.id, "worker pinned");
+                match run_worker(listen_fd, config, &shutdown) {
+                    Ok(summary) => info!(worker = i, "{summary}"),
+                    Err(e) => error!(worker = i, "worker failed: {e}"),
+                }
+            })?;
+        handles.push(h);
+    }
+
+    if let Some(admin) = &config.admin {
+        spawn_admin(admin)?;
+    }
 
-        let h = thread::spawn(move || {
-            core_affinity::set_for_current(core);
-            eprintln!("[worker {i}] pinned to core {}", core.id);
-            if let Err(e) = run_worker(listener.as_raw_fd(), config) {
-                eprintln!("[worker {i}] fatal: {e}");
+    if let Some(path) = &config.upgrade_socket {
+        let server = match takeover {
+            Some(t) => {
+                // the previous process drains once it reads this
+                if let Err(e) = t.complete() {
+                    warn!("handoff: could not notify previous process: {e}");
+                }
+                bind_handoff_socket(path, true)?
             }
+            None => handoff_server.expect("handoff socket bound at startup"),
+        };
+        let fds = listeners.iter().map(|l| l.as_raw_fd()).collect();
+        let shutdown = shutdown.clone();
+        serve_handoff(server, fds, move || {
+            // the new process binds the cluster address as soon as it's free
+            cluster::leave();
+            shutdown.trigger();
         });
-        handles.push(h);
     }
 
     for h in handles {
         let _ = h.join();
     }
+    cluster::leave();
+    access_log::finish();
+    otlp::finish();
+    info!("Flax stopped");
     Ok(())
 }
+
+/// First SIGTERM/SIGINT starts a graceful drain; a second one exits immediately.
+/// SIGUSR1 reopens the access log, SIGHUP reloads the TLS certificates.
+fn spawn_signal_thread(signals: libc::sigset_t, shutdown: Arc<ShutdownSignal>) {
+    thread::spawn(move || {
+        loop {
+            let sig = match wait_signal(&signals) {
+                Ok(sig) => sig,
+                Err(e) => {
+                    error!("sigwait failed: {e}");
+                    return;
+                }
+            };
+            if sig == libc::SIGUSR1 {
+                info!("reopening access log");
+                access_log::reopen();
+                continue;
+            }
+            if sig == libc::SIGHUP {
+                if let Err(e) = tls::reload() {
+                    error!("tls reload failed: {e}");
+                }
+                continue;
+            }
+            if shutdown.is_requested() {
+                warn!(signal = sig, "received signal while draining, exiting now");
+                std::process::exit(1);
+            }
+            info!(signal = sig, "received signal, draining connections");
+            shutdown.trigger();
+        }
+    });
+}
@@ -0,0 +1,231 @@
+use std::cell::Cell;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+use crate::core::stream_pump::Direction;
+use crate::protocol::ParseError;
+
+/// Upper bounds of the request latency buckets, in microseconds.
+pub const LATENCY_BUCKETS_US: [u64; 14] = [
+    500, 1_000, 2_500, 5_000, 10_000, 25_000, 50_000, 100_000, 250_000, 500_000, 1_000_000,
+    2_500_000, 5_000_000, 10_000_000,
+];
+
+/// Monotonic counter. Each worker only writes its own, so relaxed ordering is enough.
+#[derive(Debug)]
+pub struct Counter(AtomicU64);
+
+impl Counter {
+    pub const fn new() -> Self {
+        Self(AtomicU64::new(0))
+    }
+
+    #[inline]
+    pub fn inc(&self) {
+        self.add(1);
+    }
+
+    #[inline]
+    pub fn add(&self, n: u64) {
+        self.0.fetch_add(n, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+impl Default for Counter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Fixed-bucket histogram; the last bucket is `+Inf`.
+#[derive(Debug)]
+pub struct Histogram {
+    buckets: [Counter; LATENCY_BUCKETS_US.len() + 1],
+    sum_us: Counter,
+}
+
+impl Histogram {
+    pub const fn new() -> Self {
+        Self {
+            buckets: [const { Counter::new() }; LATENCY_BUCKETS_US.len() + 1],
+            sum_us: Counter::new(),
+        }
+    }
+
+    #[inline]
+    pub fn observe(&self, elapsed: Duration) {
+        let us = elapsed.as_micros() as u64;
+        let bucket = LATENCY_BUCKETS_US.partition_point(|&bound| bound < us);
+        self.buckets[bucket].inc();
+        self.sum_us.add(us);
+    }
+
+    /// Per-bucket (non-cumulative) counts, the last one being `+Inf`.
+    pub fn buckets(&self) -> impl Iterator<Item = u64> + '_ {
+        self.buckets.iter().map(Counter::get)
+    }
+
+    pub fn sum(&self) -> Duration {
+        Duration::from_micros(self.sum_us.get())
+    }
+}
+
+impl Default for Histogram {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Counters owned by one worker thread.
+#[derive(Debug, Default)]
+pub struct WorkerMetrics {
+    pub accepts: Counter,
+    pub accept_errors: Counter,
+    /// Client connections closed; active = accepts - closed
+    pub connections_closed: Counter,
+    /// Indexed by position in `ParseError::ALL`
+    pub parse_errors: [Counter; ParseError::ALL.len()],
+    /// Requests answered with 503 because the pool had no eligible backend
+    pub no_backend: Counter,
+    pub cache_hits: Counter,
+    pub cache_misses: Counter,
+    pub cache_evictions: Counter,
+    /// Indexed by `Direction as usize`
+    pub received_bytes: [Counter; 2],
+    pub sent_bytes: [Counter; 2],
+    pub sq_full: Counter,
+    /// Access log lines dropped because the writer thread fell behind
+    pub access_log_dropped: Counter,
+    /// Spans dropped because the OTLP exporter fell behind
+    pub trace_spans_dropped: Counter,
+    /// Completed TLS handshakes, and how many of them resumed a session
+    pub tls_handshakes: Counter,
+    pub tls_resumed: Counter,
+    /// Handshakes or TLS sessions aborted by a protocol error
+    pub tls_errors: Counter,
+    /// TLS connections handed to kernel TLS after the handshake
+    pub ktls_offloaded: Counter,
+    /// The same for TLS connections to backends
+    pub backend_tls_handshakes: Counter,
+    pub backend_tls_resumed: Counter,
+    pub backend_tls_errors: Counter,
+    /// Requests refused because `Host` and SNI disagree
+    pub misdirected: Counter,
+    /// Handshakes that failed over the client's certificate
+    pub client_cert_rejected: Counter,
+    /// Requests refused because the client certificate is not allowed
+    pub client_cert_denied: Counter,
+    /// Connections from trusted proxies closed over a bad PROXY protocol header
+    pub proxy_header_errors: Counter,
+    /// Responses Flax generated itself (bad requests, no or unreachable backend)
+    pub error_responses: Counter,
+    /// UDP client flows opened and closed; active = opened - closed
+    pub udp_flows_opened: Counter,
+    pub udp_flows_closed: Counter,
+    /// Datagrams from clients that no flow could take
+    pub udp_dropped: Counter,
+    pub request_latency: Histogram,
+}
+
+impl WorkerMetrics {
+    pub const fn new() -> Self {
+        Self {
+            accepts: Counter::new(),
+            accept_errors: Counter::new(),
+            connections_closed: Counter::new(),
+            parse_errors: [const { Counter::new() }; ParseError::ALL.len()],
+            no_backend: Counter::new(),
+            cache_hits: Counter::new(),
+            cache_misses: Counter::new(),
+            cache_evictions: Counter::new(),
+            received_bytes: [const { Counter::new() }; 2],
+            sent_bytes: [const { Counter::new() }; 2],
+            sq_full: Counter::new(),
+            access_log_dropped: Counter::new(),
+            trace_spans_dropped: Counter::new(),
+            error_responses: Counter::new(),
+            tls_handshakes: Counter::new(),
+            tls_resumed: Counter::new(),
+            tls_errors: Counter::new(),
+            ktls_offloaded: Counter::new(),
+            backend_tls_handshakes: Counter::new(),
+            backend_tls_resumed: Counter::new(),
+            backend_tls_errors: Counter::new(),
+            misdirected: Counter::new(),
+            client_cert_rejected: Counter::new(),
+            client_cert_denied: Counter::new(),
+            proxy_header_errors: Counter::new(),
+            udp_flows_opened: Counter::new(),
+            udp_flows_closed: Counter::new(),
+            udp_dropped: Counter::new(),
+            request_latency: Histogram::new(),
+        }
+    }
+
+    #[inline]
+    pub fn parse_error(&self, err: ParseError) {
+        if let Some(i) = ParseError::ALL.iter().position(|&e| e == err) {
+            self.parse_errors[i].inc();
+        }
+    }
+
+    #[inline]
+    pub fn received(&self, direction: Direction, bytes: usize) {
+        self.received_bytes[direction as usize].add(bytes as u64);
+    }
+
+    #[inline]
+    pub fn sent(&self, direction: Direction, bytes: usize) {
+        self.sent_bytes[direction as usize].add(bytes as u64);
+    }
+
+    pub fn active_connections(&self) -> u64 {
+        self.accepts
+            .get()
+            .saturating_sub(self.connections_closed.get())
+    }
+
+    pub fn active_udp_flows(&self) -> u64 {
+        self.udp_flows_opened
+            .get()
+            .saturating_sub(self.udp_flows_closed.get())
+    }
+}
+
+static WORKERS: Mutex<Vec<(usize, &'static WorkerMetrics)>> = Mutex::new(Vec::new());
+
+/// Sink for code running outside a registered worker thread.
+static DETACHED: WorkerMetrics = WorkerMetrics::new();
+
+thread_local! {
+    static CURRENT: Cell<Option<&'static WorkerMetrics>> = const { Cell::new(None) };
+}
+
+/// Create the metrics for worker `id` and make them current for this thread.
+///
+/// Worker metrics live for the rest of the process so a scrape never races
+/// a worker that is shutting down.
+pub fn register_worker(id: usize) -> &'static WorkerMetrics {
+    let metrics: &'static WorkerMetrics = Box::leak(Box::default());
+    WORKERS.lock().unwrap().push((id, metrics));
+    CURRENT.set(Some(metrics));
+    metrics
+}
+
+/// Metrics of the worker running on this thread.
+#[inline]
+pub fn worker_metrics() -> &'static WorkerMetrics {
+    CURRENT.get().unwrap_or(&DETACHED)
+}
+
+/// All registered workers, ordered by id.
+pub fn workers() -> Vec<(usize, &'static WorkerMetrics)> {
+    let mut workers = WORKERS.lock().unwrap().clone();
+    workers.sort_by_key(|&(id, _)| id);
+    workers
+}
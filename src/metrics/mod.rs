@@ -0,0 +1,12 @@
+//! Runtime metrics
+//!
+//! Every worker owns a set of atomic counters and a latency histogram that
+//! only it writes to, reachable through a thread-local so the hot path never
+//! takes a lock. Per-backend counters live on the pool entries. The admin
+//! listener renders everything in the Prometheus text format on `/metrics`.
+
+pub mod counters;
+pub mod prometheus;
+
+pub use counters::{Counter, Histogram, WorkerMetrics, register_worker, worker_metrics};
+pub use prometheus::render;
@@ -0,0 +1,459 @@
+use std::fmt::Write;
+
+use crate::backend::{BackendState, BackendStatus, Health, dns, pools};
+use crate::cluster;
+use crate::protocol::ParseError;
+
+use super::counters::{Counter, LATENCY_BUCKETS_US, WorkerMetrics, workers};
+
+const DIRECTIONS: [&str; 2] = ["client_to_backend", "backend_to_client"];
+
+type Workers = [(usize, &'static WorkerMetrics)];
+type Backends<'a> = [(&'a str, Vec<BackendStatus>)];
+
+/// Render all worker and backend metrics in the Prometheus text format.
+pub fn render() -> String {
+    let workers = workers();
+    let mut out = String::with_capacity(8 * 1024);
+
+    worker_series(
+        &mut out,
+        &workers,
+        "flax_accepted_connections_total",
+        "counter",
+        "Client connections accepted.",
+        |m| m.accepts.get(),
+    );
+    worker_series(
+        &mut out,
+        &workers,
+        "flax_accept_errors_total",
+        "counter",
+        "Failed accept operations.",
+        |m| m.accept_errors.get(),
+    );
+    worker_series(
+        &mut out,
+        &workers,
+        "flax_active_connections",
+        "gauge",
+        "Client connections currently open.",
+        WorkerMetrics::active_connections,
+    );
+    worker_series(
+        &mut out,
+        &workers,
+        "flax_no_backend_total",
+        "counter",
+        "Requests answered with 503 because no backend was eligible.",
+        |m| m.no_backend.get(),
+    );
+    worker_series(
+        &mut out,
+        &workers,
+        "flax_backend_cache_hits_total",
+        "counter",
+        "Requests served over a pooled backend connection.",
+        |m| m.cache_hits.get(),
+    );
+    worker_series(
+        &mut out,
+        &workers,
+        "flax_backend_cache_misses_total",
+        "counter",
+        "Requests that had to open a new backend connection.",
+        |m| m.cache_misses.get(),
+    );
+    worker_series(
+        &mut out,
+        &workers,
+        "flax_backend_cache_evictions_total",
+        "counter",
+        "Backend connections closed because the cache was full.",
+        |m| m.cache_evictions.get(),
+    );
+    worker_series(
+        &mut out,
+        &workers,
+        "flax_ring_sq_full_total",
+        "counter",
+        "Times the submission queue was full and had to be flushed early.",
+        |m| m.sq_full.get(),
+    );
+    worker_series(
+        &mut out,
+        &workers,
+        "flax_access_log_dropped_total",
+        "counter",
+        "Access log lines dropped because the writer fell behind.",
+        |m| m.access_log_dropped.get(),
+    );
+    worker_series(
+        &mut out,
+        &workers,
+        "flax_trace_spans_dropped_total",
+        "counter",
+        "Spans dropped because the OTLP exporter fell behind.",
+        |m| m.trace_spans_dropped.get(),
+    );
+    worker_series(
+        &mut out,
+        &workers,
+        "flax_error_responses_total",
+        "counter",
+        "Error responses generated by Flax instead of a backend.",
+        |m| m.error_responses.get(),
+    );
+
+    worker_series(
+        &mut out,
+        &workers,
+        "flax_tls_handshakes_total",
+        "counter",
+        "Completed TLS handshakes.",
+        |m| m.tls_handshakes.get(),
+    );
+    worker_series(
+        &mut out,
+        &workers,
+        "flax_tls_resumed_total",
+        "counter",
+        "TLS handshakes that resumed an earlier session.",
+        |m| m.tls_resumed.get(),
+    );
+    worker_series(
+        &mut out,
+        &workers,
+        "flax_tls_errors_total",
+        "counter",
+        "TLS connections aborted by a protocol error.",
+        |m| m.tls_errors.get(),
+    );
+    worker_series(
+        &mut out,
+        &workers,
+        "flax_ktls_offloaded_total",
+        "counter",
+        "TLS connections moved to kernel TLS after the handshake.",
+        |m| m.ktls_offloaded.get(),
+    );
+    worker_series(
+        &mut out,
+        &workers,
+        "flax_backend_tls_handshakes_total",
+        "counter",
+        "Completed TLS handshakes with backends.",
+        |m| m.backend_tls_handshakes.get(),
+    );
+    worker_series(
+        &mut out,
+        &workers,
+        "flax_backend_tls_resumed_total",
+        "counter",
+        "TLS handshakes with backends that resumed an earlier session.",
+        |m| m.backend_tls_resumed.get(),
+    );
+    worker_series(
+        &mut out,
+        &workers,
+        "flax_backend_tls_errors_total",
+        "counter",
+        "TLS connections to backends aborted by a protocol error.",
+        |m| m.backend_tls_errors.get(),
+    );
+    worker_series(
+        &mut out,
+        &workers,
+        "flax_misdirected_requests_total",
+        "counter",
+        "Requests answered with 421 because Host and SNI named different hosts.",
+        |m| m.misdirected.get(),
+    );
+    worker_series(
+        &mut out,
+        &workers,
+        "flax_client_cert_rejected_total",
+        "counter",
+        "TLS handshakes that failed over the client certificate.",
+        |m| m.client_cert_rejected.get(),
+    );
+    worker_series(
+        &mut out,
+        &workers,
+        "flax_client_cert_denied_total",
+        "counter",
+        "Requests answered with 403 because the client certificate is not allowed.",
+        |m| m.client_cert_denied.get(),
+    );
+    worker_series(
+        &mut out,
+        &workers,
+        "flax_proxy_header_errors_total",
+        "counter",
+        "Connections from trusted proxies closed over a missing or malformed PROXY protocol header.",
+        |m| m.proxy_header_errors.get(),
+    );
+    worker_series(
+        &mut out,
+        &workers,
+        "flax_udp_flows_total",
+        "counter",
+        "UDP client flows opened.",
+        |m| m.udp_flows_opened.get(),
+    );
+    worker_series(
+        &mut out,
+        &workers,
+        "flax_active_udp_flows",
+        "gauge",
+        "UDP client flows currently open.",
+        WorkerMetrics::active_udp_flows,
+    );
+    worker_series(
+        &mut out,
+        &workers,
+        "flax_udp_dropped_datagrams_total",
+        "counter",
+        "Client datagrams dropped: no backend, too many flows, or a full flow queue.",
+        |m| m.udp_dropped.get(),
+    );
+
+    let name = "flax_parse_errors_total";
+    header(
+        &mut out,
+        name,
+        "counter",
+        "Malformed request heads by kind.",
+    );
+    for &(id, m) in workers.iter() {
+        for (kind, counter) in ParseError::ALL.iter().zip(&m.parse_errors) {
+            let _ = writeln!(
+                out,
+                "{name}{{worker=\"{id}\",kind=\"{kind}\"}} {}",
+                counter.get()
+            );
+        }
+    }
+
+    direction_series(
+        &mut out,
+        &workers,
+        "flax_received_bytes_total",
+        "Bytes read, by direction.",
+        |m| &m.received_bytes,
+    );
+    direction_series(
+        &mut out,
+        &workers,
+        "flax_sent_bytes_total",
+        "Bytes written, by direction.",
+        |m| &m.sent_bytes,
+    );
+
+    latency_histogram(&mut out, &workers);
+
+    let backends: Vec<_> = pools().map(|(name, pool)| (name, pool.status())).collect();
+    backend_series(
+        &mut out,
+        &backends,
+        "flax_backend_selected_total",
+        "counter",
+        "Times the backend was picked for a request.",
+        |s| s.selected_total,
+    );
+    backend_series(
+        &mut out,
+        &backends,
+        "flax_backend_connect_failures_total",
+        "counter",
+        "Failed connects to the backend.",
+        |s| s.failures_total,
+    );
+    backend_series(
+        &mut out,
+        &backends,
+        "flax_backend_healthy",
+        "gauge",
+        "1 if the backend is considered healthy.",
+        |s| (s.health == Health::Healthy) as u64,
+    );
+    backend_series(
+        &mut out,
+        &backends,
+        "flax_backend_active",
+        "gauge",
+        "1 if the backend receives new requests, 0 while draining.",
+        |s| (s.state == BackendState::Active) as u64,
+    );
+
+    process_series(
+        &mut out,
+        "flax_dns_lookups_total",
+        "counter",
+        "Lookups of backends named in DNS.",
+        dns::LOOKUPS.get(),
+    );
+    process_series(
+        &mut out,
+        "flax_dns_lookup_failures_total",
+        "counter",
+        "Lookups of backends named in DNS that failed, keeping the last addresses.",
+        dns::LOOKUP_FAILURES.get(),
+    );
+    process_series(
+        &mut out,
+        "flax_cluster_members",
+        "gauge",
+        "Other cluster members believed alive, suspected ones included.",
+        cluster::members().len() as u64,
+    );
+    process_series(
+        &mut out,
+        "flax_cluster_health_multiplier",
+        "gauge",
+        "What missed acks stretch this cluster member's probe periods and timeouts by.",
+        cluster::health_multiplier().unwrap_or(1) as u64,
+    );
+    process_series(
+        &mut out,
+        "flax_cluster_probes_total",
+        "counter",
+        "Cluster members pinged, one per protocol period.",
+        cluster::PROBES.get(),
+    );
+    process_series(
+        &mut out,
+        "flax_cluster_indirect_probes_total",
+        "counter",
+        "Ping-reqs sent for cluster members that missed their ack.",
+        cluster::INDIRECT_PROBES.get(),
+    );
+    process_series(
+        &mut out,
+        "flax_cluster_suspicions_total",
+        "counter",
+        "Cluster members suspected after missing a probe.",
+        cluster::SUSPICIONS.get(),
+    );
+    process_series(
+        &mut out,
+        "flax_cluster_member_failures_total",
+        "counter",
+        "Cluster members declared failed.",
+        cluster::MEMBER_FAILURES.get(),
+    );
+    process_series(
+        &mut out,
+        "flax_cluster_refutations_total",
+        "counter",
+        "Suspicions of this cluster member it refuted.",
+        cluster::REFUTATIONS.get(),
+    );
+
+    out
+}
+
+/// A series kept by the whole process rather than per worker.
+fn process_series(out: &mut String, name: &str, kind: &str, help: &str, value: u64) {
+    header(out, name, kind, help);
+    let _ = writeln!(out, "{name} {value}");
+}
+
+fn worker_series(
+    out: &mut String,
+    workers: &Workers,
+    name: &str,
+    kind: &str,
+    help: &str,
+    value: impl Fn(&WorkerMetrics) -> u64,
+) {
+    header(out, name, kind, help);
+    for &(id, m) in workers {
+        let _ = writeln!(out, "{name}{{worker=\"{id}\"}} {}", value(m));
+    }
+}
+
+fn direction_series(
+    out: &mut String,
+    workers: &Workers,
+    name: &str,
+    help: &str,
+    counters: impl Fn(&WorkerMetrics) -> &[Counter; 2],
+) {
+    header(out, name, "counter", help);
+    for &(id, m) in workers {
+        for (direction, counter) in DIRECTIONS.iter().zip(counters(m)) {
+            let _ = writeln!(
+                out,
+                "{name}{{worker=\"{id}\",direction=\"{direction}\"}} {}",
+                counter.get()
+            );
+        }
+    }
+}
+
+fn latency_histogram(out: &mut String, workers: &Workers) {
+    let name = "flax_request_duration_seconds";
+    header(
+        out,
+        name,
+        "histogram",
+        "Time from a complete request head to the first response bytes from the backend.",
+    );
+    for &(id, m) in workers {
+        let histogram = &m.request_latency;
+        let mut cumulative = 0;
+        let mut counts = histogram.buckets();
+        for (count, bound) in counts.by_ref().zip(LATENCY_BUCKETS_US) {
+            cumulative += count;
+            let le = bound as f64 / 1e6;
+            let _ = writeln!(
+                out,
+                "{name}_bucket{{worker=\"{id}\",le=\"{le}\"}} {cumulative}"
+            );
+        }
+        cumulative += counts.next().unwrap_or(0);
+        let _ = writeln!(
+            out,
+            "{name}_bucket{{worker=\"{id}\",le=\"+Inf\"}} {cumulative}"
+        );
+        let sum = histogram.sum().as_secs_f64();
+        let _ = writeln!(out, "{name}_sum{{worker=\"{id}\"}} {sum}");
+        let _ = writeln!(out, "{name}_count{{worker=\"{id}\"}} {cumulative}");
+    }
+}
+
+fn backend_series(
+    out: &mut String,
+    backends: &Backends,
+    name: &str,
+    kind: &str,
+    help: &str,
+    value: impl Fn(&BackendStatus) -> u64,
+) {
+    header(out, name, kind, help);
+    for (pool, statuses) in backends {
+        for status in statuses {
+            let _ = writeln!(
+                out,
+                "{name}{{pool=\"{}\",backend=\"{}\"}} {}",
+                escape(pool),
+                status.backend.address,
+                value(status)
+            );
+        }
+    }
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {name} {help}");
+    let _ = writeln!(out, "# TYPE {name} {kind}");
+}
+
+/// Escape a label value (pool names come from the config file).
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
@@ -0,0 +1,342 @@
+//! TLS ClientHello peeking
+//!
+//! Passthrough listeners route TLS connections without terminating them, by
+//! the server name in the ClientHello. The parser reads just enough of the
+//! handshake for that: the SNI and ALPN extensions. The message may span
+//! several records, which are reassembled; nothing else is checked, since
+//! the backend does the real handshake.
+
+/// TLS record content type of handshake messages
+const HANDSHAKE: u8 = 22;
+/// Handshake message type of a ClientHello
+const CLIENT_HELLO: u8 = 1;
+const EXT_SERVER_NAME: u16 = 0;
+const EXT_ALPN: u16 = 16;
+/// Largest record payload a peer may send (TLS 1.2 allows 2048 bytes of
+/// expansion over the 16 KiB of plaintext)
+const MAX_RECORD: usize = 16384 + 2048;
+
+/// What a ClientHello asks for.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ClientHello {
+    /// Host name from the server_name extension
+    pub server_name: Option<String>,
+    /// Protocols offered through ALPN, in the client's order
+    pub alpn: Vec<String>,
+}
+
+/// Why no ClientHello could be read from the start of a connection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HelloError {
+    /// The message is not complete yet; more data is needed
+    Incomplete,
+    /// The connection does not start with a TLS handshake record
+    NotTls,
+    /// The handshake is not a well-formed ClientHello
+    Malformed,
+}
+
+/// Parse the ClientHello at the start of `buf`, the bytes a client sent
+/// first.
+pub fn peek_client_hello(buf: &[u8]) -> Result<ClientHello, HelloError> {
+    let message = handshake_message(buf)?;
+    let mut r = Reader(&message);
+    if r.u8()? != CLIENT_HELLO {
+        return Err(HelloError::Malformed);
+    }
+    let len = r.u24()?;
+    let mut body = Reader(r.bytes(len)?);
+    // legacy_version, random
+    body.bytes(2 + 32)?;
+    let session_id = body.u8()? as usize;
+    body.bytes(session_id)?;
+    let cipher_suites = body.u16()? as usize;
+    body.bytes(cipher_suites)?;
+    let compression = body.u8()? as usize;
+    body.bytes(compression)?;
+
+    let mut hello = ClientHello::default();
+    if body.0.is_empty() {
+        // a hello without extensions
+        return Ok(hello);
+    }
+    let len = body.u16()? as usize;
+    let mut extensions = Reader(body.bytes(len)?);
+    while !extensions.0.is_empty() {
+        let kind = extensions.u16()?;
+        let len = extensions.u16()? as usize;
+        let mut data = Reader(extensions.bytes(len)?);
+        match kind {
+            EXT_SERVER_NAME => hello.server_name = server_name(&mut data)?,
+            EXT_ALPN => {
+                let len = data.u16()? as usize;
+                let mut list = Reader(data.bytes(len)?);
+                while !list.0.is_empty() {
+                    let len = list.u8()? as usize;
+                    let protocol = list.bytes(len)?;
+                    hello
+                        .alpn
+                        .push(String::from_utf8_lossy(protocol).into_owned());
+                }
+            }
+            _ => {}
+        }
+    }
+    Ok(hello)
+}
+
+/// The first handshake message, reassembled from as many records as it
+/// takes.
+fn handshake_message(mut buf: &[u8]) -> Result<Vec<u8>, HelloError> {
+    let mut message = Vec::new();
+    loop {
+        let Some(header) = buf.get(..5) else {
+            return Err(HelloError::Incomplete);
+        };
+        // any TLS version from SSL 3.0 on; clients send 3.1 or 3.3 here
+        if header[0] != HANDSHAKE || header[1] != 3 {
+            return Err(if message.is_empty() {
+                HelloError::NotTls
+            } else {
+                HelloError::Malformed
+            });
+        }
+        let len = u16::from_be_bytes([header[3], header[4]]) as usize;
+        if len == 0 || len > MAX_RECORD {
+            return Err(HelloError::Malformed);
+        }
+        let Some(fragment) = buf.get(5..5 + len) else {
+            return Err(HelloError::Incomplete);
+        };
+        message.extend_from_slice(fragment);
+        buf = &buf[5 + len..];
+
+        if message.len() >= 4 {
+            let body = u32::from_be_bytes([0, message[1], message[2], message[3]]) as usize;
+            if message.len() >= 4 + body {
+                return Ok(message);
+            }
+        }
+    }
+}
+
+/// The host name of a server_name extension, if it names one that is valid
+/// in a DNS name.
+fn server_name(data: &mut Reader<'_>) -> Result<Option<String>, HelloError> {
+    let len = data.u16()? as usize;
+    let mut list = Reader(data.bytes(len)?);
+    while !list.0.is_empty() {
+        let kind = list.u8()?;
+        let len = list.u16()? as usize;
+        let name = list.bytes(len)?;
+        if kind == 0 {
+            let valid = !name.is_empty()
+                && name
+                    .iter()
+                    .all(|b| b.is_ascii_alphanumeric() || matches!(b, b'-' | b'.' | b'_'));
+            return Ok(valid.then(|| String::from_utf8_lossy(name).to_ascii_lowercase()));
+        }
+    }
+    Ok(None)
+}
+
+/// Big-endian reads off the front of a slice; running short means the
+/// message lied about a length.
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, n: usize) -> Result<&'a [u8], HelloError> {
+        if self.0.len() < n {
+            return Err(HelloError::Malformed);
+        }
+        let (head, rest) = self.0.split_at(n);
+        self.0 = rest;
+        Ok(head)
+    }
+
+    fn u8(&mut self) -> Result<u8, HelloError> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, HelloError> {
+        let b = self.bytes(2)?;
+        Ok(u16::from_be_bytes([b[0], b[1]]))
+    }
+
+    fn u24(&mut self) -> Result<usize, HelloError> {
+        let b = self.bytes(3)?;
+        Ok(u32::from_be_bytes([0, b[0], b[1], b[2]]) as usize)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Type and data of an extension
+    type Extension = (u16, Vec<u8>);
+
+    fn u16_prefixed(data: &[u8]) -> Vec<u8> {
+        let mut out = (data.len() as u16).to_be_bytes().to_vec();
+        out.extend_from_slice(data);
+        out
+    }
+
+    fn sni(name: &[u8]) -> Extension {
+        let mut entry = vec![0];
+        entry.extend(u16_prefixed(name));
+        (EXT_SERVER_NAME, u16_prefixed(&entry))
+    }
+
+    fn alpn(protocols: &[&str]) -> Extension {
+        let mut list = Vec::new();
+        for protocol in protocols {
+            list.push(protocol.len() as u8);
+            list.extend_from_slice(protocol.as_bytes());
+        }
+        (EXT_ALPN, u16_prefixed(&list))
+    }
+
+    /// A ClientHello handshake message with `extensions`, or none at all.
+    fn hello(extensions: Option<&[Extension]>) -> Vec<u8> {
+        let mut body = vec![3, 3];
+        body.extend([0x5a; 32]);
+        body.push(32);
+        body.extend([0xa5; 32]);
+        body.extend(u16_prefixed(&[0x13, 0x01, 0x13, 0x02]));
+        body.extend([1, 0]);
+        if let Some(extensions) = extensions {
+            let mut all = Vec::new();
+            for (kind, data) in extensions {
+                all.extend(kind.to_be_bytes());
+                all.extend(u16_prefixed(data));
+            }
+            body.extend(u16_prefixed(&all));
+        }
+        let mut message = vec![CLIENT_HELLO];
+        message.extend(&(body.len() as u32).to_be_bytes()[1..]);
+        message.extend(body);
+        message
+    }
+
+    /// `message` in records of at most `size` bytes each.
+    fn records(message: &[u8], size: usize) -> Vec<u8> {
+        let mut out = Vec::new();
+        for fragment in message.chunks(size) {
+            out.extend([HANDSHAKE, 3, 1]);
+            out.extend(u16_prefixed(fragment));
+        }
+        out
+    }
+
+    fn expected(server_name: Option<&str>, alpn: &[&str]) -> Result<ClientHello, HelloError> {
+        Ok(ClientHello {
+            server_name: server_name.map(str::to_string),
+            alpn: alpn.iter().map(|p| p.to_string()).collect(),
+        })
+    }
+
+    #[test]
+    fn records_reassembled() {
+        let message = hello(Some(&[sni(b"Example.COM"), alpn(&["h2", "http/1.1"])]));
+        // a single record, the 4-byte header split, an extension split, and
+        // one byte per record
+        for size in [message.len(), 2, 3, 50, 97, 1] {
+            let buf = records(&message, size);
+            assert_eq!(
+                peek_client_hello(&buf),
+                expected(Some("example.com"), &["h2", "http/1.1"]),
+                "{size}-byte records"
+            );
+            // what follows the hello is the client's next flight
+            let mut more = buf.clone();
+            more.extend([23, 3, 3, 0, 1, 0]);
+            assert_eq!(peek_client_hello(&more), peek_client_hello(&buf));
+            for len in 0..buf.len() {
+                assert_eq!(
+                    peek_client_hello(&buf[..len]),
+                    Err(HelloError::Incomplete),
+                    "{len} bytes of {size}-byte records"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn extensions() {
+        let cases: &[(&[Extension], Option<&str>, &[&str])] = &[
+            (&[], None, &[]),
+            (&[alpn(&["h2"])], None, &["h2"]),
+            (&[sni(b"a.test")], Some("a.test"), &[]),
+            (&[sni(b"under_score.test")], Some("under_score.test"), &[]),
+            (&[sni(b"")], None, &[]),
+            (&[sni(b"bad name.test")], None, &[]),
+            (&[sni(b"caf\xc3\xa9.test")], None, &[]),
+            (
+                &[(0xfe0d, vec![1, 2, 3]), sni(b"b.test")],
+                Some("b.test"),
+                &[],
+            ),
+        ];
+        for &(extensions, server_name, protocols) in cases {
+            let buf = records(&hello(Some(extensions)), 16384);
+            assert_eq!(
+                peek_client_hello(&buf),
+                expected(server_name, protocols),
+                "{extensions:?}"
+            );
+        }
+        let buf = records(&hello(None), 16384);
+        assert_eq!(peek_client_hello(&buf), expected(None, &[]));
+    }
+
+    #[test]
+    fn rejected() {
+        let message = hello(Some(&[sni(b"a.test")]));
+        let two_records = records(&message, 40);
+        let mut second_not_handshake = two_records.clone();
+        second_not_handshake[45] = 23;
+        let mut not_hello = message.clone();
+        not_hello[0] = 2;
+        let mut lying_extension = message.clone();
+        let at = lying_extension.len() - 13;
+        lying_extension[at] = 0xff;
+        let cases: &[(&str, Vec<u8>, HelloError)] = &[
+            (
+                "plain HTTP",
+                b"GET / HTTP/1.1\r\n".to_vec(),
+                HelloError::NotTls,
+            ),
+            (
+                "application data",
+                vec![23, 3, 3, 0, 1, 0],
+                HelloError::NotTls,
+            ),
+            ("SSL 2", vec![22, 2, 0, 0, 1, 0], HelloError::NotTls),
+            ("empty record", vec![22, 3, 1, 0, 0], HelloError::Malformed),
+            (
+                "oversized record",
+                vec![22, 3, 1, 0x48, 1],
+                HelloError::Malformed,
+            ),
+            (
+                "second record not handshake",
+                second_not_handshake,
+                HelloError::Malformed,
+            ),
+            (
+                "server hello",
+                records(&not_hello, 16384),
+                HelloError::Malformed,
+            ),
+            (
+                "extension longer than the list",
+                records(&lying_extension, 16384),
+                HelloError::Malformed,
+            ),
+        ];
+        for (name, buf, error) in cases {
+            assert_eq!(peek_client_hello(buf), Err(*error), "{name}");
+        }
+    }
+}
@@ -0,0 +1,526 @@
+//! DNS messages, as far as resolving backends needs them
+//!
+//! Queries ask for one name and type with recursion desired. Responses are
+//! read for their A, AAAA, SRV and CNAME records, answers and additional
+//! records alike; anything else is skipped. Compressed names are followed
+//! when reading but never written.
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+const HEADER_LEN: usize = 12;
+const CLASS_IN: u16 = 1;
+const FLAG_RESPONSE: u16 = 0x8000;
+const FLAG_TRUNCATED: u16 = 0x0200;
+const FLAG_RECURSION_DESIRED: u16 = 0x0100;
+/// Compression pointers followed within one name; more means a loop
+const MAX_POINTERS: usize = 16;
+const MAX_NAME: usize = 255;
+const MAX_LABEL: usize = 63;
+const TYPE_A: u16 = 1;
+const TYPE_CNAME: u16 = 5;
+const TYPE_AAAA: u16 = 28;
+const TYPE_SRV: u16 = 33;
+
+/// Response code of a name that does not exist
+pub const NXDOMAIN: u8 = 3;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RecordType {
+    A,
+    Aaaa,
+    Cname,
+    Srv,
+}
+
+impl RecordType {
+    fn code(self) -> u16 {
+        match self {
+            RecordType::A => TYPE_A,
+            RecordType::Cname => TYPE_CNAME,
+            RecordType::Aaaa => TYPE_AAAA,
+            RecordType::Srv => TYPE_SRV,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RecordData {
+    Ip(IpAddr),
+    Cname(String),
+    Srv {
+        priority: u16,
+        weight: u16,
+        port: u16,
+        target: String,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Record {
+    /// Owner name, lowercase and without the trailing dot
+    pub name: String,
+    pub ttl: u32,
+    pub data: RecordData,
+}
+
+#[derive(Debug, Clone)]
+pub struct Response {
+    pub id: u16,
+    /// Cut short to fit a datagram; ask again over TCP
+    pub truncated: bool,
+    pub rcode: u8,
+    /// Answer records followed by additional records
+    pub records: Vec<Record>,
+}
+
+/// Why a query could not be written or a response read.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DnsError {
+    /// The name has an empty or overlong label, or is too long overall
+    InvalidName,
+    /// The message is cut short or inconsistent
+    Malformed,
+}
+
+impl std::fmt::Display for DnsError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            DnsError::InvalidName => "invalid name",
+            DnsError::Malformed => "malformed message",
+        })
+    }
+}
+
+impl std::error::Error for DnsError {}
+
+/// Write a query for the `rtype` records of `name`.
+pub fn encode_query(id: u16, name: &str, rtype: RecordType) -> Result<Vec<u8>, DnsError> {
+    let mut out = Vec::with_capacity(HEADER_LEN + name.len() + 6);
+    out.extend_from_slice(&id.to_be_bytes());
+    out.extend_from_slice(&FLAG_RECURSION_DESIRED.to_be_bytes());
+    // one question, no answer, authority or additional records
+    out.extend_from_slice(&[0, 1, 0, 0, 0, 0, 0, 0]);
+    let name = name.strip_suffix('.').unwrap_or(name);
+    if name.len() + 2 > MAX_NAME {
+        return Err(DnsError::InvalidName);
+    }
+    for label in name.split('.') {
+        if label.is_empty() || label.len() > MAX_LABEL {
+            return Err(DnsError::InvalidName);
+        }
+        out.push(label.len() as u8);
+        out.extend_from_slice(label.as_bytes());
+    }
+    out.push(0);
+    out.extend_from_slice(&rtype.code().to_be_bytes());
+    out.extend_from_slice(&CLASS_IN.to_be_bytes());
+    Ok(out)
+}
+
+/// Read the response in `buf`.
+pub fn parse_response(buf: &[u8]) -> Result<Response, DnsError> {
+    let mut r = Reader { buf, pos: 0 };
+    let id = r.u16()?;
+    let flags = r.u16()?;
+    if flags & FLAG_RESPONSE == 0 {
+        return Err(DnsError::Malformed);
+    }
+    let questions = r.u16()?;
+    let answers = r.u16()?;
+    let authority = r.u16()?;
+    let additional = r.u16()?;
+    for _ in 0..questions {
+        r.name()?;
+        r.bytes(4)?;
+    }
+    let mut records = Vec::new();
+    for section in 0..3 {
+        let count = [answers, authority, additional][section];
+        for _ in 0..count {
+            let record = r.record()?;
+            // authority records only point elsewhere
+            if section != 1
+                && let Some(record) = record
+            {
+                records.push(record);
+            }
+        }
+    }
+    Ok(Response {
+        id,
+        truncated: flags & FLAG_TRUNCATED != 0,
+        rcode: (flags & 0x000f) as u8,
+        records,
+    })
+}
+
+struct Reader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, n: usize) -> Result<&'a [u8], DnsError> {
+        let end = self.pos.checked_add(n).ok_or(DnsError::Malformed)?;
+        let bytes = self.buf.get(self.pos..end).ok_or(DnsError::Malformed)?;
+        self.pos = end;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> Result<u8, DnsError> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, DnsError> {
+        let b = self.bytes(2)?;
+        Ok(u16::from_be_bytes([b[0], b[1]]))
+    }
+
+    fn u32(&mut self) -> Result<u32, DnsError> {
+        let b = self.bytes(4)?;
+        Ok(u32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+    }
+
+    /// A possibly compressed name, lowercase and without the trailing dot.
+    fn name(&mut self) -> Result<String, DnsError> {
+        let mut name = String::new();
+        // where reading resumes once the first pointer has been followed
+        let mut resume = None;
+        let mut pointers = 0;
+        loop {
+            let len = self.u8()? as usize;
+            match len {
+                0 => break,
+                1..=MAX_LABEL => {
+                    let label = self.bytes(len)?;
+                    if !name.is_empty() {
+                        name.push('.');
+                    }
+                    name.extend(label.iter().map(|&b| b.to_ascii_lowercase() as char));
+                    if name.len() > MAX_NAME {
+                        return Err(DnsError::Malformed);
+                    }
+                }
+                _ if len & 0xc0 == 0xc0 => {
+                    let offset = ((len & 0x3f) << 8) | self.u8()? as usize;
+                    pointers += 1;
+                    if pointers > MAX_POINTERS {
+                        return Err(DnsError::Malformed);
+                    }
+                    resume.get_or_insert(self.pos);
+                    self.pos = offset;
+                }
+                _ => return Err(DnsError::Malformed),
+            }
+        }
+        if let Some(pos) = resume {
+            self.pos = pos;
+        }
+        Ok(name)
+    }
+
+    /// The next resource record; `None` for types and classes not read.
+    fn record(&mut self) -> Result<Option<Record>, DnsError> {
+        let name = self.name()?;
+        let rtype = self.u16()?;
+        let class = self.u16()?;
+        let ttl = self.u32()?;
+        let len = self.u16()? as usize;
+        let end = self.pos + len;
+        let rdata = self.bytes(len)?;
+        if class != CLASS_IN {
+            return Ok(None);
+        }
+        let data = match rtype {
+            TYPE_A if len == 4 => {
+                RecordData::Ip(Ipv4Addr::new(rdata[0], rdata[1], rdata[2], rdata[3]).into())
+            }
+            TYPE_AAAA if len == 16 => {
+                let octets: [u8; 16] = rdata.try_into().map_err(|_| DnsError::Malformed)?;
+                RecordData::Ip(Ipv6Addr::from(octets).into())
+            }
+            TYPE_A | TYPE_AAAA => return Err(DnsError::Malformed),
+            TYPE_CNAME => {
+                self.pos = end - len;
+                let target = self.name()?;
+                if self.pos > end {
+                    return Err(DnsError::Malformed);
+                }
+                self.pos = end;
+                RecordData::Cname(target)
+            }
+            TYPE_SRV => {
+                self.pos = end - len;
+                let priority = self.u16()?;
+                let weight = self.u16()?;
+                let port = self.u16()?;
+                let target = self.name()?;
+                if self.pos > end {
+                    return Err(DnsError::Malformed);
+                }
+                self.pos = end;
+                RecordData::Srv {
+                    priority,
+                    weight,
+                    port,
+                    target,
+                }
+            }
+            _ => return Ok(None),
+        };
+        Ok(Some(Record { name, ttl, data }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Offset of the first answer: header and the question for example.com
+    const ANSWERS: u8 = 29;
+
+    /// The first record of a response, if it is one read
+    type Parsed = Result<Option<RecordData>, DnsError>;
+
+    /// A name read and the offset reading resumes at, if it is well formed
+    type Named = Option<(&'static str, usize)>;
+
+    /// A response to an A query for example.com, then `answers` records
+    fn response(answers: u16, records: &[u8]) -> Vec<u8> {
+        let mut out = vec![0x12, 0x34, 0x81, 0x80, 0, 1];
+        out.extend_from_slice(&answers.to_be_bytes());
+        out.extend_from_slice(&[0, 0, 0, 0]);
+        out.extend_from_slice(b"\x07example\x03com\x00");
+        out.extend_from_slice(&[0, 1, 0, 1]);
+        assert_eq!(out.len(), ANSWERS as usize);
+        out.extend_from_slice(records);
+        out
+    }
+
+    fn record(name: &[u8], rtype: u16, rdata: &[u8]) -> Vec<u8> {
+        let mut out = name.to_vec();
+        out.extend_from_slice(&rtype.to_be_bytes());
+        out.extend_from_slice(&CLASS_IN.to_be_bytes());
+        out.extend_from_slice(&300u32.to_be_bytes());
+        out.extend_from_slice(&(rdata.len() as u16).to_be_bytes());
+        out.extend_from_slice(rdata);
+        out
+    }
+
+    fn name_at(buf: &[u8], pos: usize) -> Result<(String, usize), DnsError> {
+        let mut r = Reader { buf, pos };
+        let name = r.name()?;
+        Ok((name, r.pos))
+    }
+
+    #[test]
+    fn encode() {
+        let label = "a".repeat(MAX_LABEL);
+        let longest = [label.as_str(); 4].join(".")[..MAX_NAME - 2].to_string();
+        let cases = [
+            ("example.com", true),
+            ("example.com.", true),
+            ("a", true),
+            (label.as_str(), true),
+            (&format!("{label}a"), false),
+            (longest.as_str(), true),
+            (&format!("{longest}a"), false),
+            ("", false),
+            (".", false),
+            ("example..com", false),
+            (".example.com", false),
+        ];
+        for (name, ok) in cases {
+            assert_eq!(encode_query(1, name, RecordType::A).is_ok(), ok, "{name:?}");
+        }
+        let query = encode_query(0x1234, "Example.COM.", RecordType::Srv).unwrap();
+        let mut expected = vec![0x12, 0x34, 0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0];
+        expected.extend_from_slice(b"\x07Example\x03COM\x00\x00\x21\x00\x01");
+        assert_eq!(query, expected);
+    }
+
+    #[test]
+    fn names() {
+        let buf = response(0, &[]);
+        let at = |extra: &[u8]| {
+            let mut buf = buf.clone();
+            buf.extend_from_slice(extra);
+            name_at(&buf, ANSWERS as usize)
+        };
+        let end = ANSWERS as usize;
+        let cases: [(&[u8], Named); 11] = [
+            (b"\x00", Some(("", end + 1))),
+            (b"\x03WwW\x00", Some(("www", end + 5))),
+            (&[0xc0, 12], Some(("example.com", end + 2))),
+            (&[0xc0, 20], Some(("com", end + 2))),
+            (b"\x03www\xc0\x0c", Some(("www.example.com", end + 6))),
+            // pointer to itself, and two pointing at each other
+            (&[0xc0, ANSWERS], None),
+            (&[0xc0, ANSWERS + 2, 0xc0, ANSWERS], None),
+            // a label and a pointer back to it, growing on every pass
+            (&[1, b'a', 0xc0, ANSWERS], None),
+            (&[0xc0, 0xff], None),
+            (&[0x40, 0], None),
+            (&[0x80, 0], None),
+        ];
+        for (name, expected) in cases {
+            let expected = expected.map(|(name, pos)| (name.to_string(), pos));
+            assert_eq!(at(name).ok(), expected, "{name:?}");
+        }
+        for truncated in [&b"\x03ww"[..], b"\x03www", &[0xc0]] {
+            assert_eq!(at(truncated), Err(DnsError::Malformed), "{truncated:?}");
+        }
+    }
+
+    #[test]
+    fn pointer_chains() {
+        // each pointer refers to the next; the last to the root name
+        let chain = |pointers: usize| {
+            let mut buf = Vec::new();
+            for i in 1..=pointers {
+                buf.extend_from_slice(&[0xc0, (2 * i) as u8]);
+            }
+            buf.push(0);
+            name_at(&buf, 0)
+        };
+        assert_eq!(chain(MAX_POINTERS), Ok((String::new(), 2)));
+        assert_eq!(chain(MAX_POINTERS + 1), Err(DnsError::Malformed));
+    }
+
+    #[test]
+    fn long_names() {
+        // labels of 63 bytes, each pointing on to the next
+        let names = |labels: usize| {
+            let mut buf = Vec::new();
+            for i in 0..labels {
+                buf.push(MAX_LABEL as u8);
+                buf.extend_from_slice(&[b'a'; MAX_LABEL]);
+                if i + 1 < labels {
+                    buf.extend_from_slice(&[0xc0, (buf.len() + 2) as u8]);
+                }
+            }
+            buf.push(0);
+            name_at(&buf, 0).map(|(name, _)| name.len())
+        };
+        assert_eq!(names(4), Ok(MAX_NAME));
+        assert_eq!(names(5), Err(DnsError::Malformed));
+    }
+
+    #[test]
+    fn records() {
+        let ip = |s: &str| RecordData::Ip(s.parse().unwrap());
+        let srv = |target: &str| RecordData::Srv {
+            priority: 1,
+            weight: 2,
+            port: 8080,
+            target: target.to_string(),
+        };
+        let v6 = [0x20, 0x01, 0x0d, 0xb8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1];
+        let cases: [(Vec<u8>, Parsed); 11] = [
+            (
+                record(&[0xc0, 12], TYPE_A, &[10, 0, 0, 1]),
+                Ok(Some(ip("10.0.0.1"))),
+            ),
+            (
+                record(&[0xc0, 12], TYPE_AAAA, &v6),
+                Ok(Some(ip("2001:db8::1"))),
+            ),
+            (
+                record(&[0xc0, 12], TYPE_A, &[10, 0, 0]),
+                Err(DnsError::Malformed),
+            ),
+            (
+                record(&[0xc0, 12], TYPE_AAAA, &[10, 0, 0, 1]),
+                Err(DnsError::Malformed),
+            ),
+            (
+                record(&[0xc0, 12], TYPE_CNAME, b"\x03www\xc0\x0c"),
+                Ok(Some(RecordData::Cname("www.example.com".into()))),
+            ),
+            (
+                record(
+                    &[0xc0, 12],
+                    TYPE_SRV,
+                    b"\x00\x01\x00\x02\x1f\x90\x03app\xc0\x0c",
+                ),
+                Ok(Some(srv("app.example.com"))),
+            ),
+            // a target running past the record's data
+            (
+                record(&[0xc0, 12], TYPE_CNAME, b"\x03www"),
+                Err(DnsError::Malformed),
+            ),
+            (
+                record(&[0xc0, 12], TYPE_SRV, b"\x00\x01\x00\x02\x1f\x90"),
+                Err(DnsError::Malformed),
+            ),
+            // a target pointing back at itself
+            (
+                record(&[0xc0, 12], TYPE_CNAME, &[0xc0, ANSWERS + 12]),
+                Err(DnsError::Malformed),
+            ),
+            (record(&[0xc0, 12], 16, b"\x02hi"), Ok(None)),
+            (
+                record(&[0xc0, ANSWERS], TYPE_A, &[10, 0, 0, 1]),
+                Err(DnsError::Malformed),
+            ),
+        ];
+        for (i, (answer, expected)) in cases.into_iter().enumerate() {
+            let buf = response(1, &answer);
+            let parsed = parse_response(&buf).map(|r| r.records.into_iter().next().map(|r| r.data));
+            assert_eq!(parsed, expected, "case {i}");
+        }
+    }
+
+    #[test]
+    fn sections() {
+        let mut answers = record(&[0xc0, 12], TYPE_CNAME, b"\x03www\xc0\x0c");
+        answers.extend(record(b"\x03www\xc0\x0c", TYPE_A, &[10, 0, 0, 1]));
+        // an authority record, skipped, and an additional one, kept
+        answers.extend(record(&[0xc0, 12], TYPE_A, &[10, 0, 0, 2]));
+        answers.extend(record(&[0xc0, 12], TYPE_A, &[10, 0, 0, 3]));
+        let mut buf = response(2, &answers);
+        buf[9] = 1;
+        buf[11] = 1;
+        let parsed = parse_response(&buf).unwrap();
+        assert_eq!(parsed.id, 0x1234);
+        assert!(!parsed.truncated);
+        assert_eq!(parsed.rcode, 0);
+        let records: Vec<_> = parsed
+            .records
+            .iter()
+            .map(|r| (r.name.as_str(), &r.data))
+            .collect();
+        assert_eq!(
+            records,
+            [
+                ("example.com", &RecordData::Cname("www.example.com".into())),
+                (
+                    "www.example.com",
+                    &RecordData::Ip("10.0.0.1".parse().unwrap())
+                ),
+                ("example.com", &RecordData::Ip("10.0.0.3".parse().unwrap())),
+            ]
+        );
+        for len in 0..buf.len() {
+            assert_eq!(
+                parse_response(&buf[..len]).err(),
+                Some(DnsError::Malformed),
+                "{len} bytes"
+            );
+        }
+    }
+
+    #[test]
+    fn flags() {
+        let mut buf = response(0, &[]);
+        buf[2] = 0x83;
+        buf[3] = 0x83;
+        let parsed = parse_response(&buf).unwrap();
+        assert!(parsed.truncated);
+        assert_eq!(parsed.rcode, NXDOMAIN);
+        // a query is not a response
+        let query = encode_query(1, "example.com", RecordType::A).unwrap();
+        assert_eq!(parse_response(&query).err(), Some(DnsError::Malformed));
+    }
+}
@@ -20,13 +20,28 @@ impl HttpBuf {
         (unsafe { self.buf.as_mut_ptr().add(self.end) }, free)
     }
 
+    /// Free space after the buffered bytes.
+    pub fn spare_mut(&mut self) -> &mut [u8] {
+        &mut self.buf[self.end..]
+    }
+
     pub fn wrote(&mut self, n: usize) {
         self.end += n;
     }
 
+    /// Append `bytes`, growing the buffer when they don't fit.
+    pub fn extend_from_slice(&mut self, bytes: &[u8]) {
+        let end = self.end + bytes.len();
+        if self.buf.len() < end {
+            self.buf.resize(end, 0);
+        }
+        self.buf[self.end..end].copy_from_slice(bytes);
+        self.end = end;
+    }
+
     pub fn find_headers_end(&self) -> Option<usize> {
         let s = &self.buf[self.start..self.end];
-        if let Some(pos) = memmem::find(&s, b"\r\n\r\n") {
+        if let Some(pos) = memmem::find(s, b"\r\n\r\n") {
             return Some(pos + 4);
         }
         None
@@ -63,6 +78,45 @@ impl HttpBuf {
     }
 }
 
+/// Why a request head could not be parsed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ParseError {
+    /// No blank line yet; more data is needed
+    Incomplete,
+    /// Request line is missing or lacks method, target or version
+    MalformedRequestLine,
+    /// Empty method token
+    InvalidMethod,
+    /// Version token too short to be `HTTP/x.y`
+    InvalidVersion,
+}
+
+impl ParseError {
+    pub const ALL: [ParseError; 4] = [
+        ParseError::Incomplete,
+        ParseError::MalformedRequestLine,
+        ParseError::InvalidMethod,
+        ParseError::InvalidVersion,
+    ];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ParseError::Incomplete => "incomplete",
+            ParseError::MalformedRequestLine => "malformed_request_line",
+            ParseError::InvalidMethod => "invalid_method",
+            ParseError::InvalidVersion => "invalid_version",
+        }
+    }
+}
+
+impl std::fmt::Display for ParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl std::error::Error for ParseError {}
+
 pub struct HttpMetadata<'a> {
     pub method_bytes: &'a [u8],
     pub path_bytes: &'a [u8],
@@ -74,26 +128,29 @@ pub struct HttpMetadata<'a> {
 
 pub fn peek_request_headers<'a>(
     request_window: &'a [u8],
-) -> Result<HttpMetadata<'a>, &'static str> {
+) -> Result<HttpMetadata<'a>, ParseError> {
     let Some(crlf_crlf_position) = memmem::find(request_window, b"\r\n\r\n") else {
-        return Err("Incomplete message");
+        return Err(ParseError::Incomplete);
     };
 
     let header_block_end_index = crlf_crlf_position + 4;
     let headers_without_final_crlfcrlf = &request_window[..crlf_crlf_position];
 
     let Some(request_line_end_rel) = memmem::find(headers_without_final_crlfcrlf, b"\r\n") else {
-        return Err("Bad message");
+        return Err(ParseError::MalformedRequestLine);
     };
     let request_line = &headers_without_final_crlfcrlf[..request_line_end_rel];
 
     let mut fields = request_line.split(|&b| b == b' ');
-    let method_bytes = fields.next().ok_or("Bad")?;
-    let path_bytes = fields.next().ok_or("Bad")?;
-    let version_bytes = fields.next().ok_or("Bad")?;
+    let method_bytes = fields.next().ok_or(ParseError::MalformedRequestLine)?;
+    let path_bytes = fields.next().ok_or(ParseError::MalformedRequestLine)?;
+    let version_bytes = fields.next().ok_or(ParseError::MalformedRequestLine)?;
 
-    if method_bytes.is_empty() || version_bytes.len() < 8 {
-        return Err("bad");
+    if method_bytes.is_empty() {
+        return Err(ParseError::InvalidMethod);
+    }
+    if version_bytes.len() < 8 {
+        return Err(ParseError::InvalidVersion);
     }
 
     let mut host_header_value: Option<&[u8]> = None;
@@ -146,6 +203,161 @@ pub fn peek_request_headers<'a>(
     })
 }
 
+/// Status code of a response that starts with `HTTP/x.y NNN`.
+pub fn peek_response_status(response: &[u8]) -> Option<u16> {
+    if !response.starts_with(b"HTTP/") {
+        return None;
+    }
+    let space = response.iter().position(|&b| b == b' ')?;
+    let code = response.get(space + 1..space + 4)?;
+    if !code.iter().all(u8::is_ascii_digit) {
+        return None;
+    }
+    code.iter()
+        .try_fold(0u16, |acc, &d| Some(acc * 10 + (d - b'0') as u16))
+}
+
+/// Length of the response at the start of `response`, head included, if
+/// its head says how long it is and that the connection stays open after
+/// it. `None` also while the head is incomplete.
+pub fn peek_response_length(response: &[u8], head_request: bool) -> Option<usize> {
+    let head_end = memmem::find(response, b"\r\n\r\n")? + 4;
+    let head = &response[..head_end];
+    if head.starts_with(b"HTTP/1.0") {
+        return None;
+    }
+    let status = peek_response_status(head)?;
+    if status < 200 {
+        // an interim response; the real one follows
+        return None;
+    }
+    let closes = find_header(head, b"Connection").is_some_and(|value| {
+        value
+            .split(|&b| b == b',')
+            .any(|token| ascii_equals_ignore_case(trim_ascii_whitespace(token), b"close"))
+    });
+    if closes {
+        return None;
+    }
+    if head_request || status == 204 || status == 304 {
+        return Some(head_end);
+    }
+    if find_header(head, b"Transfer-Encoding").is_some() {
+        return None;
+    }
+    let body = parse_usize_decimal_strict(find_header(head, b"Content-Length")?)?;
+    head_end.checked_add(body)
+}
+
+/// Find the value of the first header named `name` in a request head.
+pub fn find_header<'a>(request_head: &'a [u8], name: &[u8]) -> Option<&'a [u8]> {
+    let head_end = memmem::find(request_head, b"\r\n\r\n").unwrap_or(request_head.len());
+    request_head[..head_end]
+        .split(|&b| b == b'\n')
+        .skip(1) // request line
+        .filter_map(|line| {
+            let line = line.strip_suffix(b"\r").unwrap_or(line);
+            let colon = line.iter().position(|&b| b == b':')?;
+            Some((&line[..colon], &line[colon + 1..]))
+        })
+        .find(|(header_name, _)| ascii_equals_ignore_case(header_name, name))
+        .map(|(_, value)| trim_ascii_whitespace(value))
+}
+
+/// Header changes applied to a request head before it is forwarded.
+#[derive(Debug, Default)]
+pub struct HeaderEdits {
+    remove: Vec<&'static str>,
+    add: Vec<(&'static str, String)>,
+}
+
+impl HeaderEdits {
+    /// Drop every header named `name`.
+    pub fn remove(&mut self, name: &'static str) {
+        self.remove.push(name);
+    }
+
+    /// Append a header after the ones already present.
+    pub fn add(&mut self, name: &'static str, value: String) {
+        self.add.push((name, value));
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.remove.is_empty() && self.add.is_empty()
+    }
+
+    /// Apply the edits to the head at the front of `buf[..len]`, which ends at
+    /// `head_end`. Bytes after the head are moved along and `buf` grows when
+    /// the edited head no longer fits. Returns the new data length.
+    pub fn apply(&self, buf: &mut Vec<u8>, len: usize, head_end: usize) -> usize {
+        if self.is_empty() {
+            return len;
+        }
+        let added: usize = self
+            .add
+            .iter()
+            .map(|(name, value)| name.len() + value.len() + 4)
+            .sum();
+        let mut head = Vec::with_capacity(head_end + added);
+
+        // every line of the head up to, not including, the blank line
+        let mut lines = buf[..head_end - 2].split_inclusive(|&b| b == b'\n');
+        head.extend_from_slice(lines.next().unwrap_or_default());
+        for line in lines {
+            let name = line.split(|&b| b == b':').next().unwrap_or_default();
+            let name = trim_ascii_whitespace(name);
+            if !self
+                .remove
+                .iter()
+                .any(|r| ascii_equals_ignore_case(name, r.as_bytes()))
+            {
+                head.extend_from_slice(line);
+            }
+        }
+        for (name, value) in &self.add {
+            head.extend_from_slice(name.as_bytes());
+            head.extend_from_slice(b": ");
+            head.extend_from_slice(value.as_bytes());
+            head.extend_from_slice(b"\r\n");
+        }
+        head.extend_from_slice(b"\r\n");
+
+        let new_len = len - head_end + head.len();
+        if buf.len() < new_len {
+            buf.resize(new_len, 0);
+        }
+        buf.copy_within(head_end..len, head.len());
+        buf[..head.len()].copy_from_slice(&head);
+        new_len
+    }
+}
+
+/// A complete `Connection: close` response for errors Flax answers itself.
+pub fn error_response(status: u16, request_id: Option<&str>) -> String {
+    let reason = match status {
+        400 => "Bad Request",
+        403 => "Forbidden",
+        421 => "Misdirected Request",
+        502 => "Bad Gateway",
+        503 => "Service Unavailable",
+        504 => "Gateway Timeout",
+        _ => "Error",
+    };
+    let body = format!("{status} {reason}\n");
+    let mut response = format!(
+        "HTTP/1.1 {status} {reason}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n",
+        body.len()
+    );
+    if let Some(id) = request_id {
+        response.push_str("X-Request-Id: ");
+        response.push_str(id);
+        response.push_str("\r\n");
+    }
+    response.push_str("\r\n");
+    response.push_str(&body);
+    response
+}
+
 #[inline]
 fn ascii_equals_ignore_case(a: &[u8], b: &[u8]) -> bool {
     if a.len() != b.len() {
@@ -169,7 +381,7 @@ fn trim_ascii_whitespace(mut bytes: &[u8]) -> &[u8] {
 fn parse_usize_decimal_strict(input: &[u8]) -> Option<usize> {
     let mut value: usize = 0;
     for &ch in trim_ascii_whitespace(input) {
-        if !(b'0'..=b'9').contains(&ch) {
+        if !ch.is_ascii_digit() {
             return None;
         }
         let digit = (ch - b'0') as usize;
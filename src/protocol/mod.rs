@@ -1,3 +1,13 @@
+pub mod client_hello;
+pub mod dns;
 pub mod http1;
+pub mod proxy;
 
-pub use http1::{HttpBuf, HttpMetadata, peek_request_headers};
+pub use client_hello::{ClientHello, HelloError, peek_client_hello};
+pub use http1::{
+    HeaderEdits, HttpBuf, HttpMetadata, ParseError, error_response, find_header,
+    peek_request_headers, peek_response_length, peek_response_status,
+};
+pub use proxy::{
+    ProxiedConnection, ProxyError, ProxyVersion, ReceivedHeader, peek_proxy_header,
+};
@@ -0,0 +1,442 @@
+//! PROXY protocol headers
+//!
+//! Pools with `proxy_protocol` set tell their backends who the client is by
+//! starting every backend connection with a PROXY protocol header, as
+//! described in haproxy's proxy-protocol.txt: the text line of version 1, or
+//! the binary header of version 2, which can also carry the server name the
+//! client asked for and the protocol it negotiated.
+//!
+//! The other way round, connections from `trusted_proxies` start with a
+//! header of either version naming the client a balancer in front of Flax
+//! relays for.
+
+use std::net::{IpAddr, SocketAddr};
+
+use serde::Deserialize;
+
+/// Signature every version 2 header starts with
+const V2_SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+/// Version 2, PROXY command: the connection is relayed for a client
+const V2_PROXY: u8 = 0x21;
+/// Version 2, LOCAL command: the header says nothing about a client
+const V2_LOCAL: u8 = 0x20;
+/// Address family and transport: TCP over IPv4 or IPv6
+const V2_TCP4: u8 = 0x11;
+const V2_TCP6: u8 = 0x21;
+const PP2_TYPE_ALPN: u8 = 0x01;
+const PP2_TYPE_AUTHORITY: u8 = 0x02;
+/// Longest version 1 header, `PROXY TCP6` with the longest addresses and
+/// ports and the CRLF
+const V1_MAX: usize = 107;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ProxyVersion {
+    /// `PROXY TCP4 <source> <destination> <ports>\r\n`
+    V1,
+    /// Binary header with TLVs
+    V2,
+}
+
+/// What a header says about the client's connection.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ProxiedConnection<'a> {
+    /// Client address and the address it connected to; unknown when `None`
+    pub addresses: Option<(SocketAddr, SocketAddr)>,
+    /// Server name the client sent as SNI (version 2 only)
+    pub authority: Option<&'a str>,
+    /// Protocol negotiated with the client through ALPN (version 2 only)
+    pub alpn: Option<&'a [u8]>,
+}
+
+impl ProxyVersion {
+    /// The header announcing `conn`.
+    pub fn header(self, conn: &ProxiedConnection<'_>) -> Vec<u8> {
+        let addresses = conn.addresses.map(same_family);
+        match self {
+            ProxyVersion::V1 => v1_header(addresses),
+            ProxyVersion::V2 => v2_header(addresses, conn),
+        }
+    }
+}
+
+fn v1_header(addresses: Option<(SocketAddr, SocketAddr)>) -> Vec<u8> {
+    let Some((source, destination)) = addresses else {
+        return b"PROXY UNKNOWN\r\n".to_vec();
+    };
+    let family = if source.is_ipv4() { "TCP4" } else { "TCP6" };
+    format!(
+        "PROXY {family} {} {} {} {}\r\n",
+        source.ip(),
+        destination.ip(),
+        source.port(),
+        destination.port()
+    )
+    .into_bytes()
+}
+
+fn v2_header(addresses: Option<(SocketAddr, SocketAddr)>, conn: &ProxiedConnection<'_>) -> Vec<u8> {
+    let mut header = V2_SIGNATURE.to_vec();
+    let Some((source, destination)) = addresses else {
+        // no address block, and no TLVs either: they'd describe a connection
+        // the header doesn't claim to know
+        header.extend_from_slice(&[V2_LOCAL, 0, 0, 0]);
+        return header;
+    };
+    header.push(V2_PROXY);
+    header.push(if source.is_ipv4() { V2_TCP4 } else { V2_TCP6 });
+    // length, filled in once the TLVs are written
+    header.extend_from_slice(&[0, 0]);
+    for address in [source, destination] {
+        match address.ip() {
+            IpAddr::V4(ip) => header.extend_from_slice(&ip.octets()),
+            IpAddr::V6(ip) => header.extend_from_slice(&ip.octets()),
+        }
+    }
+    header.extend_from_slice(&source.port().to_be_bytes());
+    header.extend_from_slice(&destination.port().to_be_bytes());
+
+    if let Some(alpn) = conn.alpn {
+        tlv(&mut header, PP2_TYPE_ALPN, alpn);
+    }
+    if let Some(authority) = conn.authority {
+        tlv(&mut header, PP2_TYPE_AUTHORITY, authority.as_bytes());
+    }
+    let len = (header.len() - 16) as u16;
+    header[14..16].copy_from_slice(&len.to_be_bytes());
+    header
+}
+
+fn tlv(header: &mut Vec<u8>, kind: u8, value: &[u8]) {
+    header.push(kind);
+    header.extend_from_slice(&(value.len() as u16).to_be_bytes());
+    header.extend_from_slice(value);
+}
+
+/// Both addresses in one family, as the header has room for only one: an
+/// IPv4 address next to an IPv6 one is written IPv4-mapped.
+fn same_family((source, destination): (SocketAddr, SocketAddr)) -> (SocketAddr, SocketAddr) {
+    if source.is_ipv4() == destination.is_ipv4() {
+        return (source, destination);
+    }
+    let v6 = |address: SocketAddr| match address.ip() {
+        IpAddr::V4(ip) => SocketAddr::new(IpAddr::V6(ip.to_ipv6_mapped()), address.port()),
+        IpAddr::V6(_) => address,
+    };
+    (v6(source), v6(destination))
+}
+
+/// A header read from the start of a connection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ReceivedHeader {
+    /// Client address and the address it connected to; `None` when the
+    /// header leaves them to the connection itself (`UNKNOWN`, `LOCAL`)
+    pub addresses: Option<(SocketAddr, SocketAddr)>,
+    /// Length of the header; the client's own bytes follow it
+    pub len: usize,
+}
+
+/// Why no header could be read from the start of a connection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProxyError {
+    /// The header is not complete yet; more data is needed
+    Incomplete,
+    /// The connection does not start with a well-formed header
+    Malformed,
+}
+
+/// Parse the header at the start of `buf`, the bytes a trusted peer sent
+/// first.
+pub fn peek_proxy_header(buf: &[u8]) -> Result<ReceivedHeader, ProxyError> {
+    if buf.starts_with(&V2_SIGNATURE) {
+        return v2_received(buf);
+    }
+    if buf.starts_with(b"PROXY ") {
+        return v1_received(buf);
+    }
+    if V2_SIGNATURE.starts_with(buf) || b"PROXY ".starts_with(buf) {
+        return Err(ProxyError::Incomplete);
+    }
+    Err(ProxyError::Malformed)
+}
+
+fn v1_received(buf: &[u8]) -> Result<ReceivedHeader, ProxyError> {
+    let head = &buf[..buf.len().min(V1_MAX)];
+    let Some(end) = head.windows(2).position(|w| w == b"\r\n") else {
+        return Err(if buf.len() < V1_MAX {
+            ProxyError::Incomplete
+        } else {
+            ProxyError::Malformed
+        });
+    };
+    let line = std::str::from_utf8(&head[..end]).map_err(|_| ProxyError::Malformed)?;
+    let len = end + 2;
+    let fields: Vec<&str> = line.split(' ').collect();
+    let addresses = match fields[..] {
+        ["PROXY", "UNKNOWN", ..] => None,
+        ["PROXY", family, src, dst, src_port, dst_port] => {
+            let source = v1_address(src, src_port)?;
+            let destination = v1_address(dst, dst_port)?;
+            let ipv4 = match family {
+                "TCP4" => true,
+                "TCP6" => false,
+                _ => return Err(ProxyError::Malformed),
+            };
+            if source.is_ipv4() != ipv4 || destination.is_ipv4() != ipv4 {
+                return Err(ProxyError::Malformed);
+            }
+            Some((source, destination))
+        }
+        _ => return Err(ProxyError::Malformed),
+    };
+    Ok(ReceivedHeader { addresses, len })
+}
+
+fn v1_address(ip: &str, port: &str) -> Result<SocketAddr, ProxyError> {
+    let ip: IpAddr = ip.parse().map_err(|_| ProxyError::Malformed)?;
+    // no sign, no leading zeros
+    if port.starts_with(['+', '0']) && port != "0" {
+        return Err(ProxyError::Malformed);
+    }
+    let port = port.parse().map_err(|_| ProxyError::Malformed)?;
+    Ok(SocketAddr::new(ip, port))
+}
+
+fn v2_received(buf: &[u8]) -> Result<ReceivedHeader, ProxyError> {
+    let Some(fixed) = buf.get(..16) else {
+        return Err(ProxyError::Incomplete);
+    };
+    let len = 16 + u16::from_be_bytes([fixed[14], fixed[15]]) as usize;
+    let Some(body) = buf.get(16..len) else {
+        return Err(ProxyError::Incomplete);
+    };
+    let addresses = match fixed[12] {
+        V2_LOCAL => None,
+        V2_PROXY => match fixed[13] >> 4 {
+            // AF_INET and AF_INET6, over any transport
+            1 => {
+                let a = body.get(..12).ok_or(ProxyError::Malformed)?;
+                let ip = |at: usize| IpAddr::from(<[u8; 4]>::try_from(&a[at..at + 4]).unwrap());
+                let port = |at: usize| u16::from_be_bytes([a[at], a[at + 1]]);
+                Some((
+                    SocketAddr::new(ip(0), port(8)),
+                    SocketAddr::new(ip(4), port(10)),
+                ))
+            }
+            2 => {
+                let a = body.get(..36).ok_or(ProxyError::Malformed)?;
+                let ip = |at: usize| IpAddr::from(<[u8; 16]>::try_from(&a[at..at + 16]).unwrap());
+                let port = |at: usize| u16::from_be_bytes([a[at], a[at + 1]]);
+                Some((
+                    SocketAddr::new(ip(0), port(32)),
+                    SocketAddr::new(ip(16), port(34)),
+                ))
+            }
+            // AF_UNSPEC, AF_UNIX: nothing an IP connection can stand in for
+            _ => None,
+        },
+        _ => return Err(ProxyError::Malformed),
+    };
+    Ok(ReceivedHeader { addresses, len })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(s: &str) -> SocketAddr {
+        s.parse().unwrap()
+    }
+
+    /// A version 2 header with `command`, `family` and `body`, the length
+    /// taken from the body.
+    fn v2(command: u8, family: u8, body: &[u8]) -> Vec<u8> {
+        let mut header = V2_SIGNATURE.to_vec();
+        header.extend([command, family]);
+        header.extend((body.len() as u16).to_be_bytes());
+        header.extend(body);
+        header
+    }
+
+    fn parsed(addresses: Option<(&str, &str)>, len: usize) -> Result<ReceivedHeader, ProxyError> {
+        Ok(ReceivedHeader {
+            addresses: addresses.map(|(source, destination)| (addr(source), addr(destination))),
+            len,
+        })
+    }
+
+    #[test]
+    fn v2_round_trip() {
+        let cases = [
+            ("192.0.2.1:5000", "198.51.100.2:443", 28),
+            ("[2001:db8::1]:5000", "[2001:db8::2]:443", 52),
+        ];
+        for (source, destination, len) in cases {
+            let conn = ProxiedConnection {
+                addresses: Some((addr(source), addr(destination))),
+                authority: Some("app.test"),
+                alpn: Some(b"h2"),
+            };
+            let header = ProxyVersion::V2.header(&conn);
+            // the TLVs count towards the length
+            assert_eq!(header.len(), len + 3 + 2 + 3 + 8);
+            let mut buf = header.clone();
+            buf.extend(b"GET / HTTP/1.1\r\n");
+            assert_eq!(
+                peek_proxy_header(&buf),
+                parsed(Some((source, destination)), header.len())
+            );
+        }
+    }
+
+    #[test]
+    fn mixed_families_written_ipv4_mapped() {
+        let conn = ProxiedConnection {
+            addresses: Some((addr("192.0.2.1:5000"), addr("[2001:db8::2]:443"))),
+            ..Default::default()
+        };
+        let header = ProxyVersion::V2.header(&conn);
+        assert_eq!(header[13], V2_TCP6);
+        assert_eq!(
+            peek_proxy_header(&header),
+            parsed(Some(("[::ffff:192.0.2.1]:5000", "[2001:db8::2]:443")), 52)
+        );
+        let line = ProxyVersion::V1.header(&conn);
+        assert_eq!(
+            line,
+            b"PROXY TCP6 ::ffff:192.0.2.1 2001:db8::2 5000 443\r\n"
+        );
+    }
+
+    #[test]
+    fn v2_families() {
+        let v4 = [192, 0, 2, 1, 198, 51, 100, 2, 0x13, 0x88, 0x01, 0xbb];
+        let mut v6 = [0u8; 36];
+        v6[15] = 1;
+        v6[31] = 2;
+        v6[32..].copy_from_slice(&[0x13, 0x88, 0x01, 0xbb]);
+        let v4_pair = Some(("192.0.2.1:5000", "198.51.100.2:443"));
+        let v6_pair = Some(("[::1]:5000", "[::2]:443"));
+        let cases: &[(&str, Vec<u8>, Result<ReceivedHeader, ProxyError>)] = &[
+            ("TCP4", v2(V2_PROXY, 0x11, &v4), parsed(v4_pair, 28)),
+            ("UDP4", v2(V2_PROXY, 0x12, &v4), parsed(v4_pair, 28)),
+            ("TCP6", v2(V2_PROXY, 0x21, &v6), parsed(v6_pair, 52)),
+            // a body longer than the addresses holds TLVs
+            (
+                "TCP4 with TLVs",
+                v2(V2_PROXY, 0x11, &[&v4[..], &[0x04, 0, 1, 0]].concat()),
+                parsed(v4_pair, 32),
+            ),
+            ("UNSPEC", v2(V2_PROXY, 0x00, &[]), parsed(None, 16)),
+            ("UNIX", v2(V2_PROXY, 0x31, &[0; 216]), parsed(None, 232)),
+            ("LOCAL", v2(V2_LOCAL, 0x11, &v4), parsed(None, 28)),
+            (
+                "TCP4 too short",
+                v2(V2_PROXY, 0x11, &v4[..11]),
+                Err(ProxyError::Malformed),
+            ),
+            (
+                "TCP6 with TCP4 addresses",
+                v2(V2_PROXY, 0x21, &v4),
+                Err(ProxyError::Malformed),
+            ),
+            (
+                "version 1 binary",
+                v2(0x11, 0x11, &v4),
+                Err(ProxyError::Malformed),
+            ),
+            (
+                "unknown command",
+                v2(0x22, 0x11, &v4),
+                Err(ProxyError::Malformed),
+            ),
+        ];
+        for (name, buf, result) in cases {
+            assert_eq!(peek_proxy_header(buf), *result, "{name}");
+        }
+    }
+
+    #[test]
+    fn v2_incomplete() {
+        let header = v2(V2_PROXY, 0x21, &[0; 36]);
+        for len in 0..header.len() {
+            assert_eq!(
+                peek_proxy_header(&header[..len]),
+                Err(ProxyError::Incomplete),
+                "{len} bytes"
+            );
+        }
+        // a length running past what arrived so far
+        let mut long = v2(V2_PROXY, 0x11, &[0; 12]);
+        long[14..16].copy_from_slice(&0xffffu16.to_be_bytes());
+        assert_eq!(peek_proxy_header(&long), Err(ProxyError::Incomplete));
+    }
+
+    #[test]
+    fn v1() {
+        let longest = "PROXY TCP6 ffff:ffff:ffff:ffff:ffff:ffff:ffff:ffff \
+            ffff:ffff:ffff:ffff:ffff:ffff:ffff:ffff 65535 65535\r\n";
+        let ones = "[ffff:ffff:ffff:ffff:ffff:ffff:ffff:ffff]:65535";
+        let cases: &[(&str, Result<ReceivedHeader, ProxyError>)] = &[
+            (
+                "PROXY TCP4 192.0.2.1 198.51.100.2 5000 443\r\nGET",
+                parsed(Some(("192.0.2.1:5000", "198.51.100.2:443")), 44),
+            ),
+            (
+                "PROXY TCP6 ::1 ::2 5000 443\r\n",
+                parsed(Some(("[::1]:5000", "[::2]:443")), 29),
+            ),
+            ("PROXY UNKNOWN\r\n", parsed(None, 15)),
+            ("PROXY UNKNOWN ::1 ::2 1 2\r\n", parsed(None, 27)),
+            (longest, parsed(Some((ones, ones)), longest.len())),
+            (
+                "PROXY TCP4 ::1 ::2 5000 443\r\n",
+                Err(ProxyError::Malformed),
+            ),
+            (
+                "PROXY TCP6 192.0.2.1 ::2 5000 443\r\n",
+                Err(ProxyError::Malformed),
+            ),
+            (
+                "PROXY UDP4 192.0.2.1 192.0.2.2 5000 443\r\n",
+                Err(ProxyError::Malformed),
+            ),
+            (
+                "PROXY TCP4 192.0.2.1 192.0.2.2 05000 443\r\n",
+                Err(ProxyError::Malformed),
+            ),
+            (
+                "PROXY TCP4 192.0.2.1 192.0.2.2 +5000 443\r\n",
+                Err(ProxyError::Malformed),
+            ),
+            (
+                "PROXY TCP4 192.0.2.1 192.0.2.2 65536 443\r\n",
+                Err(ProxyError::Malformed),
+            ),
+            (
+                "PROXY TCP4 192.0.2.1 192.0.2.2 5000\r\n",
+                Err(ProxyError::Malformed),
+            ),
+            (
+                "PROXY TCP4 192.0.2.1  192.0.2.2 5000 443\r\n",
+                Err(ProxyError::Malformed),
+            ),
+            (
+                "PROXY TCP4 192.0.2.1 192.0.2.2 5000 443",
+                Err(ProxyError::Incomplete),
+            ),
+            ("PROX", Err(ProxyError::Incomplete)),
+            ("", Err(ProxyError::Incomplete)),
+            ("GET / HTTP/1.1\r\n", Err(ProxyError::Malformed)),
+        ];
+        for (line, result) in cases {
+            assert_eq!(peek_proxy_header(line.as_bytes()), *result, "{line:?}");
+        }
+        // no CRLF within the longest a header can be
+        let endless = format!("PROXY TCP4 {}", "1".repeat(V1_MAX));
+        assert_eq!(
+            peek_proxy_header(endless.as_bytes()),
+            Err(ProxyError::Malformed)
+        );
+    }
+}
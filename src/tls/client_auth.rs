@@ -0,0 +1,466 @@
+//! Client certificate authentication
+//!
+//! Listeners with `[tls.client_auth]` ask clients for a certificate and
+//! verify it against the configured CA, and against the revocation lists if
+//! any are given. The identity of a verified certificate, its subject and
+//! subject alternative names, is what ACLs and client routes match on and
+//! what backends receive in the `X-Client-Cert-*` headers.
+
+use std::collections::HashSet;
+use std::fmt::Write;
+use std::io;
+use std::sync::Arc;
+
+use rustls::RootCertStore;
+use rustls::crypto::CryptoProvider;
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, CertificateRevocationListDer};
+use rustls::server::WebPkiClientVerifier;
+use rustls::server::danger::ClientCertVerifier;
+use webpki::EndEntityCert;
+
+use crate::config::ClientAuthSection;
+use crate::util::hostname::HostMap;
+
+use super::config::load_certificates;
+
+/// Request headers carrying the client's identity to the backend. Clients
+/// cannot set them: any they send are dropped.
+pub const SUBJECT_HEADER: &str = "X-Client-Cert-Subject";
+pub const SAN_HEADER: &str = "X-Client-Cert-San";
+
+/// Build the verifier for a listener's client certificates.
+pub fn verifier(
+    section: &ClientAuthSection,
+    provider: Arc<CryptoProvider>,
+) -> io::Result<Arc<dyn ClientCertVerifier>> {
+    let mut roots = RootCertStore::empty();
+    let (added, _) = roots.add_parsable_certificates(load_certificates(&section.ca)?);
+    if added == 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{}: no usable CA certificates", section.ca.display()),
+        ));
+    }
+    let mut crls = Vec::new();
+    for path in &section.crl {
+        let pem_error = |e| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{}: {e}", path.display()),
+            )
+        };
+        for crl in CertificateRevocationListDer::pem_file_iter(path).map_err(pem_error)? {
+            crls.push(crl.map_err(pem_error)?);
+        }
+    }
+
+    let mut builder = WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider);
+    if !crls.is_empty() {
+        // the CRLs are for the issuers of client certificates, not for the CA chain
+        builder = builder.with_crls(crls).only_check_end_entity_revocation();
+    }
+    if !section.required {
+        builder = builder.allow_unauthenticated();
+    }
+    builder
+        .build()
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, format!("client_auth: {e}")))
+}
+
+/// Why a handshake failed, if it failed over the client's certificate.
+pub fn rejection(error: &io::Error) -> Option<&rustls::Error> {
+    let error = error.get_ref()?.downcast_ref::<rustls::Error>()?;
+    match error {
+        rustls::Error::InvalidCertificate(_) | rustls::Error::NoCertificatesPresented => {
+            Some(error)
+        }
+        _ => None,
+    }
+}
+
+/// What a verified client certificate says about its holder.
+#[derive(Debug, Clone)]
+pub struct ClientIdentity {
+    /// Subject distinguished name in RFC 4514 form, e.g. `CN=api,O=Example`
+    pub subject: String,
+    /// DNS names from the subject alternative names, lower case
+    pub dns_names: Vec<String>,
+    pub uris: Vec<String>,
+}
+
+impl ClientIdentity {
+    pub fn from_certificate(der: &CertificateDer<'_>) -> Option<Self> {
+        let cert = EndEntityCert::try_from(der).ok()?;
+        Some(Self {
+            subject: format_name(cert.subject())?,
+            dns_names: cert
+                .valid_dns_names()
+                .map(str::to_ascii_lowercase)
+                .collect(),
+            // unlike DNS names, URIs are not checked for characters a header can't carry
+            uris: cert
+                .valid_uri_names()
+                .filter(|uri| uri.bytes().all(|b| b.is_ascii_graphic()))
+                .map(str::to_string)
+                .collect(),
+        })
+    }
+
+    /// Value of `X-Client-Cert-San`, e.g. `DNS:api.internal, URI:spiffe://example/api`
+    pub fn san(&self) -> Option<String> {
+        let names = self.dns_names.iter().map(|n| format!("DNS:{n}"));
+        let uris = self.uris.iter().map(|u| format!("URI:{u}"));
+        let san = names.chain(uris).collect::<Vec<_>>().join(", ");
+        (!san.is_empty()).then_some(san)
+    }
+}
+
+/// A set of client certificates, by exact subject or by a SAN DNS name that
+/// matches one of the patterns (`*.example.com` allowed).
+#[derive(Debug, Clone, Default)]
+pub struct ClientMatcher {
+    subjects: HashSet<String>,
+    names: HostMap<()>,
+}
+
+impl ClientMatcher {
+    pub fn new(subjects: &[String], names: &[String]) -> Self {
+        let mut matcher = Self {
+            subjects: subjects.iter().cloned().collect(),
+            names: HostMap::default(),
+        };
+        for name in names {
+            matcher.names.insert(name, ());
+        }
+        matcher
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.subjects.is_empty() && self.names.is_empty()
+    }
+
+    pub fn matches(&self, client: &ClientIdentity) -> bool {
+        self.subjects.contains(&client.subject)
+            || client
+                .dns_names
+                .iter()
+                .any(|name| self.names.get(name).is_some())
+    }
+}
+
+/// RFC 4514 string of a DER `RDNSequence` without its outer SEQUENCE header:
+/// the last RDN first, attributes of one RDN joined by `+`.
+fn format_name(mut der: &[u8]) -> Option<String> {
+    let mut rdns = Vec::new();
+    while !der.is_empty() {
+        let (tag, mut set, rest) = element(der)?;
+        der = rest;
+        if tag != 0x31 {
+            return None;
+        }
+        let mut attributes = Vec::new();
+        while !set.is_empty() {
+            let (tag, attribute, rest) = element(set)?;
+            set = rest;
+            if tag != 0x30 {
+                return None;
+            }
+            let (0x06, oid, value) = element(attribute)? else {
+                return None;
+            };
+            let mut out = attribute_type(oid);
+            out.push('=');
+            attribute_value(value, &mut out)?;
+            attributes.push(out);
+        }
+        rdns.push(attributes.join("+"));
+    }
+    rdns.reverse();
+    Some(rdns.join(","))
+}
+
+/// One DER element: tag, contents, and what follows it.
+fn element(input: &[u8]) -> Option<(u8, &[u8], &[u8])> {
+    let (&tag, rest) = input.split_first()?;
+    let (&first, mut rest) = rest.split_first()?;
+    let len = if first < 0x80 {
+        first as usize
+    } else {
+        let octets = (first & 0x7f) as usize;
+        if octets == 0 || octets > 4 || rest.len() < octets {
+            return None;
+        }
+        let len = rest[..octets]
+            .iter()
+            .fold(0, |len, &b| len << 8 | b as usize);
+        rest = &rest[octets..];
+        len
+    };
+    (rest.len() >= len).then(|| (tag, &rest[..len], &rest[len..]))
+}
+
+fn attribute_type(oid: &[u8]) -> String {
+    let name = match oid {
+        [0x55, 0x04, 0x03] => "CN",
+        [0x55, 0x04, 0x06] => "C",
+        [0x55, 0x04, 0x07] => "L",
+        [0x55, 0x04, 0x08] => "ST",
+        [0x55, 0x04, 0x09] => "STREET",
+        [0x55, 0x04, 0x0a] => "O",
+        [0x55, 0x04, 0x0b] => "OU",
+        [0x55, 0x04, 0x05] => "serialNumber",
+        [0x09, 0x92, 0x26, 0x89, 0x93, 0xf2, 0x2c, 0x64, 0x01, 0x19] => "DC",
+        [0x09, 0x92, 0x26, 0x89, 0x93, 0xf2, 0x2c, 0x64, 0x01, 0x01] => "UID",
+        [0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x09, 0x01] => "emailAddress",
+        _ => return dotted_oid(oid),
+    };
+    name.to_string()
+}
+
+fn dotted_oid(oid: &[u8]) -> String {
+    let mut out = String::new();
+    let mut arc: u64 = 0;
+    for &b in oid {
+        arc = arc << 7 | (b & 0x7f) as u64;
+        if b & 0x80 != 0 {
+            continue;
+        }
+        if out.is_empty() {
+            let first = (arc / 40).min(2);
+            let _ = write!(out, "{first}.{}", arc - first * 40);
+        } else {
+            let _ = write!(out, ".{arc}");
+        }
+        arc = 0;
+    }
+    out
+}
+
+/// Append a string value escaped as RFC 4514 asks, with control characters
+/// escaped too so the result is safe in a header. Values that are not
+/// strings are written as `#` and the hex of their encoding.
+fn attribute_value(value: &[u8], out: &mut String) -> Option<()> {
+    let (tag, contents, rest) = element(value)?;
+    let text: String = match tag {
+        // UTF8String, PrintableString, IA5String
+        0x0c | 0x13 | 0x16 => std::str::from_utf8(contents).ok()?.to_string(),
+        // TeletexString, read as Latin-1 like most implementations do
+        0x14 => contents.iter().map(|&b| b as char).collect(),
+        // BMPString
+        0x1e => {
+            if contents.len() % 2 != 0 {
+                return None;
+            }
+            let units = contents
+                .chunks_exact(2)
+                .map(|pair| u16::from_be_bytes([pair[0], pair[1]]));
+            char::decode_utf16(units).collect::<Result<_, _>>().ok()?
+        }
+        _ => {
+            out.push('#');
+            for b in &value[..value.len() - rest.len()] {
+                let _ = write!(out, "{b:02x}");
+            }
+            return Some(());
+        }
+    };
+    let last = text.chars().count().saturating_sub(1);
+    for (i, c) in text.chars().enumerate() {
+        match c {
+            ',' | '+' | '"' | '\\' | '<' | '>' | ';' => {
+                out.push('\\');
+                out.push(c);
+            }
+            '#' if i == 0 => out.push_str("\\#"),
+            ' ' if i == 0 || i == last => out.push_str("\\ "),
+            c if c.is_control() => {
+                let mut buf = [0; 4];
+                for b in c.encode_utf8(&mut buf).bytes() {
+                    let _ = write!(out, "\\{b:02x}");
+                }
+            }
+            c => out.push(c),
+        }
+    }
+    Some(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CN: &[u8] = &[0x55, 0x04, 0x03];
+    const O: &[u8] = &[0x55, 0x04, 0x0a];
+    const UID: &[u8] = &[0x09, 0x92, 0x26, 0x89, 0x93, 0xf2, 0x2c, 0x64, 0x01, 0x01];
+
+    /// Tag, length of the contents and of what follows an element
+    type Framing = Option<(u8, usize, usize)>;
+
+    /// A DER element, with a long-form length when the contents need one
+    fn tlv(tag: u8, contents: &[u8]) -> Vec<u8> {
+        let mut out = vec![tag];
+        let len = contents.len().to_be_bytes();
+        match contents.len() {
+            0..0x80 => out.push(len[7]),
+            0x80..0x100 => out.extend_from_slice(&[0x81, len[7]]),
+            _ => out.extend_from_slice(&[0x82, len[6], len[7]]),
+        }
+        out.extend_from_slice(contents);
+        out
+    }
+
+    fn utf8(s: &str) -> Vec<u8> {
+        tlv(0x0c, s.as_bytes())
+    }
+
+    fn bmp(s: &str) -> Vec<u8> {
+        let units: Vec<u8> = s.encode_utf16().flat_map(u16::to_be_bytes).collect();
+        tlv(0x1e, &units)
+    }
+
+    fn rdn(attributes: &[(&[u8], Vec<u8>)]) -> Vec<u8> {
+        let mut set = Vec::new();
+        for (oid, value) in attributes {
+            set.extend(tlv(0x30, &[tlv(0x06, oid), value.clone()].concat()));
+        }
+        tlv(0x31, &set)
+    }
+
+    fn value(der: &[u8]) -> Option<String> {
+        let mut out = String::new();
+        attribute_value(der, &mut out)?;
+        Some(out)
+    }
+
+    #[test]
+    fn elements() {
+        let long = [&[0x04, 0x81, 0x80][..], &[0xaa; 0x80]].concat();
+        let longer = [&[0x04, 0x82, 0x01, 0x00][..], &[0xaa; 0x100], &[0x05, 0x00]].concat();
+        let cases: [(&[u8], Framing); 12] = [
+            (&[0x04, 0x00], Some((0x04, 0, 0))),
+            (&[0x04, 0x01, 0xaa, 0x05, 0x00], Some((0x04, 1, 2))),
+            (&long, Some((0x04, 0x80, 0))),
+            (&longer, Some((0x04, 0x100, 2))),
+            // a long form needn't be the shortest one
+            (&[0x04, 0x81, 0x01, 0xaa], Some((0x04, 1, 0))),
+            (&[0x04, 0x84, 0, 0, 0, 0x01, 0xaa], Some((0x04, 1, 0))),
+            // indefinite, too many length octets, or too few there
+            (&[0x04, 0x80, 0xaa, 0x00, 0x00], None),
+            (&[0x04, 0x85, 0, 0, 0, 0, 0x01, 0xaa], None),
+            (&[0x04, 0x82, 0x01], None),
+            (&[0x04, 0x02, 0xaa], None),
+            (&[0x04], None),
+            (&[], None),
+        ];
+        for (input, expected) in cases {
+            let parsed =
+                element(input).map(|(tag, contents, rest)| (tag, contents.len(), rest.len()));
+            assert_eq!(parsed, expected, "{input:02x?}");
+        }
+    }
+
+    #[test]
+    fn values() {
+        let long = "a".repeat(200);
+        let octets = format!("#048180{}", "aa".repeat(0x80));
+        let cases = [
+            (utf8("api"), Some("api")),
+            (tlv(0x13, b"Example Ltd"), Some("Example Ltd")),
+            (tlv(0x16, b"ops@example.com"), Some("ops@example.com")),
+            (utf8("Zoë"), Some("Zoë")),
+            (utf8(&long), Some(long.as_str())),
+            // RFC 4514 escapes, and control characters as hex
+            (
+                utf8(r#"a,b+c"d\e<f>g;h"#),
+                Some(r#"a\,b\+c\"d\\e\<f\>g\;h"#),
+            ),
+            (utf8("#1 # 2"), Some("\\#1 # 2")),
+            (utf8(" a b "), Some("\\ a b\\ ")),
+            (utf8(" "), Some("\\ ")),
+            (utf8("a\r\nb\u{7f}"), Some("a\\0d\\0ab\\7f")),
+            (utf8("\u{85}"), Some("\\c2\\85")),
+            (utf8(""), Some("")),
+            // TeletexString as Latin-1
+            (tlv(0x14, b"Z\xf6e"), Some("Zöe")),
+            (tlv(0x14, b"a\x0ab"), Some("a\\0ab")),
+            // BMPString, including a surrogate pair
+            (bmp("Ωmega"), Some("Ωmega")),
+            (bmp("😀"), Some("😀")),
+            (bmp(",x"), Some("\\,x")),
+            (tlv(0x1e, &[0xd8, 0x3d]), None),
+            (tlv(0x1e, &[0x00, 0x41, 0x00]), None),
+            (tlv(0x0c, b"\xff"), None),
+            (tlv(0x13, b"\xc3"), None),
+            // anything else as the hex of its encoding
+            (tlv(0x02, &[0x05]), Some("#020105")),
+            (tlv(0x30, &[]), Some("#3000")),
+            (tlv(0x04, &[0xaa; 0x80]), Some(octets.as_str())),
+            (vec![0x0c, 0x05, b'a'], None),
+            (vec![], None),
+        ];
+        for (der, expected) in cases {
+            assert_eq!(value(&der).as_deref(), expected, "{der:02x?}");
+        }
+    }
+    #[test]
+    fn names() {
+        let cn = |s: &str| rdn(&[(CN, utf8(s))]);
+        let long = "a".repeat(200);
+        let long_name = format!("CN={long}");
+        let cases = [
+            (vec![], Some("")),
+            (cn("api"), Some("CN=api")),
+            // the last RDN first
+            (
+                [rdn(&[(O, utf8("Example"))]), cn("api")].concat(),
+                Some("CN=api,O=Example"),
+            ),
+            // a multi-valued RDN, in the order its attributes are encoded
+            (
+                [
+                    rdn(&[(O, utf8("Example"))]),
+                    rdn(&[(CN, utf8("api")), (UID, utf8("42"))]),
+                ]
+                .concat(),
+                Some("CN=api+UID=42,O=Example"),
+            ),
+            (
+                rdn(&[(CN, utf8("a+b")), (O, bmp("c,d"))]),
+                Some("CN=a\\+b+O=c\\,d"),
+            ),
+            // long-form lengths on every level
+            (cn(&long), Some(long_name.as_str())),
+            (rdn(&[(&[0x55, 0x04, 0x2a], utf8("x"))]), Some("2.5.4.42=x")),
+            (
+                rdn(&[(
+                    &[0x2b, 0x06, 0x01, 0x04, 0x01, 0x82, 0x37, 0x15, 0x14],
+                    utf8("x"),
+                )]),
+                Some("1.3.6.1.4.1.311.21.20=x"),
+            ),
+            (
+                rdn(&[(
+                    &[0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x09, 0x01],
+                    tlv(0x16, b"a@b"),
+                )]),
+                Some("emailAddress=a@b"),
+            ),
+            (rdn(&[(CN, tlv(0x02, &[0x01]))]), Some("CN=#020101")),
+            // not a SET, not a SEQUENCE, no OID, or cut short
+            (tlv(0x30, &cn("api")), None),
+            (
+                tlv(0x31, &tlv(0x31, &[tlv(0x06, CN), utf8("api")].concat())),
+                None,
+            ),
+            (tlv(0x31, &tlv(0x30, &utf8("api"))), None),
+            (tlv(0x31, &tlv(0x30, &tlv(0x06, CN))), None),
+            (cn("api")[..6].to_vec(), None),
+            ([cn("api"), vec![0x31]].concat(), None),
+            (rdn(&[(CN, tlv(0x1e, &[0xdc, 0x00]))]), None),
+        ];
+        for (der, expected) in cases {
+            assert_eq!(format_name(&der).as_deref(), expected, "{der:02x?}");
+        }
+    }
+}
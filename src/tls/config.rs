@@ -0,0 +1,216 @@
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, OnceLock, RwLock};
+
+use rustls::crypto::{CryptoProvider, ring};
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::server::{
+    ClientHello, NoServerSessionStorage, ResolvesServerCert, ServerSessionMemoryCache,
+};
+use rustls::sign::CertifiedKey;
+use rustls::{ServerConfig, SupportedProtocolVersion, version};
+use tracing::{info, warn};
+
+use crate::config::TlsSection;
+use crate::util::hostname::HostMap;
+
+use super::{TlsVersion, client_auth, ktls};
+
+/// Certificate and key files as configured.
+#[derive(Debug)]
+struct CertFiles {
+    cert: PathBuf,
+    key: PathBuf,
+}
+
+impl CertFiles {
+    fn load(&self, provider: &CryptoProvider) -> io::Result<Arc<CertifiedKey>> {
+        load_certified_key(&self.cert, &self.key, provider).map(Arc::new)
+    }
+}
+
+/// The certificates being served.
+#[derive(Debug)]
+struct Loaded {
+    default: Arc<CertifiedKey>,
+    by_name: HostMap<Arc<CertifiedKey>>,
+}
+
+/// Listener certificates, selected by SNI and re-read from disk on `reload`.
+#[derive(Debug)]
+struct CertStore {
+    default: CertFiles,
+    named: Vec<(Vec<String>, CertFiles)>,
+    provider: Arc<CryptoProvider>,
+    current: RwLock<Arc<Loaded>>,
+}
+
+impl CertStore {
+    fn new(section: &TlsSection, provider: Arc<CryptoProvider>) -> io::Result<Self> {
+        let default = CertFiles {
+            cert: section.cert.clone(),
+            key: section.key.clone(),
+        };
+        let named: Vec<_> = section
+            .certificates
+            .iter()
+            .map(|c| {
+                let files = CertFiles {
+                    cert: c.cert.clone(),
+                    key: c.key.clone(),
+                };
+                (c.names.clone(), files)
+            })
+            .collect();
+        let loaded = load_all(&default, &named, &provider)?;
+        Ok(Self {
+            default,
+            named,
+            provider,
+            current: RwLock::new(Arc::new(loaded)),
+        })
+    }
+
+    /// Swap in freshly read certificates, keeping the old set if any fails to load.
+    fn reload(&self) -> io::Result<()> {
+        let loaded = load_all(&self.default, &self.named, &self.provider)?;
+        *self.current.write().unwrap() = Arc::new(loaded);
+        Ok(())
+    }
+}
+
+fn load_all(
+    default: &CertFiles,
+    named: &[(Vec<String>, CertFiles)],
+    provider: &CryptoProvider,
+) -> io::Result<Loaded> {
+    let mut by_name = HostMap::default();
+    for (names, files) in named {
+        let key = files.load(provider)?;
+        for name in names {
+            by_name.insert(name, key.clone());
+        }
+    }
+    Ok(Loaded {
+        default: default.load(provider)?,
+        by_name,
+    })
+}
+
+impl ResolvesServerCert for CertStore {
+    fn resolve(&self, client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        let current = self.current.read().unwrap().clone();
+        let named = client_hello
+            .server_name()
+            .and_then(|name| current.by_name.get(&name.to_ascii_lowercase()));
+        Some(named.unwrap_or(&current.default).clone())
+    }
+}
+
+static CERTS: OnceLock<Arc<CertStore>> = OnceLock::new();
+
+/// Build the rustls configuration shared by every worker's TLS listener.
+pub fn server_config(section: &TlsSection) -> io::Result<Arc<ServerConfig>> {
+    let provider = Arc::new(ring::default_provider());
+    let certs = Arc::new(CertStore::new(section, provider.clone())?);
+
+    let versions: &[&'static SupportedProtocolVersion] = match section.min_version {
+        TlsVersion::Tls12 => &[&version::TLS13, &version::TLS12],
+        TlsVersion::Tls13 => &[&version::TLS13],
+    };
+    let builder = ServerConfig::builder_with_provider(provider.clone())
+        .with_protocol_versions(versions)
+        .map_err(tls_error)?;
+    let builder = match &section.client_auth {
+        Some(auth) => builder.with_client_cert_verifier(client_auth::verifier(auth, provider)?),
+        None => builder.with_no_client_auth(),
+    };
+    let mut config = builder.with_cert_resolver(certs.clone());
+
+    config.alpn_protocols = section.alpn.iter().map(|p| p.as_bytes().to_vec()).collect();
+    config.session_storage = if section.session_cache == 0 {
+        Arc::new(NoServerSessionStorage {})
+    } else {
+        ServerSessionMemoryCache::new(section.session_cache)
+    };
+    if section.session_tickets {
+        config.ticketer = ring::Ticketer::new().map_err(tls_error)?;
+    }
+    if section.ktls {
+        let ciphers = ktls::probe();
+        if ciphers.is_empty() {
+            warn!("kernel tls is not available, connections stay in userspace");
+        } else {
+            info!(?ciphers, "kernel tls offload enabled");
+            config.enable_secret_extraction = true;
+        }
+    }
+
+    CERTS
+        .set(certs)
+        .map_err(|_| io::Error::other("tls already configured"))?;
+    Ok(Arc::new(config))
+}
+
+/// Re-read every certificate and key. Handshakes already under way keep the
+/// old ones.
+pub fn reload() -> io::Result<()> {
+    let Some(certs) = CERTS.get() else {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            "tls is not configured",
+        ));
+    };
+    certs.reload()?;
+    info!(
+        certificates = certs.named.len() + 1,
+        "tls certificates reloaded"
+    );
+    Ok(())
+}
+
+fn load_certified_key(
+    cert_path: &Path,
+    key_path: &Path,
+    provider: &CryptoProvider,
+) -> io::Result<CertifiedKey> {
+    let chain = load_certificates(cert_path)?;
+    let key = load_private_key(key_path)?;
+    CertifiedKey::from_der(chain, key, provider).map_err(|e| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{}: {e}", key_path.display()),
+        )
+    })
+}
+
+/// Every certificate in a PEM file; an empty file is an error.
+pub(super) fn load_certificates(path: &Path) -> io::Result<Vec<CertificateDer<'static>>> {
+    let certs = CertificateDer::pem_file_iter(path)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(|e| pem_error(path, e))?;
+    if certs.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{}: no certificates found", path.display()),
+        ));
+    }
+    Ok(certs)
+}
+
+/// The private key in a PEM file (PKCS#8, PKCS#1 or SEC1).
+pub(super) fn load_private_key(path: &Path) -> io::Result<PrivateKeyDer<'static>> {
+    PrivateKeyDer::from_pem_file(path).map_err(|e| pem_error(path, e))
+}
+
+fn pem_error(path: &Path, e: rustls::pki_types::pem::Error) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("{}: {e}", path.display()),
+    )
+}
+
+pub(super) fn tls_error(e: rustls::Error) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, e)
+}
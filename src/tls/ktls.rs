@@ -0,0 +1,308 @@
+//! Kernel TLS offload
+//!
+//! Once rustls has finished the handshake and nothing is left in its
+//! buffers, the traffic keys are installed on the socket (`TCP_ULP` "tls",
+//! then `TLS_TX`/`TLS_RX`) and the connection continues as a plain one: the
+//! kernel encrypts what the pumps send and decrypts what they receive. Which
+//! ciphers the kernel can offload is probed once at startup; connections
+//! with any other cipher, or on kernels without the `tls` module, stay in
+//! userspace.
+
+use std::io;
+use std::net::{TcpListener, TcpStream};
+use std::os::fd::{AsRawFd, RawFd};
+use std::sync::OnceLock;
+
+use rustls::{CipherSuite, ConnectionTrafficSecrets, ExtractedSecrets, ProtocolVersion};
+
+/// Ciphers the kernel implements for TLS 1.2 and 1.3.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Cipher {
+    Aes128Gcm,
+    Aes256Gcm,
+    Chacha20Poly1305,
+}
+
+impl Cipher {
+    const ALL: [Cipher; 3] = [Cipher::Aes128Gcm, Cipher::Aes256Gcm, Cipher::Chacha20Poly1305];
+
+    pub fn of_suite(suite: CipherSuite) -> Option<Self> {
+        match suite {
+            CipherSuite::TLS13_AES_128_GCM_SHA256
+            | CipherSuite::TLS_ECDHE_ECDSA_WITH_AES_128_GCM_SHA256
+            | CipherSuite::TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256 => Some(Self::Aes128Gcm),
+            CipherSuite::TLS13_AES_256_GCM_SHA384
+            | CipherSuite::TLS_ECDHE_ECDSA_WITH_AES_256_GCM_SHA384
+            | CipherSuite::TLS_ECDHE_RSA_WITH_AES_256_GCM_SHA384 => Some(Self::Aes256Gcm),
+            CipherSuite::TLS13_CHACHA20_POLY1305_SHA256
+            | CipherSuite::TLS_ECDHE_ECDSA_WITH_CHACHA20_POLY1305_SHA256
+            | CipherSuite::TLS_ECDHE_RSA_WITH_CHACHA20_POLY1305_SHA256 => {
+                Some(Self::Chacha20Poly1305)
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Offloadable ciphers; unset until `probe` ran, empty when offload is off.
+static SUPPORTED: OnceLock<Vec<Cipher>> = OnceLock::new();
+
+/// Find out which ciphers this kernel can offload in both directions and
+/// enable offload for them.
+pub fn probe() -> &'static [Cipher] {
+    SUPPORTED.get_or_init(|| {
+        Cipher::ALL
+            .into_iter()
+            .filter(|&cipher| probe_cipher(cipher).is_ok())
+            .collect()
+    })
+}
+
+/// Whether a connection that negotiated `suite` can be handed to the kernel.
+pub fn offloadable(suite: CipherSuite) -> bool {
+    let Some(supported) = SUPPORTED.get() else {
+        return false;
+    };
+    Cipher::of_suite(suite).is_some_and(|cipher| supported.contains(&cipher))
+}
+
+/// Set up a loopback connection and install all-zero keys on it.
+fn probe_cipher(cipher: Cipher) -> io::Result<()> {
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let _client = TcpStream::connect(listener.local_addr()?)?;
+    let (server, _) = listener.accept()?;
+    let fd = server.as_raw_fd();
+    enable_ulp(fd)?;
+    let info = CryptoInfo::zeroed(cipher);
+    info.install(fd, libc::TLS_TX)?;
+    info.install(fd, libc::TLS_RX)
+}
+
+/// Attach the TLS upper layer protocol. Until keys are installed the socket
+/// keeps behaving like plain TCP, so failing here leaves it usable.
+pub fn enable_ulp(fd: RawFd) -> io::Result<()> {
+    setsockopt(fd, libc::SOL_TCP, libc::TCP_ULP, b"tls")
+}
+
+/// Install both directions' traffic keys on a socket with the ULP attached.
+pub fn install(fd: RawFd, version: ProtocolVersion, secrets: ExtractedSecrets) -> io::Result<()> {
+    let version = match version {
+        ProtocolVersion::TLSv1_2 => libc::TLS_1_2_VERSION,
+        ProtocolVersion::TLSv1_3 => libc::TLS_1_3_VERSION,
+        other => {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                format!("{other:?} cannot be offloaded"),
+            ));
+        }
+    };
+    let (tx_seq, tx) = secrets.tx;
+    let (rx_seq, rx) = secrets.rx;
+    CryptoInfo::new(version, tx_seq, tx)?.install(fd, libc::TLS_TX)?;
+    CryptoInfo::new(version, rx_seq, rx)?.install(fd, libc::TLS_RX)
+}
+
+/// TLS record content types
+const RECORD_ALERT: u8 = 21;
+const RECORD_APPLICATION_DATA: u8 = 23;
+
+/// Room for the one-byte record type control message
+const CONTROL_LEN: usize = unsafe { libc::CMSG_SPACE(1) } as usize;
+
+/// Send a close_notify alert through the kernel TLS layer. Best effort.
+pub fn close_notify(fd: RawFd) {
+    // level warning, description close_notify
+    let mut alert = [1u8, 0];
+    let mut iov = libc::iovec {
+        iov_base: alert.as_mut_ptr().cast(),
+        iov_len: alert.len(),
+    };
+    let mut control = [0u8; CONTROL_LEN];
+    unsafe {
+        let mut msg: libc::msghdr = std::mem::zeroed();
+        msg.msg_iov = &mut iov;
+        msg.msg_iovlen = 1;
+        msg.msg_control = control.as_mut_ptr().cast();
+        msg.msg_controllen = control.len();
+        let cmsg = libc::CMSG_FIRSTHDR(&msg);
+        (*cmsg).cmsg_level = libc::SOL_TLS;
+        (*cmsg).cmsg_type = libc::TLS_SET_RECORD_TYPE;
+        (*cmsg).cmsg_len = libc::CMSG_LEN(1) as usize;
+        *libc::CMSG_DATA(cmsg) = RECORD_ALERT;
+        libc::sendmsg(fd, &msg, libc::MSG_DONTWAIT | libc::MSG_NOSIGNAL);
+    }
+}
+
+/// A recvmsg on an offloaded socket, with room for the record type.
+///
+/// A plain recv fails with EIO once the next record isn't application data,
+/// so the peer's close_notify would look like an error. Given a control
+/// buffer, the kernel hands over such a record along with its type instead.
+/// Boxed by its owner, since the kernel holds on to the header until the
+/// recv completes.
+pub struct RecordRecv {
+    msg: libc::msghdr,
+    iov: libc::iovec,
+    control: [u8; CONTROL_LEN],
+}
+
+impl RecordRecv {
+    pub fn new() -> Box<Self> {
+        Box::new(Self {
+            msg: unsafe { std::mem::zeroed() },
+            iov: libc::iovec {
+                iov_base: std::ptr::null_mut(),
+                iov_len: 0,
+            },
+            control: [0; CONTROL_LEN],
+        })
+    }
+
+    /// Header for a recvmsg of up to `len` bytes into `buf`.
+    pub fn prepare(&mut self, buf: *mut u8, len: usize) -> *mut libc::msghdr {
+        self.iov = libc::iovec {
+            iov_base: buf.cast(),
+            iov_len: len,
+        };
+        // cleared so a completion without a control message isn't misread
+        self.control = [0; CONTROL_LEN];
+        self.msg = unsafe { std::mem::zeroed() };
+        self.msg.msg_iov = &mut self.iov;
+        self.msg.msg_iovlen = 1;
+        self.msg.msg_control = self.control.as_mut_ptr().cast();
+        self.msg.msg_controllen = CONTROL_LEN;
+        &mut self.msg
+    }
+
+    /// The result of the completed recvmsg as the pumps should see it:
+    /// application data as is, and an alert as the end of the stream. Any
+    /// other record, e.g. a TLS 1.3 KeyUpdate, can't be handled once the
+    /// kernel has the keys and fails the connection with EIO.
+    pub fn complete(&self, res: i32) -> i32 {
+        if res <= 0 {
+            return res;
+        }
+        match self.record_type() {
+            None | Some(RECORD_APPLICATION_DATA) => res,
+            Some(RECORD_ALERT) => 0,
+            Some(_) => -libc::EIO,
+        }
+    }
+
+    fn record_type(&self) -> Option<u8> {
+        let cmsg: libc::cmsghdr =
+            unsafe { std::ptr::read_unaligned(self.control.as_ptr().cast()) };
+        let data = unsafe { libc::CMSG_LEN(0) } as usize;
+        (cmsg.cmsg_level == libc::SOL_TLS && cmsg.cmsg_type == libc::TLS_GET_RECORD_TYPE)
+            .then(|| self.control[data])
+    }
+}
+
+/// `struct tls12_crypto_info_*` for one direction.
+enum CryptoInfo {
+    Aes128Gcm(libc::tls12_crypto_info_aes_gcm_128),
+    Aes256Gcm(libc::tls12_crypto_info_aes_gcm_256),
+    Chacha20Poly1305(libc::tls12_crypto_info_chacha20_poly1305),
+}
+
+impl CryptoInfo {
+    fn new(version: u16, seq: u64, secrets: ConnectionTrafficSecrets) -> io::Result<Self> {
+        let rec_seq = seq.to_be_bytes();
+        // AES-GCM takes the first four IV bytes as salt, the rest as IV
+        let info = match secrets {
+            ConnectionTrafficSecrets::Aes128Gcm { key, iv } => {
+                let mut info = Self::zeroed_aes128();
+                info.info.version = version;
+                info.key.copy_from_slice(key.as_ref());
+                info.salt.copy_from_slice(&iv.as_ref()[..4]);
+                info.iv.copy_from_slice(&iv.as_ref()[4..]);
+                info.rec_seq = rec_seq;
+                Self::Aes128Gcm(info)
+            }
+            ConnectionTrafficSecrets::Aes256Gcm { key, iv } => {
+                let mut info = Self::zeroed_aes256();
+                info.info.version = version;
+                info.key.copy_from_slice(key.as_ref());
+                info.salt.copy_from_slice(&iv.as_ref()[..4]);
+                info.iv.copy_from_slice(&iv.as_ref()[4..]);
+                info.rec_seq = rec_seq;
+                Self::Aes256Gcm(info)
+            }
+            ConnectionTrafficSecrets::Chacha20Poly1305 { key, iv } => {
+                let mut info = Self::zeroed_chacha();
+                info.info.version = version;
+                info.key.copy_from_slice(key.as_ref());
+                info.iv.copy_from_slice(iv.as_ref());
+                info.rec_seq = rec_seq;
+                Self::Chacha20Poly1305(info)
+            }
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::Unsupported,
+                    "cipher cannot be offloaded",
+                ));
+            }
+        };
+        Ok(info)
+    }
+
+    /// All-zero keys of `cipher` for TLS 1.3, for probing.
+    fn zeroed(cipher: Cipher) -> Self {
+        match cipher {
+            Cipher::Aes128Gcm => Self::Aes128Gcm(Self::zeroed_aes128()),
+            Cipher::Aes256Gcm => Self::Aes256Gcm(Self::zeroed_aes256()),
+            Cipher::Chacha20Poly1305 => Self::Chacha20Poly1305(Self::zeroed_chacha()),
+        }
+    }
+
+    fn zeroed_aes128() -> libc::tls12_crypto_info_aes_gcm_128 {
+        let mut info: libc::tls12_crypto_info_aes_gcm_128 = unsafe { std::mem::zeroed() };
+        info.info.version = libc::TLS_1_3_VERSION;
+        info.info.cipher_type = libc::TLS_CIPHER_AES_GCM_128;
+        info
+    }
+
+    fn zeroed_aes256() -> libc::tls12_crypto_info_aes_gcm_256 {
+        let mut info: libc::tls12_crypto_info_aes_gcm_256 = unsafe { std::mem::zeroed() };
+        info.info.version = libc::TLS_1_3_VERSION;
+        info.info.cipher_type = libc::TLS_CIPHER_AES_GCM_256;
+        info
+    }
+
+    fn zeroed_chacha() -> libc::tls12_crypto_info_chacha20_poly1305 {
+        let mut info: libc::tls12_crypto_info_chacha20_poly1305 = unsafe { std::mem::zeroed() };
+        info.info.version = libc::TLS_1_3_VERSION;
+        info.info.cipher_type = libc::TLS_CIPHER_CHACHA20_POLY1305;
+        info
+    }
+
+    fn install(&self, fd: RawFd, direction: libc::c_int) -> io::Result<()> {
+        let bytes = match self {
+            Self::Aes128Gcm(info) => as_bytes(info),
+            Self::Aes256Gcm(info) => as_bytes(info),
+            Self::Chacha20Poly1305(info) => as_bytes(info),
+        };
+        setsockopt(fd, libc::SOL_TLS, direction, bytes)
+    }
+}
+
+fn as_bytes<T>(value: &T) -> &[u8] {
+    unsafe { std::slice::from_raw_parts((value as *const T).cast(), size_of::<T>()) }
+}
+
+fn setsockopt(fd: RawFd, level: libc::c_int, name: libc::c_int, value: &[u8]) -> io::Result<()> {
+    let rc = unsafe {
+        libc::setsockopt(
+            fd,
+            level,
+            name,
+            value.as_ptr().cast(),
+            value.len() as libc::socklen_t,
+        )
+    };
+    if rc < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(())
+    }
+}
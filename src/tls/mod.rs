@@ -0,0 +1,62 @@
+//! TLS termination and re-encryption
+//!
+//! Listeners with a `[tls]` section speak TLS 1.2/1.3 through rustls. The
+//! io_uring pumps stay as they are: client recvs land in a per-connection
+//! ciphertext buffer that is fed to the pair's `ServerConnection`, and the
+//! plaintext it yields goes where a plain recv would have written. Data for
+//! the client is encrypted into an outgoing buffer and sent from there.
+//! The certificate is resolved per handshake from the SNI name, falling back
+//! to the default one, so a reload swaps certificates for new connections
+//! without touching established ones or the ticket keys. With `ktls` on,
+//! connections move to kernel TLS once the handshake is done. With
+//! `client_auth`, clients must (or may) present a certificate from the
+//! configured CA, which then identifies them to ACLs, routes and backends.
+//!
+//! Pools with a `tls` section reach their backends over TLS the same way,
+//! with a `ClientConnection` on the backend side of the pumps. Backend
+//! sessions go back to the connection cache together with their fds.
+
+pub mod client_auth;
+pub mod config;
+pub mod ktls;
+pub mod session;
+pub mod upstream;
+
+use serde::Deserialize;
+
+use crate::util::hostname::normalize;
+
+pub use config::{reload, server_config};
+pub use session::{RecvOutcome, TlsSession};
+pub use upstream::UpstreamTls;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+pub enum TlsVersion {
+    #[default]
+    #[serde(rename = "1.2")]
+    Tls12,
+    #[serde(rename = "1.3")]
+    Tls13,
+}
+
+/// What to do with a request whose `Host` names a different host than the
+/// SNI of its connection.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SniHostMismatch {
+    #[default]
+    Allow,
+    /// Answer 421 Misdirected Request
+    Reject,
+}
+
+impl SniHostMismatch {
+    /// Whether a request for `host` on a connection for `sni` is refused.
+    /// Requests without either name are let through.
+    pub fn rejects(self, host: Option<&[u8]>, sni: Option<&str>) -> bool {
+        let (Self::Reject, Some(host), Some(sni)) = (self, host, sni) else {
+            return false;
+        };
+        normalize(host).is_none_or(|host| !host.eq_ignore_ascii_case(sni))
+    }
+}
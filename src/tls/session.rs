@@ -0,0 +1,343 @@
+use std::io::{self, Read, Write};
+use std::os::fd::RawFd;
+use std::sync::Arc;
+
+use rustls::pki_types::ServerName;
+use rustls::{ClientConfig, ClientConnection, Connection, ServerConfig, ServerConnection};
+
+use super::ktls;
+
+/// Room for the largest record a peer may send: header, 2^14 bytes of
+/// plaintext and up to 2048 bytes of expansion.
+const RX_CAPACITY: usize = 5 + 16384 + 2048;
+
+/// What a completed recv amounted to once decrypted.
+#[derive(Debug)]
+pub enum RecvOutcome {
+    /// This many plaintext bytes were written to the destination
+    Plaintext(usize),
+    /// Only handshake data or a partial record arrived; recv again
+    NeedMore,
+    /// The peer closed the connection
+    Closed,
+    Failed(io::Error),
+}
+
+/// TLS state of one connection: a client's to the listener, or one of ours
+/// to a backend.
+///
+/// The kernel writes ciphertext into `rx` and reads it from `tx`, so neither
+/// buffer may move while an operation on it is in flight: records produced
+/// during a send are staged in `tx_next` instead.
+pub struct TlsSession {
+    pub conn: Connection,
+    rx: Vec<u8>,
+    /// Ciphertext received but not yet taken by rustls
+    rx_len: usize,
+    tx: Vec<u8>,
+    tx_sent: usize,
+    tx_next: Vec<u8>,
+    /// A recv into `rx` is outstanding
+    pub recv_in_flight: bool,
+    /// A send of `tx` is outstanding
+    pub send_in_flight: bool,
+    /// Plaintext bytes of the pump feeding this session covered by the
+    /// queued ciphertext, reported as sent once it is all on the wire
+    owed_plaintext: Option<usize>,
+    /// rustls holds decrypted bytes that did not fit the last destination
+    plaintext_pending: bool,
+    /// The outstanding "recv" is a wakeup to hand out `plaintext_pending` bytes
+    wakeup_in_flight: bool,
+    /// `rx` holds ciphertext that arrived before the session took over the
+    /// connection and no completion has processed yet
+    ciphertext_pending: bool,
+    handshake_done: bool,
+    /// The peer sent close_notify
+    peer_closed: bool,
+    /// Kernel TLS was tried and turned down for this connection
+    offload_declined: bool,
+}
+
+impl TlsSession {
+    pub fn new(config: Arc<ServerConfig>) -> Result<Self, rustls::Error> {
+        Ok(Self::with_connection(ServerConnection::new(config)?.into()))
+    }
+
+    /// Client side of a backend connection; the ClientHello is queued right away.
+    pub fn client(config: Arc<ClientConfig>, name: ServerName<'static>) -> Result<Self, rustls::Error> {
+        Ok(Self::with_connection(ClientConnection::new(config, name)?.into()))
+    }
+
+    fn with_connection(conn: Connection) -> Self {
+        Self {
+            conn,
+            rx: vec![0; RX_CAPACITY],
+            rx_len: 0,
+            tx: Vec::new(),
+            tx_sent: 0,
+            tx_next: Vec::new(),
+            recv_in_flight: false,
+            send_in_flight: false,
+            owed_plaintext: None,
+            plaintext_pending: false,
+            wakeup_in_flight: false,
+            ciphertext_pending: false,
+            handshake_done: false,
+            peer_closed: false,
+            offload_declined: false,
+        }
+    }
+
+    /// SNI name the client asked for, on listener connections.
+    pub fn server_name(&self) -> Option<&str> {
+        match &self.conn {
+            Connection::Server(conn) => conn.server_name(),
+            Connection::Client(_) => None,
+        }
+    }
+
+    /// Where the next recv should write, or `None` when decrypted data is
+    /// already waiting and the recv should be replaced by a wakeup.
+    pub fn recv_target(&mut self) -> Option<(*mut u8, usize)> {
+        if self.plaintext_pending || self.ciphertext_pending {
+            self.wakeup_in_flight = true;
+            return None;
+        }
+        self.recv_in_flight = true;
+        let free = &mut self.rx[self.rx_len..];
+        Some((free.as_mut_ptr(), free.len()))
+    }
+
+    /// Take ciphertext read off the connection before the session was in
+    /// charge of it, like what followed a PROXY protocol header. The next
+    /// recv hands it to rustls without waiting for the peer. Returns false if
+    /// it does not fit.
+    pub fn prefill(&mut self, ciphertext: &[u8]) -> bool {
+        let Some(free) = self.rx.get_mut(self.rx_len..self.rx_len + ciphertext.len()) else {
+            return false;
+        };
+        free.copy_from_slice(ciphertext);
+        self.rx_len += ciphertext.len();
+        self.ciphertext_pending = true;
+        true
+    }
+
+    /// Decrypt the result of a completed recv into `dst`.
+    pub fn complete_recv(&mut self, res: i32, dst: &mut [u8]) -> RecvOutcome {
+        self.ciphertext_pending = false;
+        if !std::mem::take(&mut self.wakeup_in_flight) {
+            self.recv_in_flight = false;
+            match res {
+                0 => return RecvOutcome::Closed,
+                r if r < 0 => return RecvOutcome::Failed(io::Error::from_raw_os_error(-r)),
+                r => self.rx_len += r as usize,
+            }
+        }
+
+        // rustls only ever sees whole records, so a partial one is always
+        // here in `rx` and never hidden in its own buffers
+        let complete = match complete_records(&self.rx[..self.rx_len]) {
+            0 if self.rx_len == self.rx.len() => self.rx_len, // let rustls reject it
+            n => n,
+        };
+        let mut consumed = 0;
+        while consumed < complete {
+            // an empty slice would read as EOF, hence the loop condition
+            match self.conn.read_tls(&mut &self.rx[consumed..complete]) {
+                Ok(0) | Err(_) => break, // rustls is full until plaintext is read
+                Ok(n) => consumed += n,
+            }
+            if let Err(e) = self.conn.process_new_packets() {
+                return RecvOutcome::Failed(io::Error::new(io::ErrorKind::InvalidData, e));
+            }
+        }
+        self.rx.copy_within(consumed..self.rx_len, 0);
+        self.rx_len -= consumed;
+
+        let state = match self.conn.process_new_packets() {
+            Ok(state) => state,
+            Err(e) => return RecvOutcome::Failed(io::Error::new(io::ErrorKind::InvalidData, e)),
+        };
+        let available = state.plaintext_bytes_to_read();
+        if available > 0 && !dst.is_empty() {
+            let n = self.conn.reader().read(dst).unwrap_or(0);
+            self.plaintext_pending = available > n;
+            return RecvOutcome::Plaintext(n);
+        }
+        self.plaintext_pending = available > 0;
+        self.peer_closed = state.peer_has_closed();
+        if self.peer_closed || dst.is_empty() {
+            RecvOutcome::Closed
+        } else {
+            RecvOutcome::NeedMore
+        }
+    }
+
+    /// True exactly once, when the handshake has just finished.
+    pub fn take_handshake_done(&mut self) -> bool {
+        if self.handshake_done || self.conn.is_handshaking() {
+            return false;
+        }
+        self.handshake_done = true;
+        true
+    }
+
+    /// Encrypt as much of `plaintext` as rustls accepts and queue it for
+    /// the peer. Returns how many bytes were taken. Before the handshake is
+    /// done rustls holds on to it and sends it once the handshake completes.
+    pub fn encrypt(&mut self, plaintext: &[u8]) -> usize {
+        let taken = self.conn.writer().write(plaintext).unwrap_or(0);
+        self.owed_plaintext = Some(self.owed_plaintext.unwrap_or(0) + taken);
+        self.queue_output();
+        taken
+    }
+
+    /// Queue `bytes` to go out unencrypted ahead of everything else, like a
+    /// PROXY protocol header before the ClientHello. Nothing may have been
+    /// sent yet.
+    pub fn send_first(&mut self, bytes: &[u8]) {
+        debug_assert!(!self.send_in_flight && self.tx_sent == 0);
+        self.tx.splice(0..0, bytes.iter().copied());
+    }
+
+    /// Move records rustls wants to send (handshake, tickets, alerts) into
+    /// the outgoing buffers.
+    pub fn queue_output(&mut self) {
+        let out = if self.send_in_flight {
+            &mut self.tx_next
+        } else {
+            &mut self.tx
+        };
+        while self.conn.wants_write() {
+            if self.conn.write_tls(out).is_err() {
+                break;
+            }
+        }
+    }
+
+    /// Ciphertext to send next, marking the send in flight.
+    pub fn send_target(&mut self) -> Option<(*const u8, usize)> {
+        if self.send_in_flight {
+            return None;
+        }
+        if self.tx_sent == self.tx.len() {
+            self.tx.clear();
+            self.tx_sent = 0;
+            std::mem::swap(&mut self.tx, &mut self.tx_next);
+        }
+        if self.tx.is_empty() {
+            return None;
+        }
+        self.send_in_flight = true;
+        let pending = &self.tx[self.tx_sent..];
+        Some((pending.as_ptr(), pending.len()))
+    }
+
+    /// Account for a completed send of `tx`. Returns the plaintext byte count
+    /// to report to the pump once everything queued for it has been sent.
+    pub fn complete_send(&mut self, sent: usize) -> Option<usize> {
+        self.send_in_flight = false;
+        self.tx_sent += sent;
+        // plaintext written during the handshake is only encrypted after it
+        let drained = self.tx_sent == self.tx.len()
+            && self.tx_next.is_empty()
+            && !self.conn.is_handshaking();
+        if drained {
+            self.owed_plaintext.take()
+        } else {
+            None
+        }
+    }
+
+    /// Whether the connection can move to kernel TLS: the handshake is over,
+    /// its cipher can be offloaded, and nothing is buffered or in flight.
+    pub fn ready_for_offload(&self) -> bool {
+        self.handshake_done
+            && !self.offload_declined
+            && !self.io_in_flight()
+            && !self.wakeup_in_flight
+            && !self.plaintext_pending
+            && self.rx_len == 0
+            && self.tx_sent == self.tx.len()
+            && self.tx_next.is_empty()
+            && self.owed_plaintext.is_none()
+            && !self.conn.wants_write()
+            && self
+                .conn
+                .negotiated_cipher_suite()
+                .is_some_and(|suite| ktls::offloadable(suite.suite()))
+    }
+
+    /// Keep this connection in userspace.
+    pub fn decline_offload(&mut self) {
+        self.offload_declined = true;
+    }
+
+    /// Install the session's traffic keys on `fd`, which must have the TLS
+    /// ULP attached. The session is used up even if installing fails.
+    pub fn offload(self, fd: RawFd) -> io::Result<()> {
+        let Some(version) = self.conn.protocol_version() else {
+            return Err(io::Error::other("no protocol version negotiated"));
+        };
+        let secrets = self
+            .conn
+            .dangerous_extract_secrets()
+            .map_err(io::Error::other)?;
+        ktls::install(fd, version, secrets)
+    }
+
+    /// Whether the peer sent close_notify; the session can carry nothing
+    /// more from it.
+    pub fn peer_has_closed(&self) -> bool {
+        self.peer_closed
+    }
+
+    /// Whether the kernel may still write to or read from the session's buffers.
+    pub fn io_in_flight(&self) -> bool {
+        self.recv_in_flight || self.send_in_flight
+    }
+
+    /// Best-effort close_notify (or pending alert) before the fd is closed.
+    pub fn close(&mut self, fd: RawFd) {
+        if self.send_in_flight {
+            // the record stream is mid-send; anything written now would interleave
+            return;
+        }
+        self.conn.send_close_notify();
+        let mut out = Vec::new();
+        out.extend_from_slice(&self.tx[self.tx_sent..]);
+        out.extend_from_slice(&self.tx_next);
+        while self.conn.wants_write() {
+            if self.conn.write_tls(&mut out).is_err() {
+                break;
+            }
+        }
+        if !out.is_empty() {
+            unsafe {
+                libc::send(
+                    fd,
+                    out.as_ptr().cast(),
+                    out.len(),
+                    libc::MSG_DONTWAIT | libc::MSG_NOSIGNAL,
+                )
+            };
+        }
+    }
+}
+
+/// Length of the complete records at the start of `buf`. Data that does not
+/// look like TLS counts as complete, so rustls gets to reject it right away.
+fn complete_records(buf: &[u8]) -> usize {
+    let mut end = 0;
+    while let Some(header) = buf.get(end..end + 5) {
+        if !(20..=24).contains(&header[0]) {
+            return buf.len();
+        }
+        let record = 5 + u16::from_be_bytes([header[3], header[4]]) as usize;
+        if buf.len() - end < record {
+            break;
+        }
+        end += record;
+    }
+    end
+}
@@ -0,0 +1,77 @@
+use std::io;
+use std::sync::Arc;
+
+use rustls::crypto::ring;
+use rustls::pki_types::ServerName;
+use rustls::{ClientConfig, RootCertStore};
+
+use crate::config::UpstreamTlsSection;
+use crate::util::address::Address;
+
+use super::TlsSession;
+use super::config::{load_certificates, load_private_key, tls_error};
+
+/// TLS toward the backends of one pool.
+///
+/// The client configuration is shared by every worker, and with it the
+/// in-memory session cache, so a fresh connection to a backend any worker
+/// has talked to before resumes that session.
+#[derive(Debug)]
+pub struct UpstreamTls {
+    config: Arc<ClientConfig>,
+    /// Overrides the backend's IP address as SNI and certificate name;
+    /// required for Unix backends, which have none
+    server_name: Option<ServerName<'static>>,
+}
+
+impl UpstreamTls {
+    pub fn new(section: &UpstreamTlsSection) -> io::Result<Self> {
+        let mut roots = RootCertStore::empty();
+        let (added, _) = roots.add_parsable_certificates(load_certificates(&section.ca)?);
+        if added == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{}: no usable CA certificates", section.ca.display()),
+            ));
+        }
+
+        let builder = ClientConfig::builder_with_provider(Arc::new(ring::default_provider()))
+            .with_safe_default_protocol_versions()
+            .map_err(tls_error)?
+            .with_root_certificates(roots);
+        let mut config = match (&section.cert, &section.key) {
+            (Some(cert), Some(key)) => builder
+                .with_client_auth_cert(load_certificates(cert)?, load_private_key(key)?)
+                .map_err(|e| {
+                    io::Error::new(io::ErrorKind::InvalidData, format!("{}: {e}", key.display()))
+                })?,
+            _ => builder.with_no_client_auth(),
+        };
+        config.alpn_protocols = section.alpn.iter().map(|p| p.as_bytes().to_vec()).collect();
+
+        let server_name = section
+            .server_name
+            .as_deref()
+            .map(|name| ServerName::try_from(name.to_string()))
+            .transpose()
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        Ok(Self {
+            config: Arc::new(config),
+            server_name,
+        })
+    }
+
+    /// Client session for a new connection to `backend`.
+    pub fn session(&self, backend: Address) -> Result<TlsSession, rustls::Error> {
+        let name = match (&self.server_name, backend) {
+            (Some(name), _) => name.clone(),
+            (None, Address::Inet(addr)) => ServerName::IpAddress(addr.ip().into()),
+            (None, Address::Unix(_)) => {
+                return Err(rustls::Error::General(
+                    "tls to a unix backend needs server_name".into(),
+                ));
+            }
+        };
+        TlsSession::client(self.config.clone(), name)
+    }
+}
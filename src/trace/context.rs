@@ -0,0 +1,115 @@
+use std::fmt::Write;
+
+use crate::util::random;
+
+/// Bit of the trace flags saying the caller records this trace.
+pub const FLAG_SAMPLED: u8 = 0x01;
+
+/// W3C trace context of the span Flax opens for one request.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TraceContext {
+    pub trace_id: [u8; 16],
+    /// Flax's own span, sent to the backend as the parent
+    pub span_id: [u8; 8],
+    /// Span of the caller, when the request carried a valid `traceparent`
+    pub parent_id: Option<[u8; 8]>,
+    pub flags: u8,
+}
+
+impl TraceContext {
+    /// Start a new, sampled trace.
+    pub fn root() -> Self {
+        let mut trace_id = [0u8; 16];
+        while trace_id == [0; 16] {
+            random::fill(&mut trace_id);
+        }
+        Self {
+            trace_id,
+            span_id: new_span_id(),
+            parent_id: None,
+            flags: FLAG_SAMPLED,
+        }
+    }
+
+    /// Open a span below the one described by a `traceparent` header value.
+    ///
+    /// Returns `None` when the value does not follow the W3C format, in which
+    /// case the caller should start a new trace and discard `tracestate`.
+    pub fn child_of(traceparent: &[u8]) -> Option<Self> {
+        let value = traceparent;
+        if value.len() < 55 {
+            return None;
+        }
+        let [version] = parse_hex::<1>(&value[..2])?;
+        // version ff is forbidden; later versions may append fields after a dash
+        if version == 0xff
+            || (version == 0 && value.len() != 55)
+            || (value.len() > 55 && value[55] != b'-')
+        {
+            return None;
+        }
+        if value[2] != b'-' || value[35] != b'-' || value[52] != b'-' {
+            return None;
+        }
+        let trace_id = parse_hex::<16>(&value[3..35])?;
+        let parent_id = parse_hex::<8>(&value[36..52])?;
+        let [flags] = parse_hex::<1>(&value[53..55])?;
+        if trace_id == [0; 16] || parent_id == [0; 8] {
+            return None;
+        }
+        Some(Self {
+            trace_id,
+            span_id: new_span_id(),
+            parent_id: Some(parent_id),
+            flags,
+        })
+    }
+
+    pub fn sampled(&self) -> bool {
+        self.flags & FLAG_SAMPLED != 0
+    }
+
+    /// Header value announcing Flax's span as the parent of the backend's.
+    pub fn traceparent(&self) -> String {
+        format!(
+            "00-{}-{}-{:02x}",
+            hex(&self.trace_id),
+            hex(&self.span_id),
+            self.flags
+        )
+    }
+}
+
+fn new_span_id() -> [u8; 8] {
+    let mut id = [0u8; 8];
+    while id == [0; 8] {
+        random::fill(&mut id);
+    }
+    id
+}
+
+/// Lowercase hex encoding, as used by trace context and OTLP/JSON.
+pub fn hex(bytes: &[u8]) -> String {
+    let mut out = String::with_capacity(bytes.len() * 2);
+    for b in bytes {
+        let _ = write!(out, "{b:02x}");
+    }
+    out
+}
+
+/// Decode exactly `N` bytes of lowercase hex; uppercase is invalid in trace context.
+fn parse_hex<const N: usize>(text: &[u8]) -> Option<[u8; N]> {
+    if text.len() != N * 2 {
+        return None;
+    }
+    let digit = |c: u8| match c {
+        b'0'..=b'9' => Some(c - b'0'),
+        b'a'..=b'f' => Some(c - b'a' + 10),
+        _ => None,
+    };
+    let mut out = [0u8; N];
+    for (byte, pair) in out.iter_mut().zip(text.chunks_exact(2)) {
+        *byte = digit(pair[0])? << 4 | digit(pair[1])?;
+    }
+    Some(out)
+}
@@ -0,0 +1,94 @@
+//! Request IDs and distributed tracing
+//!
+//! Every forwarded request gets an `X-Request-Id` (the client's, if it sent a
+//! usable one) and a W3C `traceparent` naming a span Flax opens for the
+//! request, so backend spans hang below Flax's in the caller's trace.
+//! `tracestate` is passed through untouched when the incoming `traceparent`
+//! is valid and dropped otherwise, as the specification requires. Finished
+//! spans can optionally be exported to a local collector as OTLP/JSON.
+
+pub mod context;
+pub mod otlp;
+
+use crate::protocol::{HeaderEdits, find_header};
+
+pub use context::TraceContext;
+
+pub const REQUEST_ID_HEADER: &str = "X-Request-Id";
+/// Longer client-supplied request IDs are replaced rather than forwarded.
+const MAX_REQUEST_ID_LEN: usize = 200;
+
+/// Per-worker tracing behaviour.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TraceOptions {
+    /// Add a request ID to requests that arrive without one
+    pub request_id: bool,
+    /// Rewrite `traceparent` so backends see Flax's span as their parent
+    pub propagate: bool,
+}
+
+impl Default for TraceOptions {
+    fn default() -> Self {
+        Self {
+            request_id: true,
+            propagate: true,
+        }
+    }
+}
+
+/// Identify the request whose head is `head`: its request ID and the trace
+/// context of Flax's span, recording in `edits` the header changes that
+/// carry them to the backend.
+pub fn identify(
+    head: &[u8],
+    options: &TraceOptions,
+    edits: &mut HeaderEdits,
+) -> (Option<String>, Option<TraceContext>) {
+    let request_id = match incoming_request_id(head) {
+        Some(id) => Some(id),
+        None if options.request_id => {
+            if find_header(head, REQUEST_ID_HEADER.as_bytes()).is_some() {
+                edits.remove(REQUEST_ID_HEADER);
+            }
+            let id = new_request_id();
+            edits.add(REQUEST_ID_HEADER, id.clone());
+            Some(id)
+        }
+        None => None,
+    };
+
+    if !options.propagate && !otlp::enabled() {
+        return (request_id, None);
+    }
+    let incoming = find_header(head, b"traceparent").and_then(TraceContext::child_of);
+    let context = incoming.unwrap_or_else(TraceContext::root);
+    if options.propagate {
+        edits.remove("traceparent");
+        if incoming.is_none() {
+            edits.remove("tracestate");
+        }
+        edits.add("traceparent", context.traceparent());
+    }
+    (request_id, Some(context))
+}
+
+/// Request ID for a response Flax generates before the head could be parsed.
+pub fn request_id_for_error(head: &[u8], options: &TraceOptions) -> Option<String> {
+    incoming_request_id(head).or_else(|| options.request_id.then(new_request_id))
+}
+
+/// 128 random bits in hex.
+pub fn new_request_id() -> String {
+    let mut id = [0u8; 16];
+    crate::util::random::fill(&mut id);
+    context::hex(&id)
+}
+
+fn incoming_request_id(head: &[u8]) -> Option<String> {
+    let value = find_header(head, REQUEST_ID_HEADER.as_bytes())?;
+    let usable = !value.is_empty()
+        && value.len() <= MAX_REQUEST_ID_LEN
+        && value.iter().all(|b| b.is_ascii_graphic());
+    // visible ASCII only, so the bytes are valid UTF-8
+    usable.then(|| String::from_utf8_lossy(value).into_owned())
+}
@@ -0,0 +1,262 @@
+use std::io::{self, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::sync::mpsc::{Receiver, RecvTimeoutError, SyncSender, sync_channel};
+use std::sync::{Mutex, OnceLock};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant, UNIX_EPOCH};
+
+use serde_json::{Value, json};
+
+use crate::access_log::RequestLog;
+use crate::metrics::worker_metrics;
+use crate::protocol::peek_response_status;
+use crate::rate_limited;
+
+use super::context::hex;
+
+/// Spans queued for the exporter thread before workers start dropping them.
+const QUEUE_DEPTH: usize = 4096;
+/// Spans per export request.
+const BATCH_SIZE: usize = 512;
+/// Longest a finished span waits before it is exported.
+const EXPORT_INTERVAL: Duration = Duration::from_secs(1);
+/// Connect, write and read timeout towards the collector.
+const EXPORT_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// `SPAN_KIND_SERVER`: Flax's span covers handling an incoming request.
+const SPAN_KIND_SERVER: u8 = 2;
+const STATUS_CODE_ERROR: u8 = 2;
+
+/// Collector address, e.g. `http://127.0.0.1:4318/v1/traces`.
+///
+/// Only plain HTTP is supported; the collector is expected to run locally.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Endpoint {
+    /// `host:port`
+    pub authority: String,
+    pub path: String,
+}
+
+impl Endpoint {
+    pub fn parse(url: &str) -> io::Result<Self> {
+        let invalid = |why: &str| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("otlp endpoint {url:?}: {why}"),
+            )
+        };
+        let rest = url
+            .strip_prefix("http://")
+            .ok_or_else(|| invalid("only http:// is supported"))?;
+        let (authority, path) = match rest.find('/') {
+            Some(slash) => (&rest[..slash], &rest[slash..]),
+            None => (rest, "/v1/traces"),
+        };
+        if authority.is_empty() {
+            return Err(invalid("missing host"));
+        }
+        let authority = if authority
+            .rsplit_once(':')
+            .is_some_and(|(_, port)| !port.is_empty() && port.bytes().all(|b| b.is_ascii_digit()))
+        {
+            authority.to_string()
+        } else {
+            format!("{authority}:80")
+        };
+        Ok(Self {
+            authority,
+            path: path.to_string(),
+        })
+    }
+}
+
+struct Exporter {
+    tx: SyncSender<Option<Value>>,
+    thread: Mutex<Option<JoinHandle<()>>>,
+}
+
+static EXPORTER: OnceLock<Exporter> = OnceLock::new();
+
+/// Start the exporter thread sending spans to `endpoint`.
+pub fn start(endpoint: Endpoint, service_name: &str) -> io::Result<()> {
+    let (tx, rx) = sync_channel(QUEUE_DEPTH);
+    let service_name = service_name.to_string();
+    let thread = thread::Builder::new()
+        .name("flax-otlp".into())
+        .spawn(move || run_exporter(rx, endpoint, service_name))?;
+    EXPORTER
+        .set(Exporter {
+            tx,
+            thread: Mutex::new(Some(thread)),
+        })
+        .map_err(|_| io::Error::other("otlp exporter already started"))
+}
+
+/// Export the spans still queued and stop the exporter thread.
+pub fn finish() {
+    let Some(exporter) = EXPORTER.get() else {
+        return;
+    };
+    let Some(thread) = exporter.thread.lock().unwrap().take() else {
+        return;
+    };
+    if exporter.tx.send(None).is_ok() {
+        let _ = thread.join();
+    }
+}
+
+/// Whether finished requests should be turned into spans.
+#[inline]
+pub fn enabled() -> bool {
+    EXPORTER.get().is_some()
+}
+
+/// Queue the span of a finished request. Never blocks.
+pub fn export(log: &RequestLog) {
+    let Some(exporter) = EXPORTER.get() else {
+        return;
+    };
+    let Some(context) = log.trace.filter(|c| c.sampled()) else {
+        return;
+    };
+
+    let start = log
+        .timestamp
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos();
+    let end = start + log.started.elapsed().as_nanos();
+
+    let mut attributes = vec![
+        string_attribute("http.request.method", &log.method),
+        string_attribute("url.path", &log.path),
+    ];
+    if let Some(host) = &log.host {
+        attributes.push(string_attribute("server.address", host));
+    }
+    if let Some(client) = log.client {
+        attributes.push(string_attribute("client.address", &client.ip().to_string()));
+    }
+    if let Some(upstream) = log.upstream {
+        attributes.push(string_attribute("flax.upstream", &upstream.to_string()));
+    }
+    if let Some(id) = &log.request_id {
+        attributes.push(string_attribute("flax.request_id", id));
+    }
+    if let Some(status) = log.status {
+        attributes.push(json!({
+            "key": "http.response.status_code",
+            "value": { "intValue": status.to_string() },
+        }));
+    }
+
+    let mut span = json!({
+        "traceId": hex(&context.trace_id),
+        "spanId": hex(&context.span_id),
+        "name": log.method,
+        "kind": SPAN_KIND_SERVER,
+        "startTimeUnixNano": start.to_string(),
+        "endTimeUnixNano": end.to_string(),
+        "attributes": attributes,
+    });
+    if let Some(parent) = context.parent_id {
+        span["parentSpanId"] = hex(&parent).into();
+    }
+    if log.status.is_none_or(|s| s >= 500) {
+        span["status"] = json!({ "code": STATUS_CODE_ERROR });
+    }
+
+    if exporter.tx.try_send(Some(span)).is_err() {
+        // exporter can't keep up (or is gone) - drop rather than stall the ring
+        worker_metrics().trace_spans_dropped.inc();
+    }
+}
+
+fn string_attribute(key: &str, value: &str) -> Value {
+    json!({ "key": key, "value": { "stringValue": value } })
+}
+
+fn run_exporter(rx: Receiver<Option<Value>>, endpoint: Endpoint, service_name: String) {
+    let mut batch = Vec::with_capacity(BATCH_SIZE);
+    let mut deadline = Instant::now() + EXPORT_INTERVAL;
+    loop {
+        let stop = match rx.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+            Ok(Some(span)) => {
+                batch.push(span);
+                false
+            }
+            Ok(None) | Err(RecvTimeoutError::Disconnected) => true,
+            Err(RecvTimeoutError::Timeout) => false,
+        };
+
+        if stop || batch.len() >= BATCH_SIZE || Instant::now() >= deadline {
+            if !batch.is_empty() {
+                let spans = std::mem::replace(&mut batch, Vec::with_capacity(BATCH_SIZE));
+                let count = spans.len();
+                if let Err(e) = post(&endpoint, &service_name, spans) {
+                    rate_limited!(
+                        warn,
+                        endpoint = %endpoint.authority,
+                        spans = count,
+                        "otlp export failed: {e}"
+                    );
+                }
+            }
+            deadline = Instant::now() + EXPORT_INTERVAL;
+        }
+        if stop {
+            return;
+        }
+    }
+}
+
+fn post(endpoint: &Endpoint, service_name: &str, spans: Vec<Value>) -> io::Result<()> {
+    let body = json!({
+        "resourceSpans": [{
+            "resource": {
+                "attributes": [string_attribute("service.name", service_name)],
+            },
+            "scopeSpans": [{
+                "scope": { "name": "flax", "version": env!("CARGO_PKG_VERSION") },
+                "spans": spans,
+            }],
+        }],
+    })
+    .to_string();
+
+    let addr = endpoint
+        .authority
+        .to_socket_addrs()?
+        .next()
+        .ok_or_else(|| {
+            io::Error::new(io::ErrorKind::NotFound, "collector address did not resolve")
+        })?;
+    let mut stream = TcpStream::connect_timeout(&addr, EXPORT_TIMEOUT)?;
+    stream.set_write_timeout(Some(EXPORT_TIMEOUT))?;
+    stream.set_read_timeout(Some(EXPORT_TIMEOUT))?;
+    write!(
+        stream,
+        "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        endpoint.path,
+        endpoint.authority,
+        body.len()
+    )?;
+    stream.write_all(body.as_bytes())?;
+
+    let mut response = [0u8; 256];
+    let mut filled = 0;
+    while filled < response.len() && !response[..filled].contains(&b'\n') {
+        match stream.read(&mut response[filled..])? {
+            0 => break,
+            n => filled += n,
+        }
+    }
+    match peek_response_status(&response[..filled]) {
+        Some(status) if (200..300).contains(&status) => Ok(()),
+        Some(status) => Err(io::Error::other(format!("collector answered {status}"))),
+        None => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "no HTTP response from collector",
+        )),
+    }
+}
@@ -0,0 +1,262 @@
+//! Addresses of listeners and backends
+//!
+//! `127.0.0.1:8080` or `[::1]:8080` for TCP (and UDP), `unix:/run/app.sock`
+//! for a Unix socket at a path and `unix:@app` for one in the abstract
+//! namespace.
+
+use std::ffi::OsStr;
+use std::fmt;
+use std::mem;
+use std::net::SocketAddr;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+use std::str::FromStr;
+
+use serde::Deserialize;
+use socket2::{Domain, SockAddr};
+
+/// Longest path or abstract name: `sun_path` less the NUL that ends a path
+/// or starts an abstract name
+const MAX_UNIX_NAME: usize = 107;
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Deserialize)]
+#[serde(try_from = "String")]
+pub enum Address {
+    Inet(SocketAddr),
+    Unix(UnixAddress),
+}
+
+/// A Unix socket path or abstract name, held inline so addresses stay `Copy`
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub struct UnixAddress {
+    name: [u8; MAX_UNIX_NAME],
+    len: u8,
+    abstract_namespace: bool,
+}
+
+impl UnixAddress {
+    /// A socket file at `path`; `None` if the path doesn't fit `sun_path`.
+    pub fn pathname(path: &Path) -> Option<Self> {
+        Self::new(path.as_os_str().as_bytes(), false)
+    }
+
+    /// A socket in the abstract namespace, named without the leading NUL.
+    pub fn abstract_name(name: &[u8]) -> Option<Self> {
+        Self::new(name, true)
+    }
+
+    fn new(bytes: &[u8], abstract_namespace: bool) -> Option<Self> {
+        if bytes.is_empty() || bytes.len() > MAX_UNIX_NAME {
+            return None;
+        }
+        // NUL ends a path early, but may be part of an abstract name
+        if !abstract_namespace && bytes.contains(&0) {
+            return None;
+        }
+        let mut name = [0; MAX_UNIX_NAME];
+        name[..bytes.len()].copy_from_slice(bytes);
+        Some(Self {
+            name,
+            len: bytes.len() as u8,
+            abstract_namespace,
+        })
+    }
+
+    fn bytes(&self) -> &[u8] {
+        &self.name[..self.len as usize]
+    }
+
+    /// Socket file of the address; `None` in the abstract namespace.
+    pub fn path(&self) -> Option<&Path> {
+        (!self.abstract_namespace).then(|| Path::new(OsStr::from_bytes(self.bytes())))
+    }
+
+    pub fn sockaddr(&self) -> SockAddr {
+        let offset = usize::from(self.abstract_namespace);
+        // SAFETY: the storage starts zeroed and is large enough for a
+        // sockaddr_un, whose family and length are set to match.
+        let ((), addr) = unsafe {
+            SockAddr::try_init(|storage, len| {
+                let un = &mut *storage.cast::<libc::sockaddr_un>();
+                un.sun_family = libc::AF_UNIX as libc::sa_family_t;
+                for (dst, &src) in un.sun_path[offset..].iter_mut().zip(self.bytes()) {
+                    *dst = src as libc::c_char;
+                }
+                // a path is counted with its terminating NUL; an abstract
+                // name is exactly its bytes after the leading one
+                let path_len = offset + self.bytes().len() + usize::from(!self.abstract_namespace);
+                *len = (mem::offset_of!(libc::sockaddr_un, sun_path) + path_len) as libc::socklen_t;
+                Ok(())
+            })
+        }
+        .expect("sockaddr_un fits in sockaddr_storage");
+        addr
+    }
+}
+
+impl Address {
+    /// Address of a socket as the kernel reports it; `None` for unnamed Unix
+    /// sockets and other families.
+    pub fn from_sockaddr(addr: &SockAddr) -> Option<Self> {
+        if let Some(addr) = addr.as_socket() {
+            return Some(Address::Inet(addr));
+        }
+        if let Some(path) = addr.as_pathname() {
+            return UnixAddress::pathname(path).map(Address::Unix);
+        }
+        UnixAddress::abstract_name(addr.as_abstract_namespace()?).map(Address::Unix)
+    }
+
+    pub fn sockaddr(&self) -> SockAddr {
+        match self {
+            Address::Inet(addr) => SockAddr::from(*addr),
+            Address::Unix(addr) => addr.sockaddr(),
+        }
+    }
+
+    pub fn domain(&self) -> Domain {
+        match self {
+            Address::Inet(addr) => Domain::for_address(*addr),
+            Address::Unix(_) => Domain::UNIX,
+        }
+    }
+
+    /// IP address and port; `None` for a Unix socket.
+    pub fn inet(&self) -> Option<SocketAddr> {
+        match self {
+            Address::Inet(addr) => Some(*addr),
+            Address::Unix(_) => None,
+        }
+    }
+}
+
+impl From<SocketAddr> for Address {
+    fn from(addr: SocketAddr) -> Self {
+        Address::Inet(addr)
+    }
+}
+
+impl FromStr for Address {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        let Some(unix) = s.strip_prefix("unix:") else {
+            return s
+                .parse()
+                .map(Address::Inet)
+                .map_err(|_| format!("{s:?} is not an address, or unix: and a socket path"));
+        };
+        let addr = match unix.strip_prefix('@') {
+            Some(name) => UnixAddress::abstract_name(name.as_bytes()),
+            None => UnixAddress::pathname(Path::new(unix)),
+        };
+        addr.map(Address::Unix)
+            .ok_or_else(|| format!("{s:?}: a socket path must be 1 to {MAX_UNIX_NAME} bytes long"))
+    }
+}
+
+impl TryFrom<String> for Address {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, String> {
+        s.parse()
+    }
+}
+
+impl fmt::Display for Address {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Address::Inet(addr) => write!(f, "{addr}"),
+            Address::Unix(addr) => write!(f, "{addr}"),
+        }
+    }
+}
+
+impl fmt::Display for UnixAddress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let at = if self.abstract_namespace { "@" } else { "" };
+        write!(f, "unix:{at}{}", self.bytes().escape_ascii())
+    }
+}
+
+impl fmt::Debug for Address {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
+
+impl fmt::Debug for UnixAddress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse() {
+        let longest = format!("unix:/{}", "a".repeat(MAX_UNIX_NAME - 1));
+        let too_long = format!("unix:/{}", "a".repeat(MAX_UNIX_NAME));
+        let longest_abstract = format!("unix:@{}", "a".repeat(MAX_UNIX_NAME));
+        let too_long_abstract = format!("unix:@{}", "a".repeat(MAX_UNIX_NAME + 1));
+        let cases = [
+            ("127.0.0.1:8080", Some("127.0.0.1:8080")),
+            ("[::1]:8080", Some("[::1]:8080")),
+            ("unix:/run/app.sock", Some("unix:/run/app.sock")),
+            ("unix:app.sock", Some("unix:app.sock")),
+            ("unix:@app", Some("unix:@app")),
+            ("unix:@a\0b", Some("unix:@a\\x00b")),
+            ("unix:/a\0b", None),
+            (longest.as_str(), Some(longest.as_str())),
+            (too_long.as_str(), None),
+            (longest_abstract.as_str(), Some(longest_abstract.as_str())),
+            (too_long_abstract.as_str(), None),
+            ("unix:", None),
+            ("unix:@", None),
+            ("127.0.0.1", None),
+            ("/run/app.sock", None),
+        ];
+        for (input, expected) in cases {
+            let parsed = input.parse::<Address>().map(|a| a.to_string());
+            assert_eq!(parsed.ok().as_deref(), expected, "{input:?}");
+        }
+    }
+
+    #[test]
+    fn sockaddr_round_trip() {
+        let base = mem::offset_of!(libc::sockaddr_un, sun_path);
+        let longest = format!("unix:/{}", "a".repeat(MAX_UNIX_NAME - 1));
+        let longest_abstract = format!("unix:@{}", "a".repeat(MAX_UNIX_NAME));
+        // sockaddr length: the path and its NUL, or the NUL and the name
+        let cases = [
+            ("127.0.0.1:8080", None),
+            ("unix:/run/app.sock", Some(base + 14)),
+            ("unix:@app", Some(base + 4)),
+            ("unix:@a\0b", Some(base + 4)),
+            (longest.as_str(), Some(mem::size_of::<libc::sockaddr_un>())),
+            (
+                longest_abstract.as_str(),
+                Some(mem::size_of::<libc::sockaddr_un>()),
+            ),
+        ];
+        for (input, len) in cases {
+            let addr: Address = input.parse().unwrap();
+            let sockaddr = addr.sockaddr();
+            if let Some(len) = len {
+                assert_eq!(sockaddr.len() as usize, len, "{input:?}");
+                assert_eq!(addr.domain(), Domain::UNIX, "{input:?}");
+            }
+            assert_eq!(Address::from_sockaddr(&sockaddr), Some(addr), "{input:?}");
+        }
+    }
+
+    #[test]
+    fn path() {
+        let addr = UnixAddress::pathname(Path::new("/run/app.sock")).unwrap();
+        assert_eq!(addr.path(), Some(Path::new("/run/app.sock")));
+        assert_eq!(UnixAddress::abstract_name(b"app").unwrap().path(), None);
+        assert!(UnixAddress::abstract_name(b"").is_none());
+    }
+}
@@ -1,5 +1,7 @@
 use std::{io, os::fd::RawFd};
 
+use crate::rate_limited;
+
 pub fn close_fd_quiet(fd: RawFd) {
     // After this call, consider fd dead in all code paths.
     let ret = unsafe { libc::close(fd) };
@@ -7,14 +9,25 @@ pub fn close_fd_quiet(fd: RawFd) {
         let err = io::Error::last_os_error();
         match err.raw_os_error() {
             Some(libc::EBADF) => {
-                eprintln!("close({fd}) -> EBADF (double close / invalid fd)");
+                rate_limited!(error, fd, "close -> EBADF (double close / invalid fd)");
             }
             Some(libc::EINTR) => {
-                eprintln!("close({fd}) interrupted by signal (EINTR); not retrying");
+                rate_limited!(warn, fd, "close interrupted by signal (EINTR); not retrying");
             }
             _ => {
-                eprintln!("close({fd}) failed: {err}");
+                rate_limited!(warn, fd, "close failed: {err}");
             }
         }
     }
 }
+
+/// Shut a socket down, then close it.
+///
+/// io_uring requests hold their own reference to the file, so closing the fd
+/// alone leaves outstanding recvs armed: a peer that sends more data later
+/// would have it written into a buffer that no longer exists. Shutting the
+/// socket down first makes them complete right away without touching it.
+pub fn shutdown_and_close(fd: RawFd) {
+    unsafe { libc::shutdown(fd, libc::SHUT_RDWR) };
+    close_fd_quiet(fd);
+}
@@ -0,0 +1,149 @@
+//! Waiting for a file to change, with inotify
+//!
+//! The directory is watched rather than the file, so a file replaced by
+//! renaming another over it (as editors and deployment tools do) is noticed
+//! as well as one written in place. Only finished writes count: a file still
+//! open for writing is not read half-written. Events lost to a full queue,
+//! or the directory going away, count as a change too, since the file may
+//! have changed unseen.
+
+use std::ffi::{CString, OsString};
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+use std::time::Duration;
+use std::{io, mem};
+
+const EVENTS: u32 = libc::IN_CLOSE_WRITE | libc::IN_MOVED_TO;
+
+pub struct FileWatch {
+    fd: OwnedFd,
+    /// Name of the file within the watched directory
+    name: OsString,
+}
+
+impl FileWatch {
+    pub fn new(path: &Path) -> io::Result<Self> {
+        let name = path
+            .file_name()
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("{} does not name a file", path.display()),
+                )
+            })?
+            .to_os_string();
+        let dir = match path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        let dir = CString::new(dir.as_os_str().as_bytes())
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        let fd = unsafe { libc::inotify_init1(libc::IN_CLOEXEC) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // SAFETY: inotify_init1 just returned this fd and nothing else owns it.
+        let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+        if unsafe { libc::inotify_add_watch(fd.as_raw_fd(), dir.as_ptr(), EVENTS) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Self { fd, name })
+    }
+
+    /// Wait up to `timeout`, or for good when `None`; true if the file was
+    /// written or replaced meanwhile.
+    pub fn wait(&self, timeout: Option<Duration>) -> io::Result<bool> {
+        let timeout_ms = timeout.map_or(-1, |t| t.as_millis().min(i32::MAX as u128) as i32);
+        let mut pollfd = libc::pollfd {
+            fd: self.fd.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        match unsafe { libc::poll(&mut pollfd, 1, timeout_ms) } {
+            0 => return Ok(false),
+            n if n < 0 => {
+                let e = io::Error::last_os_error();
+                return match e.kind() {
+                    io::ErrorKind::Interrupted => Ok(false),
+                    _ => Err(e),
+                };
+            }
+            _ => {}
+        }
+        let mut buf = [0u8; 4096];
+        let n = unsafe { libc::read(self.fd.as_raw_fd(), buf.as_mut_ptr().cast(), buf.len()) };
+        if n < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let mut changed = false;
+        let mut events = &buf[..n as usize];
+        let header = mem::size_of::<libc::inotify_event>();
+        while events.len() >= header {
+            // SAFETY: the kernel writes whole events, each a header followed
+            // by `len` bytes of NUL-padded name; read_unaligned copes with
+            // the byte buffer's alignment.
+            let event: libc::inotify_event = unsafe {
+                events
+                    .as_ptr()
+                    .cast::<libc::inotify_event>()
+                    .read_unaligned()
+            };
+            let end = (header + event.len as usize).min(events.len());
+            let name = &events[header..end];
+            let name = &name[..name.iter().position(|&b| b == 0).unwrap_or(name.len())];
+            changed |= name == self.name.as_bytes()
+                || event.mask & (libc::IN_Q_OVERFLOW | libc::IN_IGNORED) != 0;
+            events = &events[end..];
+        }
+        Ok(changed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, process};
+
+    use super::*;
+
+    fn scratch(name: &str) -> std::path::PathBuf {
+        let dir = std::env::temp_dir().join(format!("flax-watch-{name}-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn other_files_ignored() {
+        let dir = scratch("other");
+        let watch = FileWatch::new(&dir.join("backends.json")).unwrap();
+        fs::write(dir.join("unrelated.json"), "{}").unwrap();
+        assert!(!watch.wait(Some(Duration::from_millis(100))).unwrap());
+        fs::write(dir.join("backends.json"), "{}").unwrap();
+        assert!(watch.wait(Some(Duration::from_millis(100))).unwrap());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn overflow_counts_as_changed() {
+        let dir = scratch("overflow");
+        let watch = FileWatch::new(&dir.join("backends.json")).unwrap();
+        let limit: usize = fs::read_to_string("/proc/sys/fs/inotify/max_queued_events")
+            .map_or(16384, |s| s.trim().parse().unwrap());
+        // alternating names, as repeats of the last event are merged
+        for i in 0..=limit {
+            fs::write(dir.join(["a", "b"][i % 2]), "").unwrap();
+        }
+        // the overflow is reported after the queued events, some reads in
+        let changed = (0..limit).any(|_| watch.wait(Some(Duration::ZERO)).unwrap());
+        assert!(changed);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn removed_directory_counts_as_changed() {
+        let dir = scratch("removed");
+        let watch = FileWatch::new(&dir.join("backends.json")).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+        assert!(watch.wait(Some(Duration::from_millis(100))).unwrap());
+    }
+}
@@ -0,0 +1,80 @@
+//! Host name matching
+//!
+//! Certificates and virtual hosts are configured by name: either an exact
+//! name or a wildcard `*.example.com`, which covers exactly one extra label
+//! (`a.example.com`, not `example.com` or `a.b.example.com`), as wildcard
+//! certificates do. Exact names win over wildcards.
+
+use std::collections::HashMap;
+
+#[derive(Debug, Clone)]
+pub struct HostMap<T> {
+    exact: HashMap<String, T>,
+    /// Keyed by the part after `*.`
+    wildcard: HashMap<String, T>,
+}
+
+impl<T> Default for HostMap<T> {
+    fn default() -> Self {
+        Self {
+            exact: HashMap::new(),
+            wildcard: HashMap::new(),
+        }
+    }
+}
+
+impl<T> HostMap<T> {
+    /// Add `pattern`, which must pass [`valid_pattern`]. Returns false if it
+    /// was already present.
+    pub fn insert(&mut self, pattern: &str, value: T) -> bool {
+        let pattern = pattern.to_ascii_lowercase();
+        match pattern.strip_prefix("*.") {
+            Some(suffix) => self.wildcard.insert(suffix.to_string(), value).is_none(),
+            None => self.exact.insert(pattern, value).is_none(),
+        }
+    }
+
+    /// Entry for `name`, which must already be [`normalize`]d.
+    pub fn get(&self, name: &str) -> Option<&T> {
+        if let Some(value) = self.exact.get(name) {
+            return Some(value);
+        }
+        let (label, parent) = name.split_once('.')?;
+        if label.is_empty() {
+            return None;
+        }
+        self.wildcard.get(parent)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.exact.is_empty() && self.wildcard.is_empty()
+    }
+}
+
+/// Whether `pattern` is a host name, optionally with a leading `*.` label.
+pub fn valid_pattern(pattern: &str) -> bool {
+    let name = pattern.strip_prefix("*.").unwrap_or(pattern);
+    !name.is_empty()
+        && name.len() <= 253
+        && name.split('.').all(|label| {
+            !label.is_empty()
+                && label.len() <= 63
+                && label.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-')
+        })
+}
+
+/// Lower-case host name of a `Host` header value or SNI name, without port,
+/// IPv6 brackets or trailing dot.
+pub fn normalize(host: &[u8]) -> Option<String> {
+    let host = std::str::from_utf8(host).ok()?.trim();
+    let name = if let Some(rest) = host.strip_prefix('[') {
+        &rest[..rest.find(']')?]
+    } else {
+        match host.rsplit_once(':') {
+            Some((name, port)) if port.bytes().all(|b| b.is_ascii_digit()) => name,
+            _ => host,
+        }
+    };
+    let name = name.strip_suffix('.').unwrap_or(name);
+    (!name.is_empty()).then(|| name.to_ascii_lowercase())
+}
@@ -0,0 +1,132 @@
+//! Diagnostics setup and rate limiting
+//!
+//! Flax logs through `tracing`. `init` installs a stderr subscriber with a
+//! filter taken from `RUST_LOG` or the config file, in text or JSON format.
+//! Events on paths a misbehaving client or backend can trigger repeatedly go
+//! through `rate_limited!`, so an error storm costs a few atomic operations
+//! per event rather than a blocking write to stderr.
+
+use std::io::{self, IsTerminal};
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::Deserialize;
+use tracing_subscriber::EnvFilter;
+
+/// Events let through per call site per window.
+pub const RATE_LIMIT_BURST: u32 = 10;
+/// Length of a rate limiting window.
+pub const RATE_LIMIT_WINDOW_MS: u64 = 1000;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogOutput {
+    /// Human readable, one line per event
+    #[default]
+    Text,
+    /// One JSON object per event, span fields included
+    Json,
+}
+
+/// Install the global subscriber. `RUST_LOG`, when set, overrides `filter`.
+pub fn init(filter: &str, output: LogOutput) -> io::Result<()> {
+    let filter = match std::env::var("RUST_LOG") {
+        Ok(env) if !env.is_empty() => EnvFilter::try_new(env),
+        _ => EnvFilter::try_new(filter),
+    }
+    .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, format!("log filter: {e}")))?;
+
+    let builder = tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_writer(io::stderr)
+        .with_ansi(io::stderr().is_terminal())
+        .with_thread_names(true);
+    let installed = match output {
+        LogOutput::Text => builder.try_init(),
+        LogOutput::Json => builder.json().with_current_span(true).try_init(),
+    };
+    installed.map_err(|e| io::Error::other(format!("log subscriber: {e}")))
+}
+
+/// Per-call-site token bucket used by `rate_limited!`.
+pub struct RateLimit {
+    window_start_ms: AtomicU64,
+    count: AtomicU32,
+    suppressed: AtomicU64,
+}
+
+impl RateLimit {
+    pub const fn new() -> Self {
+        Self {
+            window_start_ms: AtomicU64::new(0),
+            count: AtomicU32::new(0),
+            suppressed: AtomicU64::new(0),
+        }
+    }
+
+    /// `Some(n)` if the event may be emitted, `n` being how many were
+    /// suppressed since the last one that got through.
+    pub fn check(&self) -> Option<u64> {
+        let now = now_ms();
+        let start = self.window_start_ms.load(Ordering::Relaxed);
+        if now.saturating_sub(start) >= RATE_LIMIT_WINDOW_MS
+            && self
+                .window_start_ms
+                .compare_exchange(start, now, Ordering::Relaxed, Ordering::Relaxed)
+                .is_ok()
+        {
+            self.count.store(0, Ordering::Relaxed);
+        }
+        if self.count.fetch_add(1, Ordering::Relaxed) < RATE_LIMIT_BURST {
+            Some(self.suppressed.swap(0, Ordering::Relaxed))
+        } else {
+            self.suppressed.fetch_add(1, Ordering::Relaxed);
+            None
+        }
+    }
+}
+
+impl Default for RateLimit {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+/// Emit a `tracing` event at most `RATE_LIMIT_BURST` times per second from
+/// this call site; the next event that gets through carries a `suppressed`
+/// count. Events the level filter drops don't count against the limit.
+///
+/// ```ignore
+/// rate_limited!(warn, backend = %addr, "connect failed: {err}");
+/// ```
+#[macro_export]
+macro_rules! rate_limited {
+    (@level trace) => { ::tracing::Level::TRACE };
+    (@level debug) => { ::tracing::Level::DEBUG };
+    (@level info) => { ::tracing::Level::INFO };
+    (@level warn) => { ::tracing::Level::WARN };
+    (@level error) => { ::tracing::Level::ERROR };
+    ($level:ident, parent: $parent:expr, $($arg:tt)+) => {{
+        static LIMIT: $crate::util::logging::RateLimit = $crate::util::logging::RateLimit::new();
+        if ::tracing::enabled!($crate::rate_limited!(@level $level))
+            && let Some(suppressed) = LIMIT.check()
+        {
+            ::tracing::$level!(parent: $parent, suppressed, $($arg)+);
+        }
+    }};
+    ($level:ident, $($arg:tt)+) => {{
+        static LIMIT: $crate::util::logging::RateLimit = $crate::util::logging::RateLimit::new();
+        if ::tracing::enabled!($crate::rate_limited!(@level $level))
+            && let Some(suppressed) = LIMIT.check()
+        {
+            ::tracing::$level!(suppressed, $($arg)+);
+        }
+    }};
+}
@@ -1 +1,8 @@
+pub mod address;
+pub mod cidr;
 pub mod fd;
+pub mod file_watch;
+pub mod hostname;
+pub mod logging;
+pub mod random;
+pub mod signals;
@@ -0,0 +1,30 @@
+use std::{io, mem, ptr};
+
+/// Block `signals` for the calling thread and every thread it spawns afterwards.
+///
+/// Call this before starting workers so the signals are only ever consumed
+/// by whoever calls `wait_signal` with the returned set.
+pub fn block_signals(signals: &[libc::c_int]) -> io::Result<libc::sigset_t> {
+    unsafe {
+        let mut set: libc::sigset_t = mem::zeroed();
+        libc::sigemptyset(&mut set);
+        for &sig in signals {
+            libc::sigaddset(&mut set, sig);
+        }
+        let rc = libc::pthread_sigmask(libc::SIG_BLOCK, &set, ptr::null_mut());
+        if rc != 0 {
+            return Err(io::Error::from_raw_os_error(rc));
+        }
+        Ok(set)
+    }
+}
+
+/// Wait synchronously for one of the blocked signals in `set`.
+pub fn wait_signal(set: &libc::sigset_t) -> io::Result<libc::c_int> {
+    let mut sig: libc::c_int = 0;
+    let rc = unsafe { libc::sigwait(set, &mut sig) };
+    if rc != 0 {
+        return Err(io::Error::from_raw_os_error(rc));
+    }
+    Ok(sig)
+}
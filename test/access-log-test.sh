@@ -0,0 +1,119 @@
+#!/bin/bash
+# Access log: one line per request, both formats, reopen on SIGUSR1
+# after the file is rotated away, and a line per response on a kept-alive
+# connection.
+# Needs the backends from start-backends.sh (or anything on 8081-8083) and
+# starts its own on 8088.
+
+GREEN='\033[0;32m'
+RED='\033[0;31m'
+BLUE='\033[0;34m'
+NC='\033[0m'
+
+FLAX=${FLAX:-../target/release/flax}
+DIR=$(mktemp -d /tmp/flax-access-log.XXXXXX)
+LOG=$DIR/access.log
+
+fail=0
+check() {
+    local name=$1 expected=$2 actual=$3
+    if [ "$actual" = "$expected" ]; then
+        echo -e "${GREEN}✓${NC} $name"
+    else
+        echo -e "${RED}✗${NC} $name: expected $expected, got $actual"
+        fail=1
+    fi
+}
+
+for format in combined json; do
+    echo -e "${BLUE}Format: $format${NC}"
+    rm -f "$LOG" "$LOG.1"
+    printf '[access_log]\npath = "%s"\nformat = "%s"\n' "$LOG" "$format" > "$DIR/flax.toml"
+    $FLAX "$DIR/flax.toml" 2> "$DIR/flax.log" &
+    PID=$!
+    sleep 1
+
+    for _ in $(seq 5); do
+        curl -s -o /dev/null -A "access-log-test" http://localhost:3000/small.txt
+    done
+    sleep 1
+    check "5 lines written" 5 "$(wc -l < "$LOG")"
+    check "user agent logged" 5 "$(grep -c 'access-log-test' "$LOG")"
+    if [ "$format" = json ]; then
+        check "status logged" 5 "$(grep -c '"status":200' "$LOG")"
+        check "valid JSON" 0 "$(python3 -c 'import json,sys; [json.loads(l) for l in open(sys.argv[1])]' "$LOG" > /dev/null 2>&1; echo $?)"
+    else
+        check "status logged" 5 "$(grep -c '" 200 ' "$LOG")"
+    fi
+
+    mv "$LOG" "$LOG.1"
+    kill -USR1 $PID
+    sleep 1
+    for _ in $(seq 3); do
+        curl -s -o /dev/null http://localhost:3000/small.txt
+    done
+    kill $PID
+    wait $PID
+    check "rotated file untouched" 5 "$(wc -l < "$LOG.1")"
+    check "reopened file written" 3 "$(wc -l < "$LOG" 2>/dev/null)"
+done
+
+head -n 1 "$LOG.1"
+
+echo -e "${BLUE}Kept-alive connection${NC}"
+# backend answering with the path, 404 for /missing
+cat > "$DIR/backend.py" <<'PY'
+import http.server
+class Paths(http.server.BaseHTTPRequestHandler):
+    protocol_version = "HTTP/1.1"
+    def do_GET(self):
+        body = self.path.encode()
+        self.send_response(404 if self.path == "/missing" else 200)
+        self.send_header("Content-Length", str(len(body)))
+        self.end_headers()
+        self.wfile.write(body)
+    def log_message(self, *args):
+        pass
+http.server.HTTPServer(("127.0.0.1", 8088), Paths).serve_forever()
+PY
+python3 "$DIR/backend.py" &
+BACKEND=$!
+rm -f "$LOG"
+printf 'backends = ["127.0.0.1:8088"]\n[access_log]\npath = "%s"\nformat = "json"\n' \
+    "$LOG" > "$DIR/flax.toml"
+$FLAX "$DIR/flax.toml" 2> "$DIR/flax.log" &
+PID=$!
+sleep 1
+
+# two requests on one connection, noting status, path and size of each
+# response; the log is read while the connection is still open
+lines=$(python3 - "$LOG" "$DIR/received" <<'PY'
+import socket, sys, time
+sock = socket.create_connection(("127.0.0.1", 3000))
+reader = sock.makefile("rb")
+received = []
+for path in ("/first", "/missing"):
+    sock.sendall(f"GET {path} HTTP/1.1\r\nHost: localhost\r\n\r\n".encode())
+    status = reader.readline()
+    size, length = len(status), 0
+    while (line := reader.readline()) != b"\r\n":
+        size += len(line)
+        if line.lower().startswith(b"content-length:"):
+            length = int(line.split(b":")[1])
+    size += 2 + len(reader.read(length))
+    received.append(f"{status.split()[1].decode()} {path} {size}")
+open(sys.argv[2], "w").write(" ".join(received))
+time.sleep(0.5)
+print(len(open(sys.argv[1]).readlines()))
+PY
+)
+check "line written for each response" 2 "$lines"
+check "status, path and bytes per request" "$(cat "$DIR/received")" \
+    "$(python3 -c 'import json, sys
+for r in map(json.loads, open(sys.argv[1])):
+    print(r["status"], r["path"], r["bytes"], end=" ")' "$LOG" | sed "s/ $//")"
+kill $PID $BACKEND
+wait $PID $BACKEND 2> /dev/null
+
+rm -rf "$DIR"
+exit $fail
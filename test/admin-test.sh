@@ -0,0 +1,70 @@
+#!/bin/bash
+# Admin API: list, add, drain, re-weight and remove backends on a running
+# Flax, checking that traffic follows the changes.
+# Needs the backends from start-backends.sh (or anything on 8081-8083).
+
+GREEN='\033[0;32m'
+RED='\033[0;31m'
+BLUE='\033[0;34m'
+NC='\033[0m'
+
+FLAX=${FLAX:-../target/release/flax}
+ADMIN=http://127.0.0.1:9000
+TOKEN=test-token
+DIR=$(mktemp -d /tmp/flax-admin.XXXXXX)
+CONFIG=$DIR/flax.toml
+cat > "$CONFIG" <<TOML
+backends = ["127.0.0.1:8081", "127.0.0.1:8082"]
+[admin]
+listen = "127.0.0.1:9000"
+token = "$TOKEN"
+TOML
+
+$FLAX "$CONFIG" 2> "$DIR/admin.log" &
+PID=$!
+trap 'kill $PID 2>/dev/null; rm -rf "$DIR"' EXIT
+sleep 1
+
+fail=0
+check() {
+    local name=$1 expected=$2 actual=$3
+    if [ "$actual" = "$expected" ]; then
+        echo -e "${GREEN}✓${NC} $name"
+    else
+        echo -e "${RED}✗${NC} $name: expected $expected, got $actual"
+        fail=1
+    fi
+}
+api() {
+    curl -s -o /dev/null -w '%{http_code}' -H "Authorization: Bearer $TOKEN" -X "$@"
+}
+echo -e "${BLUE}Authentication${NC}"
+check "no token" 401 "$(curl -s -o /dev/null -w '%{http_code}' $ADMIN/pools)"
+check "wrong token" 401 "$(curl -s -o /dev/null -w '%{http_code}' -H 'Authorization: Bearer nope' $ADMIN/pools)"
+check "valid token" 200 "$(api GET $ADMIN/pools)"
+
+echo -e "${BLUE}Backend management${NC}"
+check "add backend" 201 "$(api POST $ADMIN/pools/default/backends -d '{"address":"127.0.0.1:8083","weight":2}')"
+check "add duplicate" 409 "$(api POST $ADMIN/pools/default/backends -d '{"address":"127.0.0.1:8083"}')"
+check "drain backend" 200 "$(api POST $ADMIN/pools/default/backends/127.0.0.1:8081/drain)"
+check "re-weight backend" 200 "$(api PUT $ADMIN/pools/default/backends/127.0.0.1:8082/weight -d '{"weight":5}')"
+check "weight out of range" 400 "$(api PUT $ADMIN/pools/default/backends/127.0.0.1:8082/weight -d '{"weight":1000}')"
+check "unknown pool" 404 "$(api GET $ADMIN/pools/nope)"
+check "unknown backend" 404 "$(api GET $ADMIN/pools/default/backends/127.0.0.1:1)"
+
+echo -e "${BLUE}Traffic${NC}"
+ok=0
+for _ in $(seq 10); do
+    curl -s -f -m 2 -o /dev/null http://localhost:3000/small.txt && ok=$((ok + 1))
+done
+check "requests served after changes" 10 "$ok"
+curl -s -H "Authorization: Bearer $TOKEN" $ADMIN/pools/default
+echo
+check "metrics scrape" 200 "$(api GET $ADMIN/metrics)"
+accepted=$(curl -s -H "Authorization: Bearer $TOKEN" $ADMIN/metrics | grep '^flax_accepted_connections_total' | awk '{s += $2} END {print s}')
+check "accepts counted" 10 "$accepted"
+
+check "remove backend" 200 "$(api DELETE $ADMIN/pools/default/backends/127.0.0.1:8083)"
+check "removed backend gone" 404 "$(api GET $ADMIN/pools/default/backends/127.0.0.1:8083)"
+
+exit $fail
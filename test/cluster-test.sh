@@ -0,0 +1,217 @@
+#!/bin/bash
+# Cluster membership: SWIM between Flax instances on localhost. Members join
+# through a seed and learn of the rest by gossip, a member only reachable
+# through others stays in through ping-reqs, killed members are suspected and
+# then declared failed everywhere, missed acks stretch the prober's timeouts,
+# suspicions are refuted, restarted members join again, upgraded ones hand
+# their address over and stopped ones leave.
+# Uses UDP 7946-7949 for the cluster; listeners and admin APIs are Unix
+# sockets.
+
+GREEN='\033[0;32m'
+RED='\033[0;31m'
+BLUE='\033[0;34m'
+NC='\033[0m'
+
+FLAX=${FLAX:-../target/release/flax}
+DIR=$(mktemp -d /tmp/flax-cluster.XXXXXX)
+
+fail=0
+check() {
+    local name=$1 expected=$2 actual=$3
+    if [ "$actual" = "$expected" ]; then
+        echo -e "${GREEN}✓${NC} $name"
+    else
+        echo -e "${RED}✗${NC} $name: expected $expected, got $actual"
+        fail=1
+    fi
+}
+
+# Member on <port> that announces itself by pinging the members on the
+# comma-separated <announce> ports, then acks only pings from <allowed ports>,
+# so the others can reach it through those alone. It gossips nothing.
+cat > "$DIR/member.py" <<'PY'
+import socket, struct, sys
+sock = socket.socket(socket.AF_INET, socket.SOCK_DGRAM)
+sock.bind(("127.0.0.1", int(sys.argv[1])))
+for port in sys.argv[2].split(","):
+    sock.sendto(struct.pack(">BBIIB", 3, 1, 1, 0, 0), ("127.0.0.1", int(port)))
+allowed = {("127.0.0.1", int(port)) for port in sys.argv[3:]}
+while True:
+    data, peer = sock.recvfrom(1500)
+    version, kind, seq = struct.unpack(">BBI", data[:6])
+    if kind == 1 and peer in allowed:
+        sock.sendto(struct.pack(">BBIIB", 3, 2, seq, 0, 0), peer)
+PY
+
+# Stranger on 7949 that tells the member on <to> that the one on <about> is
+# suspected, and leaves in the same message
+cat > "$DIR/suspect.py" <<'PY'
+import socket, struct, sys
+def addr(port):
+    return struct.pack(">B4BH", 4, 127, 0, 0, 1, port)
+sock = socket.socket(socket.AF_INET, socket.SOCK_DGRAM)
+sock.bind(("127.0.0.1", 7949))
+to, about = int(sys.argv[1]), int(sys.argv[2])
+suspect = struct.pack(">BI", 3, 0) + addr(about) + addr(7949)
+left = struct.pack(">BI", 5, 0) + addr(7949)
+ping = struct.pack(">BBIIB", 3, 1, 1, 0, 2) + suspect + left
+sock.sendto(ping, ("127.0.0.1", to))
+PY
+
+# <name> <cluster port> <seed ports...>
+start_member() {
+    local name=$1 port=$2
+    shift 2
+    local seeds=""
+    for p in "$@"; do
+        seeds+="${seeds:+, }\"127.0.0.1:$p\""
+    done
+    cat > "$DIR/$name.toml" <<TOML
+listen = "unix:$DIR/$name.sock"
+workers = 1
+upgrade_socket = "$DIR/$name-upgrade.sock"
+
+[cluster]
+bind = "127.0.0.1:$port"
+seeds = [$seeds]
+probe_interval_ms = 200
+probe_timeout_ms = 60
+indirect_probes = 2
+
+[admin]
+unix = "$DIR/$name-admin.sock"
+token = "cluster-test"
+TOML
+    $FLAX "$DIR/$name.toml" 2>> "$DIR/$name.log" &
+    eval "PID_$name=$!"
+}
+
+admin() {
+    local name=$1
+    shift
+    curl -s --unix-socket "$DIR/$name-admin.sock" -H "Authorization: Bearer cluster-test" "$@"
+}
+
+# Ports of the members <name> believes alive
+members() {
+    admin $1 http://localhost/cluster |
+        python3 -c 'import json, sys; print(" ".join(m.split(":")[1] for m in json.load(sys.stdin)["members"]))'
+}
+
+cluster_field() {
+    admin $1 http://localhost/cluster | python3 -c "import json, sys; print(json.load(sys.stdin)['$2'])"
+}
+
+metric() {
+    admin $1 http://localhost/metrics | awk -v name="$2" '$1 == name { print $2 }'
+}
+
+echo -e "${BLUE}Joining${NC}"
+start_member a 7946
+start_member b 7947 7946
+sleep 0.5
+start_member c 7948 7946
+sleep 1
+check "a learns of the members joining through it" "7947 7948" "$(members a)"
+check "b learns of c by gossip" "7946 7948" "$(members b)"
+check "c learns of b from its seed" "7946 7947" "$(members c)"
+check "probing" 1 "$([ "$(metric a flax_cluster_probes_total)" -ge 3 ] && echo 1)"
+check "no failures" 0 "$(metric a flax_cluster_member_failures_total)"
+
+echo -e "${BLUE}Indirect probes${NC}"
+# answers b alone; a and c only reach it through b's ping-reqs
+python3 "$DIR/member.py" 7949 7946,7947,7948 7947 &
+STUB=$!
+sleep 3
+check "reached through ping-reqs" "7947 7948 7949" "$(members a)"
+check "ping-reqs sent" 1 "$([ "$(metric a flax_cluster_indirect_probes_total)" -gt 0 ] && echo 1)"
+check "no failures" 0 "$(metric a flax_cluster_member_failures_total)"
+kill $STUB
+wait $STUB 2> /dev/null
+sleep 3
+check "silent member failed" "7947 7948" "$(members a)"
+
+echo -e "${BLUE}Failures${NC}"
+kill -9 $PID_c
+wait $PID_c 2> /dev/null
+# T' * N: two periods of 200ms bound the next probe of c, one more to
+# suspect it, then four periods of suspicion once the other confirms it;
+# missed acks stretch each period while they last
+health=1
+for i in $(seq 30); do
+    h=$(cluster_field a health)
+    [ "$h" -gt "$health" ] && health=$h
+    sleep 0.1
+done
+check "a declares c failed" "7947" "$(members a)"
+check "b declares c failed" "7946" "$(members b)"
+check "suspicion counted" 1 "$([ "$(metric b flax_cluster_suspicions_total)" -ge 1 ] && echo 1)"
+check "failure counted" 1 "$([ "$(metric b flax_cluster_member_failures_total)" -ge 1 ] && echo 1)"
+check "failure logged" 1 "$(grep -c 'cluster member failed.*127.0.0.1:7948' "$DIR/b.log")"
+check "missed acks stretch a's timeouts" 1 "$([ "$health" -ge 2 ] && echo 1)"
+check "until its probes succeed again" 1 "$(cluster_field a health)"
+
+# its seed tells the new c that it is dead, which it refutes
+start_member c 7948 7946
+sleep 1
+check "restarted member joins again" "7947 7948" "$(members a)"
+check "and b hears of it" "7946 7948" "$(members b)"
+check "at a new incarnation" 1 "$(cluster_field c incarnation)"
+
+echo -e "${BLUE}Refuting suspicion${NC}"
+python3 "$DIR/suspect.py" 7946 7947
+# longer than the suspicion lasts
+sleep 1.2
+check "b hears of it through a and refutes it" 1 "$(cluster_field b incarnation)"
+check "refutation counted" 1 "$(metric b flax_cluster_refutations_total)"
+check "b is still a member" "7947 7948" "$(members a)"
+check "and no longer suspected" "[]" "$(cluster_field a suspects)"
+
+echo -e "${BLUE}Upgrading${NC}"
+$FLAX --upgrade "$DIR/c.toml" 2>> "$DIR/c-new.log" &
+NEW_c=$!
+wait $PID_c
+check "clean exit of the old c" 0 $?
+PID_c=$NEW_c
+check "old c leaves on handoff" 1 "$(grep -c 'leaving cluster' "$DIR/c.log")"
+# the new c binds the address within a retry and refutes its departure
+sleep 2
+check "new c takes over the cluster address" "7946 7947" "$(members c)"
+check "a keeps it" "7947 7948" "$(members a)"
+check "at a newer incarnation" 2 "$(cluster_field c incarnation)"
+
+echo -e "${BLUE}Leaving${NC}"
+kill $PID_b
+wait $PID_b
+check "clean exit of b" 0 $?
+sleep 0.2
+check "a drops b at once" "7948" "$(members a)"
+check "c drops b at once" "7946" "$(members c)"
+check "departure logged" 1 "$(grep -c 'cluster member left.*127.0.0.1:7947' "$DIR/a.log")"
+# the silent member and the killed c before
+check "not a failure" 2 "$(metric a flax_cluster_member_failures_total)"
+
+for name in a c; do
+    pid_var=PID_$name
+    kill ${!pid_var}
+    wait ${!pid_var}
+    check "clean exit of $name" 0 $?
+done
+
+echo -e "${BLUE}Configuration${NC}"
+cat > "$DIR/bad.toml" <<TOML
+[cluster]
+bind = "0.0.0.0:7946"
+TOML
+check "specific bind address required" 1 "$($FLAX "$DIR/bad.toml" 2>&1 | grep -c "specific address")"
+cat > "$DIR/bad.toml" <<TOML
+[cluster]
+bind = "127.0.0.1:7946"
+probe_interval_ms = 100
+probe_timeout_ms = 100
+TOML
+check "probe timeout within the period" 1 "$($FLAX "$DIR/bad.toml" 2>&1 | grep -c "probe_timeout_ms")"
+
+rm -rf "$DIR"
+exit $fail
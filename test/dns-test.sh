@@ -0,0 +1,243 @@
+#!/bin/bash
+# Backends named in DNS: looked up at startup and as their records expire,
+# addresses added and drained away as they change, SRV records for ports and
+# weights (over TCP when the UDP answer is truncated, with CNAMEs followed),
+# and failed lookups keeping the last addresses while a failed AAAA query
+# alone doesn't fail the lookup.
+# Starts a stub nameserver on 5354 and its own backends on 8089-8091.
+
+GREEN='\033[0;32m'
+RED='\033[0;31m'
+BLUE='\033[0;34m'
+NC='\033[0m'
+
+FLAX=${FLAX:-../target/release/flax}
+DIR=$(mktemp -d /tmp/flax-dns.XXXXXX)
+
+fail=0
+check() {
+    local name=$1 expected=$2 actual=$3
+    if [ "$actual" = "$expected" ]; then
+        echo -e "${GREEN}✓${NC} $name"
+    else
+        echo -e "${RED}✗${NC} $name: expected $expected, got $actual"
+        fail=1
+    fi
+}
+
+# Nameserver answering from zone.json, read afresh for every query:
+# {"records": {name: {"A": [ip], "CNAME": name, "SRV": [[prio, weight, port, target]]}},
+#  "glue": {srv name: [target]}, "truncate": [name], "fail": bool or [type]}
+# Glue targets' A records go in the additional section; truncated names get
+# an empty answer with TC set over UDP; "fail" answers SERVFAIL, to queries
+# of the types listed if it is a list.
+cat > "$DIR/dns.py" <<'PY'
+import json, socket, socketserver, struct, sys, threading
+zone_path = sys.argv[1]
+
+def name_bytes(name):
+    return b"".join(bytes([len(l)]) + l.encode() for l in name.split(".")) + b"\0"
+
+def rr(name, rtype, rdata):
+    return name_bytes(name) + struct.pack(">HHIH", rtype, 1, 1, len(rdata)) + rdata
+
+def records(zone, name, rtype):
+    entry = zone["records"].get(name, {})
+    if "CNAME" in entry:
+        target = entry["CNAME"]
+        return [rr(name, 5, name_bytes(target))] + records(zone, target, rtype)
+    if rtype == 1:
+        return [rr(name, 1, socket.inet_aton(ip)) for ip in entry.get("A", [])]
+    if rtype == 33:
+        return [rr(name, 33, struct.pack(">HHH", p, w, port) + name_bytes(t))
+                for p, w, port, t in entry.get("SRV", [])]
+    return []
+
+def answer(query, udp):
+    zone = json.load(open(zone_path))
+    qid, = struct.unpack(">H", query[:2])
+    pos, labels = 12, []
+    while query[pos]:
+        labels.append(query[pos + 1:pos + 1 + query[pos]].decode())
+        pos += 1 + query[pos]
+    name = ".".join(labels).lower()
+    rtype, = struct.unpack(">H", query[pos + 1:pos + 3])
+    question = query[12:pos + 5]
+    flags, answers, additional = 0x8180, [], []
+    fail = zone.get("fail")
+    if fail is True or isinstance(fail, list) and rtype in fail:
+        flags |= 2
+    elif udp and name in zone.get("truncate", []):
+        flags |= 0x0200
+    else:
+        answers = records(zone, name, rtype)
+        if not answers and name not in zone["records"]:
+            flags |= 3
+        if rtype == 33:
+            for target in zone.get("glue", {}).get(name, []):
+                additional += records(zone, target, 1)
+    header = struct.pack(">HHHHHH", qid, flags, 1, len(answers), 0, len(additional))
+    return header + question + b"".join(answers + additional)
+
+class Tcp(socketserver.StreamRequestHandler):
+    def handle(self):
+        n, = struct.unpack(">H", self.rfile.read(2))
+        reply = answer(self.rfile.read(n), False)
+        self.wfile.write(struct.pack(">H", len(reply)) + reply)
+
+socketserver.ThreadingTCPServer.allow_reuse_address = True
+tcp = socketserver.ThreadingTCPServer(("127.0.0.1", 5354), Tcp)
+threading.Thread(target=tcp.serve_forever, daemon=True).start()
+sock = socket.socket(socket.AF_INET, socket.SOCK_DGRAM)
+sock.bind(("127.0.0.1", 5354))
+while True:
+    query, client = sock.recvfrom(512)
+    sock.sendto(answer(query, True), client)
+PY
+
+# Backend answering every request with its name
+cat > "$DIR/backend.py" <<'PY'
+import http.server, sys
+class Backend(http.server.BaseHTTPRequestHandler):
+    def do_GET(self):
+        body = sys.argv[3].encode()
+        self.send_response(200)
+        self.send_header("Content-Length", str(len(body)))
+        self.end_headers()
+        self.wfile.write(body)
+    def log_message(self, *args):
+        pass
+http.server.ThreadingHTTPServer((sys.argv[1], int(sys.argv[2])), Backend).serve_forever()
+PY
+
+zone() {
+    echo "$1" > "$DIR/zone.json.tmp"
+    mv "$DIR/zone.json.tmp" "$DIR/zone.json"
+}
+zone '{"records": {"app.test": {"A": ["127.0.0.1"]}}}'
+
+python3 "$DIR/dns.py" "$DIR/zone.json" &
+DNS=$!
+python3 "$DIR/backend.py" 127.0.0.1 8089 a1 &
+A1=$!
+python3 "$DIR/backend.py" 127.0.0.2 8089 a2 &
+A2=$!
+python3 "$DIR/backend.py" 127.0.0.1 8090 b &
+B=$!
+python3 "$DIR/backend.py" 127.0.0.1 8091 c &
+C=$!
+sleep 0.5
+
+ADMIN=http://127.0.0.1:9001
+admin() {
+    curl -s -H "Authorization: Bearer dns-test" "$@"
+}
+
+# Addresses of a pool's backends, with their state and weight
+backends() {
+    admin $ADMIN/pools/$1/backends | python3 -c '
+import json, sys
+for b in sorted(json.load(sys.stdin)["backends"], key=lambda b: b["address"]):
+    print(b["address"], b["state"], b["weight"])' | tr '\n' ' ' | sed 's/ $//'
+}
+
+metric() {
+    admin $ADMIN/metrics |
+        awk -v name="$1" 'index($1, name) == 1 { sum += $2 } END { print sum + 0 }'
+}
+
+# <count> [host]: the backends answering, sorted
+responses() {
+    for _ in $(seq $1); do
+        curl -s -m 5 -H "Host: ${2:-app.test}" http://127.0.0.1:3088/
+        echo
+    done | sort | uniq -c | awk '{ printf "%s%s:%s", sep, $2, $1; sep = " " }'
+}
+
+cat > "$DIR/flax.toml" <<TOML
+listen = "127.0.0.1:3088"
+workers = 1
+backends = ["app.test:8089"]
+
+[pools.srv]
+backends = ["srv:_http._tcp.svc.test"]
+
+[hosts."svc.test"]
+pool = "srv"
+
+[dns]
+nameservers = ["127.0.0.1:5354"]
+timeout_ms = 500
+drain_ms = 2000
+
+[admin]
+listen = "127.0.0.1:9001"
+token = "dns-test"
+TOML
+
+echo -e "${BLUE}Host names${NC}"
+zone '{"records": {"app.test": {"A": ["127.0.0.1"]}, "_http._tcp.svc.test": {"SRV": [[10, 3, 8090, "b.test"], [10, 1, 8091, "c.test"], [20, 5, 8089, "app.test"]]}, "b.test": {"A": ["127.0.0.1"]}, "c.test": {"CNAME": "c-real.test"}, "c-real.test": {"A": ["127.0.0.1"]}}, "glue": {"_http._tcp.svc.test": ["b.test"]}, "truncate": ["_http._tcp.svc.test"]}'
+$FLAX "$DIR/flax.toml" 2>> "$DIR/flax.log" &
+PID=$!
+sleep 1
+check "resolved at startup" "127.0.0.1:8089 active 1" "$(backends default)"
+check "served" "a1:4" "$(responses 4)"
+
+zone "$(sed 's/"A": \["127.0.0.1"\]}, "_http/"A": ["127.0.0.1", "127.0.0.2"]}, "_http/' "$DIR/zone.json")"
+sleep 2
+check "new address added" "127.0.0.1:8089 active 1 127.0.0.2:8089 active 1" "$(backends default)"
+check "both served" "a1:2 a2:2" "$(responses 4)"
+
+zone "$(sed 's/"A": \["127.0.0.1", "127.0.0.2"\]}, "_http/"A": ["127.0.0.2"]}, "_http/' "$DIR/zone.json")"
+sleep 1.5
+check "vanished address draining" "127.0.0.1:8089 draining 1 127.0.0.2:8089 active 1" "$(backends default)"
+check "draining address not served" "a2:4" "$(responses 4)"
+sleep 2.5
+check "drained address removed" "127.0.0.2:8089 active 1" "$(backends default)"
+
+echo -e "${BLUE}SRV records${NC}"
+check "lowest priority targets with their weights" "127.0.0.1:8090 active 3 127.0.0.1:8091 active 1" \
+    "$(backends srv)"
+check "served by weight" "b:6 c:2" "$(responses 8 svc.test)"
+
+echo -e "${BLUE}Failed lookups${NC}"
+failures=$(metric flax_dns_lookup_failures_total)
+check "no failures so far" 0 "$failures"
+zone "$(sed 's/"A": \["127.0.0.2"\]}, "_http/"A": ["127.0.0.1", "127.0.0.2"]}, "_http/; s/}$/, "fail": [28]}/' "$DIR/zone.json")"
+sleep 2
+check "A records used while AAAA queries fail" \
+    "127.0.0.1:8089 active 1 127.0.0.2:8089 active 1" "$(backends default)"
+check "not a failed lookup" 0 "$(metric flax_dns_lookup_failures_total)"
+zone '{"records": {}, "fail": true}'
+sleep 2
+check "lookups failing" 1 "$([ "$(metric flax_dns_lookup_failures_total)" -gt 0 ] && echo 1)"
+check "last addresses kept" "127.0.0.1:8089 active 1 127.0.0.2:8089 active 1" \
+    "$(backends default)"
+check "last srv targets kept" "b:6 c:2" "$(responses 8 svc.test)"
+check "lookups counted" 1 "$([ "$(metric flax_dns_lookups_total)" -gt 6 ] && echo 1)"
+
+kill $PID
+wait $PID
+check "clean exit" 0 $?
+
+echo -e "${BLUE}Configuration${NC}"
+cat > "$DIR/bad.toml" <<TOML
+backends = [{ address = "srv:_http._tcp.svc.test", weight = 2 }]
+TOML
+check "no weight for srv backends" 1 "$($FLAX "$DIR/bad.toml" 2>&1 | grep -c "weights from its SRV records")"
+cat > "$DIR/bad.toml" <<TOML
+backends = ["app.test:8089"]
+
+[backend_tls]
+ca = "$DIR/ca.pem"
+TOML
+check "tls to a dns backend needs server_name" 1 "$($FLAX "$DIR/bad.toml" 2>&1 | grep -c "needs server_name")"
+cat > "$DIR/bad.toml" <<TOML
+backends = ["app.test"]
+TOML
+$FLAX "$DIR/bad.toml" 2> /dev/null
+check "host names need a port" 1 $?
+
+kill $DNS $A1 $A2 $B $C
+rm -rf "$DIR"
+exit $fail
@@ -0,0 +1,165 @@
+#!/bin/bash
+# File discovery: backends listed in a JSON or YAML file, applied at startup
+# and whenever the file is written or renamed into place, draining before
+# removal; malformed files rejected with the last listing kept; configured
+# backends left alone.
+# Starts its own backends on 8089-8091.
+
+GREEN='\033[0;32m'
+RED='\033[0;31m'
+BLUE='\033[0;34m'
+NC='\033[0m'
+
+FLAX=${FLAX:-../target/release/flax}
+DIR=$(mktemp -d /tmp/flax-discovery.XXXXXX)
+
+fail=0
+check() {
+    local name=$1 expected=$2 actual=$3
+    if [ "$actual" = "$expected" ]; then
+        echo -e "${GREEN}✓${NC} $name"
+    else
+        echo -e "${RED}✗${NC} $name: expected $expected, got $actual"
+        fail=1
+    fi
+}
+
+# Backend answering every request with its name
+cat > "$DIR/backend.py" <<'PY'
+import http.server, sys
+class Backend(http.server.BaseHTTPRequestHandler):
+    def do_GET(self):
+        body = sys.argv[2].encode()
+        self.send_response(200)
+        self.send_header("Content-Length", str(len(body)))
+        self.end_headers()
+        self.wfile.write(body)
+    def log_message(self, *args):
+        pass
+http.server.ThreadingHTTPServer(("127.0.0.1", int(sys.argv[1])), Backend).serve_forever()
+PY
+python3 "$DIR/backend.py" 8089 a &
+A=$!
+python3 "$DIR/backend.py" 8090 b &
+B=$!
+python3 "$DIR/backend.py" 8091 c &
+C=$!
+sleep 0.5
+
+ADMIN=http://127.0.0.1:9001
+admin() {
+    curl -s -H "Authorization: Bearer discovery-test" "$@"
+}
+
+# Addresses of a pool's backends, with their state and weight
+backends() {
+    admin $ADMIN/pools/$1/backends | python3 -c '
+import json, sys
+for b in sorted(json.load(sys.stdin)["backends"], key=lambda b: b["address"]):
+    print(b["address"], b["state"], b["weight"])' | tr '\n' ' ' | sed 's/ $//'
+}
+
+# <count>: the backends answering, sorted
+responses() {
+    for _ in $(seq $1); do
+        curl -s -m 5 http://127.0.0.1:3088/
+        echo
+    done | sort | uniq -c | awk '{ printf "%s%s:%s", sep, $2, $1; sep = " " }'
+}
+
+# <listing file>
+start_flax() {
+    cat > "$DIR/flax.toml" <<TOML
+listen = "127.0.0.1:3088"
+workers = 1
+backends = ["127.0.0.1:8089"]
+
+[pools.canary]
+backends = []
+
+[discovery]
+path = "$1"
+drain_ms = 1500
+
+[admin]
+listen = "127.0.0.1:9001"
+token = "discovery-test"
+TOML
+    $FLAX "$DIR/flax.toml" 2>> "$DIR/flax.log" &
+    PID=$!
+    sleep 1
+}
+
+echo -e "${BLUE}JSON file${NC}"
+echo '{"default": ["127.0.0.1:8090"], "canary": [{"address": "127.0.0.1:8091", "weight": 2}]}' > "$DIR/backends.json"
+start_flax "$DIR/backends.json"
+check "applied at startup" "127.0.0.1:8089 active 1 127.0.0.1:8090 active 1" "$(backends default)"
+check "other pools too" "127.0.0.1:8091 active 2" "$(backends canary)"
+check "served" "a:2 b:2" "$(responses 4)"
+
+echo '{"default": [{"address": "127.0.0.1:8091", "weight": 3}]}' > "$DIR/backends.json"
+sleep 0.5
+check "written in place" "127.0.0.1:8089 active 1 127.0.0.1:8090 draining 1 127.0.0.1:8091 active 3" \
+    "$(backends default)"
+check "left out pool drained" "127.0.0.1:8091 draining 2" "$(backends canary)"
+check "draining backend not served" "a:1 c:3" "$(responses 4)"
+sleep 1.5
+check "drained backend removed" "127.0.0.1:8089 active 1 127.0.0.1:8091 active 3" "$(backends default)"
+check "left out pool emptied" "" "$(backends canary)"
+
+echo '{"default": ["127.0.0.1:8090", "127.0.0.1:8091"]}' > "$DIR/next.json"
+mv "$DIR/next.json" "$DIR/backends.json"
+sleep 0.5
+check "renamed into place" "127.0.0.1:8089 active 1 127.0.0.1:8090 active 1 127.0.0.1:8091 active 1" \
+    "$(backends default)"
+
+echo '{"default": ["127.0.0.1:8090"' > "$DIR/backends.json"
+sleep 0.5
+check "malformed file rejected" 1 "$(grep -c "backend file rejected" "$DIR/flax.log")"
+echo '{"default": ["127.0.0.1:8090"], "missing": ["127.0.0.1:8091"]}' > "$DIR/backends.json"
+sleep 0.5
+check "unknown pool rejected" 1 "$(grep "backend file rejected" "$DIR/flax.log" | grep -c "unknown pool")"
+echo '{"default": ["127.0.0.1:8090", "127.0.0.1:8090"]}' > "$DIR/backends.json"
+sleep 0.5
+check "duplicate rejected" 1 "$(grep "backend file rejected" "$DIR/flax.log" | grep -c "listed twice")"
+check "last good listing kept" "127.0.0.1:8089 active 1 127.0.0.1:8090 active 1 127.0.0.1:8091 active 1" \
+    "$(backends default)"
+
+echo '{"default": ["127.0.0.1:8089"]}' > "$DIR/backends.json"
+sleep 2
+check "configured backend left alone" "127.0.0.1:8089 active 1" "$(backends default)"
+check "diffs logged" 1 "$([ "$(grep -c "backend file applied" "$DIR/flax.log")" -ge 4 ] && echo 1)"
+kill $PID
+wait $PID
+check "clean exit" 0 $?
+
+echo -e "${BLUE}YAML file${NC}"
+cat > "$DIR/backends.yaml" <<YAML
+default:
+  - 127.0.0.1:8090
+  - address: 127.0.0.1:8091
+    weight: 3
+YAML
+start_flax "$DIR/backends.yaml"
+check "applied at startup" "127.0.0.1:8089 active 1 127.0.0.1:8090 active 1 127.0.0.1:8091 active 3" \
+    "$(backends default)"
+cat > "$DIR/backends.yaml" <<YAML
+default:
+  - 127.0.0.1:8090
+YAML
+sleep 0.5
+check "rewritten" "127.0.0.1:8089 active 1 127.0.0.1:8090 active 1 127.0.0.1:8091 draining 3" \
+    "$(backends default)"
+kill $PID
+wait $PID
+check "clean exit" 0 $?
+
+echo -e "${BLUE}Startup${NC}"
+echo 'default: [' > "$DIR/bad.yaml"
+start_flax "$DIR/bad.yaml"
+wait $PID
+check "malformed file refused at startup" 1 $?
+
+kill $A $B $C
+rm -rf "$DIR"
+exit $fail
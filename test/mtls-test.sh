@@ -0,0 +1,240 @@
+#!/bin/bash
+# Client certificate authentication on the TLS listener: verification against
+# the CA and its CRL, the rejection reason in the log, the identity headers,
+# the allow list and routing by client certificate.
+# Starts its own backends on 8098-8099 and needs openssl for the test
+# certificates.
+
+GREEN='\033[0;32m'
+RED='\033[0;31m'
+BLUE='\033[0;34m'
+NC='\033[0m'
+
+FLAX=${FLAX:-../target/release/flax}
+OPENSSL=${OPENSSL:-openssl}
+DIR=$(mktemp -d /tmp/flax-mtls.XXXXXX)
+
+fail=0
+check() {
+    local name=$1 expected=$2 actual=$3
+    if [ "$actual" = "$expected" ]; then
+        echo -e "${GREEN}✓${NC} $name"
+    else
+        echo -e "${RED}✗${NC} $name: expected $expected, got $actual"
+        fail=1
+    fi
+}
+
+# client CA run with `openssl ca` so it can revoke and publish a CRL
+mkdir "$DIR/ca"
+touch "$DIR/ca/index.txt"
+echo 1000 > "$DIR/ca/serial"
+echo 01 > "$DIR/ca/crlnumber"
+cat > "$DIR/ca.cnf" <<CNF
+[ca]
+default_ca = test
+[test]
+dir = $DIR/ca
+database = \$dir/index.txt
+serial = \$dir/serial
+crlnumber = \$dir/crlnumber
+new_certs_dir = \$dir
+certificate = $DIR/ca.pem
+private_key = $DIR/ca.key
+default_md = sha256
+default_days = 1
+default_crl_days = 1
+policy = any
+copy_extensions = copy
+[any]
+organizationName = optional
+commonName = supplied
+CNF
+"$OPENSSL" req -x509 -newkey rsa:2048 -nodes -days 1 -subj "/CN=Client CA" \
+    -keyout "$DIR/ca.key" -out "$DIR/ca.pem" 2> /dev/null
+# <file> <subject> <subjectAltName>
+client() {
+    "$OPENSSL" req -newkey rsa:2048 -nodes -subj "$2" -addext "subjectAltName=$3" \
+        -keyout "$DIR/$1.key" -out "$DIR/$1.csr" 2> /dev/null
+    "$OPENSSL" ca -batch -config "$DIR/ca.cnf" -in "$DIR/$1.csr" -out "$DIR/$1.pem" \
+        2> /dev/null
+}
+client api "/O=Example/CN=api" DNS:api.svc.internal
+client ops "/O=Example/CN=ops" DNS:ops.example.com
+client batch "/CN=batch" DNS:worker1.batch.internal
+client revoked "/CN=revoked" DNS:revoked.svc.internal
+"$OPENSSL" ca -batch -config "$DIR/ca.cnf" -revoke "$DIR/revoked.pem" 2> /dev/null
+"$OPENSSL" ca -batch -config "$DIR/ca.cnf" -gencrl -out "$DIR/ca.crl" 2> /dev/null
+# listener certificate, and a client certificate from a CA Flax does not trust
+"$OPENSSL" req -x509 -newkey rsa:2048 -nodes -days 1 -subj "/CN=localhost" \
+    -addext "subjectAltName=DNS:localhost" \
+    -keyout "$DIR/listener.key" -out "$DIR/listener.pem" 2> /dev/null
+"$OPENSSL" req -x509 -newkey rsa:2048 -nodes -days 1 -subj "/CN=api" \
+    -addext "subjectAltName=DNS:api.svc.internal" -addext "basicConstraints=CA:FALSE" \
+    -keyout "$DIR/rogue.key" -out "$DIR/rogue.pem" 2> /dev/null
+
+# backend echoing the identity headers it received
+cat > "$DIR/backend.py" <<'PY'
+import http.server, sys
+port, name = int(sys.argv[1]), sys.argv[2]
+class Backend(http.server.BaseHTTPRequestHandler):
+    protocol_version = "HTTP/1.1"
+    def do_GET(self):
+        subjects = self.headers.get_all("X-Client-Cert-Subject") or []
+        sans = self.headers.get_all("X-Client-Cert-San") or []
+        body = f"{name} subject={'|'.join(subjects)} san={'|'.join(sans)}".encode()
+        self.send_response(200)
+        self.send_header("Content-Length", str(len(body)))
+        self.end_headers()
+        self.wfile.write(body)
+    def log_message(self, *args):
+        pass
+http.server.HTTPServer(("127.0.0.1", port), Backend).serve_forever()
+PY
+python3 "$DIR/backend.py" 8098 main &
+MAIN=$!
+python3 "$DIR/backend.py" 8099 batch &
+BATCH=$!
+
+# several requests on one connection, each with its extra header lines
+# (\r\n written out), sent one by one, all at once or each in two parts;
+# prints the bodies
+cat > "$DIR/keepalive.py" <<'PY'
+import socket, ssl, sys, time
+dir, who, mode, extras = sys.argv[1], sys.argv[2], sys.argv[3], sys.argv[4:]
+context = ssl.create_default_context(cafile=f"{dir}/listener.pem")
+context.load_cert_chain(f"{dir}/{who}.pem", f"{dir}/{who}.key")
+sock = context.wrap_socket(socket.create_connection(("127.0.0.1", 3098)),
+                           server_hostname="localhost")
+requests = [b"GET / HTTP/1.1\r\nHost: localhost\r\n" + extra.replace("\\r\\n", "\r\n").encode()
+            + b"\r\n" for extra in extras]
+reader = sock.makefile("rb")
+def response():
+    length = 0
+    while (line := reader.readline()) not in (b"\r\n", b""):
+        if line.lower().startswith(b"content-length:"):
+            length = int(line.split(b":")[1])
+    return reader.read(length).decode() if line else "closed"
+if mode == "all":
+    sock.sendall(b"".join(requests))
+    bodies = [response() for _ in requests]
+else:
+    bodies = []
+    for request in requests:
+        if mode == "split":
+            sock.sendall(request[:20])
+            time.sleep(0.2)
+            request = request[20:]
+        sock.sendall(request)
+        bodies.append(response())
+print(" / ".join(bodies))
+PY
+keepalive() {
+    python3 "$DIR/keepalive.py" "$DIR" "$@"
+}
+
+start_flax() {
+    cat > "$DIR/flax.toml" <<TOML
+listen = "127.0.0.1:3098"
+backends = ["127.0.0.1:8098"]
+
+[pools.batch]
+backends = ["127.0.0.1:8099"]
+
+[[client_routes]]
+names = ["*.batch.internal"]
+pool = "batch"
+
+[tls]
+cert = "$DIR/listener.pem"
+key = "$DIR/listener.key"
+
+[tls.client_auth]
+ca = "$DIR/ca.pem"
+crl = ["$DIR/ca.crl"]
+$1
+
+[admin]
+listen = "127.0.0.1:9000"
+token = "mtls-test"
+TOML
+    : > "$DIR/flax.log"
+    $FLAX "$DIR/flax.toml" 2>> "$DIR/flax.log" &
+    PID=$!
+    sleep 1
+}
+
+metric() {
+    curl -s -H "Authorization: Bearer mtls-test" http://127.0.0.1:9000/metrics |
+        awk -v name="$1" '$1 ~ "^"name"[{]" { sum += $2 } END { print sum + 0 }'
+}
+
+URL=https://localhost:3098
+as() {
+    local who=$1
+    shift
+    curl -s --cacert "$DIR/listener.pem" --cert "$DIR/$who.pem" --key "$DIR/$who.key" "$@"
+}
+
+start_flax 'allow_subjects = ["CN=ops,O=Example"]
+allow_names = ["*.svc.internal", "*.batch.internal"]'
+
+echo -e "${BLUE}Verification${NC}"
+check "no certificate refused" 000 \
+    "$(curl -s -o /dev/null -w '%{http_code}' --cacert "$DIR/listener.pem" $URL/)"
+check "untrusted CA refused" 000 "$(as rogue -o /dev/null -w '%{http_code}' $URL/)"
+check "revoked certificate refused" 000 "$(as revoked -o /dev/null -w '%{http_code}' $URL/)"
+check "rejections counted" 3 "$(metric flax_client_cert_rejected_total)"
+check "missing certificate logged" 1 \
+    "$(grep -c 'client certificate rejected: .*[Nn]o certificates' "$DIR/flax.log")"
+check "unknown issuer logged" 1 \
+    "$(grep -c 'client certificate rejected: .*UnknownIssuer' "$DIR/flax.log")"
+check "revocation logged" 1 "$(grep -c 'client certificate rejected: .*Revoked' "$DIR/flax.log")"
+
+echo -e "${BLUE}Identity headers${NC}"
+check "subject and SAN forwarded" \
+    "main subject=CN=api,O=Example san=DNS:api.svc.internal" "$(as api $URL/)"
+check "spoofed headers replaced" \
+    "main subject=CN=api,O=Example san=DNS:api.svc.internal" \
+    "$(as api -H 'X-Client-Cert-Subject: CN=admin' -H 'X-Client-Cert-San: DNS:admin' $URL/)"
+IDENTITY="main subject=CN=api,O=Example san=DNS:api.svc.internal"
+FORGED='X-Client-Cert-Subject: CN=admin\r\nX-Client-Cert-San: DNS:admin\r\n'
+check "spoofed headers replaced on a kept-alive connection" "$IDENTITY / $IDENTITY" \
+    "$(keepalive api one '' "$FORGED")"
+check "spoofed headers replaced in pipelined requests" "$IDENTITY / $IDENTITY / $IDENTITY" \
+    "$(keepalive api all '' "$FORGED" "$FORGED")"
+check "spoofed headers replaced in a head sent in parts" "$IDENTITY / $IDENTITY" \
+    "$(keepalive api split '' "$FORGED")"
+
+echo -e "${BLUE}Allow list and routing${NC}"
+check "allowed by subject" "main subject=CN=ops,O=Example san=DNS:ops.example.com" \
+    "$(as ops $URL/)"
+check "routed by SAN" "batch subject=CN=batch san=DNS:worker1.batch.internal" \
+    "$(as batch $URL/)"
+kill $PID
+wait $PID
+
+start_flax 'required = false
+allow_subjects = ["CN=ops,O=Example"]'
+check "subject not allowed answered 403" 403 "$(as api -o /dev/null -w '%{http_code}' $URL/)"
+check "no certificate not allowed answered 403" 403 \
+    "$(curl -s -o /dev/null -w '%{http_code}' --cacert "$DIR/listener.pem" $URL/)"
+check "denials counted" 2 "$(metric flax_client_cert_denied_total)"
+check "untrusted CA still refused" 000 "$(as rogue -o /dev/null -w '%{http_code}' $URL/)"
+kill $PID
+wait $PID
+
+echo -e "${BLUE}Optional certificates${NC}"
+start_flax 'required = false'
+check "no certificate let in without identity" "main subject= san=" \
+    "$(curl -s --cacert "$DIR/listener.pem" -H 'X-Client-Cert-Subject: CN=admin' $URL/)"
+check "certificate still verified and forwarded" \
+    "main subject=CN=api,O=Example san=DNS:api.svc.internal" "$(as api $URL/)"
+
+kill $PID
+wait $PID
+check "clean exit" 0 $?
+
+kill $MAIN $BATCH
+rm -rf "$DIR"
+exit $fail
@@ -0,0 +1,203 @@
+#!/bin/bash
+# PROXY protocol from trusted proxies: v1 and v2 headers read ahead of the
+# request, the ClientHello or the relayed bytes, headers arriving in pieces,
+# LOCAL and malformed headers, and peers that are not trusted.
+# Starts its own backend on 8089 and needs openssl for the test certificate.
+
+GREEN='\033[0;32m'
+RED='\033[0;31m'
+BLUE='\033[0;34m'
+NC='\033[0m'
+
+FLAX=${FLAX:-../target/release/flax}
+OPENSSL=${OPENSSL:-openssl}
+DIR=$(mktemp -d /tmp/flax-proxy-accept.XXXXXX)
+
+fail=0
+check() {
+    local name=$1 expected=$2 actual=$3
+    if [ "$actual" = "$expected" ]; then
+        echo -e "${GREEN}✓${NC} $name"
+    else
+        echo -e "${RED}✗${NC} $name: expected $expected, got $actual"
+        fail=1
+    fi
+}
+
+"$OPENSSL" req -x509 -newkey rsa:2048 -nodes -days 1 -subj "/CN=proxied.test" \
+    -addext "subjectAltName=DNS:proxied.test" \
+    -keyout "$DIR/listener.key" -out "$DIR/listener.pem" 2> /dev/null
+
+# Backend answering every request with the v1 header its connection started
+# with, e.g. "192.0.2.1:5000 198.51.100.1:443"
+python3 -c '
+import socketserver
+class Backend(socketserver.StreamRequestHandler):
+    def handle(self):
+        parts = self.rfile.readline().decode().split()
+        body = f"{parts[2]}:{parts[4]} {parts[3]}:{parts[5]}" if len(parts) == 6 else " ".join(parts)
+        while self.rfile.readline() not in (b"\r\n", b""):
+            pass
+        self.wfile.write(b"HTTP/1.0 200 OK\r\nContent-Length: %d\r\n\r\n%s" % (len(body), body.encode()))
+class Server(socketserver.ThreadingTCPServer):
+    allow_reuse_address = True
+    daemon_threads = True
+Server(("127.0.0.1", 8089), Backend).serve_forever()' &
+BACKEND=$!
+
+# Client sending a PROXY header and then a request: <header> <split> [tls],
+# where header is v1, v2, v2-6, local, junk or none, and split is "one" (header and
+# request or ClientHello in one send) or "pieces" (the header a few bytes at a
+# time). Prints the response body with its own port replaced by "port", or
+# "closed" when the connection closes without one.
+cat > "$DIR/client.py" <<'PY'
+import socket, ssl, sys, time
+kind, split, tls = sys.argv[1], sys.argv[2], sys.argv[3:] == ["tls"]
+def v2(command, family, addresses):
+    return b"\r\n\r\n\0\r\nQUIT\n" + bytes([command, family]) + len(addresses).to_bytes(2, "big") + addresses
+header = {
+    "v1": b"PROXY TCP4 192.0.2.1 198.51.100.1 5000 443\r\n",
+    "v2": v2(0x21, 0x11, bytes([192, 0, 2, 2, 198, 51, 100, 1]) + (5001).to_bytes(2, "big") + (443).to_bytes(2, "big")),
+    "v2-6": v2(0x21, 0x21, socket.inet_pton(socket.AF_INET6, "2001:db8::7") + socket.inet_pton(socket.AF_INET6, "2001:db8::1")
+        + (5002).to_bytes(2, "big") + (443).to_bytes(2, "big")),
+    "local": v2(0x20, 0x00, b""),
+    "junk": b"PROXY TCP4 192.0.2.1\r\n",
+    "none": b"",
+}[kind]
+request = b"GET / HTTP/1.1\r\nHost: proxied.test\r\nConnection: close\r\n\r\n"
+sock = socket.create_connection(("127.0.0.1", 3088), timeout=10)
+port = sock.getsockname()[1]
+def send(first):
+    if split == "pieces":
+        for i in range(0, len(header), 5):
+            sock.sendall(header[i:i + 5])
+            time.sleep(0.02)
+        sock.sendall(first)
+    else:
+        sock.sendall(header + first)
+try:
+    if tls:
+        ctx = ssl.SSLContext(ssl.PROTOCOL_TLS_CLIENT)
+        ctx.check_hostname = False
+        ctx.verify_mode = ssl.CERT_NONE
+        incoming, outgoing = ssl.MemoryBIO(), ssl.MemoryBIO()
+        conn = ctx.wrap_bio(incoming, outgoing, server_hostname="proxied.test")
+        try:
+            conn.do_handshake()
+        except ssl.SSLWantReadError:
+            pass
+        send(outgoing.read())
+        def pump():
+            if data := outgoing.read():
+                sock.sendall(data)
+        while True:
+            try:
+                conn.do_handshake()
+                break
+            except ssl.SSLWantReadError:
+                pump()
+                data = sock.recv(65536)
+                if not data:
+                    raise EOFError
+                incoming.write(data)
+        conn.write(request)
+        pump()
+        response = b""
+        while True:
+            try:
+                if not (data := conn.read(65536)):
+                    break
+                response += data
+            except ssl.SSLWantReadError:
+                data = sock.recv(65536)
+                if not data:
+                    break
+                incoming.write(data)
+            except (ssl.SSLZeroReturnError, ssl.SSLEOFError):
+                break
+    else:
+        send(request)
+        response = b""
+        while data := sock.recv(65536):
+            response += data
+except (EOFError, ConnectionResetError, ssl.SSLError):
+    response = b""
+if not response:
+    print("closed")
+elif not response.startswith(b"HTTP/1.0 200") and not response.startswith(b"HTTP/1.1 200"):
+    print(response.split(b" ", 2)[1].decode())
+else:
+    print(response.split(b"\r\n\r\n", 1)[-1].decode().replace(f":{port} ", ":port "))
+PY
+client() {
+    timeout 20 python3 "$DIR/client.py" "$@"
+}
+
+# <mode> <trusted networks> <extra config>
+start_flax() {
+    cat > "$DIR/flax.toml" <<TOML
+listen = "127.0.0.1:3088"
+mode = "$1"
+backends = ["127.0.0.1:8089"]
+backend_proxy_protocol = "v1"
+trusted_proxies = $2
+
+$3
+
+[admin]
+listen = "127.0.0.1:9001"
+token = "proxy-accept-test"
+TOML
+    $FLAX "$DIR/flax.toml" 2>> "$DIR/flax.log" &
+    PID=$!
+    sleep 1
+}
+
+metric() {
+    curl -s -H "Authorization: Bearer proxy-accept-test" http://127.0.0.1:9001/metrics |
+        awk -v name="$1" '$1 ~ "^"name"([{]|$)" { sum += $2 } END { print sum + 0 }'
+}
+
+start_flax http '["127.0.0.0/8"]'
+echo -e "${BLUE}Plaintext listener${NC}"
+check "v1 header" "192.0.2.1:5000 198.51.100.1:443" "$(client v1 one)"
+check "v2 header" "192.0.2.2:5001 198.51.100.1:443" "$(client v2 one)"
+check "v2 header, IPv6" "2001:db8::7:5002 2001:db8::1:443" "$(client v2-6 one)"
+check "header in pieces" "192.0.2.1:5000 198.51.100.1:443" "$(client v1 pieces)"
+check "v2 header in pieces" "192.0.2.2:5001 198.51.100.1:443" "$(client v2 pieces)"
+check "LOCAL keeps the peer" "127.0.0.1:port 127.0.0.1:3088" "$(client local one)"
+check "malformed header closes" closed "$(client junk one)"
+check "missing header closes" closed "$(client none one)"
+check "errors counted" 2 "$(metric flax_proxy_header_errors_total)"
+kill $PID
+wait $PID
+
+echo -e "${BLUE}TLS listener${NC}"
+start_flax http '["127.0.0.1"]' '
+[tls]
+cert = "'"$DIR"'/listener.pem"
+key = "'"$DIR"'/listener.key"'
+check "header and ClientHello in one send" "192.0.2.1:5000 198.51.100.1:443" "$(client v1 one tls)"
+check "header in pieces" "192.0.2.2:5001 198.51.100.1:443" "$(client v2 pieces tls)"
+kill $PID
+wait $PID
+
+echo -e "${BLUE}Passthrough${NC}"
+start_flax tcp '["127.0.0.0/8"]'
+check "header ahead of relayed bytes" "192.0.2.1:5000 198.51.100.1:443" "$(client v1 one)"
+check "header in pieces" "192.0.2.2:5001 198.51.100.1:443" "$(client v2 pieces)"
+kill $PID
+wait $PID
+
+echo -e "${BLUE}Untrusted peer${NC}"
+start_flax http '["10.0.0.0/8"]'
+check "header taken for the request" 400 "$(client v1 one)"
+check "plain request" "127.0.0.1:port 127.0.0.1:3088" "$(client none one)"
+
+kill $PID
+wait $PID
+check "clean exit" 0 $?
+
+kill $BACKEND
+rm -rf "$DIR"
+exit $fail
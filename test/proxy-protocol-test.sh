@@ -0,0 +1,221 @@
+#!/bin/bash
+# PROXY protocol toward backends: v1 and v2 headers on new backend
+# connections, the SNI and ALPN TLVs when they are known, the header ahead of
+# an upstream TLS handshake, and on passthrough connections.
+# Starts its own backends on 8091-8093 and needs openssl for the test
+# certificates.
+
+GREEN='\033[0;32m'
+RED='\033[0;31m'
+BLUE='\033[0;34m'
+NC='\033[0m'
+
+FLAX=${FLAX:-../target/release/flax}
+OPENSSL=${OPENSSL:-openssl}
+DIR=$(mktemp -d /tmp/flax-proxy-protocol.XXXXXX)
+
+fail=0
+check() {
+    local name=$1 expected=$2 actual=$3
+    if [ "$actual" = "$expected" ]; then
+        echo -e "${GREEN}✓${NC} $name"
+    else
+        echo -e "${RED}✗${NC} $name: expected $expected, got $actual"
+        fail=1
+    fi
+}
+
+# test CA, and certificates it signs: <file> <subjectAltName>
+"$OPENSSL" req -x509 -newkey rsa:2048 -nodes -days 1 -subj "/CN=Test CA" \
+    -keyout "$DIR/ca.key" -out "$DIR/ca.pem" 2> /dev/null
+signed() {
+    "$OPENSSL" req -newkey rsa:2048 -nodes -subj "/CN=$1" \
+        -keyout "$DIR/$1.key" -out "$DIR/$1.csr" 2> /dev/null
+    echo "subjectAltName=$2" > "$DIR/$1.ext"
+    "$OPENSSL" x509 -req -in "$DIR/$1.csr" -CA "$DIR/ca.pem" -CAkey "$DIR/ca.key" \
+        -CAcreateserial -days 1 -extfile "$DIR/$1.ext" -out "$DIR/$1.pem" 2> /dev/null
+}
+signed listener DNS:two.test,DNS:up.test
+signed backend DNS:up.test,IP:127.0.0.1
+
+# Backend answering every request with the PROXY header its connection
+# started with, e.g. "v2 127.0.0.1:41000 127.0.0.1:3087 authority=up.test",
+# and terminating TLS after it when given a certificate
+cat > "$DIR/backend.py" <<'PY'
+import ipaddress, socketserver, ssl, sys
+port, cert, key = int(sys.argv[1]), sys.argv[2], sys.argv[3]
+SIGNATURE = b"\r\n\r\n\0\r\nQUIT\n"
+TLVS = {1: "alpn", 2: "authority"}
+def read_exact(sock, n):
+    data = b""
+    while len(data) < n:
+        chunk = sock.recv(n - len(data))
+        if not chunk:
+            raise EOFError
+        data += chunk
+    return data
+def proxy_header(sock):
+    start = read_exact(sock, 12)
+    if start == SIGNATURE:
+        command, family = read_exact(sock, 2)
+        length = int.from_bytes(read_exact(sock, 2), "big")
+        body = read_exact(sock, length)
+        if command == 0x20:
+            return "v2 local"
+        n = 4 if family == 0x11 else 16
+        src, dst = ipaddress.ip_address(body[:n]), ipaddress.ip_address(body[n:2 * n])
+        sport = int.from_bytes(body[2 * n:2 * n + 2], "big")
+        dport = int.from_bytes(body[2 * n + 2:2 * n + 4], "big")
+        header = f"v2 {src}:{sport} {dst}:{dport}"
+        tlvs = body[2 * n + 4:]
+        while tlvs:
+            kind, length = tlvs[0], int.from_bytes(tlvs[1:3], "big")
+            header += f" {TLVS.get(kind, kind)}={tlvs[3:3 + length].decode()}"
+            tlvs = tlvs[3 + length:]
+        return header
+    line = start
+    while not line.endswith(b"\r\n"):
+        line += read_exact(sock, 1)
+    parts = line.decode(errors="replace").split()
+    if parts[:1] != ["PROXY"]:
+        return "none"
+    if parts[1] == "UNKNOWN":
+        return "v1 unknown"
+    return f"v1 {parts[2]}:{parts[4]} {parts[3]}:{parts[5]}"
+class Backend(socketserver.BaseRequestHandler):
+    def handle(self):
+        sock = self.request
+        header = proxy_header(sock)
+        if cert != "-":
+            ctx = ssl.SSLContext(ssl.PROTOCOL_TLS_SERVER)
+            ctx.load_cert_chain(cert, key)
+            ctx.set_alpn_protocols(["http/1.1"])
+            sock = ctx.wrap_socket(sock, server_side=True)
+        request = b""
+        while b"\r\n\r\n" not in request:
+            chunk = sock.recv(65536)
+            if not chunk:
+                return
+            request += chunk
+        if request.startswith(b"PROXY") or SIGNATURE in request:
+            header += " repeated"
+        body = header.encode()
+        sock.sendall(b"HTTP/1.0 200 OK\r\nContent-Length: %d\r\n\r\n%s" % (len(body), body))
+class Server(socketserver.ThreadingTCPServer):
+    allow_reuse_address = True
+    daemon_threads = True
+Server(("127.0.0.1", port), Backend).serve_forever()
+PY
+python3 "$DIR/backend.py" 8091 - - &
+ONE=$!
+python3 "$DIR/backend.py" 8092 - - &
+TWO=$!
+python3 "$DIR/backend.py" 8093 "$DIR/backend.pem" "$DIR/backend.key" &
+UP=$!
+
+# <mode> <extra config>
+start_flax() {
+    cat > "$DIR/flax.toml" <<TOML
+listen = "127.0.0.1:3087"
+mode = "$1"
+backends = ["127.0.0.1:8091"]
+backend_proxy_protocol = "v1"
+
+$2
+
+[hosts."two.test"]
+pool = "two"
+
+[hosts."up.test"]
+pool = "up"
+TOML
+    $FLAX "$DIR/flax.toml" 2>> "$DIR/flax.log" &
+    PID=$!
+    sleep 1
+}
+
+# The header a request got to its backend, with the client's port in it
+# replaced by "port" once checked against the port curl used
+announced() {
+    local out
+    out=$(curl -s -w ' %{local_port}' "$@")
+    echo "${out% *}" | sed "s/^\(v[12] 127.0.0.1\):${out##* } /\1:port /"
+}
+
+start_flax http '
+[pools.two]
+backends = ["127.0.0.1:8092"]
+proxy_protocol = "v2"
+
+[pools.up]
+backends = ["127.0.0.1:8093"]
+proxy_protocol = "v2"
+
+[pools.up.tls]
+ca = "'"$DIR"'/ca.pem"'
+
+echo -e "${BLUE}Plaintext listener${NC}"
+check "v1 header" "v1 127.0.0.1:port 127.0.0.1:3087" "$(announced http://127.0.0.1:3087/)"
+check "v2 header, no TLS to describe" "v2 127.0.0.1:port 127.0.0.1:3087" \
+    "$(announced -H 'Host: two.test' http://127.0.0.1:3087/)"
+check "v2 header ahead of the upstream handshake" "v2 127.0.0.1:port 127.0.0.1:3087" \
+    "$(announced -H 'Host: up.test' http://127.0.0.1:3087/)"
+
+pids=()
+for i in $(seq 1 20); do
+    announced http://127.0.0.1:3087/ > "$DIR/concurrent.$i" &
+    pids+=($!)
+done
+wait "${pids[@]}"
+check "20 connections announced once each" 20 \
+    "$(cat "$DIR"/concurrent.* | grep -cx 'v1 127.0.0.1:port 127.0.0.1:3087')"
+kill $PID
+wait $PID
+
+echo -e "${BLUE}TLS listener${NC}"
+start_flax http '
+[pools.two]
+backends = ["127.0.0.1:8092"]
+proxy_protocol = "v2"
+
+[pools.up]
+backends = ["127.0.0.1:8093"]
+proxy_protocol = "v2"
+
+[pools.up.tls]
+ca = "'"$DIR"'/ca.pem"
+
+[tls]
+cert = "'"$DIR"'/listener.pem"
+key = "'"$DIR"'/listener.key"'
+https() {
+    announced --cacert "$DIR/ca.pem" --resolve "$1:3087:127.0.0.1" --http1.1 "https://$1:3087/"
+}
+check "SNI and ALPN TLVs" "v2 127.0.0.1:port 127.0.0.1:3087 alpn=http/1.1 authority=two.test" \
+    "$(https two.test)"
+check "TLVs ahead of the upstream handshake" \
+    "v2 127.0.0.1:port 127.0.0.1:3087 alpn=http/1.1 authority=up.test" "$(https up.test)"
+kill $PID
+wait $PID
+
+echo -e "${BLUE}Passthrough${NC}"
+start_flax tcp '
+[pools.two]
+backends = ["127.0.0.1:8092"]
+proxy_protocol = "v2"
+
+[pools.up]
+backends = ["127.0.0.1:8093"]
+proxy_protocol = "v2"'
+check "SNI from the ClientHello" "v2 127.0.0.1:port 127.0.0.1:3087 authority=up.test" \
+    "$(https up.test)"
+check "plaintext connection" "v1 127.0.0.1:port 127.0.0.1:3087" \
+    "$(announced http://127.0.0.1:3087/)"
+
+kill $PID
+wait $PID
+check "clean exit" 0 $?
+
+kill $ONE $TWO $UP
+rm -rf "$DIR"
+exit $fail
@@ -0,0 +1,173 @@
+#!/bin/bash
+# SNI certificate selection and virtual hosts: exact and wildcard
+# certificates with a default fallback, routing by Host or SNI, and the
+# SNI/Host mismatch policy.
+# Starts its own backends on 8093 and 8094 and needs openssl for the
+# test certificates.
+
+GREEN='\033[0;32m'
+RED='\033[0;31m'
+BLUE='\033[0;34m'
+NC='\033[0m'
+
+FLAX=${FLAX:-../target/release/flax}
+OPENSSL=${OPENSSL:-openssl}
+DIR=$(mktemp -d /tmp/flax-sni.XXXXXX)
+
+fail=0
+check() {
+    local name=$1 expected=$2 actual=$3
+    if [ "$actual" = "$expected" ]; then
+        echo -e "${GREEN}✓${NC} $name"
+    else
+        echo -e "${RED}✗${NC} $name: expected $expected, got $actual"
+        fail=1
+    fi
+}
+
+certificate() {
+    "$OPENSSL" req -x509 -newkey rsa:2048 -nodes -days 1 -subj "/CN=$2" \
+        -addext "subjectAltName=DNS:$2" \
+        -keyout "$DIR/$1.key" -out "$DIR/$1.pem" 2> /dev/null
+}
+certificate default localhost
+certificate wildcard '*.example.com'
+certificate shop shop.test
+
+# served certificate for a given SNI name (none when empty)
+subject() {
+    local sni=(-noservername)
+    [ -n "$1" ] && sni=(-servername "$1")
+    echo | timeout 5 "$OPENSSL" s_client -connect 127.0.0.1:3443 "${sni[@]}" 2> /dev/null |
+        grep -m1 -o 'CN *= *[a-z.*-]*' | tr -d ' '
+}
+
+# body of a request to https://<sni>/ carrying Host: <host>
+fetch() {
+    local sni=$1 host=${2:-$1}
+    curl -sk --resolve "$sni:3443:127.0.0.1" -H "Host: $host" "https://$sni:3443/"
+}
+
+# backends answering with their own name
+cat > "$DIR/named.py" <<'PY'
+import http.server, sys
+name = sys.argv[2].encode()
+class Named(http.server.BaseHTTPRequestHandler):
+    protocol_version = "HTTP/1.1"
+    def do_GET(self):
+        self.send_response(200)
+        self.send_header("Content-Length", str(len(name)))
+        self.end_headers()
+        self.wfile.write(name)
+    def log_message(self, *args):
+        pass
+http.server.HTTPServer(("127.0.0.1", int(sys.argv[1])), Named).serve_forever()
+PY
+# bodies of requests to https://<sni>/ sent one after the other on one
+# connection, each carrying the next Host; "closed" once it is closed
+cat > "$DIR/keepalive.py" <<'PY'
+import socket, ssl, sys
+context = ssl.create_default_context()
+context.check_hostname = False
+context.verify_mode = ssl.CERT_NONE
+sock = context.wrap_socket(socket.create_connection(("127.0.0.1", 3443)),
+                           server_hostname=sys.argv[1])
+reader = sock.makefile("rb")
+bodies = []
+for host in sys.argv[2:]:
+    sock.sendall(f"GET / HTTP/1.1\r\nHost: {host}\r\n\r\n".encode())
+    length = 0
+    while (line := reader.readline()) not in (b"\r\n", b""):
+        if line.lower().startswith(b"content-length:"):
+            length = int(line.split(b":")[1])
+    bodies.append(reader.read(length).decode() if line else "closed")
+print(" / ".join(bodies))
+PY
+keepalive() {
+    python3 "$DIR/keepalive.py" "$@"
+}
+
+python3 "$DIR/named.py" 8093 main &
+MAIN=$!
+python3 "$DIR/named.py" 8094 shop &
+SHOP=$!
+
+start_flax() {
+    cat > "$DIR/flax.toml" <<TOML
+listen = "127.0.0.1:3443"
+backends = ["127.0.0.1:8093"]
+route_by = "$1"
+
+[pools.shop]
+backends = ["127.0.0.1:8094"]
+
+[hosts."shop.test"]
+pool = "shop"
+
+[hosts."*.shop.test"]
+pool = "shop"
+
+[tls]
+cert = "$DIR/default.pem"
+key = "$DIR/default.key"
+sni_host_mismatch = "$2"
+
+[[tls.certificates]]
+names = ["*.example.com"]
+cert = "$DIR/wildcard.pem"
+key = "$DIR/wildcard.key"
+
+[[tls.certificates]]
+names = ["shop.test", "*.shop.test"]
+cert = "$DIR/shop.pem"
+key = "$DIR/shop.key"
+TOML
+    $FLAX "$DIR/flax.toml" 2>> "$DIR/flax.log" &
+    PID=$!
+    sleep 1
+}
+
+start_flax host allow
+
+echo -e "${BLUE}Certificate selection${NC}"
+check "exact name" CN=shop.test "$(subject shop.test)"
+check "wildcard" 'CN=*.example.com' "$(subject www.example.com)"
+check "wildcard is case-insensitive" 'CN=*.example.com' "$(subject WWW.Example.COM)"
+check "wildcard covers one label only" CN=localhost "$(subject a.b.example.com)"
+check "wildcard does not cover the apex" CN=localhost "$(subject example.com)"
+check "unknown name gets the default" CN=localhost "$(subject unknown.test)"
+check "no SNI gets the default" CN=localhost "$(subject)"
+
+echo -e "${BLUE}Routing by Host${NC}"
+check "virtual host" shop "$(fetch shop.test)"
+check "wildcard virtual host" shop "$(fetch www.shop.test)"
+check "Host with port" shop "$(fetch shop.test shop.test:3443)"
+check "other hosts use the default pool" main "$(fetch www.example.com)"
+check "Host decides over SNI" main "$(fetch shop.test www.example.com)"
+check "kept-alive connection keeps its virtual host" "shop / shop" \
+    "$(keepalive shop.test shop.test shop.test)"
+check "another virtual host ends the connection" "shop / closed" \
+    "$(keepalive shop.test shop.test www.example.com)"
+
+kill $PID
+wait $PID
+start_flax sni reject
+
+echo -e "${BLUE}Routing by SNI, mismatches rejected${NC}"
+check "SNI selects the pool" shop "$(fetch shop.test)"
+check "matching Host in another case" shop "$(fetch shop.test SHOP.test)"
+status=$(curl -sk -o /dev/null -w '%{http_code}' --resolve shop.test:3443:127.0.0.1 \
+    -H 'Host: www.example.com' https://shop.test:3443/)
+check "mismatched Host answered 421" 421 "$status"
+check "mismatched Host ends a kept-alive connection" "shop / closed" \
+    "$(keepalive shop.test shop.test www.example.com)"
+check "unrouted SNI uses the default pool" main "$(fetch www.example.com)"
+check "no SNI falls back to Host" shop "$(curl -sk -H 'Host: shop.test' https://127.0.0.1:3443/)"
+
+kill $PID
+wait $PID
+check "clean exit" 0 $?
+
+kill $MAIN $SHOP
+rm -rf "$DIR"
+exit $fail
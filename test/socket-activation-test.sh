@@ -0,0 +1,53 @@
+#!/bin/bash
+# systemd socket activation: start Flax with a pre-bound listener passed via
+# LISTEN_FDS/LISTEN_PID, the way systemd does, and check it serves requests.
+# REUSEPORT=1 sets SO_REUSEPORT on the passed socket (like ReusePort=yes),
+# so extra workers bind their own sockets instead of sharing the fd.
+# Needs the backends from start-backends.sh (or anything on 8081-8083).
+
+GREEN='\033[0;32m'
+RED='\033[0;31m'
+BLUE='\033[0;34m'
+NC='\033[0m'
+
+FLAX=${FLAX:-../target/release/flax}
+PORT=${PORT:-3000}
+DIR=$(mktemp -d /tmp/flax-activation.XXXXXX)
+CONFIG=$DIR/flax.toml
+printf 'workers = 2\n' > "$CONFIG"
+
+echo -e "${BLUE}Spawning Flax with an inherited listener on :$PORT (REUSEPORT=${REUSEPORT:-0})${NC}"
+python3 - "$FLAX" "$CONFIG" <<PY 2> "$DIR/activation.log" &
+import os, socket, sys
+s = socket.socket(socket.AF_INET, socket.SOCK_STREAM)
+s.setsockopt(socket.SOL_SOCKET, socket.SO_REUSEADDR, 1)
+if "${REUSEPORT:-0}" == "1":
+    s.setsockopt(socket.SOL_SOCKET, socket.SO_REUSEPORT, 1)
+s.bind(("0.0.0.0", $PORT))
+s.listen(128)
+if s.fileno() != 3:
+    os.dup2(s.fileno(), 3)
+os.set_inheritable(3, True)
+os.environ["LISTEN_FDS"] = "1"
+os.environ["LISTEN_PID"] = str(os.getpid())  # exec keeps the pid
+os.execv(sys.argv[1], sys.argv[1:])
+PY
+FLAX_PID=$!
+trap 'kill $FLAX_PID 2>/dev/null; rm -rf "$DIR"' EXIT
+sleep 1
+
+fail=0
+for i in {1..50}; do
+    curl -s -f -m 2 http://localhost:$PORT/small.txt > /dev/null || fail=$((fail + 1))
+done
+
+kill -TERM $FLAX_PID
+wait $FLAX_PID
+cat "$DIR/activation.log"
+
+if [ $fail -eq 0 ]; then
+    echo -e "${GREEN}✓ 50 requests served through the inherited socket${NC}"
+else
+    echo -e "${RED}✗ $fail of 50 requests failed${NC}"
+    exit 1
+fi
@@ -0,0 +1,157 @@
+#!/bin/bash
+# TCP passthrough: server-first protocols, half-close in both directions,
+# large transfers both ways at once, and balancing across backends.
+# Starts its own backends on 8086-8087; nothing may listen on 8089.
+
+GREEN='\033[0;32m'
+RED='\033[0;31m'
+BLUE='\033[0;34m'
+NC='\033[0m'
+
+FLAX=${FLAX:-../target/release/flax}
+DIR=$(mktemp -d /tmp/flax-tcp.XXXXXX)
+
+fail=0
+check() {
+    local name=$1 expected=$2 actual=$3
+    if [ "$actual" = "$expected" ]; then
+        echo -e "${GREEN}✓${NC} $name"
+    else
+        echo -e "${RED}✗${NC} $name: expected $expected, got $actual"
+        fail=1
+    fi
+}
+
+# Backend speaking first, like SMTP. The client's first line picks what it does:
+#   md5   - read to EOF, answer with the MD5 of what came after the line
+#   close - half-close first, then report what the client sends afterwards
+#           through a file
+#   echo  - echo everything back until EOF
+cat > "$DIR/backend.py" <<'PY'
+import hashlib, socket, socketserver, sys
+name, port, report = sys.argv[1], int(sys.argv[2]), sys.argv[3]
+class Backend(socketserver.StreamRequestHandler):
+    def handle(self):
+        self.wfile.write(f"220 {name}\n".encode())
+        command = self.rfile.readline().strip()
+        if command == b"md5":
+            self.wfile.write(hashlib.md5(self.rfile.read()).hexdigest().encode())
+        elif command == b"close":
+            self.wfile.write(b"bye\n")
+            self.wfile.flush()
+            self.connection.shutdown(socket.SHUT_WR)
+            with open(report, "wb") as f:
+                f.write(self.rfile.read())
+        elif command == b"echo":
+            while chunk := self.rfile.read1(65536):
+                self.wfile.write(chunk)
+class Server(socketserver.ThreadingTCPServer):
+    allow_reuse_address = True
+    daemon_threads = True
+Server(("127.0.0.1", port), Backend).serve_forever()
+PY
+python3 "$DIR/backend.py" one 8086 "$DIR/report.one" &
+ONE=$!
+python3 "$DIR/backend.py" two 8087 "$DIR/report.two" &
+TWO=$!
+
+# client driving the conversations above through Flax
+cat > "$DIR/client.py" <<'PY'
+import os, socket, sys, threading
+def connect():
+    sock = socket.create_connection(("127.0.0.1", 3086))
+    return sock, sock.makefile("rb")
+test = sys.argv[1]
+if test == "banner":
+    sock, f = connect()
+    print(f.readline().decode().strip())
+elif test == "md5":
+    data = open(sys.argv[2], "rb").read()
+    sock, f = connect()
+    f.readline()
+    sock.sendall(b"md5\n" + data)
+    sock.shutdown(socket.SHUT_WR)
+    print(f.read().decode())
+elif test == "close":
+    sock, f = connect()
+    f.readline()
+    sock.sendall(b"close\n")
+    print(f.readline().decode().strip(), "eof" if f.read() == b"" else "data")
+    sock.sendall(b"after half-close")
+    sock.shutdown(socket.SHUT_WR)
+    sock.settimeout(5)
+    print("closed" if sock.recv(1) == b"" else "open")
+elif test == "echo":
+    data = os.urandom(int(sys.argv[2]))
+    sock, f = connect()
+    f.readline()
+    sock.sendall(b"echo\n")
+    def send():
+        sock.sendall(data)
+        sock.shutdown(socket.SHUT_WR)
+    sender = threading.Thread(target=send)
+    sender.start()
+    received = f.read()
+    sender.join()
+    print("match" if received == data else f"mismatch {len(received)}")
+elif test == "refused":
+    sock, f = connect()
+    sock.settimeout(5)
+    print("closed" if f.read() == b"" else "data")
+PY
+client() {
+    timeout 30 python3 "$DIR/client.py" "$@" 2>&1
+}
+
+start_flax() {
+    cat > "$DIR/flax.toml" <<TOML
+listen = "127.0.0.1:3086"
+mode = "tcp"
+backends = [$1]
+TOML
+    $FLAX "$DIR/flax.toml" 2>> "$DIR/flax.log" &
+    PID=$!
+    sleep 1
+}
+
+start_flax '"127.0.0.1:8086"'
+
+echo -e "${BLUE}Relaying${NC}"
+check "backend speaks first" "220 one" "$(client banner)"
+head -c 5000000 /dev/urandom > "$DIR/upload.bin"
+check "5 MB upload, client half-close" "$(md5sum < "$DIR/upload.bin" | cut -c1-32)" \
+    "$(client md5 "$DIR/upload.bin")"
+check "10 MB echoed both ways at once" match "$(client echo 10000000)"
+
+echo -e "${BLUE}Backend half-close${NC}"
+check "EOF passed to the client" "bye eof" "$(client close | head -n 1)"
+check "client still heard after it" "after half-close" "$(cat "$DIR/report.one")"
+check "closed once both sides are done" closed "$(client close | tail -n 1)"
+
+pids=()
+for i in $(seq 1 20); do
+    client echo 1000000 > "$DIR/concurrent.$i" &
+    pids+=($!)
+done
+wait "${pids[@]}"
+check "20 concurrent connections" 20 "$(cat "$DIR"/concurrent.* | grep -c match)"
+kill $PID
+wait $PID
+
+echo -e "${BLUE}Balancing${NC}"
+start_flax '"127.0.0.1:8086", "127.0.0.1:8087"'
+banners=$(for _ in $(seq 1 12); do client banner; done | sort | uniq -c)
+check "6 connections each" "6 220 one,6 220 two" \
+    "$(echo "$banners" | awk '{ print $1 " " $2 " " $3 }' | paste -sd,)"
+kill $PID
+wait $PID
+
+start_flax '"127.0.0.1:8089"'
+check "refused backend closes the client" closed "$(client refused)"
+kill $PID
+wait $PID
+check "clean exit" 0 $?
+
+kill $ONE $TWO
+rm -rf "$DIR"
+exit $fail
@@ -0,0 +1,190 @@
+#!/bin/bash
+# TLS passthrough: connections routed by the SNI of their ClientHello while
+# the backends terminate TLS, including hellos that arrive in pieces or span
+# records, and connections that are not TLS at all.
+# Starts its own backends on 8085-8087 and needs openssl for the test
+# certificates.
+
+GREEN='\033[0;32m'
+RED='\033[0;31m'
+BLUE='\033[0;34m'
+NC='\033[0m'
+
+FLAX=${FLAX:-../target/release/flax}
+OPENSSL=${OPENSSL:-openssl}
+DIR=$(mktemp -d /tmp/flax-tls-passthrough.XXXXXX)
+
+fail=0
+check() {
+    local name=$1 expected=$2 actual=$3
+    if [ "$actual" = "$expected" ]; then
+        echo -e "${GREEN}✓${NC} $name"
+    else
+        echo -e "${RED}✗${NC} $name: expected $expected, got $actual"
+        fail=1
+    fi
+}
+
+# test CA, and certificates it signs: <file> <subjectAltName>
+"$OPENSSL" req -x509 -newkey rsa:2048 -nodes -days 1 -subj "/CN=Test CA" \
+    -keyout "$DIR/ca.key" -out "$DIR/ca.pem" 2> /dev/null
+signed() {
+    "$OPENSSL" req -newkey rsa:2048 -nodes -subj "/CN=$1" \
+        -keyout "$DIR/$1.key" -out "$DIR/$1.csr" 2> /dev/null
+    echo "subjectAltName=$2" > "$DIR/$1.ext"
+    "$OPENSSL" x509 -req -in "$DIR/$1.csr" -CA "$DIR/ca.pem" -CAkey "$DIR/ca.key" \
+        -CAcreateserial -days 1 -extfile "$DIR/$1.ext" -out "$DIR/$1.pem" 2> /dev/null
+}
+signed a DNS:a.test
+signed b DNS:*.b.test
+
+# HTTPS backend naming itself and the SNI and ALPN it negotiated
+cat > "$DIR/backend.py" <<'PY'
+import http.server, ssl, sys
+port, name, cert, key = int(sys.argv[1]), sys.argv[2], sys.argv[3], sys.argv[4]
+ctx = ssl.SSLContext(ssl.PROTOCOL_TLS_SERVER)
+ctx.load_cert_chain(cert, key)
+ctx.set_alpn_protocols(["h2", "http/1.1"])
+sni = [None]
+ctx.sni_callback = lambda sock, server_name, context: sni.__setitem__(0, server_name)
+class Backend(http.server.BaseHTTPRequestHandler):
+    def do_GET(self):
+        body = f"{name} sni={sni[0]} alpn={self.connection.selected_alpn_protocol()}".encode()
+        self.send_response(200)
+        self.send_header("Content-Length", str(len(body)))
+        self.end_headers()
+        self.wfile.write(body)
+    def log_message(self, *args):
+        pass
+server = http.server.HTTPServer(("127.0.0.1", port), Backend)
+server.socket = ctx.wrap_socket(server.socket, server_side=True)
+server.serve_forever()
+PY
+python3 "$DIR/backend.py" 8086 a "$DIR/a.pem" "$DIR/a.key" &
+A=$!
+python3 "$DIR/backend.py" 8087 b "$DIR/b.pem" "$DIR/b.key" &
+B=$!
+# plaintext default pool, answering whatever it is sent
+python3 -c '
+import socketserver
+class Echo(socketserver.StreamRequestHandler):
+    def handle(self):
+        self.wfile.write(b"plain " + self.rfile.readline())
+socketserver.TCPServer.allow_reuse_address = True
+socketserver.TCPServer(("127.0.0.1", 8085), Echo).serve_forever()' &
+PLAIN=$!
+
+# TLS client that writes its ClientHello in pieces: <sni> <split>, where split
+# is "chunks" (one record, several sends) or "records" (two records)
+cat > "$DIR/client.py" <<'PY'
+import socket, ssl, sys, time
+sni, split, ca = sys.argv[1], sys.argv[2], sys.argv[3]
+ctx = ssl.create_default_context(cafile=ca)
+# the test CA lacks the key usage extension strict mode wants
+ctx.verify_flags &= ~ssl.VERIFY_X509_STRICT
+ctx.set_alpn_protocols(["http/1.1"])
+incoming, outgoing = ssl.MemoryBIO(), ssl.MemoryBIO()
+tls = ctx.wrap_bio(incoming, outgoing, server_hostname=sni)
+sock = socket.create_connection(("127.0.0.1", 3086), timeout=10)
+try:
+    tls.do_handshake()
+except ssl.SSLWantReadError:
+    pass
+hello = outgoing.read()
+if split == "chunks":
+    for i in range(0, len(hello), 100):
+        sock.sendall(hello[i:i + 100])
+        time.sleep(0.02)
+else:
+    body, half = hello[5:], (len(hello) - 5) // 2
+    for part in (body[:half], body[half:]):
+        sock.sendall(hello[:3] + len(part).to_bytes(2, "big") + part)
+        time.sleep(0.05)
+def pump():
+    if data := outgoing.read():
+        sock.sendall(data)
+while True:
+    try:
+        tls.do_handshake()
+        break
+    except ssl.SSLWantReadError:
+        pump()
+        incoming.write(sock.recv(65536))
+tls.write(f"GET / HTTP/1.1\r\nHost: {sni}\r\nConnection: close\r\n\r\n".encode())
+pump()
+response = b""
+while True:
+    try:
+        response += tls.read(65536)
+    except ssl.SSLWantReadError:
+        data = sock.recv(65536)
+        if not data:
+            break
+        incoming.write(data)
+    except (ssl.SSLZeroReturnError, ssl.SSLEOFError):
+        break
+print(response.split(b"\r\n\r\n", 1)[-1].decode())
+PY
+
+cat > "$DIR/flax.toml" <<TOML
+listen = "127.0.0.1:3086"
+mode = "tcp"
+backends = ["127.0.0.1:8085"]
+
+[pools.a]
+backends = ["127.0.0.1:8086"]
+
+[pools.b]
+backends = ["127.0.0.1:8087"]
+
+[hosts."a.test"]
+pool = "a"
+
+[hosts."*.b.test"]
+pool = "b"
+TOML
+$FLAX "$DIR/flax.toml" 2> "$DIR/flax.log" &
+PID=$!
+sleep 1
+
+https() {
+    curl -s --cacert "$DIR/ca.pem" --resolve "$1:3086:127.0.0.1" "${@:2}" "https://$1:3086/"
+}
+
+echo -e "${BLUE}Routing by SNI${NC}"
+check "exact name" "a sni=a.test alpn=http/1.1" "$(https a.test --http1.1)"
+check "wildcard name" "b sni=x.b.test alpn=http/1.1" "$(https x.b.test --http1.1)"
+check "ALPN left to the backend" "ALPN protocol: h2" \
+    "$(timeout 5 "$OPENSSL" s_client -connect 127.0.0.1:3086 -servername a.test -alpn h2 \
+        < /dev/null 2> /dev/null | grep '^ALPN protocol')"
+check "unknown name to the default pool" "000" "$(https c.test -o /dev/null -w '%{http_code}')"
+
+echo -e "${BLUE}Hellos in pieces${NC}"
+check "hello over several sends" "a sni=a.test alpn=http/1.1" \
+    "$(timeout 20 python3 "$DIR/client.py" a.test chunks "$DIR/ca.pem")"
+check "hello over two records" "b sni=y.b.test alpn=http/1.1" \
+    "$(timeout 20 python3 "$DIR/client.py" y.b.test records "$DIR/ca.pem")"
+
+echo -e "${BLUE}Not TLS${NC}"
+check "plaintext relayed to the default pool" "plain hello" \
+    "$(printf 'hello\n' | timeout 5 python3 -c '
+import socket, sys
+s = socket.create_connection(("127.0.0.1", 3086))
+s.sendall(sys.stdin.buffer.read())
+print(s.makefile().readline().strip())')"
+
+pids=()
+for i in $(seq 1 20); do
+    https a.test --http1.1 > "$DIR/concurrent.$i" &
+    pids+=($!)
+done
+wait "${pids[@]}"
+check "20 concurrent connections" 20 "$(cat "$DIR"/concurrent.* | grep -o 'a sni=a.test' | wc -l)"
+
+kill $PID
+wait $PID
+check "clean exit" 0 $?
+
+kill $A $B $PLAIN
+rm -rf "$DIR"
+exit $fail
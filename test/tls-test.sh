@@ -0,0 +1,191 @@
+#!/bin/bash
+# TLS termination: TLS 1.2 and 1.3 handshakes, ALPN, session resumption,
+# large bodies in both directions, concurrent connections, and certificate
+# reload through SIGHUP and the admin API, and kernel TLS offload where the
+# kernel supports it, including a client's close_notify on an offloaded
+# connection.
+# Starts its own backend on 8092 and needs openssl for the test certificates.
+
+GREEN='\033[0;32m'
+RED='\033[0;31m'
+BLUE='\033[0;34m'
+NC='\033[0m'
+
+FLAX=${FLAX:-../target/release/flax}
+OPENSSL=${OPENSSL:-openssl}
+DIR=$(mktemp -d /tmp/flax-tls.XXXXXX)
+URL=https://localhost:3443
+
+fail=0
+check() {
+    local name=$1 expected=$2 actual=$3
+    if [ "$actual" = "$expected" ]; then
+        echo -e "${GREEN}✓${NC} $name"
+    else
+        echo -e "${RED}✗${NC} $name: expected $expected, got $actual"
+        fail=1
+    fi
+}
+
+certificate() {
+    "$OPENSSL" req -x509 -newkey rsa:2048 -nodes -days 1 -subj "/CN=$1" \
+        -addext "subjectAltName=DNS:localhost" \
+        -keyout "$DIR/$1.key" -out "$DIR/$1.pem" 2> /dev/null
+}
+certificate flax-one
+certificate flax-two
+cp "$DIR/flax-one.pem" "$DIR/cert.pem"
+cp "$DIR/flax-one.key" "$DIR/key.pem"
+
+subject() {
+    echo | timeout 5 "$OPENSSL" s_client -connect 127.0.0.1:3443 2> /dev/null |
+        grep -m1 -o 'CN *= *[a-z-]*' | tr -d ' '
+}
+
+# backend serving 2 MiB on GET and the size and digest of a POST body;
+# /keepalive leaves the connection open
+cat > "$DIR/big.py" <<'PY'
+import hashlib, http.server
+BIG = bytes(range(256)) * 8192
+class Big(http.server.BaseHTTPRequestHandler):
+    protocol_version = "HTTP/1.1"
+    def reply(self, body):
+        self.send_response(200)
+        self.send_header("Content-Length", str(len(body)))
+        if self.path != "/keepalive":
+            self.send_header("Connection", "close")
+        self.end_headers()
+        self.wfile.write(body)
+    def do_GET(self):
+        self.reply(b"kept" if self.path == "/keepalive" else BIG)
+    def do_POST(self):
+        body = self.rfile.read(int(self.headers["Content-Length"]))
+        self.reply(f"{len(body)} {hashlib.sha256(body).hexdigest()}".encode())
+    def log_message(self, *args):
+        pass
+http.server.ThreadingHTTPServer(("127.0.0.1", 8092), Big).serve_forever()
+PY
+python3 "$DIR/big.py" &
+BACKEND=$!
+
+cat > "$DIR/flax.toml" <<TOML
+listen = "127.0.0.1:3443"
+workers = 1
+backends = ["127.0.0.1:8092"]
+
+[admin]
+listen = "127.0.0.1:9000"
+token = "tls-test"
+
+[tls]
+cert = "$DIR/cert.pem"
+key = "$DIR/key.pem"
+TOML
+$FLAX "$DIR/flax.toml" 2> "$DIR/flax.log" &
+PID=$!
+sleep 1
+
+echo -e "${BLUE}Handshakes${NC}"
+check "TLS 1.3" 200 "$(curl -sk --tlsv1.3 -o /dev/null -w '%{http_code}' $URL/)"
+check "TLS 1.2" 200 "$(curl -sk --tlsv1.2 --tls-max 1.2 -o /dev/null -w '%{http_code}' $URL/)"
+alpn=$(echo | timeout 5 "$OPENSSL" s_client -connect 127.0.0.1:3443 -alpn http/1.1 2> /dev/null | grep -c 'ALPN protocol: http/1.1')
+check "ALPN http/1.1" 1 "$alpn"
+check "plain HTTP refused" 000 "$(curl -s -m 5 -o /dev/null -w '%{http_code}' http://localhost:3443/)"
+
+for version in tls1_3 tls1_2; do
+    # TLS 1.3 tickets follow the handshake; stay connected long enough to get one
+    sleep 0.5 | timeout 5 "$OPENSSL" s_client -connect 127.0.0.1:3443 -$version \
+        -sess_out "$DIR/session" > /dev/null 2>&1
+    reused=$(echo | timeout 5 "$OPENSSL" s_client -connect 127.0.0.1:3443 -$version \
+        -sess_in "$DIR/session" 2> /dev/null | grep -c '^Reused')
+    check "session resumed ($version)" 1 "$reused"
+done
+
+echo -e "${BLUE}Bodies${NC}"
+expected=$(python3 -c 'import sys; sys.stdout.buffer.write(bytes(range(256)) * 8192)' | sha256sum | cut -d' ' -f1)
+check "2 MiB response" "$expected" "$(curl -sk $URL/big | sha256sum | cut -d' ' -f1)"
+head -c 3000000 /dev/urandom > "$DIR/body"
+check "3 MB request body" "3000000 $(sha256sum < "$DIR/body" | cut -d' ' -f1)" \
+    "$(curl -sk --data-binary @"$DIR/body" $URL/upload)"
+
+downloads=()
+for i in $(seq 20); do
+    curl -sk -m 30 $URL/big | sha256sum | cut -d' ' -f1 > "$DIR/parallel.$i" &
+    downloads+=($!)
+done
+wait "${downloads[@]}"
+check "20 concurrent downloads" 20 "$(cat "$DIR"/parallel.* | grep -c "$expected")"
+
+echo -e "${BLUE}Certificate reload${NC}"
+check "initial certificate" CN=flax-one "$(subject)"
+cp "$DIR/flax-two.pem" "$DIR/cert.pem"
+cp "$DIR/flax-two.key" "$DIR/key.pem"
+kill -HUP $PID
+sleep 0.5
+check "reloaded on SIGHUP" CN=flax-two "$(subject)"
+cp "$DIR/flax-one.pem" "$DIR/cert.pem"
+cp "$DIR/flax-one.key" "$DIR/key.pem"
+check "admin reload" '{"reloaded":true}' \
+    "$(curl -s -X POST -H 'Authorization: Bearer tls-test' http://127.0.0.1:9000/tls/reload)"
+check "reloaded through admin API" CN=flax-one "$(subject)"
+echo "not a key" > "$DIR/key.pem"
+check "broken key rejected" 500 "$(curl -s -o /dev/null -w '%{http_code}' -X POST \
+    -H 'Authorization: Bearer tls-test' http://127.0.0.1:9000/tls/reload)"
+check "previous certificate kept" CN=flax-one "$(subject)"
+
+echo -e "${BLUE}Metrics${NC}"
+metrics=$(curl -s -H 'Authorization: Bearer tls-test' http://127.0.0.1:9000/metrics)
+check "handshakes counted" 1 "$(echo "$metrics" | grep -cE '^flax_tls_handshakes_total\{worker="0"\} [1-9]')"
+check "resumptions counted" 1 "$(echo "$metrics" | grep -cE '^flax_tls_resumed_total\{worker="0"\} [1-9]')"
+
+kill $PID
+wait $PID
+check "clean exit" 0 $?
+
+echo -e "${BLUE}Kernel TLS${NC}"
+cp "$DIR/flax-one.key" "$DIR/key.pem"
+echo "ktls = true" >> "$DIR/flax.toml"
+$FLAX "$DIR/flax.toml" 2> "$DIR/flax.log" &
+PID=$!
+sleep 1
+check "2 MiB response" "$expected" "$(curl -sk $URL/big | sha256sum | cut -d' ' -f1)"
+check "3 MB request body" "3000000 $(sha256sum < "$DIR/body" | cut -d' ' -f1)" \
+    "$(curl -sk --data-binary @"$DIR/body" $URL/upload)"
+# a client ending with close_notify leaves the backend connection cached;
+# read as an error, the alert would take the backend connection down too
+closed=$(timeout 10 python3 - <<'PY'
+import socket, ssl
+ctx = ssl.create_default_context()
+ctx.check_hostname = False
+ctx.verify_mode = ssl.CERT_NONE
+with ctx.wrap_socket(socket.create_connection(("127.0.0.1", 3443)),
+                     server_hostname="localhost") as tls:
+    tls.sendall(b"GET /keepalive HTTP/1.1\r\nHost: localhost\r\n\r\n")
+    response = b""
+    while not response.endswith(b"kept"):
+        response += tls.recv(4096)
+    try:
+        tls.unwrap()
+        print("closed")
+    except (ssl.SSLError, OSError) as error:
+        print(type(error).__name__)
+PY
+)
+check "close_notify ends the connection" closed "$closed"
+curl -sk -o /dev/null $URL/keepalive
+hits=$(curl -s -H 'Authorization: Bearer tls-test' http://127.0.0.1:9000/metrics |
+    grep -cE '^flax_backend_cache_hits_total\{worker="0"\} [1-9]')
+check "backend connection reused after close_notify" 1 "$hits"
+if grep -q "kernel tls is not available" "$DIR/flax.log"; then
+    echo -e "${BLUE}-${NC} kernel has no TLS support, offload not exercised"
+else
+    metrics=$(curl -s -H 'Authorization: Bearer tls-test' http://127.0.0.1:9000/metrics)
+    check "connections offloaded" 1 \
+        "$(echo "$metrics" | grep -cE '^flax_ktls_offloaded_total\{worker="0"\} [1-9]')"
+fi
+kill $PID
+wait $PID
+
+kill $BACKEND
+rm -rf "$DIR"
+exit $fail
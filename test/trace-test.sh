@@ -0,0 +1,159 @@
+#!/bin/bash
+# Request IDs and trace context: generated or kept X-Request-Id, traceparent
+# rewritten with Flax's span as the parent, tracestate handling, IDs on
+# Flax's own error responses and in the access log, and OTLP/JSON export to
+# a stand-in collector.
+# Starts its own header-echo backend on 8091 and collector on 4318.
+
+GREEN='\033[0;32m'
+RED='\033[0;31m'
+BLUE='\033[0;34m'
+NC='\033[0m'
+
+FLAX=${FLAX:-../target/release/flax}
+DIR=$(mktemp -d /tmp/flax-trace.XXXXXX)
+
+fail=0
+check() {
+    local name=$1 expected=$2 actual=$3
+    if [ "$actual" = "$expected" ]; then
+        echo -e "${GREEN}✓${NC} $name"
+    else
+        echo -e "${RED}✗${NC} $name: expected $expected, got $actual"
+        fail=1
+    fi
+}
+
+# backend answering with the request head it received, keeping the
+# connection open for /keepalive
+cat > "$DIR/echo.py" <<'PY'
+import http.server
+class Echo(http.server.BaseHTTPRequestHandler):
+    protocol_version = "HTTP/1.1"
+    def do_GET(self):
+        body = str(self.headers).encode()
+        self.send_response(200)
+        self.send_header("Content-Length", str(len(body)))
+        if self.path != "/keepalive":
+            self.send_header("Connection", "close")
+        self.end_headers()
+        self.wfile.write(body)
+    def log_message(self, *args):
+        pass
+http.server.HTTPServer(("127.0.0.1", 8091), Echo).serve_forever()
+PY
+# collector appending each export body as one line
+cat > "$DIR/collector.py" <<'PY'
+import http.server, sys
+class Collector(http.server.BaseHTTPRequestHandler):
+    def do_POST(self):
+        body = self.rfile.read(int(self.headers["Content-Length"]))
+        with open(sys.argv[1], "ab") as f:
+            f.write(body + b"\n")
+        self.send_response(200)
+        self.send_header("Content-Length", "2")
+        self.end_headers()
+        self.wfile.write(b"{}")
+    def log_message(self, *args):
+        pass
+http.server.HTTPServer(("127.0.0.1", 4318), Collector).serve_forever()
+PY
+python3 "$DIR/echo.py" &
+ECHO=$!
+python3 "$DIR/collector.py" "$DIR/spans.jsonl" &
+COLLECTOR=$!
+
+cat > "$DIR/flax.toml" <<TOML
+backends = ["127.0.0.1:8091"]
+
+[access_log]
+path = "$DIR/access.log"
+format = "json"
+
+[trace]
+otlp_endpoint = "http://127.0.0.1:4318/v1/traces"
+service_name = "flax-test"
+TOML
+$FLAX "$DIR/flax.toml" 2> "$DIR/flax.log" &
+PID=$!
+sleep 1
+
+echo -e "${BLUE}Request IDs${NC}"
+body=$(curl -s http://localhost:3000/)
+check "request id generated" 1 "$(echo "$body" | grep -ciE '^x-request-id: [0-9a-f]{32}')"
+body=$(curl -s -H 'X-Request-Id: abc-123' http://localhost:3000/)
+check "client request id kept" 1 "$(echo "$body" | grep -ci '^x-request-id: abc-123')"
+check "request id not duplicated" 1 "$(echo "$body" | grep -ci '^x-request-id')"
+
+echo -e "${BLUE}Trace context${NC}"
+body=$(curl -s http://localhost:3000/)
+check "traceparent created" 1 "$(echo "$body" | grep -cE '^traceparent: 00-[0-9a-f]{32}-[0-9a-f]{16}-01')"
+parent=00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01
+body=$(curl -s -H "traceparent: $parent" -H 'tracestate: vendor=1' http://localhost:3000/)
+forwarded=$(echo "$body" | grep -i '^traceparent' | tr -d '\r' | cut -d' ' -f2)
+check "trace id kept" 4bf92f3577b34da6a3ce929d0e0e4736 "$(echo "$forwarded" | cut -d- -f2)"
+check "parent replaced by flax span" 1 "$([ "$(echo "$forwarded" | cut -d- -f3)" != 00f067aa0ba902b7 ] && echo 1)"
+check "tracestate passed through" 1 "$(echo "$body" | grep -c '^tracestate: vendor=1')"
+body=$(curl -s -H 'traceparent: garbage' -H 'tracestate: vendor=1' http://localhost:3000/)
+check "invalid traceparent replaced" 1 "$(echo "$body" | grep -cE '^traceparent: 00-[0-9a-f]{32}')"
+check "tracestate dropped with invalid parent" 0 "$(echo "$body" | grep -c '^tracestate')"
+
+echo -e "${BLUE}Kept-alive connections${NC}"
+# request IDs and traceparent span IDs of two requests on one connection
+ids=$(python3 - <<'PY'
+import socket
+sock = socket.create_connection(("127.0.0.1", 3000))
+reader = sock.makefile("rb")
+ids, spans = set(), set()
+for _ in range(2):
+    sock.sendall(b"GET /keepalive HTTP/1.1\r\nHost: localhost\r\n\r\n")
+    length = 0
+    while (line := reader.readline()) != b"\r\n":
+        if line.lower().startswith(b"content-length:"):
+            length = int(line.split(b":")[1])
+    for header in reader.read(length).decode().lower().splitlines():
+        name, _, value = header.partition(": ")
+        if name == "x-request-id":
+            ids.add(value)
+        elif name == "traceparent":
+            spans.add(value.split("-")[2])
+print(len(ids), len(spans))
+PY
+)
+check "each request identified and traced" "2 2" "$ids"
+
+echo -e "${BLUE}Error responses${NC}"
+kill $ECHO
+wait $ECHO 2>/dev/null
+headers=$(curl -s -D - -o /dev/null -H 'X-Request-Id: down-1' http://localhost:3000/)
+check "502 when backend is down" 1 "$(echo "$headers" | grep -c '^HTTP/1.1 502')"
+check "502 carries request id" 1 "$(echo "$headers" | grep -c '^X-Request-Id: down-1')"
+status=$(python3 - <<'PY'
+import socket
+s = socket.create_connection(("127.0.0.1", 3000))
+s.sendall(b"BROKEN\r\n\r\n")
+print(s.recv(4096).split(b"\r\n")[0].decode())
+PY
+)
+check "400 on malformed request" "HTTP/1.1 400 Bad Request" "$status"
+
+sleep 2
+kill $PID
+wait $PID
+
+echo -e "${BLUE}Logs and export${NC}"
+check "request ids in access log" 8 "$(grep -c '"request_id":"[^"]' "$DIR/access.log")"
+check "502 in access log" 1 "$(grep -c '"request_id":"down-1".*"status":502\|"status":502.*"request_id":"down-1"' "$DIR/access.log")"
+spans=$(python3 - "$DIR/spans.jsonl" <<'PY'
+import json, sys
+spans = [s for line in open(sys.argv[1])
+         for r in json.loads(line)["resourceSpans"]
+         for ss in r["scopeSpans"] for s in ss["spans"]]
+print(len(spans), sum(s.get("parentSpanId") == "00f067aa0ba902b7" for s in spans))
+PY
+)
+check "spans exported (total, with client parent)" "8 1" "$spans"
+
+kill $COLLECTOR
+rm -rf "$DIR"
+exit $fail
@@ -0,0 +1,155 @@
+#!/bin/bash
+# UDP mode: datagrams relayed per client flow, replies back to the client
+# they answer, flows expiring when idle or at the session timeout, affinity by
+# client address, and backends that refuse datagrams.
+# Starts its own UDP backends on 8089-8090.
+
+GREEN='\033[0;32m'
+RED='\033[0;31m'
+BLUE='\033[0;34m'
+NC='\033[0m'
+
+FLAX=${FLAX:-../target/release/flax}
+DIR=$(mktemp -d /tmp/flax-udp.XXXXXX)
+
+fail=0
+check() {
+    local name=$1 expected=$2 actual=$3
+    if [ "$actual" = "$expected" ]; then
+        echo -e "${GREEN}✓${NC} $name"
+    else
+        echo -e "${RED}✗${NC} $name: expected $expected, got $actual"
+        fail=1
+    fi
+}
+
+# Backend answering every datagram with its name and the datagram
+cat > "$DIR/backend.py" <<'PY'
+import socket, sys
+sock = socket.socket(socket.AF_INET, socket.SOCK_DGRAM)
+sock.bind(("127.0.0.1", int(sys.argv[1])))
+while True:
+    data, client = sock.recvfrom(65535)
+    sock.sendto(sys.argv[2].encode() + b" " + data, client)
+PY
+python3 "$DIR/backend.py" 8089 a &
+A=$!
+python3 "$DIR/backend.py" 8090 b &
+B=$!
+
+# Clients, each from a socket of its own: <clients> <datagrams> <interval>.
+# Sends every client's datagrams in turn, then prints per client the
+# backends that answered, in order, or "-" for a datagram left unanswered;
+# "mixed" when a reply carried another client's datagram.
+cat > "$DIR/client.py" <<'PY'
+import socket, sys, time
+clients, datagrams, interval = int(sys.argv[1]), int(sys.argv[2]), float(sys.argv[3])
+socks = []
+for _ in range(clients):
+    sock = socket.socket(socket.AF_INET, socket.SOCK_DGRAM)
+    sock.settimeout(1)
+    socks.append(sock)
+answers = [[] for _ in socks]
+for n in range(datagrams):
+    for i, sock in enumerate(socks):
+        payload = b"client %d datagram %d" % (i, n)
+        sock.sendto(payload, ("127.0.0.1", 3088))
+        try:
+            reply = sock.recv(65535)
+        except socket.timeout:
+            answers[i].append("-")
+            continue
+        name, _, echoed = reply.partition(b" ")
+        answers[i].append(name.decode() if echoed == payload else "mixed")
+    time.sleep(interval)
+for a in answers:
+    print("".join(a))
+PY
+clients() {
+    timeout 60 python3 "$DIR/client.py" "$@"
+}
+
+# <extra config>
+start_flax() {
+    cat > "$DIR/flax.toml" <<TOML
+listen = "127.0.0.1:3088"
+mode = "udp"
+workers = 1
+backends = ["127.0.0.1:8089", "127.0.0.1:8090"]
+
+$1
+
+[admin]
+listen = "127.0.0.1:9001"
+token = "udp-test"
+TOML
+    $FLAX "$DIR/flax.toml" 2>> "$DIR/flax.log" &
+    PID=$!
+    sleep 1
+}
+
+metric() {
+    curl -s -H "Authorization: Bearer udp-test" http://127.0.0.1:9001/metrics |
+        awk -v name="$1" '$1 ~ "^"name"([{]|$)" { sum += $2 } END { print sum + 0 }'
+}
+
+start_flax '
+[udp]
+idle_timeout_ms = 1000'
+echo -e "${BLUE}Flows${NC}"
+clients 4 3 0 > "$DIR/flows"
+check "each flow stays on its backend" "aaa bbb aaa bbb" "$(echo $(cat "$DIR/flows"))"
+check "flows open" 4 "$(metric flax_active_udp_flows)"
+check "20 clients answered their own datagrams" 100 \
+    "$(clients 20 5 0 | grep -o '[ab]' | wc -l)"
+sleep 2.5
+check "idle flows expire" 0 "$(metric flax_active_udp_flows)"
+check "flows opened" 24 "$(metric flax_udp_flows_total)"
+check "a datagram after expiry opens a new flow" "a" "$(clients 1 1 0)"
+check "64 KiB datagram" 60002 "$(python3 -c '
+import socket
+s = socket.socket(socket.AF_INET, socket.SOCK_DGRAM)
+s.settimeout(2)
+s.sendto(b"x" * 60000, ("127.0.0.1", 3088))
+print(len(s.recv(65535)))')"
+kill $PID
+wait $PID
+check "clean exit" 0 $?
+
+echo -e "${BLUE}Session timeout${NC}"
+start_flax '
+[udp]
+session_timeout_ms = 1000'
+check "busy flow ends at the session timeout" ab "$(clients 1 16 0.2 | tr -s ab | cut -c1-2)"
+kill $PID
+wait $PID
+
+echo -e "${BLUE}Affinity${NC}"
+start_flax '
+[udp]
+affinity = "client_ip"'
+check "every port of a client to one backend" 1 \
+    "$(clients 8 2 0 | sort -u | wc -l)"
+kill $PID
+wait $PID
+
+echo -e "${BLUE}Refusing backend${NC}"
+cat > "$DIR/flax.toml" <<TOML
+listen = "127.0.0.1:3088"
+mode = "udp"
+workers = 1
+backends = ["127.0.0.1:8089", "127.0.0.1:8091"]
+TOML
+$FLAX "$DIR/flax.toml" 2>> "$DIR/flax.log" &
+PID=$!
+sleep 1
+clients 6 1 0 > /dev/null
+check "unreachable backend taken out of rotation" "aaaa" "$(echo $(clients 4 1 0) | tr -d ' ')"
+
+kill $PID
+wait $PID
+check "clean exit" 0 $?
+
+kill $A $B
+rm -rf "$DIR"
+exit $fail
@@ -0,0 +1,155 @@
+#!/bin/bash
+# Unix sockets: backends at a path and in the abstract namespace, balanced,
+# kept alive and added through the admin API; listeners on both kinds, in http
+# and tcp mode, replacing stale socket files but not live ones.
+# Starts its own backends on Unix sockets only.
+
+GREEN='\033[0;32m'
+RED='\033[0;31m'
+BLUE='\033[0;34m'
+NC='\033[0m'
+
+FLAX=${FLAX:-../target/release/flax}
+DIR=$(mktemp -d /tmp/flax-unix.XXXXXX)
+# abstract names outlive no process, but must not clash with another run
+ABSTRACT=flax-test-$$
+
+fail=0
+check() {
+    local name=$1 expected=$2 actual=$3
+    if [ "$actual" = "$expected" ]; then
+        echo -e "${GREEN}✓${NC} $name"
+    else
+        echo -e "${RED}✗${NC} $name: expected $expected, got $actual"
+        fail=1
+    fi
+}
+
+# Backend answering every request on a connection with its name; "@name"
+# binds in the abstract namespace
+cat > "$DIR/backend.py" <<'PY'
+import socketserver, sys
+name, path = sys.argv[1], sys.argv[2]
+class Backend(socketserver.StreamRequestHandler):
+    def handle(self):
+        while self.rfile.readline():
+            while self.rfile.readline() not in (b"\r\n", b""):
+                pass
+            body = name.encode()
+            self.wfile.write(b"HTTP/1.1 200 OK\r\nContent-Length: %d\r\n\r\n%s" % (len(body), body))
+class Server(socketserver.ThreadingUnixStreamServer):
+    daemon_threads = True
+Server("\0" + path[1:] if path.startswith("@") else path, Backend).serve_forever()
+PY
+python3 "$DIR/backend.py" a "$DIR/a.sock" &
+A=$!
+python3 "$DIR/backend.py" b "@$ABSTRACT-b" &
+B=$!
+python3 "$DIR/backend.py" c "$DIR/c.sock" &
+C=$!
+sleep 0.5
+
+# <listen> <mode> <backends> [extra config]
+start_flax() {
+    cat > "$DIR/flax.toml" <<TOML
+listen = "$1"
+mode = "$2"
+workers = 1
+backends = $3
+
+$4
+TOML
+    $FLAX "$DIR/flax.toml" 2>> "$DIR/flax.log" &
+    PID=$!
+    sleep 1
+}
+
+ADMIN=http://127.0.0.1:9001
+admin() {
+    curl -s -H "Authorization: Bearer unix-test" "$@"
+}
+
+metric() {
+    admin $ADMIN/metrics |
+        awk -v name="$1" 'index($1, name) == 1 { sum += $2 } END { print sum + 0 }'
+}
+
+echo -e "${BLUE}Unix backends${NC}"
+start_flax 127.0.0.1:3088 http "[\"unix:$DIR/a.sock\", \"unix:@$ABSTRACT-b\"]" '
+[admin]
+listen = "127.0.0.1:9001"
+token = "unix-test"'
+responses=""
+for _ in 1 2 3 4; do
+    responses+=$(curl -s -m 5 http://127.0.0.1:3088/)
+done
+check "path and abstract backends balanced" abab "$responses"
+check "backend label" 2 \
+    "$(metric "flax_backend_selected_total{pool=\"default\",backend=\"unix:@$ABSTRACT-b\"}")"
+check "keep-alive requests on one backend connection" aaa \
+    "$(curl -s -m 5 http://127.0.0.1:3088/ http://127.0.0.1:3088/ http://127.0.0.1:3088/)"
+
+check "backend added through the admin API" 201 "$(admin -o /dev/null -w '%{http_code}' \
+    -X POST -d "{\"address\": \"unix:$DIR/c.sock\"}" $ADMIN/pools/default/backends)"
+encoded=$(python3 -c 'import sys, urllib.parse; print(urllib.parse.quote(sys.argv[1], safe=""))' "unix:$DIR/c.sock")
+check "backend looked up percent-encoded" "unix:$DIR/c.sock" \
+    "$(admin $ADMIN/pools/default/backends/$encoded | python3 -c 'import json, sys; print(json.load(sys.stdin)["address"])')"
+responses=""
+for _ in 1 2 3; do
+    responses+=$(curl -s -m 5 http://127.0.0.1:3088/)
+done
+check "new backend in rotation" "abc" "$(echo "$responses" | grep -o . | sort | tr -d '\n')"
+check "backend removed" 200 "$(admin -o /dev/null -w '%{http_code}' -X DELETE $ADMIN/pools/default/backends/$encoded)"
+
+admin -o /dev/null -X POST -d "{\"address\": \"unix:$DIR/missing.sock\"}" $ADMIN/pools/default/backends
+codes=""
+for _ in $(seq 9); do
+    codes+=$(curl -s -o /dev/null -w '%{http_code} ' -m 5 http://127.0.0.1:3088/)
+done
+check "missing socket answers 502" 3 "$(echo $codes | grep -o 502 | wc -l)"
+codes=""
+for _ in $(seq 6); do
+    codes+=$(curl -s -o /dev/null -w '%{http_code}' -m 5 http://127.0.0.1:3088/)
+done
+check "missing socket taken out of rotation" 200200200200200200 "$codes"
+kill $PID
+wait $PID
+check "clean exit" 0 $?
+
+echo -e "${BLUE}Unix listeners${NC}"
+# a socket file left behind by a process that is gone
+python3 -c 'import socket, sys; socket.socket(socket.AF_UNIX).bind(sys.argv[1])' "$DIR/flax.sock"
+start_flax "unix:$DIR/flax.sock" http "[\"unix:$DIR/a.sock\"]"
+check "stale socket file replaced" a "$(curl -s -m 5 --unix-socket "$DIR/flax.sock" http://localhost/)"
+cp "$DIR/flax.toml" "$DIR/second.toml"
+timeout 5 $FLAX "$DIR/second.toml" 2>> "$DIR/flax.log"
+check "live socket left alone" 1 $?
+check "still served" a "$(curl -s -m 5 --unix-socket "$DIR/flax.sock" http://localhost/)"
+kill $PID
+wait $PID
+
+start_flax "unix:@$ABSTRACT-listen" tcp "[\"unix:@$ABSTRACT-b\"]"
+check "abstract listener relaying to an abstract backend" b \
+    "$(curl -s -m 5 --abstract-unix-socket "$ABSTRACT-listen" http://localhost/)"
+kill $PID
+wait $PID
+check "clean exit" 0 $?
+
+echo -e "${BLUE}Configuration${NC}"
+cat > "$DIR/bad.toml" <<TOML
+listen = "unix:$DIR/udp.sock"
+mode = "udp"
+backends = ["127.0.0.1:8089"]
+TOML
+check "no unix listener for udp" 1 "$($FLAX "$DIR/bad.toml" 2>&1 | grep -c "unix listen address")"
+cat > "$DIR/bad.toml" <<TOML
+backends = ["unix:$DIR/a.sock"]
+
+[backend_tls]
+ca = "$DIR/ca.pem"
+TOML
+check "tls to a unix backend needs server_name" 1 "$($FLAX "$DIR/bad.toml" 2>&1 | grep -c "needs server_name")"
+
+kill $A $B $C
+rm -rf "$DIR"
+exit $fail
@@ -0,0 +1,51 @@
+#!/bin/bash
+# Zero-downtime upgrade: hammer the load balancer while a second Flax
+# process takes over the listeners, and count failed requests.
+# Needs the backends from start-backends.sh (or anything on 8081-8083).
+
+GREEN='\033[0;32m'
+RED='\033[0;31m'
+BLUE='\033[0;34m'
+NC='\033[0m'
+
+FLAX=${FLAX:-../target/release/flax}
+DIR=$(mktemp -d /tmp/flax-upgrade.XXXXXX)
+CONFIG=$DIR/flax.toml
+printf 'upgrade_socket = "%s"\n' "$DIR/upgrade.sock" > "$CONFIG"
+
+echo -e "${BLUE}Starting old process${NC}"
+$FLAX "$CONFIG" 2> "$DIR/old.log" &
+OLD=$!
+sleep 1
+
+fail=0
+total=0
+end=$((SECONDS + 6))
+(
+    sleep 2
+    echo -e "${BLUE}Starting new process with --upgrade${NC}"
+    $FLAX --upgrade "$CONFIG" 2> "$DIR/new.log" &
+    echo $! > "$DIR/new.pid"
+) &
+
+while [ $SECONDS -lt $end ]; do
+    if ! curl -s -f -m 2 http://localhost:3000/small.txt > /dev/null; then
+        fail=$((fail + 1))
+    fi
+    total=$((total + 1))
+done
+
+wait $OLD
+echo "Old process exited with $?"
+tail -n +1 "$DIR/old.log" | grep -E "handoff|drained"
+
+kill -TERM "$(cat "$DIR/new.pid")"
+sleep 1
+rm -rf "$DIR"
+
+if [ $fail -eq 0 ]; then
+    echo -e "${GREEN}✓ $total requests, none failed during upgrade${NC}"
+else
+    echo -e "${RED}✗ $fail of $total requests failed during upgrade${NC}"
+    exit 1
+fi
@@ -0,0 +1,249 @@
+#!/bin/bash
+# TLS to backends: CA verification, SNI override, client certificates, ALPN,
+# session resumption on fresh connects, reuse of cached connections and
+# their closing once their backend is removed, and request and response
+# bodies, behind a plaintext and a TLS listener.
+# Starts its own TLS backends on 8095-8098 and needs openssl for the test
+# certificates.
+
+GREEN='\033[0;32m'
+RED='\033[0;31m'
+BLUE='\033[0;34m'
+NC='\033[0m'
+
+FLAX=${FLAX:-../target/release/flax}
+OPENSSL=${OPENSSL:-openssl}
+DIR=$(mktemp -d /tmp/flax-upstream-tls.XXXXXX)
+
+fail=0
+check() {
+    local name=$1 expected=$2 actual=$3
+    if [ "$actual" = "$expected" ]; then
+        echo -e "${GREEN}✓${NC} $name"
+    else
+        echo -e "${RED}✗${NC} $name: expected $expected, got $actual"
+        fail=1
+    fi
+}
+
+# test CA, and certificates it signs: <file> <CN> <subjectAltName>
+"$OPENSSL" req -x509 -newkey rsa:2048 -nodes -days 1 -subj "/CN=Test CA" \
+    -keyout "$DIR/ca.key" -out "$DIR/ca.pem" 2> /dev/null
+signed() {
+    "$OPENSSL" req -newkey rsa:2048 -nodes -subj "/CN=$2" \
+        -keyout "$DIR/$1.key" -out "$DIR/$1.csr" 2> /dev/null
+    echo "subjectAltName=$3" > "$DIR/$1.ext"
+    "$OPENSSL" x509 -req -in "$DIR/$1.csr" -CA "$DIR/ca.pem" -CAkey "$DIR/ca.key" \
+        -CAcreateserial -days 1 -extfile "$DIR/$1.ext" -out "$DIR/$1.pem" 2> /dev/null
+}
+signed by-address backend IP:127.0.0.1
+signed by-name backend.test DNS:backend.test
+signed client flax-client DNS:flax-client
+signed listener localhost DNS:localhost
+# valid for 127.0.0.1 but from a CA Flax does not trust
+"$OPENSSL" req -x509 -newkey rsa:2048 -nodes -days 1 -subj "/CN=rogue" \
+    -addext "subjectAltName=IP:127.0.0.1" \
+    -keyout "$DIR/rogue.key" -out "$DIR/rogue.pem" 2> /dev/null
+
+# HTTPS backend describing the TLS session a request came in on; with
+# KEEPALIVE set it keeps connections open, /connection tells which one a
+# request came in on and how many requests it carried, and /open how many
+# connections are open
+cat > "$DIR/backend.py" <<'PY'
+import hashlib, http.server, os, ssl, sys
+port, name, cert, key = int(sys.argv[1]), sys.argv[2], sys.argv[3], sys.argv[4]
+ctx = ssl.SSLContext(ssl.PROTOCOL_TLS_SERVER)
+ctx.load_cert_chain(cert, key)
+ctx.set_alpn_protocols(["http/1.1"])
+if len(sys.argv) > 5:
+    ctx.load_verify_locations(sys.argv[5])
+    ctx.verify_mode = ssl.CERT_REQUIRED
+sni = [None]
+ctx.sni_callback = lambda sock, server_name, context: sni.__setitem__(0, server_name)
+class Backend(http.server.BaseHTTPRequestHandler):
+    if os.environ.get("KEEPALIVE"):
+        protocol_version = "HTTP/1.1"
+    requests = 0
+    open = 0
+    def setup(self):
+        super().setup()
+        Backend.open += 1
+    def finish(self):
+        Backend.open -= 1
+        super().finish()
+    def reply(self, body):
+        self.send_response(200)
+        self.send_header("Content-Length", str(len(body)))
+        self.end_headers()
+        self.wfile.write(body)
+    def do_GET(self):
+        self.requests += 1
+        if self.path == "/connection":
+            return self.reply(f"{self.client_address[1]} {self.requests}".encode())
+        if self.path == "/open":
+            return self.reply(str(Backend.open).encode())
+        if self.path.startswith("/bytes/"):
+            return self.reply(b"x" * int(self.path[7:]))
+        tls = self.connection
+        subject = dict(rdn[0] for rdn in (tls.getpeercert() or {}).get("subject", ()))
+        self.reply(" ".join([
+            name,
+            f"sni={sni[0]}",
+            f"client={subject.get('commonName')}",
+            f"alpn={tls.selected_alpn_protocol()}",
+            f"resumed={tls.session_reused}",
+        ]).encode())
+    def do_POST(self):
+        body = self.rfile.read(int(self.headers["Content-Length"]))
+        self.reply(hashlib.md5(body).hexdigest().encode())
+    def log_message(self, *args):
+        pass
+server = http.server.ThreadingHTTPServer(("127.0.0.1", port), Backend)
+server.socket = ctx.wrap_socket(server.socket, server_side=True)
+server.serve_forever()
+PY
+python3 "$DIR/backend.py" 8095 main "$DIR/by-address.pem" "$DIR/by-address.key" &
+MAIN=$!
+python3 "$DIR/backend.py" 8096 mtls "$DIR/by-name.pem" "$DIR/by-name.key" "$DIR/ca.pem" &
+MTLS=$!
+python3 "$DIR/backend.py" 8097 rogue "$DIR/rogue.pem" "$DIR/rogue.key" &
+ROGUE=$!
+KEEPALIVE=1 python3 "$DIR/backend.py" 8098 keepalive "$DIR/by-address.pem" "$DIR/by-address.key" &
+KEEPALIVE=$!
+
+start_flax() {
+    cat > "$DIR/flax.toml" <<TOML
+listen = "127.0.0.1:3095"
+backends = ["127.0.0.1:8095"]
+# one worker, so every request sees the same connection cache
+workers = 1
+
+[backend_tls]
+ca = "$DIR/ca.pem"
+
+[pools.mtls]
+backends = ["127.0.0.1:8096"]
+
+[pools.mtls.tls]
+ca = "$DIR/ca.pem"
+server_name = "backend.test"
+cert = "$DIR/client.pem"
+key = "$DIR/client.key"
+
+[pools.rogue]
+backends = ["127.0.0.1:8097"]
+
+[pools.rogue.tls]
+ca = "$DIR/ca.pem"
+
+[hosts."mtls.test"]
+pool = "mtls"
+
+[hosts."rogue.test"]
+pool = "rogue"
+
+[pools.keepalive]
+backends = ["127.0.0.1:8098"]
+
+[pools.keepalive.tls]
+ca = "$DIR/ca.pem"
+
+[hosts."keepalive.test"]
+pool = "keepalive"
+
+[admin]
+listen = "127.0.0.1:9000"
+token = "upstream-tls-test"
+$1
+TOML
+    $FLAX "$DIR/flax.toml" 2>> "$DIR/flax.log" &
+    PID=$!
+    sleep 1
+}
+
+metric() {
+    curl -s -H "Authorization: Bearer upstream-tls-test" http://127.0.0.1:9000/metrics |
+        awk -v name="$1" '$1 ~ "^"name"[{]" { sum += $2 } END { print sum + 0 }'
+}
+
+head -c 3000000 /dev/urandom > "$DIR/upload.bin"
+upload_md5=$(md5sum < "$DIR/upload.bin" | cut -c1-32)
+
+start_flax ""
+URL=http://127.0.0.1:3095
+
+echo -e "${BLUE}Verification and client certificates${NC}"
+check "verified by the pool's CA" \
+    "main sni=None client=None alpn=http/1.1 resumed=False" "$(curl -s $URL/)"
+check "server_name is sent as SNI and verified" \
+    "mtls sni=backend.test client=flax-client alpn=http/1.1 resumed=False" \
+    "$(curl -s -H 'Host: mtls.test' $URL/)"
+check "untrusted backend answered 502" 502 \
+    "$(curl -s -o /dev/null -w '%{http_code}' -H 'Host: rogue.test' $URL/)"
+check "handshake failure counted" 1 "$(metric flax_backend_tls_errors_total)"
+
+echo -e "${BLUE}Session resumption${NC}"
+check "fresh connect resumes" \
+    "main sni=None client=None alpn=http/1.1 resumed=True" "$(curl -s $URL/)"
+check "fresh connect with client certificate resumes" \
+    "mtls sni=backend.test client=flax-client alpn=http/1.1 resumed=True" \
+    "$(curl -s -H 'Host: mtls.test' $URL/)"
+check "resumptions counted" 2 "$(metric flax_backend_tls_resumed_total)"
+
+echo -e "${BLUE}Connection reuse${NC}"
+first=$(curl -s -H 'Host: keepalive.test' $URL/connection)
+second=$(curl -s -H 'Host: keepalive.test' $URL/connection)
+check "second request through the cached connection" "${first% *} 2" "$second"
+check "cache hit counted" 1 "$(metric flax_backend_cache_hits_total)"
+check "backend closing its connection" \
+    "main sni=None client=None alpn=http/1.1 resumed=True" "$(curl -s $URL/)"
+check "closed connections not cached" 1 "$(metric flax_backend_cache_hits_total)"
+# the one asking and the one cached
+check "connection cached" 2 "$(curl -s --cacert "$DIR/ca.pem" https://127.0.0.1:8098/open)"
+curl -s -o /dev/null -X DELETE -H "Authorization: Bearer upstream-tls-test" \
+    http://127.0.0.1:9000/pools/keepalive/backends/127.0.0.1:8098
+# any request has the worker look for connections to removed backends
+curl -s -o /dev/null $URL/
+sleep 0.2
+check "closed once its backend is removed" 1 \
+    "$(curl -s --cacert "$DIR/ca.pem" https://127.0.0.1:8098/open)"
+
+echo -e "${BLUE}Bodies${NC}"
+check "2 MB response" 2000000 "$(curl -s $URL/bytes/2000000 | wc -c)"
+check "3 MB request body" "$upload_md5" "$(curl -s --data-binary @"$DIR/upload.bin" $URL/)"
+
+pids=()
+for i in $(seq 1 20); do
+    curl -s -o "$DIR/concurrent.$i" $URL/bytes/500000 &
+    pids+=($!)
+done
+wait "${pids[@]}"
+ok=0
+for i in $(seq 1 20); do
+    [ "$(wc -c < "$DIR/concurrent.$i")" = 500000 ] && ok=$((ok + 1))
+done
+check "20 concurrent downloads" 20 $ok
+
+kill $PID
+wait $PID
+start_flax "
+[tls]
+cert = \"$DIR/listener.pem\"
+key = \"$DIR/listener.key\""
+URL=https://localhost:3095
+
+echo -e "${BLUE}TLS on both sides${NC}"
+check "client certificate on a TLS listener" \
+    "mtls sni=backend.test client=flax-client alpn=http/1.1 resumed=False" \
+    "$(curl -s --cacert "$DIR/ca.pem" -H 'Host: mtls.test' $URL/)"
+check "2 MB response" 2000000 "$(curl -s --cacert "$DIR/ca.pem" $URL/bytes/2000000 | wc -c)"
+check "3 MB request body" "$upload_md5" \
+    "$(curl -s --cacert "$DIR/ca.pem" --data-binary @"$DIR/upload.bin" $URL/)"
+
+kill $PID
+wait $PID
+check "clean exit" 0 $?
+
+kill $MAIN $MTLS $ROGUE $KEEPALIVE
+rm -rf "$DIR"
+exit $fail